@@ -1,11 +1,19 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{ArgAction, Parser, Subcommand};
+
+use crate::cache::cache_options::CacheMode;
+use crate::exec::work_list::Schedule;
+use crate::file_system::output_times::OutputTimes;
+use crate::subcommand::completions::CompletionShell;
 
 /// Command-line arguments for Hexmake
 #[derive(Parser)]
 #[command(version)]
-#[command(arg_required_else_help = true)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(disable_help_subcommand = true)]
 #[command(about = "Run a multi-step build with caching")]
 #[command(
     long_about = r#"Hexmake runs a multi-step build using caching. You give it a file describing all
@@ -15,8 +23,8 @@ when possible.
 "#
 )]
 #[command(
-    after_long_help = r#"The tool expects a Hexmake file to exist in the current directory.
-A Hexmake file looks like this:
+    after_long_help = r#"The tool expects a Hexmake file to exist in the current directory or one of
+its parents. A Hexmake file looks like this:
 
 ```json
 {
@@ -36,13 +44,468 @@ A Hexmake file looks like this:
   ]
 }
 ```
+
+Exit codes:
+  0  The command succeeded
+  1  The build failed: a rule failed, the build timed out, or the cache audit
+     found a difference
+  2  The command line is wrong
+  3  The Hexmake file could not be read or parsed
+  4  The Hexmake file does not make sense
+  5  The targets could not be planned
+  6  Hexmake itself failed, such as because a file could not be written
 "#
 )]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// The rules or output files to build
     pub targets: Vec<Arc<String>>,
 
+    /// Read the Hexmake file from PATH, or from standard input if PATH is
+    /// `-`, instead of looking for it. Paths in it are relative to the
+    /// current directory.
+    #[arg(short, long, value_name = "PATH", global = true)]
+    pub file: Option<PathBuf>,
+
+    /// Change to DIR before doing anything else, including looking for
+    /// the Hexmake file
+    #[arg(short = 'C', long, value_name = "DIR", global = true)]
+    pub directory: Option<PathBuf>,
+
+    /// Build every rule in the Hexmake file
+    #[arg(long)]
+    pub all: bool,
+
+    /// Build every rule that is affected by changes to the given files
+    #[arg(long, value_name = "PATH", num_args = 1.., conflicts_with_all = ["targets", "all"])]
+    pub affected_by: Vec<Arc<String>>,
+
     /// List available targets and exit
     #[arg(long)]
     pub list_targets: bool,
+
+    /// With --list-targets, show each target's description, outputs, and tags
+    #[arg(long, requires = "list_targets")]
+    pub long: bool,
+
+    /// With --list-targets, print the targets as JSON
+    #[arg(long, requires = "list_targets", conflicts_with = "long")]
+    pub json: bool,
+
+    /// With --list-targets, print a `//package:rule` label for every rule
+    /// and alias, including those in sub-workspaces
+    #[arg(long, requires = "list_targets", conflicts_with_all = ["long", "json"])]
+    pub labels: bool,
+
+    /// With --list-targets, only list targets whose names match a glob
+    #[arg(long, value_name = "GLOB", requires = "list_targets")]
+    pub filter: Option<String>,
+
+    /// Print the commands that building the targets would run, then exit
+    #[arg(long)]
+    pub print_commands: bool,
+
+    /// Print each rule that building the targets involves, in order, with
+    /// its commands and whether it is expected to come from the cache,
+    /// then exit without building
+    #[arg(long, conflicts_with = "print_commands")]
+    pub dry_run: bool,
+
+    /// Run a rule's commands in the workspace under strace, and print the
+    /// files they read as a suggested `inputs` list
+    #[arg(long, value_name = "RULE", conflicts_with_all = ["targets", "all", "affected_by"])]
+    pub trace_fs: Option<Arc<String>>,
+
+    /// Show the build on a full-screen display with a row per worker,
+    /// instead of printing a line for each step
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Serve a page that shows the progress of the build, at
+    /// http://127.0.0.1:PORT/ (8080 if no port is given)
+    #[arg(
+        long,
+        value_name = "PORT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "8080"
+    )]
+    pub web_ui: Option<u16>,
+
+    /// Write a summary of the build to a file when it finishes, as
+    /// Markdown if the file name ends in .md and as JSON otherwise
+    #[arg(long, value_name = "PATH")]
+    pub summary_file: Option<PathBuf>,
+
+    /// After a successful build, write out/MANIFEST.json listing every
+    /// output with its hash, size, and the rule that produced it
+    #[arg(long)]
+    pub manifest: bool,
+
+    /// Show a desktop notification when the build finishes
+    #[arg(long)]
+    pub notify: bool,
+
+    /// Run a shell command when the build finishes, instead of showing a
+    /// desktop notification
+    #[arg(long, value_name = "COMMAND")]
+    pub notify_command: Option<String>,
+
+    /// Print only errors, warnings, and a summary at the end of the build
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print more about each rule. With -v, print the worker, cache key,
+    /// CPU time, peak memory, and disk I/O of each rule, and with -vv,
+    /// also the output of commands that is hidden once another one fails.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// When a rule fails, keep building the rules that do not depend on
+    /// it, and report every failure at the end
+    #[arg(short, long)]
+    pub keep_going: bool,
+
+    /// How many tasks to run at once (one per CPU by default)
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: Option<u32>,
+
+    /// Wait to start more tasks while the system load average is above N
+    #[arg(short, long, value_name = "N")]
+    pub load_average: Option<f64>,
+
+    /// Which ready task to start first when several have the same priority
+    #[arg(long, value_enum, default_value_t = Schedule::Depth)]
+    pub schedule: Schedule,
+
+    /// Cancel the build if it is still running after this long, such as
+    /// 90s, 15m, or 2h
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Give a variable listed in the Hexmake file's `env` a value for this
+    /// build, overriding the environment and the env file
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_setting)]
+    pub env_overrides: Vec<(String, String)>,
+
+    /// Give a build parameter listed in the Hexmake file's `defines` a
+    /// value for this build
+    #[arg(long = "define", value_name = "NAME=VALUE", value_parser = parse_setting)]
+    pub defines: Vec<(String, String)>,
+
+    /// Rebuild the given targets even if their outputs are in the cache
+    #[arg(long)]
+    pub force: bool,
+
+    /// Rebuild every rule even if its outputs are in the cache
+    #[arg(long)]
+    pub force_all: bool,
+
+    /// How to use the build cache
+    #[arg(long, value_enum, default_value_t = CacheMode::ReadWrite)]
+    pub cache: CacheMode,
+
+    /// Rebuild N% of the rules whose outputs come from the cache (10% if
+    /// no N is given), and fail if any output comes out different from
+    /// the cached one
+    #[arg(
+        long,
+        value_name = "N%",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10%",
+        value_parser = parse_percent
+    )]
+    pub audit_cache: Option<u32>,
+
+    /// Hard link newly built outputs to their copies in the cache
+    #[arg(long)]
+    pub hardlink_outputs: bool,
+
+    /// What modification time outputs get when they are copied into out/
+    #[arg(long, value_enum, default_value_t = OutputTimes::Now)]
+    pub output_times: OutputTimes,
+
+    /// Fail if any source file or download differs from Hexmake.lock
+    #[arg(long)]
+    pub locked: bool,
+}
+
+/// Parse a duration for `--timeout`: a number followed by `s`, `m`, or
+/// `h`, or a plain number of seconds
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit_seconds) = match text.as_bytes().last() {
+        Some(b's') => (&text[..text.len() - 1], 1),
+        Some(b'm') => (&text[..text.len() - 1], 60),
+        Some(b'h') => (&text[..text.len() - 1], 60 * 60),
+        _ => (text, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => match number.checked_mul(unit_seconds) {
+            Some(seconds) => Ok(Duration::from_secs(seconds)),
+            None => Err("duration is too large".to_string()),
+        },
+        _ => Err(format!(
+            "`{text}` is not a duration, such as 90s, 15m, or 2h"
+        )),
+    }
+}
+
+/// Parse a percentage for `--audit-cache`, such as `25%`. The percent
+/// sign may be left off.
+fn parse_percent(text: &str) -> Result<u32, String> {
+    match text.strip_suffix('%').unwrap_or(text).parse::<u32>() {
+        Ok(percent) if (1..=100).contains(&percent) => Ok(percent),
+        _ => Err(format!("`{text}` is not a percentage from 1% to 100%")),
+    }
+}
+
+/// Parse a `NAME=VALUE` setting for `--env` or `--define`
+fn parse_setting(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("`{text}` is not of the form NAME=VALUE")),
+    }
+}
+
+/// Subcommands for operations other than building
+#[derive(Subcommand)]
+pub enum Command {
+    /// Add a rule to the end of the Hexmake file
+    AddRule {
+        /// The name of the new rule
+        name: String,
+
+        /// An input of the rule; can be given more than once
+        #[arg(long = "input", value_name = "PATH")]
+        inputs: Vec<String>,
+
+        /// An output of the rule; can be given more than once
+        #[arg(long = "output", value_name = "PATH", required = true)]
+        outputs: Vec<String>,
+
+        /// A shell command for the rule to run; can be given more than once
+        #[arg(long = "command", value_name = "COMMAND")]
+        commands: Vec<String>,
+    },
+
+    /// Time repeated cold-cache, warm-cache, and no-op builds of a target
+    Bench {
+        /// The rule, alias, or output file to build
+        target: Arc<String>,
+
+        /// How many builds of each kind to time
+        #[arg(long, value_name = "N", default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+    },
+
+    /// Manage the build cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+
+    /// Remove the outputs of the given targets and everything they depend on
+    Clean {
+        /// The rules or output files whose outputs should be removed
+        #[arg(required_unless_present = "stale")]
+        targets: Vec<Arc<String>>,
+
+        /// Also remove the cache entries for those rules
+        #[arg(long)]
+        cache: bool,
+
+        /// Remove files in out/ that are not an output of any rule
+        #[arg(long)]
+        stale: bool,
+    },
+
+    /// Print a script that makes a shell complete Hexmake's subcommands,
+    /// options, and targets
+    Completions {
+        /// The shell to print the script for
+        shell: CompletionShell,
+    },
+
+    /// Check the Hexmake file, the cache, the shell, and the file system
+    /// for anything that would break or slow down builds, and say how to
+    /// fix it
+    Doctor {
+        /// Also check that this remote cache archive, as given to `hexmake
+        /// cache pull`, can be reached
+        #[arg(long, value_name = "URL")]
+        remote: Option<String>,
+    },
+
+    /// Print what builds would see of this machine, as JSON: the variables
+    /// passed through to commands, the build parameters, the toolchains,
+    /// and the Hexmake version and OS
+    Env,
+
+    /// Rewrite the Hexmake file with sorted keys, rules, and inputs
+    Fmt {
+        /// Only check that the file is formatted, and fail if it is not
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Build a target and copy its outputs into a prefix directory
+    Install {
+        /// The rule, alias, or output file to build and install
+        target: Arc<String>,
+
+        /// The directory to install into, such as /usr/local
+        #[arg(long, value_name = "DIR")]
+        prefix: PathBuf,
+    },
+
+    /// Record the hashes of all source files and downloads in Hexmake.lock
+    Lock,
+
+    /// Print the output files of the given targets
+    Outputs {
+        /// The rules, aliases, or output files to look up
+        #[arg(required = true)]
+        targets: Vec<Arc<String>>,
+
+        /// Print a JSON object mapping each rule name to its outputs
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Print the tasks that would run to build the given targets, in order
+    Plan {
+        /// The rules, aliases, or output files to plan
+        #[arg(required = true)]
+        targets: Vec<Arc<String>>,
+
+        /// Print a JSON array with one object per task
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Answer questions about the build graph
+    Query {
+        #[command(subcommand)]
+        query: Query,
+    },
+
+    /// Remove a rule from the Hexmake file, if no other rule or alias uses it
+    RemoveRule {
+        /// The name of the rule to remove
+        name: String,
+    },
+
+    /// Replace the inputs of a rule in the Hexmake file
+    SetInputs {
+        /// The name of the rule to change
+        name: String,
+
+        /// The new inputs of the rule
+        paths: Vec<String>,
+    },
+
+    /// Replace the outputs of a rule in the Hexmake file
+    SetOutputs {
+        /// The name of the rule to change
+        name: String,
+
+        /// The new outputs of the rule
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
+    /// List what differs for a target and the rules it depends on, compared
+    /// to when an earlier build hashed them
+    WhyChanged {
+        /// The rule, alias, or output file to explain
+        target: Arc<String>,
+
+        /// The ID of the build to compare against, instead of the most
+        /// recent one
+        #[arg(long, value_name = "BUILD_ID")]
+        against: Option<String>,
+    },
+}
+
+/// Operations on the build cache, for `hexmake cache`
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Merge a cache archive into the cache, skipping entries it already has
+    Pull {
+        /// The URL or path of a .tar.gz cache archive
+        source: String,
+    },
+
+    /// Package the cache entries that the last build used, and upload them
+    Push {
+        /// The URL to upload the .tar.gz cache archive to with HTTP PUT, or
+        /// a path to write it to
+        destination: String,
+    },
+
+    /// Keep the cache entries of the given targets, and of everything they
+    /// depend on, through garbage collection
+    Pin {
+        /// The rules, aliases, or output files whose entries to keep
+        #[arg(required = true)]
+        targets: Vec<Arc<String>>,
+    },
+
+    /// Let garbage collection remove entries that were pinned
+    Unpin {
+        /// The rules, aliases, or output files whose entries to release
+        #[arg(required = true)]
+        targets: Vec<Arc<String>>,
+    },
+}
+
+/// Questions that can be asked with `hexmake query`
+#[derive(Subcommand)]
+pub enum Query {
+    /// Print every source file that the given targets transitively read
+    Sources {
+        /// The rules, aliases, or output files to look up
+        #[arg(required = true)]
+        targets: Vec<Arc<String>>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        for text in ["", "s", "0s", "-5m", "1.5h", "10d"] {
+            assert_eq!(
+                parse_duration(text),
+                Err(format!(
+                    "`{text}` is not a duration, such as 90s, 15m, or 2h"
+                ))
+            );
+        }
+        assert_eq!(
+            parse_duration("999999999999999999h"),
+            Err("duration is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("25%"), Ok(25));
+        assert_eq!(parse_percent("100"), Ok(100));
+        for text in ["", "%", "0%", "101%", "12.5%", "-5%"] {
+            assert_eq!(
+                parse_percent(text),
+                Err(format!("`{text}` is not a percentage from 1% to 100%"))
+            );
+        }
+    }
 }
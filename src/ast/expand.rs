@@ -0,0 +1,774 @@
+//! Expansion of variables in a Hexmake file. This happens before any
+//! hashing, so that the cache key reflects the text that is actually run.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::{HexRule, HexmakeFile, RuleCommand, shell_quote};
+use crate::toolchain::ResolvedTool;
+
+const ENV_START: &str = "$ENV{";
+const DEFINE_START: &str = "$DEFINE{";
+const TOOL_START: &str = "$TOOL{";
+const VAR_START: &str = "${";
+
+/// The values that references in a Hexmake file expand to
+struct Expansions<'a> {
+    /// The names of the environment variables that the file allows
+    allowed_env: &'a [Arc<String>],
+
+    /// The values of those environment variables that are set
+    env: &'a BTreeMap<Arc<String>, Arc<String>>,
+
+    /// The tools in the file's `toolchains` section
+    tools: &'a BTreeMap<Arc<String>, ResolvedTool>,
+
+    /// The values of the file's build parameters
+    defines: &'a BTreeMap<Arc<String>, String>,
+}
+
+/// Expand the references in every command of a Hexmake file, and the
+/// `$DEFINE{name}` references in the paths and contents of its rules.
+/// `env` has the values of the environment variables that the
+/// file allows commands to see, and `tools` has the verified tools
+/// from the `toolchains` section. The hash of every tool a rule
+/// refers to is recorded in the rule, so that it is part of the
+/// cache key, along with the commands as they would be with the
+/// tools' paths left out.
+pub fn expand_file(
+    hexmake_file: &mut HexmakeFile,
+    env: &BTreeMap<Arc<String>, Arc<String>>,
+    tools: &BTreeMap<Arc<String>, ResolvedTool>,
+) -> Result<(), String> {
+    let expansions = Expansions {
+        allowed_env: &hexmake_file.env,
+        env,
+        tools,
+        defines: &hexmake_file.defines,
+    };
+    let portable_tools = tools
+        .iter()
+        .map(|(name, tool)| {
+            let tool = ResolvedTool {
+                path: format!("{TOOL_START}{name}}}"),
+                sha256: tool.sha256.clone(),
+            };
+            (name.clone(), tool)
+        })
+        .collect();
+    let portable_expansions = Expansions {
+        tools: &portable_tools,
+        ..expansions
+    };
+
+    let mut rules = Vec::new();
+    for rule in &hexmake_file.rules {
+        let in_rule = |error| format!("In rule `{}`: {error}", rule.name);
+        let expanded_rule = expansions.expand_rule_fields(rule).map_err(in_rule)?;
+        let rule = expanded_rule.as_ref().unwrap_or(rule);
+
+        let mut used_tools = BTreeSet::new();
+        let commands = expansions
+            .expand_commands(rule, &mut used_tools)
+            .map_err(in_rule)?;
+
+        if commands == rule.commands {
+            rules.push(rule.clone());
+        } else {
+            let portable_commands = if used_tools.is_empty() {
+                None
+            } else {
+                Some(
+                    portable_expansions
+                        .expand_commands(rule, &mut BTreeSet::new())
+                        .map_err(in_rule)?,
+                )
+            };
+            let tool_hashes = used_tools
+                .into_iter()
+                .map(|name| {
+                    let sha256 = tools[&name].sha256.clone();
+                    (name, sha256)
+                })
+                .collect();
+            rules.push(Arc::new(HexRule {
+                commands,
+                tool_hashes,
+                portable_commands,
+                ..(**rule).clone()
+            }));
+        }
+    }
+    hexmake_file.rules = rules;
+
+    Ok(())
+}
+
+/// Substitute the file's `vars` for the `${name}` references in the
+/// inputs, outputs, and commands of its rules. This happens as the file is
+/// parsed, so everything else sees the rules as if they had been written
+/// out in full. A `${name}` whose name is not in `vars` is left alone,
+/// because shell commands use the same syntax for their own variables.
+pub fn expand_vars(hexmake_file: &mut HexmakeFile) -> Result<(), String> {
+    if hexmake_file.vars.is_empty() {
+        return Ok(());
+    }
+
+    let vars = &hexmake_file.vars;
+    let expand_path = |path: &HexPath| HexPath::try_from(substitute_vars(path, vars));
+    let mut rules = Vec::new();
+    for rule in &hexmake_file.rules {
+        let in_rule = |error| format!("In rule `{}`: {error}", rule.name);
+        let inputs: Result<Vec<_>, _> = rule.inputs.iter().map(expand_path).collect();
+        let outputs: Result<Vec<_>, _> = rule.outputs.iter().map(expand_path).collect();
+        let stdout_output = rule.stdout_output.as_ref().map(expand_path).transpose();
+        let commands = rule
+            .commands
+            .iter()
+            .map(|command| match command {
+                RuleCommand::Shell(text) => RuleCommand::Shell(substitute_vars(text, vars)),
+                RuleCommand::Argv(args) => {
+                    RuleCommand::Argv(args.iter().map(|arg| substitute_vars(arg, vars)).collect())
+                }
+            })
+            .collect();
+        rules.push(Arc::new(HexRule {
+            inputs: inputs.map_err(in_rule)?,
+            outputs: outputs.map_err(in_rule)?,
+            stdout_output: stdout_output.map_err(in_rule)?,
+            commands,
+            ..(**rule).clone()
+        }));
+    }
+    hexmake_file.rules = rules;
+
+    Ok(())
+}
+
+/// Replace each `${name}` in a string whose name is in `vars` with its
+/// value. The values are used as they are, without expanding any
+/// references in them except as part of the text they are put into.
+fn substitute_vars(text: &str, vars: &BTreeMap<Arc<String>, String>) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(VAR_START) {
+        result.push_str(&rest[..start]);
+        let after_start = &rest[start + VAR_START.len()..];
+        let value = after_start
+            .find('}')
+            .and_then(|end| Some((end, vars.get(&after_start[..end].to_string())?)));
+        match value {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &after_start[end + 1..];
+            }
+            None => {
+                result.push_str(VAR_START);
+                rest = after_start;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+impl Expansions<'_> {
+    /// Expand the `$DEFINE{name}` references in the paths and content of
+    /// a rule, or return None if it has none
+    fn expand_rule_fields(&self, rule: &HexRule) -> Result<Option<Arc<HexRule>>, String> {
+        let expand_paths = |paths: &[HexPath]| -> Result<Vec<HexPath>, String> {
+            paths.iter().map(|path| self.expand_path(path)).collect()
+        };
+        let inputs = expand_paths(&rule.inputs)?;
+        let outputs = expand_paths(&rule.outputs)?;
+        let stdout_output = match &rule.stdout_output {
+            Some(path) => Some(self.expand_path(path)?),
+            None => None,
+        };
+        let content = match &rule.content {
+            Some(content) => Some(self.expand_defines(content)?),
+            None => None,
+        };
+
+        if inputs == rule.inputs
+            && outputs == rule.outputs
+            && stdout_output == rule.stdout_output
+            && content == rule.content
+        {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(HexRule {
+            inputs,
+            outputs,
+            stdout_output,
+            content,
+            ..rule.clone()
+        })))
+    }
+
+    /// Expand the `$DEFINE{name}` references in a path
+    fn expand_path(&self, path: &HexPath) -> Result<HexPath, String> {
+        if !path.contains(DEFINE_START) {
+            return Ok(path.clone());
+        }
+        HexPath::try_from(self.expand_defines(path)?)
+    }
+
+    /// Expand the `$DEFINE{name}` references in a string, leaving every
+    /// other use of `$` alone
+    fn expand_defines(&self, text: &str) -> Result<String, String> {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(DEFINE_START) {
+            result.push_str(&rest[..start]);
+            let after_start = &rest[start + DEFINE_START.len()..];
+            let Some(end) = after_start.find('}') else {
+                return Err(format!("Unterminated `{DEFINE_START}` in `{text}`"));
+            };
+            result.push_str(self.expand_define(&after_start[..end])?);
+            rest = &after_start[end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /// Expand the references in every command of a rule. The names of
+    /// the tools that the commands refer to are added to `used_tools`.
+    fn expand_commands(
+        &self,
+        rule: &HexRule,
+        used_tools: &mut BTreeSet<Arc<String>>,
+    ) -> Result<Vec<RuleCommand>, String> {
+        let mut commands = Vec::new();
+        for command in &rule.commands {
+            commands.push(match command {
+                RuleCommand::Shell(text) => {
+                    RuleCommand::Shell(self.expand_string(text, rule, true, used_tools)?)
+                }
+                RuleCommand::Argv(args) => {
+                    RuleCommand::Argv(self.expand_args(args, rule, used_tools)?)
+                }
+            });
+        }
+        Ok(commands)
+    }
+
+    /// Expand the arguments of a command that is run without a shell.
+    /// An argument that is nothing but a placeholder such as `$inputs`
+    /// becomes one argument per path, so that no path is split apart.
+    fn expand_args(
+        &self,
+        args: &[String],
+        rule: &HexRule,
+        used_tools: &mut BTreeSet<Arc<String>>,
+    ) -> Result<Vec<String>, String> {
+        let mut expanded = Vec::new();
+        for arg in args {
+            match rule_placeholder(arg, rule) {
+                Some((len, values)) if len == arg.len() => expanded.extend(values?),
+                _ => expanded.push(self.expand_string(arg, rule, false, used_tools)?),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Expand `$ENV{NAME}`, `$DEFINE{NAME}`, and `$TOOL{NAME}` references
+    /// in a string. An environment variable must be one of the ones
+    /// allowed by the Hexmake file; if it is allowed but not set, it
+    /// expands to the empty string. A build parameter must be declared in
+    /// `defines`. A tool expands to the path of its executable, and its
+    /// name is added to `used_tools`.
+    ///
+    /// The placeholders `$inputs`, `$outputs`, `$outputN`, and `$name`
+    /// expand to the rule's own fields. When `quote` is set, the string
+    /// is a shell command, and each path is quoted if the shell needs it.
+    fn expand_string(
+        &self,
+        text: &str,
+        rule: &HexRule,
+        quote: bool,
+        used_tools: &mut BTreeSet<Arc<String>>,
+    ) -> Result<String, String> {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(dollar) = rest.find('$') {
+            result.push_str(&rest[..dollar]);
+            rest = &rest[dollar..];
+
+            let start = if rest.starts_with(ENV_START) {
+                ENV_START
+            } else if rest.starts_with(DEFINE_START) {
+                DEFINE_START
+            } else if rest.starts_with(TOOL_START) {
+                TOOL_START
+            } else if let Some((len, values)) = rule_placeholder(rest, rule) {
+                let values = values?;
+                let words: Vec<_> = if quote {
+                    values.iter().map(|value| shell_quote(value)).collect()
+                } else {
+                    values
+                };
+                result.push_str(&words.join(" "));
+                rest = &rest[len..];
+                continue;
+            } else {
+                // Some other use of `$`, which is left for the shell
+                result.push('$');
+                rest = &rest[1..];
+                continue;
+            };
+
+            let after_start = &rest[start.len()..];
+            let Some(end) = after_start.find('}') else {
+                return Err(format!("Unterminated `{start}` in `{text}`"));
+            };
+            let name = &after_start[..end];
+
+            if start == ENV_START {
+                result.push_str(&self.expand_env(name)?);
+            } else if start == DEFINE_START {
+                result.push_str(self.expand_define(name)?);
+            } else {
+                let Some((name, tool)) = self.tools.get_key_value(&name.to_string()) else {
+                    return Err(format!(
+                        "`$TOOL{{{name}}}` refers to a tool that is not listed in `toolchains`"
+                    ));
+                };
+                result.push_str(&tool.path);
+                used_tools.insert(name.clone());
+            }
+
+            rest = &after_start[end + 1..];
+        }
+        result.push_str(rest);
+
+        Ok(result)
+    }
+
+    /// The value that `$DEFINE{name}` expands to
+    fn expand_define(&self, name: &str) -> Result<&str, String> {
+        match self.defines.get(&name.to_string()) {
+            Some(value) => Ok(value),
+            None => Err(format!(
+                "`$DEFINE{{{name}}}` refers to a parameter that is not listed in `defines`"
+            )),
+        }
+    }
+
+    /// The value that `$ENV{name}` expands to
+    fn expand_env(&self, name: &str) -> Result<String, String> {
+        if !self
+            .allowed_env
+            .iter()
+            .any(|allowed| allowed.as_str() == name)
+        {
+            return Err(format!(
+                "`$ENV{{{name}}}` refers to a variable that is not listed in `env`"
+            ));
+        }
+        Ok(match self.env.get(&name.to_string()) {
+            Some(value) => value.to_string(),
+            None => String::new(),
+        })
+    }
+}
+
+/// The word after the `$` at the start of `text`: the ASCII letters,
+/// digits, and underscores that follow it
+fn placeholder_word(text: &str) -> &str {
+    let word = &text[1..];
+    match word.find(|c: char| !c.is_ascii_alphanumeric() && c != '_') {
+        Some(end) => &word[..end],
+        None => word,
+    }
+}
+
+/// Whether `text` uses the `$inputs` or `$in` placeholder, and so
+/// depends on the order of the rule's inputs
+pub fn mentions_inputs(text: &str) -> bool {
+    text.match_indices('$')
+        .any(|(start, _)| matches!(placeholder_word(&text[start..]), "inputs" | "in"))
+}
+
+/// Recognize a placeholder for one of a rule's own fields at the start
+/// of `text`, which begins with `$`. Return the length of the
+/// placeholder and the values it stands for. Anything else, such as
+/// `$inputs_dir`, is not a placeholder.
+fn rule_placeholder(text: &str, rule: &HexRule) -> Option<(usize, Result<Vec<String>, String>)> {
+    let word = placeholder_word(text);
+    let paths = |paths: &[HexPath]| paths.iter().map(|path| path.to_string()).collect();
+
+    let values = match word {
+        "inputs" | "in" => Ok(paths(&rule.inputs)),
+        "outputs" | "out" => Ok(paths(&rule.outputs)),
+        "name" => Ok(vec![rule.name.to_string()]),
+        _ => {
+            let index = word.strip_prefix("output")?;
+            if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            match index
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| rule.outputs.get(i))
+            {
+                Some(output) => Ok(vec![output.to_string()]),
+                None => Err(format!(
+                    "`${word}` refers to an output that the rule does not have"
+                )),
+            }
+        }
+    };
+    Some((1 + word.len(), values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_string() {
+        let allowed_env = vec![Arc::new("CC".to_string()), Arc::new("CFLAGS".to_string())];
+        let env = BTreeMap::from([(Arc::new("CC".to_string()), Arc::new("clang".to_string()))]);
+        let tools = BTreeMap::from([(
+            Arc::new("protoc".to_string()),
+            ResolvedTool {
+                path: "/opt/protoc/bin/protoc".to_string(),
+                sha256: "abcd".to_string(),
+            },
+        )]);
+        let expansions = Expansions {
+            allowed_env: &allowed_env,
+            env: &env,
+            tools: &tools,
+            defines: &BTreeMap::new(),
+        };
+        let rule = HexRule::new("test".into());
+        let mut used_tools = BTreeSet::new();
+        let mut expand = |text: &str| expansions.expand_string(text, &rule, true, &mut used_tools);
+
+        assert_eq!(
+            expand("$ENV{CC} -c main.c $ENV{CFLAGS}"),
+            Ok("clang -c main.c ".to_string())
+        );
+
+        // Ordinary shell variables are left alone
+        assert_eq!(
+            expand("echo $HOME ${CC} $"),
+            Ok("echo $HOME ${CC} $".to_string())
+        );
+
+        assert_eq!(
+            expand("$TOOL{protoc} --cpp_out=out foo.proto"),
+            Ok("/opt/protoc/bin/protoc --cpp_out=out foo.proto".to_string())
+        );
+        assert_eq!(
+            expand("$TOOL{javac}"),
+            Err("`$TOOL{javac}` refers to a tool that is not listed in `toolchains`".to_string())
+        );
+
+        assert_eq!(
+            expand("$ENV{HOME}"),
+            Err("`$ENV{HOME}` refers to a variable that is not listed in `env`".to_string())
+        );
+        assert_eq!(
+            expand("$ENV{CC"),
+            Err("Unterminated `$ENV{` in `$ENV{CC`".to_string())
+        );
+
+        assert_eq!(used_tools, BTreeSet::from([Arc::new("protoc".to_string())]));
+    }
+
+    #[test]
+    fn test_rule_placeholders() {
+        let expansions = Expansions {
+            allowed_env: &[],
+            env: &BTreeMap::new(),
+            tools: &BTreeMap::new(),
+            defines: &BTreeMap::new(),
+        };
+        let mut rule = HexRule::new("link".into());
+        rule.inputs = vec![
+            "main.o".try_into().unwrap(),
+            "My Files/util.o".try_into().unwrap(),
+        ];
+        rule.outputs = vec![
+            "out/main".try_into().unwrap(),
+            "out/main.map".try_into().unwrap(),
+        ];
+        let mut used_tools = BTreeSet::new();
+        let mut expand =
+            |text: &str, quote| expansions.expand_string(text, &rule, quote, &mut used_tools);
+
+        assert_eq!(
+            expand("cc -o $output0 $inputs -Wl,-Map=$output1", true),
+            Ok("cc -o out/main main.o 'My Files/util.o' -Wl,-Map=out/main.map".to_string())
+        );
+        assert_eq!(
+            expand("echo $name: $outputs", true),
+            Ok("echo link: out/main out/main.map".to_string())
+        );
+        assert_eq!(
+            expand("ld -o $out $in", true),
+            Ok("ld -o out/main out/main.map main.o 'My Files/util.o'".to_string())
+        );
+        assert_eq!(
+            expand("--inputs=$inputs", false),
+            Ok("--inputs=main.o My Files/util.o".to_string())
+        );
+
+        // Longer names are ordinary shell variables
+        assert_eq!(
+            expand(
+                "echo $inputs_dir $names ${name} $output $index $out_dir",
+                true
+            ),
+            Ok("echo $inputs_dir $names ${name} $output $index $out_dir".to_string())
+        );
+
+        // A shell variable with the name of a placeholder needs braces
+        assert_eq!(
+            expand("for name in *.c; do cc -c ${name} $name; done", true),
+            Ok("for name in *.c; do cc -c ${name} link; done".to_string())
+        );
+        assert_eq!(
+            expand("while read in out; do cp ${in} ${out}; done", true),
+            Ok("while read in out; do cp ${in} ${out}; done".to_string())
+        );
+
+        assert_eq!(
+            expand("cp $output0 $output2", true),
+            Err("`$output2` refers to an output that the rule does not have".to_string())
+        );
+
+        // An argument that is only a placeholder becomes one argument per path
+        let args = ["cc", "-o", "$output0", "$inputs", "-DNAME=$name"].map(String::from);
+        assert_eq!(
+            expansions.expand_args(&args, &rule, &mut BTreeSet::new()),
+            Ok(vec![
+                "cc".to_string(),
+                "-o".to_string(),
+                "out/main".to_string(),
+                "main.o".to_string(),
+                "My Files/util.o".to_string(),
+                "-DNAME=link".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expand_file() {
+        let parse = |env: &str| -> HexmakeFile {
+            serde_json::from_str(&format!(
+                r#"{{
+                    "env": {env},
+                    "rules": [
+                        {{
+                            "name": "main",
+                            "outputs": ["out/main"],
+                            "inputs": ["main.c"],
+                            "commands": ["$ENV{{CC}} -o out/main main.c"]
+                        }}
+                    ]
+                }}"#
+            ))
+            .unwrap()
+        };
+        let env = BTreeMap::from([(Arc::new("CC".to_string()), Arc::new("gcc".to_string()))]);
+
+        let mut hexmake_file = parse(r#"["CC"]"#);
+        expand_file(&mut hexmake_file, &env, &BTreeMap::new()).unwrap();
+        assert_eq!(
+            hexmake_file.rules[0].commands,
+            vec!["gcc -o out/main main.c"]
+        );
+
+        let mut hexmake_file = parse("[]");
+        assert_eq!(
+            expand_file(&mut hexmake_file, &env, &BTreeMap::new()),
+            Err(
+                "In rule `main`: `$ENV{CC}` refers to a variable that is not listed in `env`"
+                    .to_string()
+            )
+        );
+
+        // Rules record the hashes of the tools they use
+        let mut hexmake_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "name": "main",
+                        "outputs": ["out/main"],
+                        "inputs": ["main.c"],
+                        "commands": ["$TOOL{cc} -o out/main main.c"]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let tools = BTreeMap::from([(
+            Arc::new("cc".to_string()),
+            ResolvedTool {
+                path: "/opt/cc".to_string(),
+                sha256: "abcd".to_string(),
+            },
+        )]);
+        expand_file(&mut hexmake_file, &BTreeMap::new(), &tools).unwrap();
+        assert_eq!(
+            hexmake_file.rules[0].commands,
+            vec!["/opt/cc -o out/main main.c"]
+        );
+        assert_eq!(
+            hexmake_file.rules[0].tool_hashes,
+            BTreeMap::from([(Arc::new("cc".to_string()), "abcd".to_string())])
+        );
+
+        // The cache key leaves out where the tools are
+        assert_eq!(
+            hexmake_file.rules[0].portable_commands,
+            Some(vec!["$TOOL{cc} -o out/main main.c".into()])
+        );
+    }
+
+    #[test]
+    fn test_vars() {
+        let hexmake_file = HexmakeFile::parse(
+            r#"{
+                "vars": {"cflags": "-O2 -Wall", "src": "src/app", "mode": "$DEFINE{mode}"},
+                "defines": {"mode": "debug"},
+                "rules": [
+                    {
+                        "name": "main",
+                        "outputs": ["out/${src}/main.o"],
+                        "inputs": ["${src}/main.c"],
+                        "commands": [
+                            "cc ${cflags} -c ${src}/main.c -o $output0 # ${HOME} ${mode}",
+                            ["cc", "${cflags}", "${src}/main.c"]
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let main = &hexmake_file.rules[0];
+        assert_eq!(
+            main.outputs,
+            vec![HexPath::try_from("out/src/app/main.o").unwrap()]
+        );
+        assert_eq!(
+            main.inputs,
+            vec![HexPath::try_from("src/app/main.c").unwrap()]
+        );
+        // `${HOME}` is not a var, so it is left for the shell, and a var
+        // can hold a reference that is expanded later
+        assert_eq!(
+            main.commands,
+            vec![
+                RuleCommand::Shell(
+                    "cc -O2 -Wall -c src/app/main.c -o $output0 # ${HOME} $DEFINE{mode}"
+                        .to_string()
+                ),
+                RuleCommand::Argv(vec![
+                    "cc".to_string(),
+                    "-O2 -Wall".to_string(),
+                    "src/app/main.c".to_string()
+                ]),
+            ]
+        );
+
+        // A value must still make a valid path
+        assert_eq!(
+            HexmakeFile::parse(
+                r#"{"vars": {"dir": "../up"}, "rules": [{"name": "main",
+                    "outputs": ["out/main"], "inputs": ["${dir}/main.c"], "commands": []}]}"#
+            )
+            .map(|_| ()),
+            Err("In rule `main`: Path `../up/main.c` contains `..` as a component".to_string())
+        );
+    }
+
+    #[test]
+    fn test_defines() {
+        let parse = || -> HexmakeFile {
+            serde_json::from_str(
+                r#"{
+                    "defines": {"version": "dev", "mode": "debug"},
+                    "rules": [
+                        {
+                            "name": "version",
+                            "kind": "write",
+                            "outputs": ["out/version.txt"],
+                            "content": "$DEFINE{version}"
+                        },
+                        {
+                            "name": "package",
+                            "outputs": ["out/app-$DEFINE{version}.tar"],
+                            "inputs": ["out/version.txt", "config/$DEFINE{mode}.conf"],
+                            "commands": ["tar cf $output0 $inputs # $DEFINE{mode} $HOME"]
+                        }
+                    ]
+                }"#,
+            )
+            .unwrap()
+        };
+        let expand = |hexmake_file: &mut HexmakeFile| {
+            expand_file(hexmake_file, &BTreeMap::new(), &BTreeMap::new())
+        };
+
+        // The defaults are used unless the parameters are given values
+        let mut hexmake_file = parse();
+        hexmake_file
+            .set_defines(&[("version".to_string(), "1.2".to_string())])
+            .unwrap();
+        expand(&mut hexmake_file).unwrap();
+        let version = &hexmake_file.rules[0];
+        assert_eq!(version.content.as_deref(), Some("1.2"));
+        let package = &hexmake_file.rules[1];
+        assert_eq!(
+            package.outputs,
+            vec![HexPath::try_from("out/app-1.2.tar").unwrap()]
+        );
+        assert_eq!(
+            package.inputs,
+            vec![
+                HexPath::try_from("out/version.txt").unwrap(),
+                HexPath::try_from("config/debug.conf").unwrap(),
+            ]
+        );
+        assert_eq!(
+            package.commands,
+            vec!["tar cf out/app-1.2.tar out/version.txt config/debug.conf # debug $HOME"]
+        );
+
+        // Only declared parameters can be set or used
+        assert_eq!(
+            parse().set_defines(&[("release".to_string(), "yes".to_string())]),
+            Err(
+                "Cannot set `release` with --define, because it is not listed in `defines`"
+                    .to_string()
+            )
+        );
+        let mut hexmake_file = parse();
+        hexmake_file
+            .defines
+            .retain(|name, _| name.as_str() != "mode");
+        assert_eq!(
+            expand(&mut hexmake_file),
+            Err("In rule `package`: `$DEFINE{mode}` refers to a parameter that is not listed in `defines`".to_string())
+        );
+
+        // A value must still make a valid path
+        let mut hexmake_file = parse();
+        hexmake_file
+            .set_defines(&[("mode".to_string(), "../secret".to_string())])
+            .unwrap();
+        assert_eq!(
+            expand(&mut hexmake_file),
+            Err(
+                "In rule `package`: Path `config/../secret.conf` contains `..` as a component"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -26,15 +26,12 @@ impl HexPath {
         HexPath::try_from(format!("{}/{}", self.path, child_path))
     }
 
+    /// The parent of this path, or None if there is no slash
+    /// in the path and so no way to compute a parent
     pub fn parent(&self) -> Option<HexPath> {
-        match self.path.rfind('/') {
-            None => {
-                // No slash found, so there is no way to compute a parent
-                None
-            }
-
-            Some(last_slash) => Some(HexPath::new(Arc::new(self.path[0..last_slash].to_string()))),
-        }
+        self.path
+            .rfind('/')
+            .map(|last_slash| HexPath::new(Arc::new(self.path[0..last_slash].to_string())))
     }
 }
 
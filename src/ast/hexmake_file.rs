@@ -1,18 +1,117 @@
 use std::{
+    collections::BTreeMap,
     fmt::{self, Display, Formatter},
     ops::Deref,
+    path::Path,
     sync::Arc,
 };
 
+use crate::ast::expand::expand_vars;
 use crate::ast::hex_path::HexPath;
 use serde::Deserialize;
 
 /// An entire Hexmake file
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct HexmakeFile {
     #[serde(default)]
     pub env: Vec<Arc<String>>,
+
+    /// Environment variables that are passed through, like `env`, but
+    /// whose values are kept out of the cache key and the logs
+    #[serde(default)]
+    pub secret_environ: Vec<Arc<String>>,
+
+    /// A file of `KEY=VALUE` lines that supplies values for the variables
+    /// in `env` and `secret_environ` that the process does not set
+    #[serde(default)]
+    pub env_file: Option<String>,
+
+    /// Text that the inputs, outputs, and commands of rules refer to with
+    /// `${name}`, such as compiler flags that many rules share
+    #[serde(default)]
+    pub vars: BTreeMap<Arc<String>, String>,
+
+    /// Build parameters that commands and paths refer to with
+    /// `$DEFINE{name}`, with their default values. `--define` can give
+    /// them other values.
+    #[serde(default)]
+    pub defines: BTreeMap<Arc<String>, String>,
+
     pub rules: Vec<Arc<HexRule>>,
+
+    /// Names that stand for a list of other targets
+    #[serde(default)]
+    pub aliases: BTreeMap<Arc<String>, Vec<Arc<String>>>,
+
+    /// The targets to build when none are given on the command line
+    #[serde(default)]
+    pub default_targets: Vec<Arc<String>>,
+
+    /// The maximum number of rules that can run at once in each pool
+    #[serde(default)]
+    pub pools: BTreeMap<Arc<String>, usize>,
+
+    /// A shell command to run when a build command fails
+    #[serde(default)]
+    pub on_failure: Option<String>,
+
+    /// Tools that commands refer to with `$TOOL{name}`
+    #[serde(default)]
+    pub toolchains: BTreeMap<Arc<String>, Toolchain>,
+
+    /// Subdirectories with their own Hexmake file, whose outputs can be
+    /// used as inputs here
+    #[serde(default)]
+    pub subworkspaces: Vec<Arc<String>>,
+
+    /// Limits on what the build cache keeps
+    #[serde(default)]
+    pub cache: CacheSettings,
+
+    /// How large a single source input can be before it is reported
+    #[serde(default)]
+    pub input_limits: InputLimits,
+
+    /// Which files in a directory input are part of it
+    #[serde(default)]
+    pub source_trees: SourceTreeSettings,
+
+    /// Where `hexmake install` puts outputs, and with what permissions,
+    /// by output file or directory
+    #[serde(default)]
+    pub install: BTreeMap<String, InstallSetting>,
+}
+
+impl HexmakeFile {
+    /// Read and parse the Hexmake file at a path
+    pub fn load(path: &Path) -> Result<HexmakeFile, String> {
+        let source = fs_err::read_to_string(path)
+            .map_err(|error| format!("Could not open Hexmake file: {error}"))?;
+        HexmakeFile::parse(&source)
+    }
+
+    /// Parse the source of a Hexmake file, and substitute its `vars`
+    pub fn parse(source: &str) -> Result<HexmakeFile, String> {
+        let mut hexmake_file = serde_json::from_str(source)
+            .map_err(|error| format!("Could not parse Hexmake file: {error}"))?;
+        expand_vars(&mut hexmake_file)?;
+        Ok(hexmake_file)
+    }
+
+    /// Give some of the build parameters in `defines` new values
+    pub fn set_defines(&mut self, values: &[(String, String)]) -> Result<(), String> {
+        for (name, value) in values {
+            match self.defines.get_mut(name) {
+                Some(default) => *default = value.clone(),
+                None => {
+                    return Err(format!(
+                        "Cannot set `{name}` with --define, because it is not listed in `defines`"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Display for HexmakeFile {
@@ -21,16 +120,114 @@ impl Display for HexmakeFile {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Hash, PartialEq)]
+#[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq)]
 /// One rule in a Hexmake file
 pub struct HexRule {
     pub name: RuleName,
     pub outputs: Vec<HexPath>,
+
+    #[serde(default)]
     pub inputs: Vec<HexPath>,
-    pub commands: Vec<String>,
+
+    /// Commands that build the outputs. Built-in kinds of rule have no
+    /// commands.
+    #[serde(default)]
+    pub commands: Vec<RuleCommand>,
+
+    /// An output that the standard output of the commands is written to
+    #[serde(default)]
+    pub stdout_output: Option<HexPath>,
+
+    /// How the rule builds its outputs
+    #[serde(default)]
+    pub kind: RuleKind,
+
+    /// For an `http_file` rule, the URL to download
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// For an `http_file` rule, the expected SHA-256 hash of the download
+    #[serde(default)]
+    pub sha256: Option<String>,
+
+    /// For an `extract` rule, the number of leading directories to
+    /// remove from the path of each file in the archives
+    #[serde(default)]
+    pub strip_components: usize,
+
+    /// For a `write` rule, the text to write to the output
+    #[serde(default)]
+    pub content: Option<String>,
+
+    /// For a `template` rule, the value to put in place of each
+    /// `${name}` in the template
+    #[serde(default)]
+    pub substitutions: BTreeMap<String, String>,
+
+    /// Rules whose outputs are all inputs of this rule
+    #[serde(default)]
+    pub deps: Vec<RuleName>,
+
+    /// Files that must accompany this rule's outputs when another rule
+    /// uses them, such as data files or shared libraries
+    #[serde(default)]
+    pub runfiles: Vec<HexPath>,
+
+    /// A human-readable explanation of what the rule does
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// Free-form labels for grouping rules in listings
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// When several tasks are ready to run, ones with a higher
+    /// priority are started first
+    #[serde(default)]
+    pub priority: i64,
+
+    /// The pool that limits how many rules like this one run at once
+    #[serde(default)]
+    pub pool: Option<Arc<String>>,
+
+    /// How much memory the rule's commands need, in megabytes. Without
+    /// this, the most that the rule used when it was last built is used.
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+
+    /// Limits on the resources that the rule's commands can use
+    #[serde(default)]
+    pub limits: RuleLimits,
+
+    /// Whether the commands can read the volatile status file, whose
+    /// contents change with every build but are not part of the cache key
+    #[serde(default)]
+    pub stamp: bool,
+
+    /// Whether to find the headers that the rule's C and C++ inputs
+    /// include, and add them to its inputs
+    #[serde(default)]
+    pub include_scan: Option<IncludeScan>,
+
+    /// The SHA-256 hash of every tool that the commands refer to. This
+    /// is filled in when the commands are expanded.
+    #[serde(skip)]
+    pub tool_hashes: BTreeMap<Arc<String>, String>,
+
+    /// The commands with their `$TOOL{name}` references left as they
+    /// are, if they have any. Tools expand to absolute paths, so the cache
+    /// key uses these instead, so that it does not depend on where the
+    /// workspace is.
+    #[serde(skip)]
+    pub portable_commands: Option<Vec<RuleCommand>>,
 }
 
 impl HexRule {
+    /// The commands as they go into the cache key
+    pub fn hashed_commands(&self) -> &[RuleCommand] {
+        self.portable_commands.as_deref().unwrap_or(&self.commands)
+    }
+
     #[cfg(test)]
     pub fn new(name: RuleName) -> HexRule {
         HexRule {
@@ -38,11 +235,334 @@ impl HexRule {
             outputs: vec![],
             inputs: vec![],
             commands: vec![],
+            ..Default::default()
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+/// The ways that a rule can build its outputs
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    /// Run the rule's shell commands
+    #[default]
+    Command,
+
+    /// Download a URL into the rule's one output
+    HttpFile,
+
+    /// Unpack tar and zip archives into the rule's one output directory
+    Extract,
+
+    /// Copy the rule's one input to its output
+    Copy,
+
+    /// Write the rule's `content` to its output
+    Write,
+
+    /// Concatenate the rule's inputs, in order, into its output
+    Concat,
+
+    /// Make an empty directory
+    Mkdir,
+
+    /// Fill in the variables in the rule's one input to make its output
+    Template,
+}
+
+impl RuleKind {
+    /// The name of the kind, as written in a Hexmake file
+    pub fn name(self) -> &'static str {
+        match self {
+            RuleKind::Command => "command",
+            RuleKind::HttpFile => "http_file",
+            RuleKind::Extract => "extract",
+            RuleKind::Copy => "copy",
+            RuleKind::Write => "write",
+            RuleKind::Concat => "concat",
+            RuleKind::Mkdir => "mkdir",
+            RuleKind::Template => "template",
+        }
+    }
+}
+
+impl Display for RuleKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// One of the commands of a rule. In a Hexmake file, this is either a
+/// string or an array of strings.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(untagged)]
+pub enum RuleCommand {
+    /// A command line for the shell to run
+    Shell(String),
+
+    /// A program and its arguments, which are run directly, without a
+    /// shell
+    Argv(Vec<String>),
+}
+
+/// Shows the command as the shell would need it written
+impl Display for RuleCommand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RuleCommand::Shell(command) => write!(f, "{command}"),
+            RuleCommand::Argv(args) => {
+                let words: Vec<_> = args.iter().map(|arg| shell_quote(arg)).collect();
+                write!(f, "{}", words.join(" "))
+            }
+        }
+    }
+}
+
+impl From<&str> for RuleCommand {
+    fn from(command: &str) -> Self {
+        RuleCommand::Shell(command.to_string())
+    }
+}
+
+impl PartialEq<&str> for RuleCommand {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, RuleCommand::Shell(command) if command == other)
+    }
+}
+
+/// Quote a word for the shell, if it needs it
+pub fn shell_quote(word: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(is_plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// A tool with a pinned version, which is either a file on the local
+/// machine or a file to download
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Toolchain {
+    /// The location of the tool on the local machine
+    #[serde(default)]
+    pub path: Option<String>,
+
+    /// Where to download the tool from
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// The expected SHA-256 hash of the tool, in hex
+    pub sha256: String,
+}
+
+/// Limits on the resources that the commands of a rule can use, so that a
+/// runaway step cannot take over a shared machine. Each limit applies to
+/// every process that the commands start.
+#[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq)]
+#[serde(default)]
+pub struct RuleLimits {
+    /// The most memory that a process can allocate, in megabytes
+    pub max_memory_mb: Option<u64>,
+
+    /// The most CPU time that a process can use, in seconds
+    pub max_cpu_seconds: Option<u64>,
+
+    /// The most files that a process can have open at once
+    pub max_open_files: Option<u64>,
+
+    /// Whether a process that crashes can write a core dump. This is
+    /// left as the system has it unless it is set.
+    pub core_dumps: Option<bool>,
+}
+
+/// Limits on what the build cache keeps. Whenever a build finishes, the
+/// cache is garbage collected to stay within them.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CacheSettings {
+    /// When the cached outputs take up more than this many megabytes,
+    /// the least recently written ones are removed
+    pub max_size_mb: u64,
+
+    /// How many megabytes of outputs to keep when removing them
+    pub target_size_mb: u64,
+
+    /// Remove entries that have not been used for this many days
+    pub max_age_days: Option<u64>,
+
+    /// Keep at most this many entries, removing the least recently used
+    pub max_entries: Option<usize>,
+
+    /// Stop using entries this many hours after they were written, for
+    /// rules whose outputs depend on the time
+    pub ttl_hours: Option<u64>,
+
+    /// How `hexmake cache pull` and `push` talk to a server
+    pub remote: RemoteCacheSettings,
+
+    /// The group and mode of the files that are written to the cache, for
+    /// a cache that several users share
+    pub permissions: CachePermissions,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            max_size_mb: 200,
+            target_size_mb: 100,
+            max_age_days: None,
+            max_entries: None,
+            ttl_hours: None,
+            remote: RemoteCacheSettings::default(),
+            permissions: CachePermissions::default(),
+        }
+    }
+}
+
+/// The ownership and permissions of the files that are written to the
+/// cache. Without them, new files get the user's default group and umask.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CachePermissions {
+    /// The group that owns new files, by name or number
+    pub group: Option<String>,
+
+    /// The mode of new files, in octal, such as `664`. Directories also
+    /// get the execute bits that go with each read bit. If only `group`
+    /// is set, this is `664`.
+    pub mode: Option<String>,
+}
+
+/// Sizes above which a single source input is reported, because it was
+/// probably listed by mistake, such as by naming the whole workspace
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct InputLimits {
+    /// Warn about an input that holds more than this many megabytes
+    pub warn_size_mb: Option<u64>,
+
+    /// Warn about an input that holds more than this many files
+    pub warn_files: Option<u64>,
+
+    /// Fail the build if an input holds more than this many megabytes
+    pub max_size_mb: Option<u64>,
+
+    /// Fail the build if an input holds more than this many files
+    pub max_files: Option<u64>,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        InputLimits {
+            warn_size_mb: Some(1024),
+            warn_files: Some(10_000),
+            max_size_mb: None,
+            max_files: None,
+        }
+    }
+}
+
+/// Where to look for the headers that a rule's C and C++ sources include
+#[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq)]
+pub struct IncludeScan {
+    /// Directories to look for included headers in, like the `-I`
+    /// options of the compiler
+    #[serde(default)]
+    pub include_dirs: Vec<HexPath>,
+}
+
+/// How `hexmake install` installs an output file, or the files in an
+/// output directory
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct InstallSetting {
+    /// Where to install the output, relative to the prefix. Without this,
+    /// the output's path without `out/` is used.
+    pub path: Option<String>,
+
+    /// The permissions of the installed files, in octal, such as `755`.
+    /// Without this, the files keep the permissions they have in `out/`.
+    pub mode: Option<String>,
+}
+
+impl InstallSetting {
+    /// The permission bits that `mode` gives, if it is set
+    pub fn mode_bits(&self) -> Result<Option<u32>, String> {
+        self.mode.as_deref().map(parse_mode).transpose()
+    }
+}
+
+/// Parse a file mode in octal, such as `755`
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(bits),
+        _ => Err(format!("`{mode}` is not an octal file mode, such as 755")),
+    }
+}
+
+/// Which files under a source tree are hashed and copied along with it.
+/// By default, files that ignore files exclude are left out, and hidden
+/// files are kept.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SourceTreeSettings {
+    /// Keep files that `.gitignore` and `.hexignore` files exclude
+    pub include_ignored: bool,
+
+    /// Keep files and directories whose names start with a dot
+    pub include_hidden: bool,
+}
+
+impl Default for SourceTreeSettings {
+    fn default() -> Self {
+        SourceTreeSettings {
+            include_ignored: false,
+            include_hidden: true,
+        }
+    }
+}
+
+/// Limits on the network requests that pull and push cache archives, so
+/// that a server that is down or slow cannot hold up a build
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RemoteCacheSettings {
+    /// How long to wait for a connection to the server
+    pub connect_timeout_seconds: u64,
+
+    /// How long a whole request, including the transfer, may take
+    pub timeout_seconds: u64,
+
+    /// How many more times to try a request that failed in a way that
+    /// might not happen again, such as a timeout or a server error
+    pub retries: u32,
+
+    /// The environment variable that holds a bearer token to send with
+    /// each request
+    pub token_env: Option<String>,
+
+    /// The environment variables that hold a user name and password to
+    /// send with each request, for a server that uses basic
+    /// authentication instead of tokens
+    pub username_env: Option<String>,
+    pub password_env: Option<String>,
+}
+
+impl Default for RemoteCacheSettings {
+    fn default() -> Self {
+        RemoteCacheSettings {
+            connect_timeout_seconds: 10,
+            timeout_seconds: 300,
+            retries: 3,
+            token_env: None,
+            username_env: None,
+            password_env: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[serde(transparent)]
 pub struct RuleName {
     pub name: Arc<String>,
@@ -142,7 +662,6 @@ mod tests {
         assert_eq!(
             hexmake_file,
             HexmakeFile {
-                env: vec![],
                 rules: vec![
                     HexRule {
                         name: "out/lib.o".to_string().into(),
@@ -151,7 +670,8 @@ mod tests {
                             HexPath::try_from("lib.c").unwrap(),
                             HexPath::try_from("lib.h").unwrap()
                         ],
-                        commands: vec!["gcc -o out/lib.o -c lib.c".to_string()]
+                        commands: vec!["gcc -o out/lib.o -c lib.c".into()],
+                        ..Default::default()
                     }
                     .into(),
                     HexRule {
@@ -161,7 +681,8 @@ mod tests {
                             HexPath::try_from("lib.h").unwrap(),
                             HexPath::try_from("main.c").unwrap()
                         ],
-                        commands: vec!["gcc -o out/main.o -c main.c".to_string()]
+                        commands: vec!["gcc -o out/main.o -c main.c".into()],
+                        ..Default::default()
                     }
                     .into(),
                     HexRule {
@@ -171,14 +692,40 @@ mod tests {
                             HexPath::try_from("out/lib.o").unwrap(),
                             HexPath::try_from("out/main.o").unwrap()
                         ],
-                        commands: vec!["gcc -o out/main out/lib.o out/main.o".to_string()]
+                        commands: vec!["gcc -o out/main out/lib.o out/main.o".into()],
+                        ..Default::default()
                     }
                     .into()
-                ]
+                ],
+                ..Default::default()
             }
         );
     }
 
+    #[test]
+    fn test_rule_commands() {
+        let commands: Vec<RuleCommand> = serde_json::from_str(
+            r#"["cc -o out/main main.c", ["cp", "my file.txt", "out/it's here"]]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                RuleCommand::Shell("cc -o out/main main.c".to_string()),
+                RuleCommand::Argv(vec![
+                    "cp".to_string(),
+                    "my file.txt".to_string(),
+                    "out/it's here".to_string()
+                ]),
+            ]
+        );
+        assert_eq!(commands[0].to_string(), "cc -o out/main main.c");
+        assert_eq!(
+            commands[1].to_string(),
+            r#"cp 'my file.txt' 'out/it'\''s here'"#
+        );
+    }
+
     #[test]
     fn test_bad_path() {
         let input = indoc! {r###"
@@ -1,4 +1,5 @@
 //! AST for a Hexmake file
 
+pub mod expand;
 pub mod hex_path;
 pub mod hexmake_file;
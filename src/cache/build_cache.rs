@@ -1,21 +1,186 @@
+use std::cmp::Reverse;
 use std::collections::{BTreeMap, BTreeSet};
-use std::io;
-use std::sync::Arc;
+use std::ffi::CString;
+use std::io::{self, Read};
+use std::path::Component;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 
 use crate::ast::hex_path::HexPath;
-use crate::ast::hexmake_file::HexRule;
-use crate::cache::build_hash::BuildHash;
-use crate::file_system::vfs::VirtualFileSystem;
+use crate::ast::hexmake_file::{CachePermissions, HexRule, parse_mode};
+use crate::cache::build_hash::{
+    BuildHash, HashBreakdown, TreeHasher, hash_contents, is_build_hash,
+};
+use crate::cache::cache_options::{CacheMode, CacheOptions};
+use crate::environment::BuildEnvironment;
+use crate::file_system::vfs::{VirtualFileSystem, is_side_file, side_file_name};
 
 /// A cache of previously built outputs
 pub struct BuildCache {
     root: HexPath,
-    env: Arc<BTreeMap<Arc<String>, Arc<String>>>,
+    env: Arc<BuildEnvironment>,
     vfs: Box<dyn VirtualFileSystem>,
+    options: CacheOptions,
+
+    /// The permissions that new cache files get, if they are set
+    permissions: Option<SharedPermissions>,
+
+    /// The hashes of the inputmaps that this build has used or written
+    touched_entries: Mutex<BTreeSet<String>>,
+
+    /// What went into the most recent hash of each rule that this build
+    /// has hashed
+    hash_breakdowns: Mutex<BTreeMap<String, HashBreakdown>>,
+
+    /// The most recent hash of each rule that this build has hashed
+    rule_hashes: Mutex<BTreeMap<String, BuildHash>>,
+}
+
+/// The result of looking up a rule in the cache with [BuildCache::probe]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheProbe {
+    /// The cache has outputs for the rule. If `outputs_current` is true,
+    /// then the files in `out/` are already the same as the cached ones.
+    Hit { outputs_current: bool },
+
+    /// The cache has no outputs for the rule
+    Miss,
+
+    /// Some inputs of the rule are not present, so it cannot be looked up
+    Unknown,
+}
+
+/// The outputs that the cache has for a rule, found with
+/// [BuildCache::lookup_outputs]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedOutputs {
+    /// The hash of the rule, under which the outputs are cached
+    pub rule_hash: BuildHash,
+
+    /// The hash of each output, by its path
+    pub outputs: BTreeMap<HexPath, String>,
+}
+
+/// How many outputs of a rule are copied out of the cache at once
+const RESTORE_THREADS: usize = 4;
+
+/// How many seconds old a side file must be before garbage collection
+/// takes it to be left over from a write that never finished
+const STALE_SIDE_FILE_SECONDS: u64 = 60 * 60;
+
+/// The result of trying to retrieve a rule's outputs with
+/// [BuildCache::retrieve_outputs]
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheRetrieval {
+    /// The outputs were copied from the cache into the workspace
+    Hit,
+
+    /// The cache has no usable entry for the rule
+    Miss,
+
+    /// The cache had an entry for the rule, but it could not be read, so
+    /// it was removed. The outputs in the workspace were left as they were.
+    Evicted { error: String },
+
+    /// The cache had an entry for the rule, but this user is not allowed
+    /// to read it, so it was left for the users who can
+    Denied { error: String },
+}
+
+/// The mode and group that files and directories written to a shared
+/// cache get, from [CachePermissions]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SharedPermissions {
+    file_mode: u32,
+
+    /// The mode of files that were executable, and of directories
+    executable_mode: u32,
+    group: Option<u32>,
+}
+
+impl SharedPermissions {
+    fn resolve(settings: &CachePermissions) -> Result<Option<SharedPermissions>, io::Error> {
+        let group = match &settings.group {
+            Some(group) => Some(group_id(group)?),
+            None => None,
+        };
+        let file_mode = match (&settings.mode, group) {
+            (Some(mode), _) => parse_mode(mode).map_err(io::Error::other)?,
+            (None, Some(_)) => 0o664,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(SharedPermissions {
+            file_mode,
+            executable_mode: file_mode | ((file_mode & 0o444) >> 2),
+            group,
+        }))
+    }
+
+    /// The mode of directories, which are set-group-ID when a group is
+    /// set, so that the files made in them get that group too
+    fn dir_mode(&self) -> u32 {
+        match self.group {
+            Some(_) => self.executable_mode | 0o2000,
+            None => self.executable_mode,
+        }
+    }
+}
+
+/// Look up a group by name, or take it as a number
+fn group_id(group: &str) -> Result<u32, io::Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(io::Error::other)?;
+    // SAFETY: the name is a valid C string, and the entry that getgrnam()
+    // returns is read before anything else could replace it
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::other(format!(
+            "The cache's group `{group}` does not exist"
+        )));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// Treat a permission error, such as from a cache file that another user
+/// wrote, as the given result
+fn unless_denied<T>(result: Result<T, io::Error>, denied: T) -> Result<T, io::Error> {
+    match result {
+        Err(error) if error.kind() == io::ErrorKind::PermissionDenied => Ok(denied),
+        result => result,
+    }
+}
+
+/// An output that [BuildCache::retrieve_outputs] has copied from the cache
+/// to a side name, ready to be renamed into place
+struct StagedOutput {
+    output_path: HexPath,
+    side_path: HexPath,
+
+    /// The cached file to copy, or the manifest of the cached tree
+    source: HexPath,
+    is_tree: bool,
+}
+
+/// What happened when importing a cache archive with
+/// [BuildCache::import_archive]
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportCounts {
+    /// Files that were added to the cache
+    pub added: usize,
+
+    /// Files that the cache already had
+    pub skipped: usize,
 }
 
 /*
- * A cache of previously built outputs. It has two kinds of files:
+ * A cache of previously built outputs. It has three kinds of files:
  * 1. Inputmaps. The file `.hex/cache/inputmaps/ABCD` has an input map for
  *    the build rule with the given hash. The file will contain a list of hashes,
  *    one per line, of the outputs of the build rule, in the same order that the
@@ -24,98 +189,1081 @@ pub struct BuildCache {
  *    a file whose hash is ABCD. It is possible fo the same output to be used
  *    by multiple inputmaps; that means that Hexmake ran a build but determined
  *    that it already had the output for that rule, after all.
+ * 3. Tree manifests, for outputs that are directories. The file
+ *    `.hex/cache/trees/ABCD` describes the directory whose hash is ABCD,
+ *    with one line per directory (`dir PATH`) and per file
+ *    (`file HASH PATH`) inside it, where the paths are relative to the
+ *    output. The contents of each file are stored in `outputs/` under the
+ *    hash of the contents alone, so identical files in different trees
+ *    are stored once.
+ *
+ * Every file is written under a side name and then renamed into place, so
+ * that other builds, possibly of other users, never see one half written.
+ *
+ * Additionally, `.hex/cache/last_build` lists the hashes of the inputmaps
+ * that the most recent build used or wrote, so that `hexmake cache push`
+ * can share just those entries, and `.hex/cache/pinned` lists the hashes
+ * of the inputmaps that garbage collection must keep, each followed by the
+ * name of its rule.
  */
 impl BuildCache {
     pub fn new(
-        env: Arc<BTreeMap<Arc<String>, Arc<String>>>,
+        env: Arc<BuildEnvironment>,
         vfs: Box<dyn VirtualFileSystem>,
+        options: CacheOptions,
     ) -> Result<Self, io::Error> {
         let root = HexPath::try_from(".hex/cache").unwrap();
+        let permissions = SharedPermissions::resolve(&options.settings.permissions)?;
+
+        for dir in [
+            root.clone(),
+            root.child("inputmaps").unwrap(),
+            root.child("outputs").unwrap(),
+            root.child("trees").unwrap(),
+        ] {
+            let existed = vfs.exists(&dir)?;
+            vfs.create_dir_all(&dir)?;
+            // Only new directories are changed, because the existing ones
+            // may belong to another user
+            if !existed && let Some(permissions) = &permissions {
+                vfs.set_permissions(&dir, permissions.dir_mode(), permissions.group)?;
+            }
+        }
+
+        Ok(BuildCache {
+            root,
+            env,
+            vfs,
+            options,
+            permissions,
+            touched_entries: Mutex::default(),
+            hash_breakdowns: Mutex::default(),
+            rule_hashes: Mutex::default(),
+        })
+    }
 
-        vfs.create_dir_all(&root.child("inputmaps").unwrap())?;
-        vfs.create_dir_all(&root.child("outputs").unwrap())?;
+    /// Write a file into the cache
+    fn write_cache_file(&self, path: &HexPath, contents: &[u8]) -> Result<(), io::Error> {
+        self.store_cache_file(path, |side_path| self.vfs.write(side_path, contents))
+    }
+
+    /// Copy a file from the workspace into the cache
+    fn copy_into_cache(&self, source: &HexPath, path: &HexPath) -> Result<(), io::Error> {
+        self.store_cache_file(path, |side_path| self.vfs.copy(source, side_path))
+    }
+
+    /// Write a cache file with `write` under a side name, give it the
+    /// cache's permissions, and then rename it into place, so that it is
+    /// never seen half written or with the wrong permissions
+    fn store_cache_file(
+        &self,
+        path: &HexPath,
+        write: impl FnOnce(&HexPath) -> Result<(), io::Error>,
+    ) -> Result<(), io::Error> {
+        let side_path = HexPath::try_from(side_file_name(path)).map_err(io::Error::other)?;
+        let result = write(&side_path).and_then(|()| {
+            if let Some(permissions) = &self.permissions {
+                let mode = if self.vfs.mode(&side_path)? & 0o111 != 0 {
+                    permissions.executable_mode
+                } else {
+                    permissions.file_mode
+                };
+                self.vfs
+                    .set_permissions(&side_path, mode, permissions.group)?;
+            }
+            self.vfs.rename(&side_path, path)
+        });
+        if result.is_err() {
+            let _ = self.vfs.remove_file(&side_path);
+        }
+        result
+    }
 
-        Ok(BuildCache { root, env, vfs })
+    /// Remove a file from the cache while garbage collecting. A file that
+    /// another build already removed, or that this user may not remove,
+    /// is skipped.
+    fn remove_cache_file(&self, path: &HexPath) -> Result<(), io::Error> {
+        match self.vfs.remove_file(path) {
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Hash a rule with its inputs from the workspace, and record what
+    /// went into the hash
+    fn rule_hash(&self, rule: &HexRule) -> Result<BuildHash, io::Error> {
+        self.rule_hash_with_inputs(rule, |input, tree| {
+            tree.add_from_vfs(input, self.vfs.as_ref())
+        })
+    }
+
+    /// Like [BuildCache::rule_hash], with each input added by `hash_input`
+    fn rule_hash_with_inputs(
+        &self,
+        rule: &HexRule,
+        hash_input: impl FnMut(&HexPath, &mut TreeHasher) -> Result<(), io::Error>,
+    ) -> Result<BuildHash, io::Error> {
+        let (rule_hash, breakdown) = BuildHash::hash_with_breakdown(&self.env, rule, hash_input)?;
+        self.hash_breakdowns
+            .lock()
+            .unwrap()
+            .insert(rule.name.to_string(), breakdown);
+        self.rule_hashes
+            .lock()
+            .unwrap()
+            .insert(rule.name.to_string(), rule_hash.clone());
+        Ok(rule_hash)
+    }
+
+    /// The most recent hash that this build computed for a rule, if it
+    /// has hashed the rule at all
+    pub fn last_rule_hash(&self, rule: &HexRule) -> Option<BuildHash> {
+        self.rule_hashes
+            .lock()
+            .unwrap()
+            .get(rule.name.as_str())
+            .cloned()
     }
 
     /// Return the environment variables that should be passed to build commands
-    pub fn env(&self) -> &Arc<BTreeMap<Arc<String>, Arc<String>>> {
+    pub fn env(&self) -> &Arc<BuildEnvironment> {
         &self.env
     }
 
-    /// Try to retrieve previously built outputs of the given rule.
-    /// Return Ok(true) if there was a cache hit and the retrieval succeeded.
-    pub fn retrieve_outputs(&self, rule: &HexRule) -> Result<bool, io::Error> {
-        let rule_hash = BuildHash::hash(&self.env, rule, &*self.vfs)?;
+    /// Try to retrieve previously built outputs of the given rule. An
+    /// entry that cannot be read, such as because one of its files is
+    /// missing, is removed from the cache so that the rule can be built
+    /// and cached again.
+    pub fn retrieve_outputs(&self, rule: &HexRule) -> Result<CacheRetrieval, io::Error> {
+        if self.options.mode == CacheMode::WriteOnly {
+            return Ok(CacheRetrieval::Miss);
+        }
+
+        let rule_hash = self.rule_hash(rule)?;
+        self.retrieve_outputs_with_hash(rule, &rule_hash)
+    }
+
+    /// Like [BuildCache::retrieve_outputs], but for a rule whose hash is
+    /// already known, such as from [BuildCache::lookup_outputs]. The
+    /// rule's inputs do not need to be in the workspace.
+    pub fn retrieve_outputs_with_hash(
+        &self,
+        rule: &HexRule,
+        rule_hash: &BuildHash,
+    ) -> Result<CacheRetrieval, io::Error> {
+        if self.options.mode == CacheMode::WriteOnly {
+            return Ok(CacheRetrieval::Miss);
+        }
+
+        let inputmap_path = self
+            .root
+            .child("inputmaps")
+            .unwrap()
+            .child(rule_hash)
+            .unwrap();
+
+        if !self.vfs.exists(&inputmap_path)? || self.is_expired(&inputmap_path)? {
+            return Ok(CacheRetrieval::Miss);
+        }
+
+        // Copy every output to a side name first, and only replace the
+        // outputs in the workspace once all of them have been copied. That
+        // way, the workspace never has a mix of old and new outputs.
+        let mut staged = Vec::new();
+        if let Err(error) = self.stage_outputs(rule, &inputmap_path, &mut staged) {
+            for staged_output in &staged {
+                let _ = self.remove_path(&staged_output.side_path);
+            }
+            if error.kind() == io::ErrorKind::PermissionDenied {
+                return Ok(CacheRetrieval::Denied {
+                    error: error.to_string(),
+                });
+            }
+            let _ = self.vfs.remove_file(&inputmap_path);
+            return Ok(CacheRetrieval::Evicted {
+                error: error.to_string(),
+            });
+        }
+        for staged_output in staged {
+            let output_path = &staged_output.output_path;
+            if staged_output.is_tree || !self.vfs.is_file(output_path)? {
+                self.remove_path(output_path)?;
+            }
+            self.vfs.rename(&staged_output.side_path, output_path)?;
+        }
+
+        self.touch_entry(rule_hash)?;
+        Ok(CacheRetrieval::Hit)
+    }
+
+    /// Record that this build used the entry with the given hash, both
+    /// for garbage collection and for `hexmake cache push`
+    pub fn touch_entry(&self, rule_hash: &BuildHash) -> Result<(), io::Error> {
+        let inputmap_path = self
+            .root
+            .child("inputmaps")
+            .unwrap()
+            .child(rule_hash)
+            .unwrap();
+        // An entry that another user wrote may not be writable, and then
+        // it only looks older to garbage collection than it is
+        unless_denied(self.vfs.touch(&inputmap_path), ())?;
+        self.touched_entries
+            .lock()
+            .unwrap()
+            .insert(rule_hash.0.clone());
+        Ok(())
+    }
+
+    /// Find the cached outputs of a rule without restoring them. Inputs
+    /// that are in `cached_inputs` are taken to have the contents of the
+    /// given cached output, whatever is in the workspace, and the others
+    /// are read from the workspace. Return None if the cache has no
+    /// complete entry for the rule, or if some of its inputs are missing.
+    pub fn lookup_outputs(
+        &self,
+        rule: &HexRule,
+        cached_inputs: &BTreeMap<HexPath, String>,
+    ) -> Result<Option<CachedOutputs>, io::Error> {
+        match unless_denied(self.find_cached_outputs(rule, cached_inputs), None) {
+            // A damaged entry is not found here, and it is removed when
+            // the rule that made it is retrieved
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => Ok(None),
+            result => result,
+        }
+    }
+
+    /// [BuildCache::lookup_outputs], failing if a cache file cannot be read
+    fn find_cached_outputs(
+        &self,
+        rule: &HexRule,
+        cached_inputs: &BTreeMap<HexPath, String>,
+    ) -> Result<Option<CachedOutputs>, io::Error> {
+        if self.options.mode == CacheMode::WriteOnly {
+            return Ok(None);
+        }
+        for input in &rule.inputs {
+            if !cached_inputs.contains_key(input) && !self.vfs.exists(input)? {
+                return Ok(None);
+            }
+        }
+
+        let rule_hash =
+            self.rule_hash_with_inputs(rule, |input, tree| match cached_inputs.get(input) {
+                Some(output_hash) => self.hash_cached_output(input, output_hash, tree),
+                None => tree.add_from_vfs(input, self.vfs.as_ref()),
+            })?;
         let inputmap_path = self
             .root
             .child("inputmaps")
             .unwrap()
             .child(&rule_hash)
             .unwrap();
+        if !self.vfs.exists(&inputmap_path)? || self.is_expired(&inputmap_path)? {
+            return Ok(None);
+        }
 
-        if !self.vfs.exists(&inputmap_path)? {
-            return Ok(false);
+        let output_hashes = match self.read_inputmap(&inputmap_path) {
+            Ok(output_hashes) => output_hashes,
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        if output_hashes.len() != rule.outputs.len() {
+            return Ok(None);
+        }
+        let mut outputs = BTreeMap::new();
+        for (output_path, output_hash) in rule.outputs.iter().zip(output_hashes) {
+            if !self.vfs.exists(&self.tree_path(&output_hash)?)?
+                && !self.vfs.exists(&self.output_path(&output_hash)?)?
+            {
+                return Ok(None);
+            }
+            outputs.insert(output_path.clone(), output_hash);
         }
 
-        let inputmap = String::from_utf8(self.vfs.read(&inputmap_path)?).unwrap();
-        let output_hashes: Vec<&str> = inputmap.split("\n").collect();
+        Ok(Some(CachedOutputs { rule_hash, outputs }))
+    }
+
+    /// Hash a cached output as if it had been restored to `output_path`
+    fn hash_cached_output(
+        &self,
+        output_path: &HexPath,
+        output_hash: &str,
+        tree: &mut TreeHasher,
+    ) -> Result<(), io::Error> {
+        let tree_path = self.tree_path(output_hash)?;
+        if !self.vfs.exists(&tree_path)? {
+            tree.add_file(
+                output_path,
+                &self.vfs.read(&self.output_path(output_hash)?)?,
+            );
+            return Ok(());
+        }
+
+        tree.add_dir(output_path);
+        let manifest = self.read_manifest(&tree_path)?;
+        for entry in parse_tree(&manifest) {
+            match entry {
+                TreeEntry::Dir { path } => tree.add_dir(&format!("{output_path}/{path}")),
+                TreeEntry::File { hash, path } => tree.add_file(
+                    &format!("{output_path}/{path}"),
+                    &self.vfs.read(&self.output_path(hash)?)?,
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the cached outputs of a rule, as listed in its inputmap, to
+    /// side names next to the outputs, unless they are already in place.
+    /// Every output is added to `staged` before any is copied, so that the
+    /// caller can remove partial copies if this fails. The outputs are
+    /// copied on several threads at once, because a rule with many large
+    /// outputs would otherwise wait on each copy in turn.
+    fn stage_outputs(
+        &self,
+        rule: &HexRule,
+        inputmap_path: &HexPath,
+        staged: &mut Vec<StagedOutput>,
+    ) -> Result<(), io::Error> {
+        let output_hashes = self.read_inputmap(inputmap_path)?;
+        if output_hashes.len() != rule.outputs.len() {
+            return Err(io::Error::other(format!(
+                "{inputmap_path} lists {} outputs instead of {}",
+                output_hashes.len(),
+                rule.outputs.len()
+            )));
+        }
 
         for (output_path, output_hash) in rule.outputs.iter().zip(output_hashes.iter()) {
-            let cached_path = self
-                .root
-                .child("outputs")
-                .unwrap()
-                .child(output_hash)
-                .unwrap();
+            // An output that is already the same as the cached one is left
+            // alone, so that it keeps its modification time
+            if self.vfs.exists(output_path)?
+                && BuildHash::hash_tree(&output_path, self.vfs.as_ref())?.0 == *output_hash
+            {
+                continue;
+            }
 
-            // Remove any prior existing file. Ignore errors, because
-            // the file may not exist.
-            let _ = self.vfs.remove_file(output_path);
+            let side_path =
+                HexPath::try_from(format!("{output_path}.hexrestore")).map_err(io::Error::other)?;
+            self.remove_path(&side_path)?;
             if let Some(parent) = output_path.parent() {
                 // Create the parent if needed
                 self.vfs.create_dir_all(&parent)?;
             }
-            self.vfs.copy(&cached_path, output_path)?;
+
+            self.check_cached_output(output_path, output_hash)?;
+            let tree_path = self.tree_path(output_hash)?;
+            let is_tree = self.vfs.exists(&tree_path)?;
+            staged.push(StagedOutput {
+                output_path: output_path.clone(),
+                side_path,
+                source: if is_tree {
+                    tree_path
+                } else {
+                    self.output_path(output_hash)?
+                },
+                is_tree,
+            });
+        }
+
+        // Each thread repeatedly takes the next output that nobody has
+        // started on yet
+        let next = AtomicUsize::new(0);
+        let copy_outputs = || -> Result<(), io::Error> {
+            while let Some(staged_output) = staged.get(next.fetch_add(1, Ordering::Relaxed)) {
+                if staged_output.is_tree {
+                    self.restore_tree(&staged_output.source, &staged_output.side_path)?;
+                } else {
+                    self.restore_file(&staged_output.source, &staged_output.side_path)?;
+                }
+            }
+            Ok(())
+        };
+        thread::scope(|scope| {
+            let helpers: Vec<_> = (1..RESTORE_THREADS.min(staged.len()))
+                .map(|_| scope.spawn(copy_outputs))
+                .collect();
+            let mut result = copy_outputs();
+            for helper in helpers {
+                result = result.and(helper.join().unwrap());
+            }
+            result
+        })
+    }
+
+    /// Check that a cached output has the hash it is stored under, once
+    /// it is restored to `output_path`. The hash covers the output's path,
+    /// so an output imported from an archive can only be checked here. An
+    /// output that does not match is removed from the cache, so that the
+    /// rule can be cached again.
+    fn check_cached_output(
+        &self,
+        output_path: &HexPath,
+        output_hash: &str,
+    ) -> Result<(), io::Error> {
+        let actual = BuildHash::hash_described_tree(|tree| {
+            self.hash_cached_output(output_path, output_hash, tree)
+        })?;
+        if actual.0 == output_hash {
+            return Ok(());
         }
 
-        Ok(true)
+        let tree_path = self.tree_path(output_hash)?;
+        let cached_path = if self.vfs.exists(&tree_path)? {
+            tree_path
+        } else {
+            self.output_path(output_hash)?
+        };
+        let _ = self.vfs.remove_file(&cached_path);
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{cached_path} does not match its hash"),
+        ))
+    }
+
+    /// Remove a file or directory tree, if it exists
+    fn remove_path(&self, path: &HexPath) -> Result<(), io::Error> {
+        if self.vfs.is_file(path)? {
+            self.vfs.remove_file(path)
+        } else if self.vfs.exists(path)? {
+            self.vfs.remove_dir_all(path)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read the manifest of a cached tree. A manifest that is not text is
+    /// invalid data, so that the entry is treated like a damaged one.
+    fn read_manifest(&self, tree_path: &HexPath) -> Result<String, io::Error> {
+        String::from_utf8(self.vfs.read(tree_path)?).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{tree_path} is not valid text"),
+            )
+        })
+    }
+
+    /// Read the output hashes in an inputmap. An inputmap that is not
+    /// text, or that has a line that cannot name a file in the cache, is
+    /// invalid data, so that the entry is treated like a damaged one.
+    fn read_inputmap(&self, inputmap_path: &HexPath) -> Result<Vec<String>, io::Error> {
+        let inputmap = String::from_utf8(self.vfs.read(inputmap_path)?).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{inputmap_path} is not valid text"),
+            )
+        })?;
+        let output_hashes: Vec<String> = inputmap.lines().map(String::from).collect();
+        for output_hash in &output_hashes {
+            if output_hash.contains('/') || self.output_path(output_hash).is_err() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{inputmap_path} has an invalid output hash `{output_hash}`"),
+                ));
+            }
+        }
+        Ok(output_hashes)
+    }
+
+    /// Create the directory tree that the given manifest describes
+    fn restore_tree(&self, tree_path: &HexPath, output_path: &HexPath) -> Result<(), io::Error> {
+        self.vfs.create_dir_all(output_path)?;
+
+        let manifest = self.read_manifest(tree_path)?;
+        for entry in parse_tree(&manifest) {
+            match entry {
+                TreeEntry::Dir { path } => {
+                    self.vfs
+                        .create_dir_all(&output_path.child(path).map_err(io::Error::other)?)?;
+                }
+                TreeEntry::File { hash, path } => {
+                    let file_path = output_path.child(path).map_err(io::Error::other)?;
+                    if let Some(parent) = file_path.parent() {
+                        self.vfs.create_dir_all(&parent)?;
+                    }
+                    self.restore_file(&self.output_path(hash)?, &file_path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a file out of the cache, and give it the modification time
+    /// that the options ask for. A cached copy that an output was linked
+    /// to is read-only, but the restored file is separate, so its owner
+    /// may write to it again.
+    fn restore_file(&self, cached_path: &HexPath, path: &HexPath) -> Result<(), io::Error> {
+        self.vfs.copy(cached_path, path)?;
+        let mode = self.vfs.mode(path)?;
+        if mode & 0o200 == 0 {
+            self.vfs.set_permissions(path, mode | 0o200, None)?;
+        }
+        if let Some(modtime) = self
+            .options
+            .output_times
+            .time_for(self.vfs.modtime(cached_path)?)
+        {
+            self.vfs.set_modtime(path, modtime)?;
+        }
+        Ok(())
     }
 
     /// Add build outputs to the cache
     pub fn insert_outputs(&self, rule: &HexRule) -> Result<(), io::Error> {
         let mut inputmap = String::new();
         for output_path in rule.outputs.iter() {
-            // Copy the output to the cached dir
             let output_hash = BuildHash::hash_tree(&output_path, self.vfs.as_ref())?;
+            if !self.vfs.is_file(output_path)? {
+                self.insert_tree(output_path, &output_hash)?;
+                inputmap.push_str(&format!("{}\n", output_hash.0));
+                continue;
+            }
+
+            // Copy the output to the cached dir
             let cached_path = self
                 .root
                 .child("outputs")
                 .unwrap()
                 .child(&output_hash)
                 .unwrap();
-            self.vfs.copy(output_path, &cached_path)?;
+
+            // The cache is content-addressed, so an existing file with
+            // the same hash already has the right contents
+            if !self.vfs.exists(&cached_path)? {
+                self.copy_into_cache(output_path, &cached_path)?;
+            }
+
+            if self.options.hardlink_outputs {
+                self.link_output_to_cache(output_path, &cached_path);
+            }
 
             // Add it to the inputmap
             inputmap.push_str(&format!("{}\n", output_hash.0));
         }
 
-        let rule_hash = BuildHash::hash(&self.env, rule, self.vfs.as_ref())?;
+        let rule_hash = self.rule_hash(rule)?;
         let inputmap_path = self
             .root
             .child("inputmaps")
             .unwrap()
             .child(&rule_hash)
             .unwrap();
-        self.vfs.write(&inputmap_path, inputmap.as_bytes())?;
+        self.write_cache_file(&inputmap_path, inputmap.as_bytes())?;
 
+        self.touched_entries.lock().unwrap().insert(rule_hash.0);
         Ok(())
     }
 
-    /// Garbage collect the cache if it has grown too large
+    /// Add a directory output to the cache, as a manifest in `trees/` and
+    /// the contents of its files in `outputs/`
+    fn insert_tree(&self, output_path: &HexPath, output_hash: &BuildHash) -> Result<(), io::Error> {
+        let tree_path = self.tree_path(output_hash)?;
+        if self.vfs.exists(&tree_path)? {
+            return Ok(());
+        }
+
+        let prefix = format!("{output_path}/");
+        let mut manifest = String::new();
+        for entry in self.vfs.tree_walk_metadata(output_path)? {
+            let Some(path) = entry.path.strip_prefix(&prefix) else {
+                continue;
+            };
+            if !entry.is_file {
+                manifest.push_str(&format!("dir {path}\n"));
+                continue;
+            }
+
+            let file_hash = hash_contents(&self.vfs.read(&entry.path)?);
+            let cached_path = self.output_path(&file_hash.0)?;
+            if !self.vfs.exists(&cached_path)? {
+                self.copy_into_cache(&entry.path, &cached_path)?;
+            }
+            manifest.push_str(&format!("file {} {path}\n", file_hash.0));
+        }
+
+        // The manifest is written last, so that it never refers to files
+        // that are not in the cache yet
+        self.write_cache_file(&tree_path, manifest.as_bytes())
+    }
+
+    /// Replace a file in the workspace with a hard link to its copy in the
+    /// cache. The link is made under a side name and then renamed over the
+    /// original, so the output is never missing. The cached copy is made
+    /// read-only first, so that writing to the output in place fails
+    /// instead of changing the cache. If either step fails, for example
+    /// because the two are on different filesystems or another user owns
+    /// the cached copy, the output is simply left as a separate copy.
+    fn link_output_to_cache(&self, output_path: &HexPath, cached_path: &HexPath) {
+        let Ok(side_path) = HexPath::try_from(format!("{output_path}.hexlink")) else {
+            return;
+        };
+        let Ok(mode) = self.vfs.mode(cached_path) else {
+            return;
+        };
+        if mode & 0o222 != 0
+            && self
+                .vfs
+                .set_permissions(cached_path, mode & !0o222, None)
+                .is_err()
+        {
+            return;
+        }
+
+        let _ = self.vfs.remove_file(&side_path);
+        if self.vfs.hard_link(cached_path, &side_path).is_err() {
+            return;
+        }
+        if self.vfs.rename(&side_path, output_path).is_err() {
+            let _ = self.vfs.remove_file(&side_path);
+        }
+    }
+
+    /// Look up the given rule in the cache without changing anything.
+    /// This is used to predict what a build would do.
+    pub fn probe(&self, rule: &HexRule) -> Result<CacheProbe, io::Error> {
+        unless_denied(self.probe_entry(rule), CacheProbe::Miss)
+    }
+
+    /// [BuildCache::probe], failing if a cache file cannot be read
+    fn probe_entry(&self, rule: &HexRule) -> Result<CacheProbe, io::Error> {
+        if self.options.mode == CacheMode::WriteOnly {
+            return Ok(CacheProbe::Miss);
+        }
+        for input in &rule.inputs {
+            if !self.vfs.exists(input)? {
+                return Ok(CacheProbe::Unknown);
+            }
+        }
+
+        let rule_hash = self.rule_hash(rule)?;
+        let inputmap_path = self
+            .root
+            .child("inputmaps")
+            .unwrap()
+            .child(&rule_hash)
+            .unwrap();
+        if !self.vfs.exists(&inputmap_path)? || self.is_expired(&inputmap_path)? {
+            return Ok(CacheProbe::Miss);
+        }
+
+        // Check whether the workspace already has the cached outputs. An
+        // inputmap that is damaged will be evicted by the build.
+        let output_hashes = match self.read_inputmap(&inputmap_path) {
+            Ok(output_hashes) => output_hashes,
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                return Ok(CacheProbe::Miss);
+            }
+            Err(error) => return Err(error),
+        };
+        let mut outputs_current = true;
+        for (output_path, output_hash) in rule.outputs.iter().zip(&output_hashes) {
+            if !self.vfs.exists(output_path)?
+                || BuildHash::hash_tree(&output_path, self.vfs.as_ref())?.0 != *output_hash
+            {
+                outputs_current = false;
+            }
+        }
+
+        Ok(CacheProbe::Hit { outputs_current })
+    }
+
+    /// Remove the cache entry for the given rule, if there is one. Return
+    /// Ok(false) if there is no entry or if the rule's inputs are not all
+    /// present, in which case its hash cannot be computed. The output files
+    /// that the entry refers to are left for the garbage collector, because
+    /// other entries may share them.
+    pub fn remove_entry(&self, rule: &HexRule) -> Result<bool, io::Error> {
+        for input in &rule.inputs {
+            if !self.vfs.exists(input)? {
+                return Ok(false);
+            }
+        }
+
+        let rule_hash = BuildHash::hash(&self.env, rule, self.vfs.as_ref())?;
+        let inputmap_path = self
+            .root
+            .child("inputmaps")
+            .unwrap()
+            .child(&rule_hash)
+            .unwrap();
+        if !self.vfs.exists(&inputmap_path)? {
+            return Ok(false);
+        }
+
+        self.vfs.remove_file(&inputmap_path)?;
+        Ok(true)
+    }
+
+    /// Pin the cache entry for the given rule, so that garbage collection
+    /// keeps it and its outputs. Return Ok(false) if there is no entry to
+    /// pin, or if the rule's inputs are not all present.
+    pub fn pin_entry(&self, rule: &HexRule) -> Result<bool, io::Error> {
+        for input in &rule.inputs {
+            if !self.vfs.exists(input)? {
+                return Ok(false);
+            }
+        }
+
+        let rule_hash = BuildHash::hash(&self.env, rule, self.vfs.as_ref())?;
+        let inputmap_path = self
+            .root
+            .child("inputmaps")
+            .unwrap()
+            .child(&rule_hash)
+            .unwrap();
+        if !self.vfs.exists(&inputmap_path)? {
+            return Ok(false);
+        }
+
+        let mut pinned = self.pinned_entries()?;
+        pinned.insert(rule_hash.0, rule.name.to_string());
+        self.write_pinned_entries(&pinned)?;
+        Ok(true)
+    }
+
+    /// Unpin every cache entry that was pinned for the rule with the
+    /// given name, including entries for earlier versions of its inputs.
+    /// Return how many entries were unpinned.
+    pub fn unpin_entries(&self, rule_name: &str) -> Result<usize, io::Error> {
+        let mut pinned = self.pinned_entries()?;
+        let before = pinned.len();
+        pinned.retain(|_, name| name != rule_name);
+        let unpinned = before - pinned.len();
+        if unpinned > 0 {
+            self.write_pinned_entries(&pinned)?;
+        }
+        Ok(unpinned)
+    }
+
+    /// Read the pinned entries, as a map from inputmap hash to rule name
+    fn pinned_entries(&self) -> Result<BTreeMap<String, String>, io::Error> {
+        let pinned_path = self.root.child("pinned").unwrap();
+        if !self.vfs.exists(&pinned_path)? {
+            return Ok(BTreeMap::new());
+        }
+        let pinned = String::from_utf8(self.vfs.read(&pinned_path)?).unwrap();
+        Ok(pinned
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(hash, rule_name)| (hash.to_string(), rule_name.to_string()))
+            .collect())
+    }
+
+    fn write_pinned_entries(&self, pinned: &BTreeMap<String, String>) -> Result<(), io::Error> {
+        let mut contents = String::new();
+        for (hash, rule_name) in pinned {
+            contents.push_str(&format!("{hash} {rule_name}\n"));
+        }
+        self.write_cache_file(&self.root.child("pinned").unwrap(), contents.as_bytes())
+    }
+
+    /// The output files of the pinned entries, which garbage collection
+    /// must not remove
+    fn pinned_outputs(
+        &self,
+        pinned: &BTreeMap<String, String>,
+    ) -> Result<BTreeSet<HexPath>, io::Error> {
+        let mut outputs = BTreeSet::new();
+        for hash in pinned.keys() {
+            let inputmap_path = self
+                .root
+                .child("inputmaps")
+                .unwrap()
+                .child(hash)
+                .map_err(io::Error::other)?;
+            if !self.vfs.exists(&inputmap_path)? {
+                continue;
+            }
+            // A damaged entry has no outputs worth keeping
+            let output_hashes = match self.read_inputmap(&inputmap_path) {
+                Ok(output_hashes) => output_hashes,
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => continue,
+                Err(error) => return Err(error),
+            };
+            for output_hash in &output_hashes {
+                match self.output_files(output_hash) {
+                    Ok(files) => outputs.extend(files),
+                    Err(error) if error.kind() == io::ErrorKind::InvalidData => {}
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// Record the entries that this build used or wrote, replacing the
+    /// record of the previous build. A cache that this user may not write
+    /// to is left as it is.
+    pub fn save_touched_entries(&self) -> Result<(), io::Error> {
+        let mut contents = String::new();
+        for hash in self.touched_entries.lock().unwrap().iter() {
+            contents.push_str(&format!("{hash}\n"));
+        }
+        let last_build = self.root.child("last_build").unwrap();
+        unless_denied(self.write_cache_file(&last_build, contents.as_bytes()), ())
+    }
+
+    /// Write what went into the hash of every rule that this build hashed,
+    /// as a JSON object from rule names to [HashBreakdown]s
+    pub fn save_hash_breakdowns(&self, path: &HexPath) -> Result<(), io::Error> {
+        let json = serde_json::to_string_pretty(&*self.hash_breakdowns.lock().unwrap())?;
+        if let Some(parent) = path.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+        self.vfs.write(path, json.as_bytes())
+    }
+
+    /// Package the entries that the most recent build used or wrote as a
+    /// cache archive, in the format that [BuildCache::import_archive]
+    /// reads. Entries that have since been removed are left out. Return
+    /// the archive and the number of entries in it.
+    pub fn export_archive(&self) -> Result<(Vec<u8>, usize), io::Error> {
+        let last_build_path = self.root.child("last_build").unwrap();
+        if !self.vfs.exists(&last_build_path)? {
+            return Err(io::Error::other(
+                "There is no record of a build to push. Run a build first",
+            ));
+        }
+        let last_build = String::from_utf8(self.vfs.read(&last_build_path)?).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{last_build_path} is not valid text"),
+            )
+        })?;
+
+        let mut inputmaps = Vec::new();
+        let mut trees = BTreeSet::new();
+        let mut outputs = BTreeSet::new();
+        for hash in last_build.lines() {
+            let inputmap_path = self
+                .root
+                .child("inputmaps")
+                .unwrap()
+                .child(hash)
+                .map_err(io::Error::other)?;
+            if !self.vfs.exists(&inputmap_path)? {
+                continue;
+            }
+            // A damaged entry, or one with a damaged manifest, is left out
+            let output_hashes = match self.read_inputmap(&inputmap_path) {
+                Ok(output_hashes) => output_hashes,
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => continue,
+                Err(error) => return Err(error),
+            };
+            let inputmap = self.vfs.read(&inputmap_path)?;
+            let mut complete = true;
+            let mut entry_trees = Vec::new();
+            let mut entry_outputs = Vec::new();
+            for output_hash in &output_hashes {
+                let tree_path = self.tree_path(output_hash)?;
+                if self.vfs.exists(&tree_path)? {
+                    entry_trees.push(tree_path);
+                }
+                match self.output_files(output_hash) {
+                    Ok(files) => entry_outputs.extend(files),
+                    Err(error) if error.kind() == io::ErrorKind::InvalidData => complete = false,
+                    Err(error) => return Err(error),
+                }
+            }
+            for output_path in &entry_outputs {
+                complete &= self.vfs.exists(output_path)?;
+            }
+            if complete {
+                inputmaps.push((format!("inputmaps/{hash}"), inputmap));
+                trees.extend(entry_trees);
+                outputs.extend(entry_outputs);
+            }
+        }
+
+        // Outputs and trees go first, so that importing never writes an
+        // inputmap before the files it refers to
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for path in outputs.iter().chain(&trees) {
+            let name = path.strip_prefix(&format!("{}/", self.root)).unwrap();
+            append_file(&mut builder, name, &self.vfs.read(path)?)?;
+        }
+        for (name, contents) in &inputmaps {
+            append_file(&mut builder, name, contents)?;
+        }
+        let archive = builder.into_inner()?.finish()?;
+
+        Ok((archive, inputmaps.len()))
+    }
+
+    /// The path of the cached output file with the given hash
+    fn output_path(&self, output_hash: &str) -> Result<HexPath, io::Error> {
+        self.root
+            .child("outputs")
+            .unwrap()
+            .child(output_hash)
+            .map_err(io::Error::other)
+    }
+
+    /// The path of the manifest for the cached directory tree with the
+    /// given hash
+    fn tree_path(&self, output_hash: &str) -> Result<HexPath, io::Error> {
+        self.root
+            .child("trees")
+            .unwrap()
+            .child(output_hash)
+            .map_err(io::Error::other)
+    }
+
+    /// The files in `outputs/` that hold an output with the given hash.
+    /// For a file, that is just the file itself, and for a directory
+    /// tree, it is the contents of the files in the tree.
+    fn output_files(&self, output_hash: &str) -> Result<Vec<HexPath>, io::Error> {
+        let tree_path = self.tree_path(output_hash)?;
+        if !self.vfs.exists(&tree_path)? {
+            return Ok(vec![self.output_path(output_hash)?]);
+        }
+
+        let manifest = self.read_manifest(&tree_path)?;
+        let mut result = Vec::new();
+        for entry in parse_tree(&manifest) {
+            if let TreeEntry::File { hash, .. } = entry {
+                result.push(self.output_path(hash)?);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Merge a cache archive, as a gzipped tar file, into this cache.
+    /// The archive holds `inputmaps/`, `outputs/`, and `trees/` files laid
+    /// out like the cache itself. Files that the cache already has are
+    /// skipped, and the inputmaps are written last, so that an entry never
+    /// refers to an output that has not been written yet.
+    ///
+    /// An archive with an inputmap that does not list hashes is rejected.
+    /// The files of directory outputs are stored under the hash of their
+    /// contents, and an archive where one of them does not match is
+    /// rejected too. Outputs that an inputmap lists are stored under a
+    /// hash that covers their path as well, so they are checked when they
+    /// are restored, and any other output must match its contents.
+    pub fn import_archive(&self, archive: impl Read) -> Result<ImportCounts, io::Error> {
+        let mut counts = ImportCounts::default();
+        let mut inputmaps = Vec::new();
+        let mut manifests = Vec::new();
+        let mut listed_outputs = BTreeSet::new();
+        let mut uncheckable_outputs = BTreeSet::new();
+
+        for entry in tar::Archive::new(GzDecoder::new(archive)).entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let components: Vec<Component> = path.components().collect();
+            let (kind, name) = match components[..] {
+                [Component::Normal(kind), Component::Normal(name)]
+                    if kind == "inputmaps" || kind == "outputs" || kind == "trees" =>
+                {
+                    (kind.to_string_lossy(), name.to_string_lossy())
+                }
+                [Component::Normal(_)] if entry.header().entry_type().is_dir() => continue,
+                _ => {
+                    return Err(io::Error::other(format!(
+                        "Unexpected entry `{}` in cache archive",
+                        path.display()
+                    )));
+                }
+            };
+
+            let cache_path = self
+                .root
+                .child(&kind)
+                .unwrap()
+                .child(&name)
+                .map_err(io::Error::other)?;
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            if kind == "trees" {
+                let manifest = String::from_utf8(contents.clone()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("`{}` in cache archive is not valid text", path.display()),
+                    )
+                })?;
+                manifests.push(manifest);
+            }
+            if kind == "inputmaps" {
+                let output_hashes = std::str::from_utf8(&contents)
+                    .ok()
+                    .filter(|inputmap| inputmap.lines().all(is_build_hash))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("`{}` in cache archive is not an inputmap", path.display()),
+                        )
+                    })?;
+                listed_outputs.extend(output_hashes.lines().map(String::from));
+            }
+            if self.vfs.exists(&cache_path)? {
+                counts.skipped += 1;
+                continue;
+            }
+
+            if kind == "inputmaps" {
+                inputmaps.push((cache_path, contents));
+            } else {
+                if kind == "outputs" && hash_contents(&contents).0 != name {
+                    uncheckable_outputs.insert(name.into_owned());
+                }
+                self.write_cache_file(&cache_path, &contents)?;
+                counts.added += 1;
+            }
+        }
+
+        // An output that is not stored under the hash of its contents can
+        // only be a whole output that an inputmap lists, because the hash of
+        // such an output covers its path, and it is checked when it is
+        // restored. It must not be one of the files of a directory.
+        let tree_files: BTreeSet<&str> = manifests
+            .iter()
+            .flat_map(|manifest| parse_tree(manifest))
+            .filter_map(|entry| match entry {
+                TreeEntry::File { hash, .. } => Some(hash),
+                TreeEntry::Dir { .. } => None,
+            })
+            .collect();
+        let mismatched: Vec<&String> = uncheckable_outputs
+            .iter()
+            .filter(|hash| !listed_outputs.contains(*hash) || tree_files.contains(hash.as_str()))
+            .collect();
+        if let Some(hash) = mismatched.first() {
+            for hash in &mismatched {
+                let _ = self.vfs.remove_file(&self.output_path(hash)?);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("`outputs/{hash}` in cache archive does not match its hash"),
+            ));
+        }
+
+        for (cache_path, contents) in inputmaps {
+            self.write_cache_file(&cache_path, &contents)?;
+            counts.added += 1;
+        }
+
+        Ok(counts)
+    }
+
+    /// Garbage collect the cache if it has grown too large, or if it has
+    /// entries that are too old or too many. Side files that were left
+    /// behind by a process that stopped while writing are removed too.
     pub fn maybe_gc(&self) -> Result<(), io::Error> {
-        const MAX_SIZE: u64 = 200 * 1024 * 1024; // 200 MB
-        const TARGET_SIZE: u64 = 100 * 1024 * 1024; // 100 MB
+        let settings = &self.options.settings;
+        let max_size = settings.max_size_mb * 1024 * 1024;
+        let target_size = settings.target_size_mb * 1024 * 1024;
+
+        self.remove_stale_side_files()?;
+        let pinned = self.pinned_entries()?;
+        let removed_inputmaps = self.remove_old_inputmaps(&pinned)?;
 
         let outputs_dir = self.root.child("outputs").unwrap();
 
@@ -123,84 +1271,199 @@ impl BuildCache {
         let mut output_files: Vec<(HexPath, u64, u64)> = Vec::new(); // (path, size, modtime)
         let mut total_size: u64 = 0;
 
-        for file_path in self.vfs.list_dir(&outputs_dir)? {
-            if self.vfs.is_file(&file_path)? {
-                let size = self.vfs.file_size(&file_path)?;
-                let modtime = self.vfs.modtime(&file_path)?;
-                output_files.push((file_path, size, modtime));
-                total_size += size;
+        for entry in self.vfs.tree_walk_metadata(&outputs_dir)? {
+            if entry.is_file && !is_side_file(&entry.path) {
+                output_files.push((entry.path, entry.size, entry.modtime));
+                total_size += entry.size;
             }
         }
 
         // If we're over the limit, delete oldest files
-        if total_size > MAX_SIZE {
+        let mut remaining_outputs = BTreeSet::new();
+        if total_size > max_size {
             // Sort by modification time (oldest first)
             output_files.sort_by_key(|(_, _, modtime)| *modtime);
 
-            // Delete oldest files until we're under the target size
-            let mut remaining_outputs = BTreeSet::new();
+            // Delete oldest files until we're under the target size,
+            // keeping the outputs of pinned entries
+            let pinned_outputs = self.pinned_outputs(&pinned)?;
             for (file_path, size, _) in output_files {
-                if total_size <= TARGET_SIZE {
+                if total_size <= target_size || pinned_outputs.contains(&file_path) {
                     remaining_outputs.insert(file_path);
                 } else {
-                    self.vfs.remove_file(&file_path)?;
+                    self.remove_cache_file(&file_path)?;
                     total_size -= size;
                 }
             }
+        } else if removed_inputmaps {
+            remaining_outputs = output_files.into_iter().map(|(path, _, _)| path).collect();
+        } else {
+            return Ok(());
+        }
 
-            // Delete inputmaps that reference missing outputs, and collect the set of
-            // outputs that are still referenced by valid inputmaps
-            let referenced_outputs = self.cleanup_orphaned_inputmaps(&remaining_outputs)?;
+        // Delete inputmaps that reference missing outputs, and collect the set of
+        // outputs that are still referenced by valid inputmaps. If some
+        // inputmap cannot be read, it is not known which outputs it refers
+        // to, so none are deleted.
+        let Some(referenced_outputs) = self.cleanup_orphaned_inputmaps(&remaining_outputs)? else {
+            return Ok(());
+        };
 
-            // Delete orphaned outputs (outputs not referenced by any inputmap)
-            self.cleanup_orphaned_outputs(&remaining_outputs, &referenced_outputs)?;
-        }
+        // Delete orphaned outputs (outputs not referenced by any inputmap)
+        self.cleanup_orphaned_outputs(&remaining_outputs, &referenced_outputs)?;
+        self.cleanup_orphaned_trees(&referenced_outputs)?;
+
+        Ok(())
+    }
 
+    /// Remove the side files in the cache that are too old to belong to a
+    /// write that is still going on. Newer ones are left alone, because
+    /// another build may be about to rename them into place.
+    fn remove_stale_side_files(&self) -> Result<(), io::Error> {
+        let now = self.vfs.now()?;
+        for entry in self.vfs.tree_walk_metadata(&self.root)? {
+            if entry.is_file
+                && is_side_file(&entry.path)
+                && now.saturating_sub(entry.modtime) >= STALE_SIDE_FILE_SECONDS
+            {
+                self.remove_cache_file(&entry.path)?;
+            }
+        }
         Ok(())
     }
 
+    /// Remove the inputmaps that have expired, the ones that have not been
+    /// used for longer than the maximum age, and the least recently used
+    /// ones beyond the maximum number of entries. Pinned entries are kept
+    /// unless they have expired, and do not count towards the maximum.
+    /// The outputs of removed entries are left for
+    /// [BuildCache::cleanup_orphaned_outputs]. Returns whether any
+    /// inputmap was removed.
+    fn remove_old_inputmaps(&self, pinned: &BTreeMap<String, String>) -> Result<bool, io::Error> {
+        let settings = &self.options.settings;
+        if settings.ttl_hours.is_none()
+            && settings.max_age_days.is_none()
+            && settings.max_entries.is_none()
+        {
+            return Ok(false);
+        }
+
+        let inputmaps_dir = self.root.child("inputmaps").unwrap();
+        let pinned_paths: BTreeSet<HexPath> = pinned
+            .keys()
+            .map(|hash| inputmaps_dir.child(hash).map_err(io::Error::other))
+            .collect::<Result<_, _>>()?;
+        let mut removed = false;
+        let mut inputmaps: Vec<(HexPath, u64)> = Vec::new();
+        for entry in self.vfs.tree_walk_metadata(&inputmaps_dir)? {
+            if !entry.is_file || is_side_file(&entry.path) {
+                continue;
+            }
+            if self.is_expired(&entry.path)? {
+                self.remove_cache_file(&entry.path)?;
+                removed = true;
+            } else if !pinned_paths.contains(&entry.path) {
+                inputmaps.push((entry.path, entry.modtime));
+            }
+        }
+
+        // Sort by modification time (newest first), which is when each
+        // entry was last used
+        inputmaps.sort_by_key(|(_, modtime)| Reverse(*modtime));
+
+        let now = self.vfs.now()?;
+        let oldest_allowed = settings
+            .max_age_days
+            .map(|days| now.saturating_sub(days * 24 * 60 * 60));
+        for (index, (inputmap_path, modtime)) in inputmaps.iter().enumerate() {
+            let too_old = oldest_allowed.is_some_and(|oldest| *modtime < oldest);
+            let too_many = settings.max_entries.is_some_and(|max| index >= max);
+            if too_old || too_many {
+                self.remove_cache_file(inputmap_path)?;
+                removed = true;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Whether the given inputmap was written longer ago than the
+    /// time to live, so that its entry can no longer be used
+    fn is_expired(&self, inputmap_path: &HexPath) -> Result<bool, io::Error> {
+        let Some(ttl_hours) = self.options.settings.ttl_hours else {
+            return Ok(false);
+        };
+        let age = self
+            .vfs
+            .now()?
+            .saturating_sub(self.vfs.created(inputmap_path)?);
+        Ok(age >= ttl_hours * 60 * 60)
+    }
+
     /// Remove inputmap files that reference non-existent output files.
-    /// Returns the set of output files that are referenced by valid inputmaps.
+    /// Returns the set of output files and tree manifests that are
+    /// referenced by valid inputmaps, or None if some inputmap could not
+    /// be read.
     fn cleanup_orphaned_inputmaps(
         &self,
         existing_outputs: &BTreeSet<HexPath>,
-    ) -> Result<BTreeSet<HexPath>, io::Error> {
+    ) -> Result<Option<BTreeSet<HexPath>>, io::Error> {
         let inputmaps_dir = self.root.child("inputmaps").unwrap();
         let mut referenced_outputs = BTreeSet::new();
+        let mut all_read = true;
 
-        for inputmap_path in self.vfs.list_dir(&inputmaps_dir)? {
-            if !self.vfs.is_file(&inputmap_path)? {
+        for entry in self.vfs.tree_walk_metadata(&inputmaps_dir)? {
+            if !entry.is_file || is_side_file(&entry.path) {
                 continue;
             }
-
-            // Read the inputmap and check if all referenced outputs exist
-            let inputmap = String::from_utf8(self.vfs.read(&inputmap_path)?).unwrap();
-            let output_hashes: Vec<&str> = inputmap.split('\n').collect();
+            let inputmap_path = entry.path;
+
+            // Read the inputmap and check if all referenced outputs exist.
+            // A damaged inputmap is removed like one with a missing output.
+            let output_hashes = match self.read_inputmap(&inputmap_path) {
+                Ok(output_hashes) => output_hashes,
+                Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                    all_read = false;
+                    continue;
+                }
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                    self.remove_cache_file(&inputmap_path)?;
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
 
             let mut has_missing_output = false;
             let mut this_inputmap_outputs = Vec::new();
 
-            for output_hash in output_hashes {
-                if output_hash.is_empty() {
-                    continue;
+            for output_hash in &output_hashes {
+                let tree_path = self.tree_path(output_hash)?;
+                if self.vfs.exists(&tree_path)? {
+                    this_inputmap_outputs.push(tree_path);
+                }
+                // A damaged manifest counts as a missing output
+                let files = match self.output_files(output_hash) {
+                    Ok(files) => files,
+                    Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                        has_missing_output = true;
+                        break;
+                    }
+                    Err(error) => return Err(error),
+                };
+                for output_path in files {
+                    if !existing_outputs.contains(&output_path) {
+                        has_missing_output = true;
+                    }
+                    this_inputmap_outputs.push(output_path);
                 }
-                let output_path = self
-                    .root
-                    .child("outputs")
-                    .unwrap()
-                    .child(output_hash)
-                    .unwrap();
-                this_inputmap_outputs.push(output_path.clone());
-
-                if !existing_outputs.contains(&output_path) {
-                    has_missing_output = true;
+                if has_missing_output {
                     break;
                 }
             }
 
             // If any output is missing, delete this inputmap
             if has_missing_output {
-                self.vfs.remove_file(&inputmap_path)?;
+                self.remove_cache_file(&inputmap_path)?;
             } else {
                 // This is a valid inputmap, track its outputs as referenced
                 for output_path in this_inputmap_outputs {
@@ -209,7 +1472,25 @@ impl BuildCache {
             }
         }
 
-        Ok(referenced_outputs)
+        Ok(all_read.then_some(referenced_outputs))
+    }
+
+    /// Remove tree manifests that no inputmap refers to
+    fn cleanup_orphaned_trees(
+        &self,
+        referenced_outputs: &BTreeSet<HexPath>,
+    ) -> Result<(), io::Error> {
+        let trees_dir = self.root.child("trees").unwrap();
+        for entry in self.vfs.tree_walk_metadata(&trees_dir)? {
+            if entry.is_file
+                && !is_side_file(&entry.path)
+                && !referenced_outputs.contains(&entry.path)
+            {
+                self.remove_cache_file(&entry.path)?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Remove orphaned output files (outputs not referenced by any inputmap)
@@ -220,7 +1501,7 @@ impl BuildCache {
     ) -> Result<(), io::Error> {
         for output_path in existing_outputs {
             if !referenced_outputs.contains(output_path) {
-                self.vfs.remove_file(output_path)?;
+                self.remove_cache_file(output_path)?;
             }
         }
 
@@ -228,20 +1509,518 @@ impl BuildCache {
     }
 }
 
+/// An entry of a tree manifest, with its path relative to the tree
+enum TreeEntry<'a> {
+    Dir { path: &'a str },
+    File { hash: &'a str, path: &'a str },
+}
+
+/// Parse a tree manifest, skipping any lines that are not understood
+fn parse_tree(manifest: &str) -> Vec<TreeEntry<'_>> {
+    let mut result = Vec::new();
+    for line in manifest.lines() {
+        match line.split_once(' ') {
+            Some(("dir", path)) => result.push(TreeEntry::Dir { path }),
+            Some(("file", rest)) => {
+                if let Some((hash, path)) = rest.split_once(' ') {
+                    result.push(TreeEntry::File { hash, path });
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Add a file to a cache archive. The mode and time are fixed, because
+/// only the contents of cache files matter.
+fn append_file(
+    builder: &mut tar::Builder<impl io::Write>,
+    name: &str,
+    contents: &[u8],
+) -> Result<(), io::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, name, contents)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ast::hexmake_file::{CachePermissions, CacheSettings};
     use crate::file_system::fake::FakeFileSystem;
+    use crate::file_system::output_times::OutputTimes;
     use crate::file_system::vfs::VirtualFileSystem;
 
+    #[test]
+    fn test_remove_entry() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![HexPath::try_from("out/test.txt").unwrap()];
+        rule.inputs = vec![HexPath::try_from("test.txt").unwrap()];
+
+        // A rule whose inputs are missing has no entry to remove
+        assert!(!cache.remove_entry(&rule).unwrap());
+
+        // Insert an entry, and then remove it
+        cache
+            .vfs
+            .write(&HexPath::try_from("test.txt").unwrap(), b"test")
+            .unwrap();
+        cache
+            .vfs
+            .write(&HexPath::try_from("out/test.txt").unwrap(), b"result")
+            .unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        assert!(cache.remove_entry(&rule).unwrap());
+
+        // The entry is gone, so the outputs can no longer be retrieved
+        assert!(!cache.remove_entry(&rule).unwrap());
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Miss);
+    }
+
+    #[test]
+    fn test_probe() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+
+        let input = HexPath::try_from("test.txt").unwrap();
+        let output = HexPath::try_from("out/test.txt").unwrap();
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![output.clone()];
+        rule.inputs = vec![input.clone()];
+
+        assert_eq!(cache.probe(&rule).unwrap(), CacheProbe::Unknown);
+
+        cache.vfs.write(&input, b"test").unwrap();
+        assert_eq!(cache.probe(&rule).unwrap(), CacheProbe::Miss);
+
+        cache.vfs.write(&output, b"result").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        assert_eq!(
+            cache.probe(&rule).unwrap(),
+            CacheProbe::Hit {
+                outputs_current: true
+            }
+        );
+
+        cache.vfs.write(&output, b"modified").unwrap();
+        assert_eq!(
+            cache.probe(&rule).unwrap(),
+            CacheProbe::Hit {
+                outputs_current: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_retrieve_current_outputs() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+
+        let input = HexPath::try_from("test.txt").unwrap();
+        let output = HexPath::try_from("out/test.txt").unwrap();
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![output.clone()];
+        rule.inputs = vec![input.clone()];
+        cache.vfs.write(&input, b"test").unwrap();
+        cache.vfs.write(&output, b"result").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+
+        // An output that is already current is not copied again
+        let modtime = cache.vfs.modtime(&output).unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        assert_eq!(cache.vfs.modtime(&output).unwrap(), modtime);
+
+        cache.vfs.write(&output, b"modified").unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        assert_eq!(cache.vfs.read(&output).unwrap(), b"result");
+    }
+
+    #[test]
+    fn test_write_only_mode() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let options = CacheOptions {
+            mode: CacheMode::WriteOnly,
+            ..CacheOptions::default()
+        };
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![HexPath::try_from("out/test.txt").unwrap()];
+        cache
+            .vfs
+            .write(&HexPath::try_from("out/test.txt").unwrap(), b"result")
+            .unwrap();
+
+        // Outputs are inserted, but existing entries are never used
+        cache.insert_outputs(&rule).unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Miss);
+        assert!(cache.remove_entry(&rule).unwrap());
+    }
+
+    #[test]
+    fn test_hardlink_outputs() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let options = CacheOptions {
+            hardlink_outputs: true,
+            ..CacheOptions::default()
+        };
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+
+        let output = HexPath::try_from("out/test.txt").unwrap();
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![output.clone()];
+        cache.vfs.write(&output, b"result").unwrap();
+
+        cache.insert_outputs(&rule).unwrap();
+
+        // The output is still in place, and no side file is left behind
+        assert_eq!(cache.vfs.read(&output).unwrap(), b"result");
+        assert!(
+            !cache
+                .vfs
+                .exists(&HexPath::try_from("out/test.txt.hexlink").unwrap())
+                .unwrap()
+        );
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        assert_eq!(cache.vfs.read(&output).unwrap(), b"result");
+
+        // The output shares the cached copy, which is read-only so that
+        // the output cannot be changed in place
+        assert_eq!(cache.vfs.mode(&output).unwrap(), 0o444);
+        let hash = BuildHash::hash_tree(&&output, cache.vfs.as_ref()).unwrap();
+        let cached_path = cache.output_path(&hash.0).unwrap();
+        assert_eq!(cache.vfs.mode(&cached_path).unwrap(), 0o444);
+
+        // A copy restored from the cache is writable again
+        cache.vfs.remove_file(&output).unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        assert_eq!(cache.vfs.mode(&output).unwrap(), 0o644);
+        assert_eq!(cache.vfs.mode(&cached_path).unwrap(), 0o444);
+    }
+
+    #[test]
+    fn test_output_times() {
+        let output = HexPath::try_from("out/test.txt").unwrap();
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![output.clone()];
+
+        // Restore an output that was cached earlier, and return its time
+        // and the time of the cached copy
+        let restore = |output_times: OutputTimes| {
+            let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+            let fake_vfs = unsafe {
+                &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem)
+            };
+            let options = CacheOptions {
+                output_times,
+                ..CacheOptions::default()
+            };
+            let cache = BuildCache::new(Arc::default(), vfs, options).unwrap();
+            cache.vfs.write(&output, b"result").unwrap();
+            cache.insert_outputs(&rule).unwrap();
+            let hash = BuildHash::hash_tree(&&output, cache.vfs.as_ref()).unwrap();
+            let cached_time = cache
+                .vfs
+                .modtime(&cache.output_path(&hash.0).unwrap())
+                .unwrap();
+
+            fake_vfs.advance_clock(100);
+            cache.vfs.write(&output, b"modified").unwrap();
+            assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+            (cache.vfs.modtime(&output).unwrap(), cached_time)
+        };
+
+        let (restored_time, cached_time) = restore(OutputTimes::Now);
+        assert!(restored_time > cached_time + 100);
+        let (restored_time, cached_time) = restore(OutputTimes::Preserve);
+        assert_eq!(restored_time, cached_time);
+        let (restored_time, _) = restore(OutputTimes::Fixed);
+        assert_eq!(Some(restored_time), OutputTimes::Fixed.time_for(0));
+    }
+
+    /// Make a gzipped tar file with the given files
+    fn make_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents) in files {
+            append_file(&mut builder, path, contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_import_archive() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+
+        let input = HexPath::try_from("test.txt").unwrap();
+        let output = HexPath::try_from("out/test.txt").unwrap();
+        let mut rule = HexRule::new("test".into());
+        rule.inputs = vec![input.clone()];
+        rule.outputs = vec![output.clone()];
+        cache.vfs.write(&input, b"test").unwrap();
+
+        let rule_hash = BuildHash::hash(&cache.env, &rule, cache.vfs.as_ref()).unwrap();
+        let inputmap_path = format!("inputmaps/{}", rule_hash.0);
+        let output_hash = BuildHash::hash_described_tree(|tree| {
+            tree.add_file(&output, b"result");
+            Ok(())
+        })
+        .unwrap();
+        let inputmap = format!("{}\n", output_hash.0);
+        let output_file = format!("outputs/{}", output_hash.0);
+        let archive = make_archive(&[
+            (&inputmap_path, inputmap.as_bytes()),
+            (&output_file, b"result"),
+        ]);
+
+        assert_eq!(
+            cache.import_archive(&archive[..]).unwrap(),
+            ImportCounts {
+                added: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        assert_eq!(cache.vfs.read(&output).unwrap(), b"result");
+
+        // Importing again changes nothing
+        assert_eq!(
+            cache.import_archive(&archive[..]).unwrap(),
+            ImportCounts {
+                added: 0,
+                skipped: 2
+            }
+        );
+
+        // Files outside of the cache layout are rejected
+        let archive = make_archive(&[("outputs/nested/file", b"")]);
+        assert_eq!(
+            cache.import_archive(&archive[..]).unwrap_err().to_string(),
+            "Unexpected entry `outputs/nested/file` in cache archive"
+        );
+    }
+
+    #[test]
+    fn test_import_tampered_archive() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+
+        // A file of a directory output is rejected right away, and not
+        // left in the cache
+        let good_hash = hash_contents(b"good").0;
+        let manifest = format!("file {good_hash} a.txt\n");
+        let blob = format!("outputs/{good_hash}");
+        let archive = make_archive(&[(&blob, b"tampered"), ("trees/ABCD", manifest.as_bytes())]);
+        assert_eq!(
+            cache.import_archive(&archive[..]).unwrap_err().to_string(),
+            format!("`outputs/{good_hash}` in cache archive does not match its hash")
+        );
+        assert!(
+            !cache
+                .vfs
+                .exists(&cache.output_path(&good_hash).unwrap())
+                .unwrap()
+        );
+
+        // A file output is stored under a hash of its path too, so it is
+        // only caught when it is restored, and then it is removed
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![path("out/test.txt")];
+        let rule_hash = BuildHash::hash(&cache.env, &rule, cache.vfs.as_ref()).unwrap();
+        let output_hash = BuildHash::hash_described_tree(|tree| {
+            tree.add_file("out/test.txt", b"result");
+            Ok(())
+        })
+        .unwrap();
+        let inputmap_path = format!("inputmaps/{}", rule_hash.0);
+        let inputmap = format!("{}\n", output_hash.0);
+        let output_file = format!("outputs/{}", output_hash.0);
+        let archive = make_archive(&[
+            (&inputmap_path, inputmap.as_bytes()),
+            (&output_file, b"tampered"),
+        ]);
+        cache.import_archive(&archive[..]).unwrap();
+        cache.vfs.write(&path("out/test.txt"), b"old").unwrap();
+        assert_eq!(
+            cache.retrieve_outputs(&rule).unwrap(),
+            CacheRetrieval::Evicted {
+                error: format!(".hex/cache/{output_file} does not match its hash")
+            }
+        );
+        assert_eq!(cache.vfs.read(&path("out/test.txt")).unwrap(), b"old");
+        assert!(
+            !cache
+                .vfs
+                .exists(&cache.output_path(&output_hash).unwrap())
+                .unwrap()
+        );
+
+        // Building the rule caches the right contents again
+        cache.vfs.write(&path("out/test.txt"), b"result").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        cache.vfs.remove_file(&path("out/test.txt")).unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        assert_eq!(cache.vfs.read(&path("out/test.txt")).unwrap(), b"result");
+    }
+
+    #[test]
+    fn test_import_archive_with_bad_inputmap() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let options = CacheOptions {
+            settings: CacheSettings {
+                max_size_mb: 0,
+                target_size_mb: 0,
+                ..CacheSettings::default()
+            },
+            ..CacheOptions::default()
+        };
+        let cache = BuildCache::new(Arc::default(), vfs, options).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+        let inputmap_path = |hash: &str| path(&format!(".hex/cache/inputmaps/{hash}"));
+
+        // An inputmap must list hashes, and is not written otherwise
+        let bad_hash = hash_contents(b"bad").0;
+        let bad_name = format!("inputmaps/{bad_hash}");
+        for contents in [
+            &b"../x\n"[..],
+            b"\xff\xfe",
+            bad_hash.to_ascii_lowercase().as_bytes(),
+        ] {
+            let archive = make_archive(&[(&bad_name, contents)]);
+            assert_eq!(
+                cache.import_archive(&archive[..]).unwrap_err().to_string(),
+                format!("`{bad_name}` in cache archive is not an inputmap")
+            );
+            assert!(!cache.vfs.exists(&inputmap_path(&bad_hash)).unwrap());
+        }
+
+        // An output that neither an inputmap nor a manifest names must be
+        // stored under the hash of its contents
+        let good_hash = hash_contents(b"good").0;
+        let archive = make_archive(&[(&format!("outputs/{good_hash}"), b"tampered")]);
+        assert_eq!(
+            cache.import_archive(&archive[..]).unwrap_err().to_string(),
+            format!("`outputs/{good_hash}` in cache archive does not match its hash")
+        );
+        assert!(
+            !cache
+                .vfs
+                .exists(&cache.output_path(&good_hash).unwrap())
+                .unwrap()
+        );
+
+        // Damaged inputmaps that are already in the cache, even pinned
+        // ones, are left out of an archive and removed by garbage
+        // collection
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![path("out/test.txt")];
+        cache.vfs.write(&path("out/test.txt"), b"result").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        let rule_hash = BuildHash::hash(&cache.env, &rule, cache.vfs.as_ref()).unwrap();
+        let unreadable_hash = hash_contents(b"unreadable").0;
+        cache
+            .vfs
+            .write(&inputmap_path(&bad_hash), b"../x\n")
+            .unwrap();
+        cache
+            .vfs
+            .write(&inputmap_path(&unreadable_hash), b"\xff\xfe")
+            .unwrap();
+        cache
+            .vfs
+            .write(
+                &path(".hex/cache/pinned"),
+                format!("{bad_hash} bad\n").as_bytes(),
+            )
+            .unwrap();
+        cache
+            .vfs
+            .write(
+                &path(".hex/cache/last_build"),
+                format!("{}\n{bad_hash}\n{unreadable_hash}\n", rule_hash.0).as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(cache.export_archive().unwrap().1, 1);
+
+        cache.maybe_gc().unwrap();
+        assert!(!cache.vfs.exists(&inputmap_path(&bad_hash)).unwrap());
+        assert!(!cache.vfs.exists(&inputmap_path(&unreadable_hash)).unwrap());
+    }
+
+    #[test]
+    fn test_export_archive() {
+        let new_cache = || {
+            let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+            let env = Arc::new(BuildEnvironment::default());
+            BuildCache::new(env, vfs, CacheOptions::default()).unwrap()
+        };
+        let output = HexPath::try_from("out/test.txt").unwrap();
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![output.clone()];
+        let mut other_rule = HexRule::new("other".into());
+        other_rule.outputs = vec![HexPath::try_from("out/other.txt").unwrap()];
+
+        // Only the entries of the last build are exported
+        let cache = new_cache();
+        assert_eq!(
+            cache.export_archive().unwrap_err().to_string(),
+            "There is no record of a build to push. Run a build first"
+        );
+        cache.vfs.write(&other_rule.outputs[0], b"other").unwrap();
+        cache.insert_outputs(&other_rule).unwrap();
+        cache.save_touched_entries().unwrap();
+
+        let cache = BuildCache {
+            touched_entries: Mutex::default(),
+            ..cache
+        };
+        cache.vfs.write(&output, b"result").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        cache.save_touched_entries().unwrap();
+        let (archive, entries) = cache.export_archive().unwrap();
+        assert_eq!(entries, 1);
+
+        let other_cache = new_cache();
+        assert_eq!(
+            other_cache.import_archive(&archive[..]).unwrap(),
+            ImportCounts {
+                added: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(
+            other_cache.retrieve_outputs(&rule).unwrap(),
+            CacheRetrieval::Hit
+        );
+        assert_eq!(other_cache.vfs.read(&output).unwrap(), b"result");
+        assert_eq!(
+            other_cache.retrieve_outputs(&other_rule).unwrap(),
+            CacheRetrieval::Miss
+        );
+    }
+
     #[test]
     fn test_gc_does_nothing_when_under_limit() {
         let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
         let fake_vfs =
             unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
 
-        let env = Arc::new(BTreeMap::new());
-        let cache = BuildCache::new(env, vfs).unwrap();
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
 
         // Create some small files (total well under 200 MB)
         fake_vfs
@@ -293,8 +2072,8 @@ mod tests {
         let fake_vfs =
             unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
 
-        let env = Arc::new(BTreeMap::new());
-        let cache = BuildCache::new(env, vfs).unwrap();
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
 
         // Create files totaling over 200 MB (will trigger GC)
         // These will have different modification times due to the fake clock
@@ -356,8 +2135,8 @@ mod tests {
         let fake_vfs =
             unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
 
-        let env = Arc::new(BTreeMap::new());
-        let cache = BuildCache::new(env, vfs).unwrap();
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
 
         // Create output files
         fake_vfs
@@ -423,8 +2202,8 @@ mod tests {
         let fake_vfs =
             unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
 
-        let env = Arc::new(BTreeMap::new());
-        let cache = BuildCache::new(env, vfs).unwrap();
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
 
         // Create large output files to trigger GC (over 200 MB total)
         fake_vfs
@@ -484,14 +2263,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_gc_removes_stale_side_files() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+
+        // Side files from writes that were cut off an hour ago are removed,
+        // wherever they are, but a recent one may still be renamed
+        let stale = [
+            ".hex/cache/outputs/ABCD.100-0.tmp",
+            ".hex/cache/inputmaps/ABCD.100-1.tmp",
+            ".hex/cache/last_build.100-2.tmp",
+        ];
+        for stale in stale {
+            cache.vfs.write(&path(stale), b"partial").unwrap();
+        }
+        fake_vfs.advance_clock(STALE_SIDE_FILE_SECONDS);
+        let recent = path(".hex/cache/outputs/ABCD.200-0.tmp");
+        cache.vfs.write(&recent, b"partial").unwrap();
+
+        cache.maybe_gc().unwrap();
+        for stale in stale {
+            assert!(!cache.vfs.exists(&path(stale)).unwrap());
+        }
+        assert!(cache.vfs.exists(&recent).unwrap());
+    }
+
     #[test]
     fn test_gc_deletes_unreferenced_outputs() {
         let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
         let fake_vfs =
             unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
 
-        let env = Arc::new(BTreeMap::new());
-        let cache = BuildCache::new(env, vfs).unwrap();
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
 
         // Create output files that total over 200 MB to trigger GC
         fake_vfs
@@ -571,4 +2380,449 @@ mod tests {
                 .unwrap()
         );
     }
+
+    /// Add a cache entry for each name, each with one output of the same
+    /// name. The entries are written one day apart, in order.
+    fn write_daily_entries(fake_vfs: &FakeFileSystem, names: &[&str]) {
+        for name in names {
+            fake_vfs
+                .write(
+                    &HexPath::try_from(format!(".hex/cache/outputs/{name}")).unwrap(),
+                    b"data",
+                )
+                .unwrap();
+            fake_vfs
+                .write(
+                    &HexPath::try_from(format!(".hex/cache/inputmaps/{name}")).unwrap(),
+                    format!("{name}\n").as_bytes(),
+                )
+                .unwrap();
+            fake_vfs.advance_clock(24 * 60 * 60);
+        }
+    }
+
+    #[test]
+    fn test_gc_deletes_entries_older_than_max_age() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+
+        let options = CacheOptions {
+            settings: CacheSettings {
+                max_age_days: Some(2),
+                ..CacheSettings::default()
+            },
+            ..CacheOptions::default()
+        };
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+        write_daily_entries(fake_vfs, &["old", "recent", "new"]);
+
+        // Using an entry makes it recent again
+        fake_vfs
+            .touch(&HexPath::try_from(".hex/cache/inputmaps/old").unwrap())
+            .unwrap();
+
+        cache.maybe_gc().unwrap();
+
+        let exists = |path: &str| cache.vfs.exists(&HexPath::try_from(path).unwrap()).unwrap();
+        assert!(exists(".hex/cache/inputmaps/old"));
+        assert!(exists(".hex/cache/outputs/old"));
+        assert!(!exists(".hex/cache/inputmaps/recent"));
+        assert!(!exists(".hex/cache/outputs/recent"));
+        assert!(exists(".hex/cache/inputmaps/new"));
+        assert!(exists(".hex/cache/outputs/new"));
+    }
+
+    #[test]
+    fn test_gc_deletes_entries_beyond_max_entries() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+
+        let options = CacheOptions {
+            settings: CacheSettings {
+                max_entries: Some(2),
+                ..CacheSettings::default()
+            },
+            ..CacheOptions::default()
+        };
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+        write_daily_entries(fake_vfs, &["first", "second", "third"]);
+
+        cache.maybe_gc().unwrap();
+
+        let exists = |path: &str| cache.vfs.exists(&HexPath::try_from(path).unwrap()).unwrap();
+        assert!(!exists(".hex/cache/inputmaps/first"));
+        assert!(!exists(".hex/cache/outputs/first"));
+        assert!(exists(".hex/cache/inputmaps/second"));
+        assert!(exists(".hex/cache/inputmaps/third"));
+        assert!(exists(".hex/cache/outputs/third"));
+    }
+
+    #[test]
+    fn test_pinned_entries_survive_gc() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let options = CacheOptions {
+            settings: CacheSettings {
+                max_entries: Some(0),
+                ..CacheSettings::default()
+            },
+            ..CacheOptions::default()
+        };
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+
+        let mut rule = HexRule::new("bootstrap".into());
+        rule.outputs = vec![HexPath::try_from("out/compiler").unwrap()];
+        rule.inputs = vec![HexPath::try_from("compiler.c").unwrap()];
+
+        // There is nothing to pin before the rule has been built
+        cache
+            .vfs
+            .write(&HexPath::try_from("compiler.c").unwrap(), b"source")
+            .unwrap();
+        assert!(!cache.pin_entry(&rule).unwrap());
+
+        cache
+            .vfs
+            .write(&HexPath::try_from("out/compiler").unwrap(), b"binary")
+            .unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        assert!(cache.pin_entry(&rule).unwrap());
+
+        // A pinned entry is kept even though no entries are allowed
+        cache.maybe_gc().unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+
+        // Once unpinned, it is removed
+        assert_eq!(cache.unpin_entries("bootstrap").unwrap(), 1);
+        assert_eq!(cache.unpin_entries("bootstrap").unwrap(), 0);
+        cache.maybe_gc().unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Miss);
+    }
+
+    #[test]
+    fn test_gc_deletes_expired_entries() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+
+        let options = CacheOptions {
+            settings: CacheSettings {
+                ttl_hours: Some(36),
+                ..CacheSettings::default()
+            },
+            ..CacheOptions::default()
+        };
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+        write_daily_entries(fake_vfs, &["old", "new"]);
+
+        // Using an entry does not extend its life
+        fake_vfs
+            .touch(&HexPath::try_from(".hex/cache/inputmaps/old").unwrap())
+            .unwrap();
+
+        let exists = |path: &str| cache.vfs.exists(&HexPath::try_from(path).unwrap()).unwrap();
+        cache.maybe_gc().unwrap();
+        assert!(!exists(".hex/cache/inputmaps/old"));
+        assert!(!exists(".hex/cache/outputs/old"));
+        assert!(exists(".hex/cache/inputmaps/new"));
+        assert!(exists(".hex/cache/outputs/new"));
+
+        // An expired entry is not used even before it is collected
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![HexPath::try_from("out/test.txt").unwrap()];
+        cache
+            .vfs
+            .write(&HexPath::try_from("out/test.txt").unwrap(), b"result")
+            .unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        assert_eq!(
+            cache.probe(&rule).unwrap(),
+            CacheProbe::Hit {
+                outputs_current: true
+            }
+        );
+        fake_vfs.advance_clock(36 * 60 * 60);
+        assert_eq!(cache.probe(&rule).unwrap(), CacheProbe::Miss);
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Miss);
+    }
+
+    #[test]
+    fn test_tree_outputs() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+
+        let mut rule = HexRule::new("unpack".into());
+        rule.outputs = vec![path("out/tree"), path("out/count.txt")];
+        for (file, contents) in [
+            ("out/tree/a.txt", "same"),
+            ("out/tree/sub/b.txt", "same"),
+            ("out/tree/sub/c.txt", "other"),
+            ("out/count.txt", "3"),
+        ] {
+            cache.vfs.write(&path(file), contents.as_bytes()).unwrap();
+        }
+        let tree_hash = BuildHash::hash_tree(&&path("out/tree"), cache.vfs.as_ref()).unwrap();
+        cache.insert_outputs(&rule).unwrap();
+
+        // Files with the same contents are stored once
+        let outputs = cache.vfs.tree_walk(&path(".hex/cache/outputs")).unwrap();
+        assert_eq!(outputs.len(), 3);
+
+        // A stale file in the tree is replaced by the cached tree
+        cache.vfs.remove_dir_all(&path("out")).unwrap();
+        cache
+            .vfs
+            .write(&path("out/tree/stale.txt"), b"stale")
+            .unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        assert_eq!(
+            cache.vfs.tree_walk(&path("out/tree")).unwrap(),
+            vec![
+                path("out/tree/a.txt"),
+                path("out/tree/sub/b.txt"),
+                path("out/tree/sub/c.txt")
+            ]
+        );
+        assert_eq!(
+            cache.vfs.read(&path("out/tree/sub/c.txt")).unwrap(),
+            b"other"
+        );
+        assert_eq!(cache.vfs.read(&path("out/count.txt")).unwrap(), b"3");
+        assert_eq!(
+            BuildHash::hash_tree(&&path("out/tree"), cache.vfs.as_ref()).unwrap(),
+            tree_hash
+        );
+
+        // Losing a file of the tree invalidates the entry, and the manifest
+        // is collected along with it
+        cache
+            .vfs
+            .remove_file(&cache.output_path(&hash_contents(b"other").0).unwrap())
+            .unwrap();
+        let remaining_outputs = cache
+            .vfs
+            .tree_walk(&path(".hex/cache/outputs"))
+            .unwrap()
+            .into_iter()
+            .collect();
+        let referenced = cache
+            .cleanup_orphaned_inputmaps(&remaining_outputs)
+            .unwrap()
+            .unwrap();
+        cache.cleanup_orphaned_trees(&referenced).unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Miss);
+        assert!(
+            !cache
+                .vfs
+                .exists(&cache.tree_path(&tree_hash.0).unwrap())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_retrieve_outputs_evicts_unreadable_entries() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+
+        let mut rule = HexRule::new("pair".into());
+        rule.outputs = vec![path("out/a.txt"), path("out/b.txt")];
+        cache.vfs.write(&path("out/a.txt"), b"new a").unwrap();
+        cache.vfs.write(&path("out/b.txt"), b"new b").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+
+        // Lose the cached copy of the second output
+        let b_hash = BuildHash::hash_tree(&&path("out/b.txt"), cache.vfs.as_ref()).unwrap();
+        cache
+            .vfs
+            .remove_file(&cache.output_path(&b_hash.0).unwrap())
+            .unwrap();
+
+        // The retrieval fails without touching either output, and the
+        // entry is removed so that the rule is built and cached again
+        cache.vfs.write(&path("out/a.txt"), b"old a").unwrap();
+        cache.vfs.write(&path("out/b.txt"), b"old b").unwrap();
+        assert_eq!(
+            cache.retrieve_outputs(&rule).unwrap(),
+            CacheRetrieval::Evicted {
+                error: format!("File not found: .hex/cache/outputs/{}", b_hash.0)
+            }
+        );
+        assert_eq!(cache.vfs.read(&path("out/a.txt")).unwrap(), b"old a");
+        assert_eq!(cache.vfs.read(&path("out/b.txt")).unwrap(), b"old b");
+        assert_eq!(
+            cache.vfs.tree_walk(&path("out")).unwrap(),
+            vec![path("out/a.txt"), path("out/b.txt")]
+        );
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Miss);
+
+        // A damaged inputmap is removed in the same way
+        cache.insert_outputs(&rule).unwrap();
+        let rule_hash = BuildHash::hash(&cache.env, &rule, cache.vfs.as_ref()).unwrap();
+        let inputmap_path = path(&format!(".hex/cache/inputmaps/{}", rule_hash.0));
+        cache.vfs.write(&inputmap_path, b"\xff\xfe").unwrap();
+        assert_eq!(
+            cache.retrieve_outputs(&rule).unwrap(),
+            CacheRetrieval::Evicted {
+                error: format!("{inputmap_path} is not valid text")
+            }
+        );
+        assert!(!cache.vfs.exists(&inputmap_path).unwrap());
+
+        // So is an entry whose tree manifest is damaged. A rule that uses
+        // the tree is not found when it is looked up either.
+        let mut rule = HexRule::new("tree".into());
+        rule.outputs = vec![path("out/tree")];
+        cache.vfs.write(&path("out/tree/c.txt"), b"c").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+        let tree_hash = BuildHash::hash_tree(&&path("out/tree"), cache.vfs.as_ref()).unwrap();
+        let tree_path = cache.tree_path(&tree_hash).unwrap();
+        cache.vfs.write(&tree_path, b"\xff\xfe").unwrap();
+        cache.vfs.remove_dir_all(&path("out/tree")).unwrap();
+        let mut user = HexRule::new("user".into());
+        user.inputs = vec![path("out/tree")];
+        let cached_inputs = BTreeMap::from([(path("out/tree"), tree_hash.0.clone())]);
+        assert_eq!(cache.lookup_outputs(&user, &cached_inputs).unwrap(), None);
+        assert_eq!(
+            cache.retrieve_outputs(&rule).unwrap(),
+            CacheRetrieval::Evicted {
+                error: format!("{tree_path} is not valid text")
+            }
+        );
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Miss);
+    }
+
+    #[test]
+    fn test_shared_permissions() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+        let env = Arc::new(BuildEnvironment::default());
+        let mut options = CacheOptions::default();
+        options.settings.permissions = CachePermissions {
+            group: Some("4242".to_string()),
+            mode: Some("660".to_string()),
+        };
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+
+        let mut rule = HexRule::new("tool".into());
+        rule.outputs = vec![path("out/run.sh"), path("out/data.txt")];
+        cache.vfs.write(&path("out/run.sh"), b"echo hi").unwrap();
+        cache
+            .vfs
+            .set_permissions(&path("out/run.sh"), 0o755, None)
+            .unwrap();
+        cache.vfs.write(&path("out/data.txt"), b"data").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+
+        // Every cache file has the group and mode, and executable files
+        // stay executable
+        let cached = |output: &str| {
+            let hash = BuildHash::hash_tree(&&path(output), cache.vfs.as_ref()).unwrap();
+            cache.output_path(&hash.0).unwrap()
+        };
+        assert_eq!(cache.vfs.mode(&cached("out/run.sh")).unwrap(), 0o770);
+        assert_eq!(cache.vfs.mode(&cached("out/data.txt")).unwrap(), 0o660);
+        let rule_hash = BuildHash::hash(&cache.env, &rule, cache.vfs.as_ref()).unwrap();
+        let inputmap_path = path(&format!(".hex/cache/inputmaps/{}", rule_hash.0));
+        assert_eq!(cache.vfs.mode(&inputmap_path).unwrap(), 0o660);
+        assert_eq!(fake_vfs.group(&inputmap_path).unwrap(), Some(4242));
+
+        // No side files are left behind
+        assert!(
+            cache
+                .vfs
+                .tree_walk(&path(".hex/cache"))
+                .unwrap()
+                .iter()
+                .all(|file| !is_side_file(file))
+        );
+    }
+
+    #[test]
+    fn test_permission_denied_entries() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+        let env = Arc::new(BuildEnvironment::default());
+        let mut options = CacheOptions::default();
+        options.settings.max_size_mb = 0;
+        options.settings.target_size_mb = 0;
+        let cache = BuildCache::new(env, vfs, options).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+
+        let mut rule = HexRule::new("test".into());
+        rule.inputs = vec![path("test.txt")];
+        rule.outputs = vec![path("out/test.txt")];
+        cache.vfs.write(&path("test.txt"), b"test").unwrap();
+        cache.vfs.write(&path("out/test.txt"), b"result").unwrap();
+        cache.insert_outputs(&rule).unwrap();
+
+        // An entry whose output cannot be read is left in place for the
+        // users who can read it
+        let output_hash = BuildHash::hash_tree(&&path("out/test.txt"), cache.vfs.as_ref()).unwrap();
+        let cached_output = cache.output_path(&output_hash.0).unwrap();
+        fake_vfs.deny_access(&cached_output);
+        cache.vfs.write(&path("out/test.txt"), b"old").unwrap();
+        assert_eq!(
+            cache.retrieve_outputs(&rule).unwrap(),
+            CacheRetrieval::Denied {
+                error: format!("Permission denied: {cached_output}")
+            }
+        );
+        assert_eq!(cache.vfs.read(&path("out/test.txt")).unwrap(), b"old");
+
+        // An inputmap that cannot be read is a miss
+        let rule_hash = BuildHash::hash(&cache.env, &rule, cache.vfs.as_ref()).unwrap();
+        let inputmap_path = path(&format!(".hex/cache/inputmaps/{}", rule_hash.0));
+        fake_vfs.deny_access(&inputmap_path);
+        assert_eq!(cache.probe(&rule).unwrap(), CacheProbe::Miss);
+        assert_eq!(cache.lookup_outputs(&rule, &BTreeMap::new()).unwrap(), None);
+        cache.touch_entry(&rule_hash).unwrap();
+
+        // Garbage collection skips the files that it may not remove, and
+        // keeps every output while an inputmap cannot be read
+        cache.maybe_gc().unwrap();
+        assert!(cache.vfs.exists(&inputmap_path).unwrap());
+        assert!(cache.vfs.exists(&cached_output).unwrap());
+    }
+
+    #[test]
+    fn test_retrieve_many_outputs() {
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let env = Arc::new(BuildEnvironment::default());
+        let cache = BuildCache::new(env, vfs, CacheOptions::default()).unwrap();
+        let path = |path: &str| HexPath::try_from(path).unwrap();
+
+        // More outputs than there are threads to copy them
+        let mut rule = HexRule::new("many".into());
+        for index in 0..10 {
+            let output = path(&format!("out/part{index}.bin"));
+            cache
+                .vfs
+                .write(&output, format!("part {index}").as_bytes())
+                .unwrap();
+            rule.outputs.push(output);
+        }
+        cache.insert_outputs(&rule).unwrap();
+
+        cache.vfs.remove_dir_all(&path("out")).unwrap();
+        assert_eq!(cache.retrieve_outputs(&rule).unwrap(), CacheRetrieval::Hit);
+        for (index, output) in rule.outputs.iter().enumerate() {
+            assert_eq!(
+                cache.vfs.read(output).unwrap(),
+                format!("part {index}").as_bytes()
+            );
+        }
+        assert_eq!(cache.vfs.tree_walk(&path("out")).unwrap().len(), 10);
+    }
 }
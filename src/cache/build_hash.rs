@@ -2,13 +2,21 @@ use std::collections::BTreeMap;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::io;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, available_parallelism};
 
-use ring::digest::{Context, Digest, SHA256};
+use ring::digest::{Context, Digest, SHA256, digest};
+use serde::{Deserialize, Serialize};
 
 use crate::ast::hex_path::HexPath;
-use crate::ast::hexmake_file::HexRule;
-use crate::file_system::vfs::VirtualFileSystem;
+use crate::ast::hexmake_file::{HexRule, RuleCommand, RuleKind};
+use crate::environment::BuildEnvironment;
+use crate::file_system::vfs::{VirtualFileSystem, WalkEntry};
+
+/// How many files of a directory input each thread that hashes it should
+/// have, at least. Smaller directories are hashed on one thread, because
+/// starting threads would take longer than the hashing.
+const FILES_PER_HASH_THREAD: usize = 64;
 
 /// A hash of a build rule and its inputs. This is the key
 /// for the build cache.
@@ -37,30 +45,154 @@ impl Deref for BuildHash {
 impl BuildHash {
     /// Construct a build hash from the given rule and filesystem state
     pub fn hash(
-        env: &BTreeMap<Arc<String>, Arc<String>>,
+        env: &BuildEnvironment,
         rule: &HexRule,
         vfs: &dyn VirtualFileSystem,
     ) -> Result<BuildHash, io::Error> {
+        BuildHash::hash_with_inputs(env, rule, |input, tree| tree.add_from_vfs(input, vfs))
+    }
+
+    /// Construct a build hash from the given rule, with the contents of
+    /// each input added by `hash_input` rather than read from the file
+    /// system. This lets a rule be hashed with inputs that have not been
+    /// written to the workspace yet.
+    pub fn hash_with_inputs(
+        env: &BuildEnvironment,
+        rule: &HexRule,
+        hash_input: impl FnMut(&HexPath, &mut TreeHasher) -> Result<(), io::Error>,
+    ) -> Result<BuildHash, io::Error> {
+        Ok(BuildHash::hash_with_breakdown(env, rule, hash_input)?.0)
+    }
+
+    /// Like [BuildHash::hash_with_inputs], but also return the parts that
+    /// went into the hash. Each input is hashed by itself, the way
+    /// [BuildHash::hash_tree] hashes it, and only its hash goes into the
+    /// rule's hash, so that the breakdown can say which inputs differ.
+    pub fn hash_with_breakdown(
+        env: &BuildEnvironment,
+        rule: &HexRule,
+        mut hash_input: impl FnMut(&HexPath, &mut TreeHasher) -> Result<(), io::Error>,
+    ) -> Result<(BuildHash, HashBreakdown), io::Error> {
         let mut context = Context::new(&SHA256);
 
         hash_rule(&mut context, rule);
         hash_env(&mut context, env);
-        hash_trees(&mut context, &rule.inputs, vfs)?;
+        hash_usize(&mut context, rule.inputs.len());
+        let mut input_hashes = BTreeMap::new();
+        for input in &rule.inputs {
+            let mut input_context = Context::new(&SHA256);
+            hash_input(input, &mut TreeHasher(&mut input_context))?;
+            let input_digest = input_context.finish();
+            hash_bytes(&mut context, input_digest.as_ref());
+            input_hashes.insert(input.to_string(), hex_string_for_digest(input_digest));
+        }
 
         let digest = context.finish();
 
-        Ok(BuildHash(hex_string_for_digest(digest)))
+        Ok((
+            BuildHash(hex_string_for_digest(digest)),
+            HashBreakdown::new(env, rule, input_hashes),
+        ))
     }
 
     /// Hash a file tree by itself
     pub fn hash_tree(path: &&HexPath, vfs: &dyn VirtualFileSystem) -> Result<BuildHash, io::Error> {
+        BuildHash::hash_described_tree(|tree| tree.add_from_vfs(path, vfs))
+    }
+
+    /// Hash a file tree by itself, like [BuildHash::hash_tree], with its
+    /// files and directories added by `describe` rather than read from
+    /// the file system
+    pub fn hash_described_tree(
+        describe: impl FnOnce(&mut TreeHasher) -> Result<(), io::Error>,
+    ) -> Result<BuildHash, io::Error> {
         let mut context = Context::new(&SHA256);
-        hash_tree(&mut context, path, vfs)?;
+        describe(&mut TreeHasher(&mut context))?;
         let digest = context.finish();
         Ok(BuildHash(hex_string_for_digest(digest)))
     }
 }
 
+/// The parts that make up the build hash of a rule. Hexmake saves the
+/// breakdown of each rule that a build hashes, so that `hexmake
+/// why-changed` can say what made a rule's hash differ from that build.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HashBreakdown {
+    /// The hash of everything in the rule's definition, including its
+    /// commands, outputs, and settings such as `url` or `content`
+    pub definition: String,
+
+    /// The rule's commands, as the shell would need them written
+    pub commands: Vec<String>,
+
+    pub outputs: Vec<String>,
+
+    /// The hash of each tool that the commands refer to
+    pub tools: BTreeMap<String, String>,
+
+    /// The variables passed through from `env`, with their values
+    pub env: BTreeMap<String, String>,
+
+    /// The names of the secret variables that are set
+    pub secrets: Vec<String>,
+
+    /// The hash of each input, as [BuildHash::hash_tree] computes it
+    pub inputs: BTreeMap<String, String>,
+}
+
+impl HashBreakdown {
+    /// The breakdown of a rule's hash, given the hashes of its inputs
+    pub fn new(
+        env: &BuildEnvironment,
+        rule: &HexRule,
+        inputs: BTreeMap<String, String>,
+    ) -> HashBreakdown {
+        let mut context = Context::new(&SHA256);
+        hash_rule(&mut context, rule);
+        HashBreakdown {
+            definition: hex_string_for_digest(context.finish()),
+            commands: rule
+                .hashed_commands()
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            outputs: rule.outputs.iter().map(|o| o.to_string()).collect(),
+            tools: rule
+                .tool_hashes
+                .iter()
+                .map(|(name, sha256)| (name.to_string(), sha256.clone()))
+                .collect(),
+            env: env
+                .vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            secrets: env.secrets.keys().map(|name| name.to_string()).collect(),
+            inputs,
+        }
+    }
+}
+
+/// Hash the contents of a file, without its path. The files inside a
+/// cached directory tree are stored under these hashes.
+pub fn hash_contents(contents: &[u8]) -> BuildHash {
+    BuildHash(hex_string_for_digest(ring::digest::digest(
+        &SHA256, contents,
+    )))
+}
+
+/// Whether some text has the form of a [BuildHash]: 64 uppercase hex
+/// digits
+pub fn is_build_hash(text: &str) -> bool {
+    text.len() == 64 && text.chars().all(|c| matches!(c, '0'..='9' | 'A'..='F'))
+}
+
+/// Compute the SHA-256 hash of some bytes, as a lowercase hex string
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    let digest = ring::digest::digest(&SHA256, bytes);
+    hex_string_for_digest(digest).to_ascii_lowercase()
+}
+
 /// Convert the result of hashing into a hex string
 fn hex_string_for_digest(digest: Digest) -> String {
     let mut hex_digest = String::new();
@@ -83,20 +215,72 @@ fn hash_rule(context: &mut Context, rule: &HexRule) {
         hash_string(context, input);
     }
 
-    hash_usize(context, rule.commands.len());
-    for command in &rule.commands {
-        hash_string(context, command);
+    let commands = rule.hashed_commands();
+    hash_usize(context, commands.len());
+    for command in commands {
+        match command {
+            RuleCommand::Shell(command) => hash_string(context, command),
+            RuleCommand::Argv(args) => {
+                // No string is this long, so this cannot be mistaken
+                // for a shell command
+                hash_u64(context, u64::MAX);
+                hash_usize(context, args.len());
+                for arg in args {
+                    hash_string(context, arg);
+                }
+            }
+        }
+    }
+
+    if let Some(stdout_output) = &rule.stdout_output {
+        hash_string(context, "stdout_output");
+        hash_string(context, stdout_output);
+    }
+
+    if rule.kind != RuleKind::Command {
+        hash_string(context, rule.kind.name());
+        hash_optional_string(context, &rule.url);
+        hash_optional_string(context, &rule.sha256);
+        hash_usize(context, rule.strip_components);
+        hash_optional_string(context, &rule.content);
+        hash_usize(context, rule.substitutions.len());
+        for (name, value) in &rule.substitutions {
+            hash_string(context, name);
+            hash_string(context, value);
+        }
+    }
+
+    // The volatile status file is left out on purpose, but whether the
+    // rule reads it is not
+    if rule.stamp {
+        hash_string(context, "stamp");
+    }
+
+    if !rule.tool_hashes.is_empty() {
+        hash_usize(context, rule.tool_hashes.len());
+        for (name, sha256) in &rule.tool_hashes {
+            hash_string(context, name);
+            hash_string(context, sha256);
+        }
     }
 }
 
 /// Hash the environment variables. This will encode the number of variables
-/// followed by the name and value of each variable.
-fn hash_env(context: &mut Context, env: &BTreeMap<Arc<String>, Arc<String>>) {
-    hash_usize(context, env.len());
-    for (name, value) in env {
+/// followed by the name and value of each variable. If any secrets are set,
+/// their number and names follow, but not their values.
+fn hash_env(context: &mut Context, env: &BuildEnvironment) {
+    hash_usize(context, env.vars.len());
+    for (name, value) in &env.vars {
         hash_string(context, name);
         hash_string(context, value);
     }
+
+    if !env.secrets.is_empty() {
+        hash_usize(context, env.secrets.len());
+        for name in env.secrets.keys() {
+            hash_string(context, name);
+        }
+    }
 }
 
 // Add a 64-bit integer to a hash
@@ -115,51 +299,119 @@ fn hash_string(context: &mut Context, value: &str) {
     hash_bytes(context, value.as_bytes());
 }
 
+// Add an optional string to a hash. This will encode 0 for None, or 1
+// followed by the string.
+fn hash_optional_string(context: &mut Context, value: &Option<String>) {
+    match value {
+        None => hash_usize(context, 0),
+        Some(value) => {
+            hash_usize(context, 1);
+            hash_string(context, value);
+        }
+    }
+}
+
 // Add bytes to a hash. This will prefix the bytes by the number of bytes.
 fn hash_bytes(context: &mut Context, value: &[u8]) {
     hash_usize(context, value.len());
     context.update(value);
 }
 
-/// Hash a list of filesystem trees
-fn hash_trees(
-    context: &mut Context,
-    paths: &[HexPath],
-    vfs: &dyn VirtualFileSystem,
-) -> Result<(), io::Error> {
-    hash_usize(context, paths.len());
-    for path in paths {
-        hash_tree(context, path, vfs)?;
+/// Adds the entries of one file tree to a hash. The entries must be
+/// added in the order of their paths, the way the file system walks them.
+pub struct TreeHasher<'a>(&'a mut Context);
+
+impl TreeHasher<'_> {
+    /// Add a file and its contents
+    pub fn add_file(&mut self, path: &str, contents: &[u8]) {
+        self.add_file_digest(path, digest(&SHA256, contents));
+    }
+
+    /// Add a file whose contents have already been hashed. Only the
+    /// digest of each file goes into the tree's hash, so that the files
+    /// can be hashed separately and then combined in the order of their
+    /// paths.
+    fn add_file_digest(&mut self, path: &str, contents_digest: Digest) {
+        hash_string(self.0, path);
+        // Use 0 to mean the path is a file
+        hash_usize(self.0, 0);
+        hash_bytes(self.0, contents_digest.as_ref());
+    }
+
+    /// Add a directory
+    pub fn add_dir(&mut self, path: &str) {
+        hash_string(self.0, path);
+        // Use 1 for a directory
+        hash_usize(self.0, 1);
+    }
+
+    /// Add a filesystem tree.
+    /// This will handle both files and directory trees.
+    /// It will return an error, though, if the tree doesn't exist at all.
+    pub fn add_from_vfs(
+        &mut self,
+        path: &HexPath,
+        vfs: &dyn VirtualFileSystem,
+    ) -> Result<(), io::Error> {
+        if !vfs.exists(path)? {
+            return Err(io::Error::other(format!("{path} does not exist")));
+        }
+
+        let entries = vfs.tree_walk_metadata(path)?;
+        let digests = hash_files(&entries, vfs)?;
+        for (entry, contents_digest) in entries.iter().zip(digests) {
+            match contents_digest {
+                Some(contents_digest) => self.add_file_digest(&entry.path, contents_digest),
+                None => self.add_dir(&entry.path),
+            }
+        }
+
+        Ok(())
     }
-    Ok(())
 }
 
-/// Hash a filesystem tree.
-/// This will handle both files and directory trees.
-/// It will return an error, though, if the tree doesn't exist at all.
-fn hash_tree(
-    context: &mut Context,
-    path: &HexPath,
+/// Hash the contents of the files among some walked entries. A large
+/// directory is hashed on several threads, each of which repeatedly
+/// takes the next file that nobody has started on yet. The result has
+/// the digest of each file, or None for a directory, in the same order
+/// as the entries.
+fn hash_files(
+    entries: &[WalkEntry],
     vfs: &dyn VirtualFileSystem,
-) -> Result<(), io::Error> {
-    if !vfs.exists(path)? {
-        return Err(io::Error::other(format!("{path} does not exist")));
-    }
-
-    for entry_path in vfs.tree_walk(path)? {
-        hash_string(context, &entry_path);
-        if vfs.is_file(&entry_path)? {
-            // Use 0 to mean the path is a file
-            hash_usize(context, 0);
-            let contents = vfs.read(&entry_path)?;
-            hash_bytes(context, &contents);
-        } else {
-            // Use 1 for a directory
-            hash_usize(context, 1);
+) -> Result<Vec<Option<Digest>>, io::Error> {
+    let file_count = entries.iter().filter(|entry| entry.is_file).count();
+    let threads = available_parallelism()
+        .map_or(1, |cpus| cpus.get())
+        .min(file_count / FILES_PER_HASH_THREAD)
+        .max(1);
+
+    let next = AtomicUsize::new(0);
+    let hash_some = || -> Result<Vec<(usize, Digest)>, io::Error> {
+        let mut digests = Vec::new();
+        loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(entry) = entries.get(index) else {
+                return Ok(digests);
+            };
+            if entry.is_file {
+                digests.push((index, digest(&SHA256, &vfs.read(&entry.path)?)));
+            }
         }
-    }
+    };
+    let digests = thread::scope(|scope| {
+        let helpers: Vec<_> = (1..threads).map(|_| scope.spawn(hash_some)).collect();
+        let mut digests = hash_some()?;
+        for helper in helpers {
+            digests.extend(helper.join().unwrap()?);
+        }
+        Ok::<_, io::Error>(digests)
+    })?;
 
-    Ok(())
+    let mut result = vec![None; entries.len()];
+    for (index, contents_digest) in digests {
+        result[index] = Some(contents_digest);
+    }
+    Ok(result)
 }
 
 #[cfg(test)]
@@ -181,9 +433,11 @@ mod tests {
         rule.inputs = vec![HexPath::try_from("test.txt").unwrap()];
         rule.commands = vec!["cp test.txt out/text.txt".into()];
 
-        let mut env: BTreeMap<Arc<String>, Arc<String>> = BTreeMap::new();
-        env.insert("ENV1".to_string().into(), "env1".to_string().into());
-        env.insert("ENV2".to_string().into(), "env2".to_string().into());
+        let mut env = BuildEnvironment::default();
+        env.vars
+            .insert("ENV1".to_string().into(), "env1".to_string().into());
+        env.vars
+            .insert("ENV2".to_string().into(), "env2".to_string().into());
 
         vfs.write(&HexPath::try_from("test.txt").unwrap(), b"test")
             .unwrap();
@@ -197,7 +451,7 @@ mod tests {
         // A hash should be a hex string (this specific value depends on the VFS implementation)
         assert_eq!(
             &base_hash.0,
-            "6EB9CD32A5CB18E0D77E012C8958F924B1DA9A441A19736EF623B6582C73FCA8"
+            "3A6D7194847748CC2DC921EC5E3ABC511878BC5E056CB343B7B41798C362116C"
         );
 
         // Hashing twice gives back the same value
@@ -226,12 +480,68 @@ mod tests {
             rule.commands = vec!["/usr/bin/cp test.txt out/text.txt".into()];
             let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
             test_hashes.push(hash);
+
+            // Running the same words without a shell is a different command
+            rule.commands = vec![RuleCommand::Argv(
+                ["cp", "test.txt", "out/text.txt"].map(String::from).into(),
+            )];
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash);
+        }
+
+        // Switching the version of a tool will affect the hash
+        {
+            let mut rule = rule.clone();
+            rule.tool_hashes
+                .insert("cp".to_string().into(), "1234".to_string());
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash);
+
+            rule.tool_hashes
+                .insert("cp".to_string().into(), "5678".to_string());
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash);
+
+            // But where the tool is does not
+            rule.portable_commands = Some(vec!["$TOOL{cp} test.txt out/test.txt".into()]);
+            rule.commands = vec!["/home/a/ws/.hex/tools/5678/cp test.txt out/test.txt".into()];
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            rule.commands = vec!["/home/b/ws/.hex/tools/5678/cp test.txt out/test.txt".into()];
+            assert_eq!(BuildHash::hash(&env, &rule, &*vfs).unwrap(), hash);
+            test_hashes.push(hash);
+        }
+
+        // Capturing standard output will affect the hash
+        {
+            let mut rule = rule.clone();
+            rule.stdout_output = Some(HexPath::try_from("out/test.txt").unwrap());
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash);
+        }
+
+        // Built-in rules hash their own fields
+        {
+            let mut rule = rule.clone();
+            rule.kind = RuleKind::HttpFile;
+            rule.url = Some("https://example.com/test.txt".to_string());
+            rule.sha256 = Some("1234".to_string());
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash);
+
+            rule.sha256 = Some("5678".to_string());
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash);
+
+            rule.substitutions
+                .insert("VERSION".to_string(), "1.0".to_string());
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash);
         }
 
         // Changing the environment will affect the hash
         {
             let mut env = env.clone();
-            env.insert(
+            env.vars.insert(
                 "ENV1".to_string().into(),
                 "different-env1".to_string().into(),
             );
@@ -239,6 +549,19 @@ mod tests {
             test_hashes.push(hash);
         }
 
+        // Setting a secret will affect the hash, but not its value
+        {
+            let mut env = env.clone();
+            env.secrets
+                .insert("TOKEN".to_string().into(), "secret1".to_string().into());
+            let hash = BuildHash::hash(&env, &rule, &*vfs).unwrap();
+            test_hashes.push(hash.clone());
+
+            env.secrets
+                .insert("TOKEN".to_string().into(), "secret2".to_string().into());
+            assert_eq!(BuildHash::hash(&env, &rule, &*vfs).unwrap(), hash);
+        }
+
         assert_eq!(
             test_hashes.len(),
             BTreeSet::from_iter(test_hashes.iter().cloned()).len(),
@@ -246,4 +569,71 @@ mod tests {
             test_hashes
         );
     }
+
+    #[test]
+    fn test_hash_breakdown() {
+        let vfs = FakeFileSystem::default();
+        let input = HexPath::try_from("src/main.c").unwrap();
+        vfs.write(&input, b"int main() {}").unwrap();
+        let mut rule = HexRule::new("compile".into());
+        rule.inputs = vec![input.clone()];
+        rule.outputs = vec![HexPath::try_from("out/main").unwrap()];
+        rule.commands = vec![RuleCommand::Argv(vec!["cc".into(), "a b.c".into()])];
+        let mut env = BuildEnvironment::default();
+        env.secrets
+            .insert("TOKEN".to_string().into(), "secret".to_string().into());
+
+        let (hash, breakdown) = BuildHash::hash_with_breakdown(&env, &rule, |input, tree| {
+            tree.add_from_vfs(input, &vfs)
+        })
+        .unwrap();
+        assert_eq!(hash, BuildHash::hash(&env, &rule, &vfs).unwrap());
+        assert_eq!(breakdown.commands, vec!["cc 'a b.c'"]);
+        assert_eq!(breakdown.secrets, vec!["TOKEN"]);
+        assert_eq!(
+            breakdown.inputs["src/main.c"],
+            BuildHash::hash_tree(&&input, &vfs).unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_hash_large_tree() {
+        let vfs = FakeFileSystem::default();
+        let paths: Vec<String> = (0..FILES_PER_HASH_THREAD * 4)
+            .map(|i| format!("data/{:03}/{i}.txt", i % 7))
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            vfs.write(
+                &HexPath::try_from(path.as_str()).unwrap(),
+                i.to_string().as_bytes(),
+            )
+            .unwrap();
+        }
+
+        // Hashing the files on several threads gives the same result as
+        // adding them and their directories one at a time in the order of
+        // their paths
+        let dirs: Vec<String> = (0..7).map(|i| format!("data/{i:03}")).collect();
+        let mut sorted: Vec<&String> = paths.iter().chain(&dirs).collect();
+        sorted.sort();
+        let mut context = Context::new(&SHA256);
+        let mut tree = TreeHasher(&mut context);
+        tree.add_dir("data");
+        for path in sorted {
+            if dirs.contains(path) {
+                tree.add_dir(path);
+                continue;
+            }
+            tree.add_file(
+                path,
+                &vfs.read(&HexPath::try_from(path.as_str()).unwrap())
+                    .unwrap(),
+            );
+        }
+        let expected = BuildHash(hex_string_for_digest(context.finish()));
+
+        let data = HexPath::try_from("data").unwrap();
+        assert_eq!(BuildHash::hash_tree(&&data, &vfs).unwrap(), expected);
+        assert_eq!(BuildHash::hash_tree(&&data, &vfs).unwrap(), expected);
+    }
 }
@@ -0,0 +1,31 @@
+use clap::ValueEnum;
+
+use crate::ast::hexmake_file::CacheSettings;
+use crate::file_system::output_times::OutputTimes;
+
+/// Settings that control how the build cache behaves
+#[derive(Default)]
+pub struct CacheOptions {
+    pub mode: CacheMode,
+
+    /// Whether to replace each newly built output in `out/` with a hard
+    /// link to its copy in the cache, so that the two share disk space
+    pub hardlink_outputs: bool,
+
+    /// What modification time outputs get when they are restored
+    pub output_times: OutputTimes,
+
+    /// The limits that garbage collection keeps the cache within
+    pub settings: CacheSettings,
+}
+
+/// How a build uses the cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum CacheMode {
+    /// Use cached outputs when possible, and insert newly built outputs
+    #[default]
+    ReadWrite,
+
+    /// Ignore existing entries and build every rule, but insert the results
+    WriteOnly,
+}
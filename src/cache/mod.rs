@@ -1,2 +1,3 @@
 pub mod build_cache;
 pub mod build_hash;
+pub mod cache_options;
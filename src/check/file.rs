@@ -1,4 +1,6 @@
-use crate::ast::hexmake_file::HexmakeFile;
+use std::collections::BTreeSet;
+
+use crate::ast::hexmake_file::{HexRule, HexmakeFile, RuleCommand, RuleKind, parse_mode};
 
 /// Check that a Hexmake file is valid
 pub fn check_file(hexmake_file: &HexmakeFile) -> Result<(), String> {
@@ -14,6 +16,242 @@ pub fn check_file(hexmake_file: &HexmakeFile) -> Result<(), String> {
                 rule.name
             ));
         }
+        check_kind(rule)?;
+        if rule
+            .commands
+            .iter()
+            .any(|command| matches!(command, RuleCommand::Argv(args) if args.is_empty()))
+        {
+            return Err(format!(
+                "Rule `{}` has a command that is an empty array",
+                rule.name
+            ));
+        }
+        if let Some(pool) = &rule.pool
+            && !hexmake_file.pools.contains_key(pool)
+        {
+            return Err(format!(
+                "Rule `{}` uses undeclared pool `{pool}`",
+                rule.name
+            ));
+        }
+    }
+
+    for (pool, limit) in &hexmake_file.pools {
+        if *limit == 0 {
+            return Err(format!("Pool `{pool}` has a limit of 0"));
+        }
+    }
+
+    let cache = &hexmake_file.cache;
+    if cache.target_size_mb > cache.max_size_mb {
+        return Err(format!(
+            "The cache's `target_size_mb` of {} is larger than its `max_size_mb` of {}",
+            cache.target_size_mb, cache.max_size_mb
+        ));
+    }
+    for (value, field) in [
+        (
+            cache.remote.connect_timeout_seconds,
+            "connect_timeout_seconds",
+        ),
+        (cache.remote.timeout_seconds, "timeout_seconds"),
+    ] {
+        if value == 0 {
+            return Err(format!("The remote cache's `{field}` must be at least 1"));
+        }
+    }
+    if cache.remote.password_env.is_some() && cache.remote.username_env.is_none() {
+        return Err("The remote cache's `password_env` needs a `username_env`".to_string());
+    }
+    if let Some(mode) = &cache.permissions.mode {
+        parse_mode(mode).map_err(|error| format!("The cache's `mode` is invalid: {error}"))?;
+    }
+
+    for dir in &hexmake_file.subworkspaces {
+        if dir.is_empty() || dir.starts_with("out/") || dir.starts_with('/') || dir.ends_with('/') {
+            return Err(format!(
+                "Sub-workspace `{dir}` must be a relative directory outside of `out/`"
+            ));
+        }
+    }
+
+    for (name, toolchain) in &hexmake_file.toolchains {
+        if toolchain.path.is_some() == toolchain.url.is_some() {
+            return Err(format!(
+                "Tool `{name}` must have exactly one of `path` and `url`"
+            ));
+        }
+    }
+
+    let rule_names: BTreeSet<&str> = hexmake_file
+        .rules
+        .iter()
+        .map(|rule| rule.name.as_str())
+        .collect();
+    let outputs: BTreeSet<&str> = hexmake_file
+        .rules
+        .iter()
+        .flat_map(|rule| rule.outputs.iter().map(|output| output.path.as_str()))
+        .collect();
+    for rule in &hexmake_file.rules {
+        for dep in &rule.deps {
+            if !rule_names.contains(dep.as_str()) {
+                return Err(format!(
+                    "Rule `{}` depends on `{dep}`, but no rule exists with that name",
+                    rule.name
+                ));
+            }
+        }
+    }
+
+    for (alias, members) in &hexmake_file.aliases {
+        if alias.starts_with("out/") {
+            return Err(format!("Alias `{alias}` has a name starting with `out/`"));
+        }
+        if rule_names.contains(alias.as_str()) {
+            return Err(format!("Alias `{alias}` has the same name as a rule"));
+        }
+        for member in members {
+            if !rule_names.contains(member.as_str())
+                && !outputs.contains(member.as_str())
+                && !hexmake_file.aliases.contains_key(member)
+            {
+                return Err(format!(
+                    "Alias `{alias}` refers to unknown target `{member}`"
+                ));
+            }
+        }
+    }
+
+    for target in &hexmake_file.default_targets {
+        if !rule_names.contains(target.as_str())
+            && !outputs.contains(target.as_str())
+            && !hexmake_file.aliases.contains_key(target)
+        {
+            return Err(format!(
+                "`default_targets` refers to unknown target `{target}`"
+            ));
+        }
+    }
+
+    for (output, setting) in &hexmake_file.install {
+        if !outputs
+            .iter()
+            .any(|declared| output == declared || output.starts_with(&format!("{declared}/")))
+        {
+            return Err(format!(
+                "`install` refers to `{output}`, which is not an output of any rule"
+            ));
+        }
+        if let Some(path) = &setting.path
+            && (path.starts_with('/') || path.split('/').any(|part| part == ".."))
+        {
+            return Err(format!(
+                "The install path `{path}` of `{output}` must be relative to the prefix"
+            ));
+        }
+        setting
+            .mode_bits()
+            .map_err(|error| format!("The install mode of `{output}` is invalid: {error}"))?;
+    }
+
+    Ok(())
+}
+
+/// Check that a rule has the fields that its kind needs, and none of
+/// the fields that belong to other kinds
+fn check_kind(rule: &HexRule) -> Result<(), String> {
+    let kind = rule.kind;
+    let require = |present: bool, field: &str| {
+        if present {
+            Ok(())
+        } else {
+            Err(format!(
+                "Rule `{}` of kind `{kind}` needs `{field}`",
+                rule.name
+            ))
+        }
+    };
+    let forbid = |present: bool, field: &str| {
+        if present {
+            Err(format!(
+                "Rule `{}` of kind `{kind}` cannot have `{field}`",
+                rule.name
+            ))
+        } else {
+            Ok(())
+        }
+    };
+
+    if kind != RuleKind::Command {
+        // Built-in kinds do their work without commands, and each one
+        // produces a single file or directory
+        forbid(!rule.commands.is_empty(), "commands")?;
+        if rule.outputs.len() != 1 {
+            return Err(format!(
+                "Rule `{}` of kind `{kind}` must have exactly one output",
+                rule.name
+            ));
+        }
+    }
+
+    // Fields that belong to a single kind, and whether that kind needs them
+    let kind_fields = [
+        (rule.url.is_some(), "url", RuleKind::HttpFile, true),
+        (rule.sha256.is_some(), "sha256", RuleKind::HttpFile, true),
+        (
+            rule.strip_components != 0,
+            "strip_components",
+            RuleKind::Extract,
+            false,
+        ),
+        (rule.content.is_some(), "content", RuleKind::Write, true),
+        (
+            !rule.substitutions.is_empty(),
+            "substitutions",
+            RuleKind::Template,
+            false,
+        ),
+    ];
+    for (present, field, owner, needed) in kind_fields {
+        if kind == owner {
+            require(present || !needed, field)?;
+        } else {
+            forbid(present, field)?;
+        }
+    }
+
+    forbid(rule.stamp && kind != RuleKind::Command, "stamp")?;
+    forbid(
+        rule.include_scan.is_some() && kind != RuleKind::Command,
+        "include_scan",
+    )?;
+
+    if let Some(stdout_output) = &rule.stdout_output {
+        forbid(kind != RuleKind::Command, "stdout_output")?;
+        if !rule.outputs.contains(stdout_output) {
+            return Err(format!(
+                "Rule `{}` writes standard output to `{stdout_output}`, which is not one of its outputs",
+                rule.name
+            ));
+        }
+    }
+
+    match kind {
+        RuleKind::Command => {}
+        RuleKind::HttpFile | RuleKind::Write | RuleKind::Mkdir => {
+            forbid(!rule.inputs.is_empty(), "inputs")?
+        }
+        RuleKind::Extract | RuleKind::Concat => require(!rule.inputs.is_empty(), "inputs")?,
+        RuleKind::Copy | RuleKind::Template => {
+            if rule.inputs.len() != 1 {
+                return Err(format!(
+                    "Rule `{}` of kind `{kind}` must have exactly one input",
+                    rule.name
+                ));
+            }
+        }
     }
 
     Ok(())
@@ -85,4 +323,426 @@ mod tests {
             Err("Rule `out/foo` has a name starting with `out/`".to_string())
         );
     }
+
+    #[test]
+    fn test_check_pools() {
+        let check_pools = |pools: &str, pool: &str| {
+            let hexmake_file = serde_json::from_str(&format!(
+                r#"{{
+                    "rules": [
+                        {{
+                            "name": "foo",
+                            "outputs": ["out/foo"],
+                            "inputs": [],
+                            "commands": ["touch out/foo"],
+                            "pool": "{pool}"
+                        }}
+                    ],
+                    "pools": {pools}
+                }}"#
+            ))
+            .unwrap();
+            check_file(&hexmake_file)
+        };
+
+        assert_eq!(check_pools(r#"{"link": 1}"#, "link"), Ok(()));
+
+        assert_eq!(
+            check_pools(r#"{"link": 1}"#, "bogus"),
+            Err("Rule `foo` uses undeclared pool `bogus`".to_string())
+        );
+
+        assert_eq!(
+            check_pools(r#"{"link": 0}"#, "link"),
+            Err("Pool `link` has a limit of 0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_aliases() {
+        let check_aliases = |aliases: &str| {
+            let hexmake_file = serde_json::from_str(&format!(
+                r#"{{
+                    "rules": [
+                        {{
+                            "name": "foo",
+                            "outputs": ["out/foo"],
+                            "inputs": [],
+                            "commands": ["touch out/foo"]
+                        }}
+                    ],
+                    "aliases": {aliases}
+                }}"#
+            ))
+            .unwrap();
+            check_file(&hexmake_file)
+        };
+
+        // Valid aliases, referring to a rule, an output, and another alias
+        assert_eq!(
+            check_aliases(r#"{"a": ["foo", "out/foo"], "b": ["a"]}"#),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_aliases(r#"{"out/a": ["foo"]}"#),
+            Err("Alias `out/a` has a name starting with `out/`".to_string())
+        );
+
+        assert_eq!(
+            check_aliases(r#"{"foo": ["out/foo"]}"#),
+            Err("Alias `foo` has the same name as a rule".to_string())
+        );
+
+        assert_eq!(
+            check_aliases(r#"{"a": ["bogus"]}"#),
+            Err("Alias `a` refers to unknown target `bogus`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_default_targets() {
+        let check_default_targets = |default_targets: &str| {
+            let hexmake_file = serde_json::from_str(&format!(
+                r#"{{
+                    "rules": [
+                        {{
+                            "name": "foo",
+                            "outputs": ["out/foo"],
+                            "inputs": [],
+                            "commands": ["touch out/foo"]
+                        }}
+                    ],
+                    "aliases": {{"a": ["foo"]}},
+                    "default_targets": {default_targets}
+                }}"#
+            ))
+            .unwrap();
+            check_file(&hexmake_file)
+        };
+
+        // Valid default targets, referring to a rule, an output, and an alias
+        assert_eq!(check_default_targets(r#"["foo", "out/foo", "a"]"#), Ok(()));
+
+        assert_eq!(
+            check_default_targets(r#"["bogus"]"#),
+            Err("`default_targets` refers to unknown target `bogus`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_subworkspaces() {
+        let check_subworkspaces = |subworkspaces: &str| {
+            let hexmake_file = serde_json::from_str(&format!(
+                r#"{{"rules": [], "subworkspaces": {subworkspaces}}}"#
+            ))
+            .unwrap();
+            check_file(&hexmake_file)
+        };
+
+        assert_eq!(check_subworkspaces(r#"["vendor/zlib"]"#), Ok(()));
+        assert_eq!(
+            check_subworkspaces(r#"["out/zlib"]"#),
+            Err(
+                "Sub-workspace `out/zlib` must be a relative directory outside of `out/`"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_check_install() {
+        let check_install = |install: &str| {
+            let hexmake_file = serde_json::from_str(&format!(
+                r#"{{
+                    "rules": [
+                        {{
+                            "name": "docs",
+                            "outputs": ["out/docs"],
+                            "commands": ["make-docs"]
+                        }}
+                    ],
+                    "install": {install}
+                }}"#
+            ))
+            .unwrap();
+            check_file(&hexmake_file)
+        };
+
+        assert_eq!(
+            check_install(
+                r#"{"out/docs": {"path": "share/doc"}, "out/docs/run.sh": {"mode": "755"}}"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check_install(r#"{"out/bogus": {}}"#),
+            Err("`install` refers to `out/bogus`, which is not an output of any rule".to_string())
+        );
+        assert_eq!(
+            check_install(r#"{"out/docs": {"path": "../etc"}}"#),
+            Err(
+                "The install path `../etc` of `out/docs` must be relative to the prefix"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_install(r#"{"out/docs": {"mode": "rwx"}}"#),
+            Err(
+                "The install mode of `out/docs` is invalid: `rwx` is not an octal file mode, such as 755"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_check_cache_settings() {
+        let check_cache = |cache: &str| {
+            let hexmake_file =
+                serde_json::from_str(&format!(r#"{{"rules": [], "cache": {cache}}}"#)).unwrap();
+            check_file(&hexmake_file)
+        };
+
+        assert_eq!(check_cache(r#"{"max_age_days": 30}"#), Ok(()));
+        assert_eq!(
+            check_cache(r#"{"max_size_mb": 50}"#),
+            Err(
+                "The cache's `target_size_mb` of 100 is larger than its `max_size_mb` of 50"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_cache(r#"{"remote": {"timeout_seconds": 60, "retries": 0}}"#),
+            Ok(())
+        );
+        assert_eq!(
+            check_cache(r#"{"remote": {"connect_timeout_seconds": 0}}"#),
+            Err("The remote cache's `connect_timeout_seconds` must be at least 1".to_string())
+        );
+        assert_eq!(
+            check_cache(r#"{"remote": {"password_env": "CACHE_PASSWORD"}}"#),
+            Err("The remote cache's `password_env` needs a `username_env`".to_string())
+        );
+        assert_eq!(
+            check_cache(r#"{"permissions": {"group": "build", "mode": "664"}}"#),
+            Ok(())
+        );
+        assert_eq!(
+            check_cache(r#"{"permissions": {"mode": "999"}}"#),
+            Err(
+                "The cache's `mode` is invalid: `999` is not an octal file mode, such as 755"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_check_commands() {
+        let check_commands = |commands: &str| {
+            let hexmake_file = serde_json::from_str(&format!(
+                r#"{{"rules": [{{"name": "foo", "outputs": ["out/foo"], "commands": {commands}}}]}}"#
+            ))
+            .unwrap();
+            check_file(&hexmake_file)
+        };
+
+        assert_eq!(
+            check_commands(r#"["mkdir out/foo", ["touch", "out/foo/a b"]]"#),
+            Ok(())
+        );
+        assert_eq!(
+            check_commands(r#"["true", []]"#),
+            Err("Rule `foo` has a command that is an empty array".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_toolchains() {
+        let check_toolchains = |toolchains: &str| {
+            let hexmake_file = serde_json::from_str(&format!(
+                r#"{{
+                    "rules": [],
+                    "toolchains": {toolchains}
+                }}"#
+            ))
+            .unwrap();
+            check_file(&hexmake_file)
+        };
+
+        assert_eq!(
+            check_toolchains(r#"{"cc": {"path": "/usr/bin/cc", "sha256": "abcd"}}"#),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_toolchains(r#"{"cc": {"sha256": "abcd"}}"#),
+            Err("Tool `cc` must have exactly one of `path` and `url`".to_string())
+        );
+
+        assert_eq!(
+            check_toolchains(
+                r#"{"cc": {"path": "cc", "url": "https://example.com/cc", "sha256": "abcd"}}"#
+            ),
+            Err("Tool `cc` must have exactly one of `path` and `url`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_kind() {
+        let check_rule = |rule: &str| {
+            let hexmake_file = serde_json::from_str(&format!(r#"{{"rules": [{rule}]}}"#)).unwrap();
+            check_file(&hexmake_file)
+        };
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "zlib",
+                    "kind": "http_file",
+                    "outputs": ["out/zlib.tar.gz"],
+                    "inputs": [],
+                    "url": "https://example.com/zlib.tar.gz",
+                    "sha256": "abcd"
+                }"#
+            ),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "zlib",
+                    "kind": "http_file",
+                    "outputs": ["out/zlib.tar.gz"],
+                    "inputs": [],
+                    "url": "https://example.com/zlib.tar.gz"
+                }"#
+            ),
+            Err("Rule `zlib` of kind `http_file` needs `sha256`".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "zlib",
+                    "kind": "http_file",
+                    "outputs": ["out/a", "out/b"],
+                    "inputs": [],
+                    "url": "https://example.com/zlib.tar.gz",
+                    "sha256": "abcd"
+                }"#
+            ),
+            Err("Rule `zlib` of kind `http_file` must have exactly one output".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "foo",
+                    "outputs": ["out/foo"],
+                    "inputs": [],
+                    "commands": ["touch out/foo"],
+                    "url": "https://example.com/foo"
+                }"#
+            ),
+            Err("Rule `foo` of kind `command` cannot have `url`".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "zlib",
+                    "kind": "extract",
+                    "outputs": ["out/zlib"],
+                    "inputs": ["out/zlib.tar.gz"],
+                    "strip_components": 1
+                }"#
+            ),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "zlib",
+                    "kind": "extract",
+                    "outputs": ["out/zlib"]
+                }"#
+            ),
+            Err("Rule `zlib` of kind `extract` needs `inputs`".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "version",
+                    "kind": "write",
+                    "outputs": ["out/version.txt"],
+                    "content": "1.0"
+                }"#
+            ),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "version",
+                    "kind": "write",
+                    "outputs": ["out/version.txt"]
+                }"#
+            ),
+            Err("Rule `version` of kind `write` needs `content`".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "version",
+                    "kind": "write",
+                    "outputs": ["out/version.txt"],
+                    "content": "1.0",
+                    "stamp": true
+                }"#
+            ),
+            Err("Rule `version` of kind `write` cannot have `stamp`".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "headers",
+                    "kind": "copy",
+                    "outputs": ["out/include"],
+                    "inputs": ["include"],
+                    "include_scan": {}
+                }"#
+            ),
+            Err("Rule `headers` of kind `copy` cannot have `include_scan`".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "config",
+                    "kind": "copy",
+                    "inputs": ["a.conf", "b.conf"],
+                    "outputs": ["out/app.conf"]
+                }"#
+            ),
+            Err("Rule `config` of kind `copy` must have exactly one input".to_string())
+        );
+
+        assert_eq!(
+            check_rule(
+                r#"{
+                    "name": "describe",
+                    "outputs": ["out/version.txt"],
+                    "commands": ["git describe"],
+                    "stdout_output": "out/other.txt"
+                }"#
+            ),
+            Err("Rule `describe` writes standard output to `out/other.txt`, which is not one of its outputs".to_string())
+        );
+    }
 }
@@ -0,0 +1,242 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::{InputLimits, RuleName};
+use crate::error::Error;
+use crate::file_system::vfs::{VirtualFileSystem, WalkEntry};
+use crate::graph::planner::BuildPlan;
+
+/// How many of the largest parts of an input to report
+const LARGEST_PARTS: usize = 3;
+
+/// How much a file tree holds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct TreeSize {
+    files: u64,
+    bytes: u64,
+}
+
+impl TreeSize {
+    fn add(&mut self, entry: &WalkEntry) {
+        if entry.is_file {
+            self.files += 1;
+            self.bytes += entry.size;
+        }
+    }
+
+    fn describe(&self) -> String {
+        let files = match self.files {
+            1 => "1 file".to_string(),
+            files => format!("{files} files"),
+        };
+        format!("{files} and {}", format_bytes(self.bytes))
+    }
+}
+
+/// Show a number of bytes in the largest unit that keeps it above 1
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Check that no source input of the plan's tasks is larger than the
+/// limits, before the build spends time hashing and copying it. A large
+/// input is usually a mistake, such as listing the whole workspace as an
+/// input. Inputs above a warning threshold are returned as warnings, and
+/// an input above a hard limit is an error. Each message names the rules
+/// that use the input and the largest parts of it.
+pub fn check_input_sizes(
+    plan: &BuildPlan,
+    vfs: &dyn VirtualFileSystem,
+    limits: &InputLimits,
+) -> Result<Vec<String>, Error> {
+    let mut users: BTreeMap<HexPath, Vec<RuleName>> = BTreeMap::new();
+    for (rule_name, task) in &plan.tasks {
+        for input in &task.lock().unwrap().rule.inputs {
+            if !input.is_output() {
+                users
+                    .entry(input.clone())
+                    .or_default()
+                    .push(rule_name.clone());
+            }
+        }
+    }
+
+    let mut warnings = Vec::new();
+    for (input, rule_names) in &users {
+        // Missing inputs are reported when the rules that need them run
+        if !vfs.exists(input)? {
+            continue;
+        }
+        let entries = vfs.tree_walk_metadata(input)?;
+        let mut total = TreeSize::default();
+        for entry in &entries {
+            total.add(entry);
+        }
+
+        // The hard limits come first, so that they are the ones reported
+        let exceeded = [
+            ("max_files", limits.max_files, total.files),
+            (
+                "max_size_mb",
+                limits.max_size_mb,
+                total.bytes / (1024 * 1024),
+            ),
+            ("warn_files", limits.warn_files, total.files),
+            (
+                "warn_size_mb",
+                limits.warn_size_mb,
+                total.bytes / (1024 * 1024),
+            ),
+        ]
+        .into_iter()
+        .find_map(|(field, limit, actual)| {
+            let limit = limit?;
+            (actual > limit).then_some((field, limit))
+        });
+        let Some((field, limit)) = exceeded else {
+            continue;
+        };
+
+        let rules = rule_names
+            .iter()
+            .map(|rule_name| format!("`{rule_name}`"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut message = format!(
+            "Input `{input}` of {rules} has {}, over the `{field}` limit of {limit}",
+            total.describe()
+        );
+        let parts = largest_parts(input, &entries);
+        if !parts.is_empty() {
+            message.push_str(". The largest parts are ");
+            message.push_str(
+                &parts
+                    .iter()
+                    .map(|(path, size)| format!("`{path}` ({})", size.describe()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+        if field.starts_with("max_") {
+            return Err(message.into());
+        }
+        warnings.push(message);
+    }
+    Ok(warnings)
+}
+
+/// The largest files and directories directly inside an input directory,
+/// with how much each one holds, largest first
+fn largest_parts(input: &HexPath, entries: &[WalkEntry]) -> Vec<(String, TreeSize)> {
+    let prefix = format!("{input}/");
+    let mut parts: BTreeMap<String, TreeSize> = BTreeMap::new();
+    for entry in entries {
+        let Some(relative) = entry.path.strip_prefix(&prefix) else {
+            continue;
+        };
+        let child = relative.split('/').next().unwrap();
+        parts
+            .entry(format!("{prefix}{child}"))
+            .or_default()
+            .add(entry);
+    }
+
+    let mut parts: Vec<_> = parts.into_iter().collect();
+    parts.sort_by_key(|(_, size)| Reverse((size.bytes, size.files)));
+    parts.truncate(LARGEST_PARTS);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::graph::planner::plan_build;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 bytes");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(200 * 1024 * 1024), "200.0 MB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024 * 1024), "3072.0 GB");
+    }
+
+    #[test]
+    fn test_check_input_sizes() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "package".into(),
+                    outputs: vec![HexPath::try_from("out/app.tar").unwrap()],
+                    inputs: vec![
+                        HexPath::try_from("assets").unwrap(),
+                        HexPath::try_from("main.c").unwrap(),
+                    ],
+                    commands: vec!["tar cf out/app.tar assets main.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        let plan = plan_build(&hexmake_file, &vec!["package".to_string().into()]).unwrap();
+
+        let vfs = FakeFileSystem::default();
+        let write = |path: &str, size: usize| {
+            vfs.write(&HexPath::try_from(path).unwrap(), &vec![b'x'; size])
+                .unwrap();
+        };
+        write("main.c", 100);
+        write("assets/logo.png", 2000);
+        write("assets/video/intro.mp4", 5000);
+        write("assets/video/outro.mp4", 4000);
+        for i in 0..4 {
+            write(&format!("assets/icons/{i}.png"), 10);
+        }
+        write("assets/notes.txt", 1);
+
+        // Small inputs are fine
+        let limits = InputLimits {
+            warn_files: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_input_sizes(&plan, &vfs, &limits).unwrap(),
+            Vec::<String>::new()
+        );
+
+        // A large input gets a warning naming its largest parts
+        let limits = InputLimits {
+            warn_files: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_input_sizes(&plan, &vfs, &limits).unwrap(),
+            vec![
+                "Input `assets` of `package` has 8 files and 10.8 KB, over the `warn_files` \
+                 limit of 5. The largest parts are `assets/video` (2 files and 8.8 KB), \
+                 `assets/logo.png` (1 file and 2.0 KB), `assets/icons` (4 files and 40 bytes)"
+                    .to_string()
+            ]
+        );
+
+        // Above a hard limit, it is an error
+        let limits = InputLimits {
+            max_files: Some(5),
+            ..Default::default()
+        };
+        assert!(check_input_sizes(&plan, &vfs, &limits).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::thread::sleep;
+use std::time::Duration;
+use std::{env, io};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ureq::{Agent, RequestBuilder};
+
+use crate::ast::hexmake_file::RemoteCacheSettings;
+
+/// How long to wait before the first retry of a remote cache request.
+/// Each later retry waits twice as long as the one before.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether a location given on the command line is a URL rather than a
+/// local path
+pub fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Read the contents of a URL or a local file. A download that fails in
+/// a way that might not happen again is retried as the settings say.
+pub fn read_url_or_path(
+    location: &str,
+    settings: &RemoteCacheSettings,
+) -> Result<Vec<u8>, io::Error> {
+    if !is_url(location) {
+        return fs_err::read(location);
+    }
+
+    let agent = remote_agent(settings);
+    let authorization = authorization(settings, |name| env::var(name).ok());
+    with_retries(settings.retries, FIRST_RETRY_DELAY, || {
+        authorize(agent.get(location), &authorization)
+            .call()?
+            .body_mut()
+            .with_config()
+            .limit(u64::MAX)
+            .read_to_vec()
+    })
+    .map_err(|error| io::Error::other(format!("Could not download {location}: {error}")))
+}
+
+/// Write contents to a URL, with an HTTP PUT, or to a local file. An
+/// upload is retried like a download in [read_url_or_path].
+pub fn write_url_or_path(
+    location: &str,
+    contents: &[u8],
+    settings: &RemoteCacheSettings,
+) -> Result<(), io::Error> {
+    if !is_url(location) {
+        return fs_err::write(location, contents);
+    }
+
+    let agent = remote_agent(settings);
+    let authorization = authorization(settings, |name| env::var(name).ok());
+    with_retries(settings.retries, FIRST_RETRY_DELAY, || {
+        authorize(agent.put(location), &authorization).send(contents)
+    })
+    .map_err(|error| io::Error::other(format!("Could not upload to {location}: {error}")))?;
+    Ok(())
+}
+
+/// Ask a server whether a URL exists, without downloading it, and give
+/// the HTTP status of the answer. This is not retried, so that a server
+/// that is down is reported quickly.
+pub fn check_url(url: &str, settings: &RemoteCacheSettings) -> Result<u16, ureq::Error> {
+    let authorization = authorization(settings, |name| env::var(name).ok());
+    let response = authorize(remote_agent(settings).head(url), &authorization)
+        .config()
+        .http_status_as_error(false)
+        .build()
+        .call()?;
+    Ok(response.status().as_u16())
+}
+
+/// Fetch the contents of a URL
+pub fn download(url: &str) -> Result<Vec<u8>, io::Error> {
+    let mut response = ureq::get(url)
+        .call()
+        .map_err(|error| io::Error::other(format!("Could not download {url}: {error}")))?;
+    response
+        .body_mut()
+        .with_config()
+        .limit(u64::MAX)
+        .read_to_vec()
+        .map_err(|error| io::Error::other(format!("Could not download {url}: {error}")))
+}
+
+/// An HTTP client with the timeouts of the remote cache settings
+fn remote_agent(settings: &RemoteCacheSettings) -> Agent {
+    Agent::config_builder()
+        .timeout_connect(Some(Duration::from_secs(settings.connect_timeout_seconds)))
+        .timeout_global(Some(Duration::from_secs(settings.timeout_seconds)))
+        .build()
+        .into()
+}
+
+/// The `Authorization` header to send to the remote cache, made from the
+/// environment variables that the settings name, as `var` looks them up.
+/// If they are not set, no credentials are sent, so that a cache that
+/// anyone may read still works for jobs that are not given the secrets,
+/// such as CI jobs for pull requests from forks.
+fn authorization(
+    settings: &RemoteCacheSettings,
+    var: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let value = |name: &Option<String>| {
+        name.as_deref()
+            .and_then(&var)
+            .filter(|value| !value.is_empty())
+    };
+    if let Some(token) = value(&settings.token_env) {
+        return Some(format!("Bearer {token}"));
+    }
+    let username = value(&settings.username_env)?;
+    let password = value(&settings.password_env).unwrap_or_default();
+    Some(format!(
+        "Basic {}",
+        BASE64.encode(format!("{username}:{password}"))
+    ))
+}
+
+/// Add the `Authorization` header, if there is one, to a request. The
+/// header is never printed, and errors from requests only give the URL.
+fn authorize<B>(request: RequestBuilder<B>, authorization: &Option<String>) -> RequestBuilder<B> {
+    match authorization {
+        Some(authorization) => request.header("Authorization", authorization.as_str()),
+        None => request,
+    }
+}
+
+/// Run a request, and run it again up to `retries` more times while it
+/// fails in a way that might not happen again. The delay between tries
+/// doubles each time, plus a random amount of up to as much again, so
+/// that many CI jobs that failed together do not all retry together.
+fn with_retries<T>(
+    retries: u32,
+    first_delay: Duration,
+    mut request: impl FnMut() -> Result<T, ureq::Error>,
+) -> Result<T, ureq::Error> {
+    let mut delay = first_delay;
+    for _ in 0..retries {
+        match request() {
+            Err(error) if is_transient(&error) => {
+                let jittered = delay.mul_f64(1.0 + jitter());
+                eprintln!(
+                    "Warning: {error}; trying again in {:.1}s",
+                    jittered.as_secs_f64()
+                );
+                sleep(jittered);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    request()
+}
+
+/// Whether a failed request might succeed if it is tried again
+fn is_transient(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::StatusCode(status) => *status >= 500 || *status == 429,
+        ureq::Error::Io(_)
+        | ureq::Error::Timeout(_)
+        | ureq::Error::HostNotFound
+        | ureq::Error::ConnectionFailed
+        | ureq::Error::Protocol(_) => true,
+        _ => false,
+    }
+}
+
+/// A random fraction from 0 to 1
+fn jitter() -> f64 {
+    let random: [u8; 4] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .map(|random| random.expose())
+        .unwrap_or_default();
+    u32::from_le_bytes(random) as f64 / u32::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_authorization() {
+        let vars = BTreeMap::from([
+            ("TOKEN", "secret"),
+            ("USER", "ci"),
+            ("PASSWORD", "hunter2"),
+            ("EMPTY", ""),
+        ]);
+        let authorization = |settings: RemoteCacheSettings| {
+            authorization(&settings, |name| {
+                vars.get(name).map(|value| value.to_string())
+            })
+        };
+
+        assert_eq!(authorization(RemoteCacheSettings::default()), None);
+        assert_eq!(
+            authorization(RemoteCacheSettings {
+                token_env: Some("TOKEN".into()),
+                ..RemoteCacheSettings::default()
+            }),
+            Some("Bearer secret".to_string())
+        );
+        assert_eq!(
+            authorization(RemoteCacheSettings {
+                username_env: Some("USER".into()),
+                password_env: Some("PASSWORD".into()),
+                ..RemoteCacheSettings::default()
+            }),
+            Some("Basic Y2k6aHVudGVyMg==".to_string())
+        );
+
+        // Variables that are not set, or are empty, send no credentials
+        assert_eq!(
+            authorization(RemoteCacheSettings {
+                token_env: Some("EMPTY".into()),
+                username_env: Some("MISSING".into()),
+                ..RemoteCacheSettings::default()
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_with_retries() {
+        // Respond to each request with the next of some results
+        let run = |retries: u32, results: Vec<Result<&'static str, ureq::Error>>| {
+            let mut results = results.into_iter();
+            let mut requests = 0;
+            let result = with_retries(retries, Duration::ZERO, || {
+                requests += 1;
+                results.next().unwrap()
+            });
+            (result.map_err(|error| error.to_string()), requests)
+        };
+
+        // Server errors and timeouts are retried
+        assert_eq!(
+            run(
+                3,
+                vec![
+                    Err(ureq::Error::StatusCode(503)),
+                    Err(ureq::Error::ConnectionFailed),
+                    Ok("archive"),
+                ]
+            ),
+            (Ok("archive"), 3)
+        );
+
+        // Only as many times as allowed
+        assert_eq!(
+            run(
+                1,
+                vec![
+                    Err(ureq::Error::StatusCode(500)),
+                    Err(ureq::Error::StatusCode(502)),
+                ]
+            ),
+            (Err("http status: 502".to_string()), 2)
+        );
+
+        // A missing archive will not appear by asking again
+        assert_eq!(
+            run(3, vec![Err(ureq::Error::StatusCode(404))]),
+            (Err("http status: 404".to_string()), 1)
+        );
+    }
+}
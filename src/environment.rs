@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use crate::ast::hexmake_file::HexmakeFile;
+
+/// The environment variables that are passed through to build commands
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildEnvironment {
+    /// Variables from the `env` field. Their values are part of the
+    /// cache key.
+    pub vars: BTreeMap<Arc<String>, Arc<String>>,
+
+    /// Variables from the `secret_environ` field. Only the fact that they
+    /// are set is part of the cache key, and their values are redacted
+    /// from command output.
+    pub secrets: BTreeMap<Arc<String>, Arc<String>>,
+}
+
+/// The text that replaces secret values in command output
+const REDACTED: &[u8] = b"[REDACTED]";
+
+impl BuildEnvironment {
+    /// Make an environment from the variables that the given Hexmake
+    /// file allows. Their values come from the overrides given on the
+    /// command line, or else the current process, or else the Hexmake
+    /// file's `env_file`.
+    pub fn from_hexmake_file(
+        hexmake_file: &HexmakeFile,
+        overrides: &[(String, String)],
+    ) -> Result<BuildEnvironment, String> {
+        let file_values = match &hexmake_file.env_file {
+            Some(path) => read_env_file(path)?,
+            None => BTreeMap::new(),
+        };
+        let mut env = BuildEnvironment {
+            vars: current_values(&hexmake_file.env, &file_values),
+            secrets: current_values(&hexmake_file.secret_environ, &file_values),
+        };
+        for (name, value) in overrides {
+            let name = Arc::new(name.clone());
+            let value = Arc::new(value.clone());
+            if hexmake_file.env.contains(&name) {
+                env.vars.insert(name, value);
+            } else if hexmake_file.secret_environ.contains(&name) {
+                env.secrets.insert(name, value);
+            } else {
+                return Err(format!(
+                    "Cannot set `{name}` with --env, because it is not listed in `env`"
+                ));
+            }
+        }
+        Ok(env)
+    }
+
+    /// All of the variables that build commands should see
+    pub fn command_vars(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars
+            .iter()
+            .chain(self.secrets.iter())
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Replace the values of any secrets in some command output
+    pub fn redact(&self, output: &[u8]) -> Vec<u8> {
+        let mut result = output.to_vec();
+        for secret in self.secrets.values() {
+            result = replace_bytes(&result, secret.as_bytes(), REDACTED);
+        }
+        result
+    }
+}
+
+/// Look up the current values of some environment variables, falling
+/// back to the values from an env file. Variables that are set in neither
+/// are left out.
+fn current_values(
+    names: &[Arc<String>],
+    file_values: &BTreeMap<String, String>,
+) -> BTreeMap<Arc<String>, Arc<String>> {
+    let mut result = BTreeMap::new();
+    for name in names {
+        let value = env::var(name.as_str())
+            .ok()
+            .or_else(|| file_values.get(name.as_str()).cloned());
+        if let Some(value) = value {
+            result.insert(name.clone(), Arc::new(value));
+        }
+    }
+    result
+}
+
+/// Read the variables in an env file. A file that does not exist has no
+/// variables, so that a file that each developer keeps locally is optional.
+fn read_env_file(path: &str) -> Result<BTreeMap<String, String>, String> {
+    match fs_err::read_to_string(path) {
+        Ok(text) => parse_env_file(&text).map_err(|error| format!("In {path}: {error}")),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => Err(format!("Could not read env file: {error}")),
+    }
+}
+
+/// Parse the `KEY=VALUE` lines of an env file. Blank lines and lines
+/// starting with `#` are ignored, and a value can be wrapped in single or
+/// double quotes to keep its leading and trailing spaces.
+fn parse_env_file(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut result = BTreeMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(format!("line {} is not of the form KEY=VALUE", index + 1));
+        };
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("line {} has no valid variable name", index + 1));
+        }
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|(open, close)| value.strip_prefix(*open)?.strip_suffix(*close))
+            .unwrap_or(value);
+        result.insert(name.to_string(), value.to_string());
+    }
+    Ok(result)
+}
+
+/// Replace every occurrence of `from` in `bytes` with `to`
+fn replace_bytes(bytes: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    if from.is_empty() {
+        return bytes.to_vec();
+    }
+
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index..].starts_with(from) {
+            result.extend_from_slice(to);
+            index += from.len();
+        } else {
+            result.push(bytes[index]);
+            index += 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let env = BuildEnvironment {
+            secrets: BTreeMap::from([
+                (
+                    Arc::new("TOKEN".to_string()),
+                    Arc::new("hunter2".to_string()),
+                ),
+                (Arc::new("EMPTY".to_string()), Arc::new(String::new())),
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            env.redact(b"token=hunter2, again hunter2!"),
+            b"token=[REDACTED], again [REDACTED]!"
+        );
+        assert_eq!(env.redact(b"nothing secret"), b"nothing secret");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let hexmake_file = HexmakeFile {
+            env: vec![Arc::new("HEXMAKE_TEST_CFLAGS".to_string())],
+            secret_environ: vec![Arc::new("HEXMAKE_TEST_TOKEN".to_string())],
+            ..Default::default()
+        };
+        let setting = |name: &str, value: &str| (name.to_string(), value.to_string());
+
+        let env = BuildEnvironment::from_hexmake_file(
+            &hexmake_file,
+            &[
+                setting("HEXMAKE_TEST_CFLAGS", "-O0 -g"),
+                setting("HEXMAKE_TEST_TOKEN", "hunter2"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            env.command_vars().collect::<Vec<_>>(),
+            vec![
+                ("HEXMAKE_TEST_CFLAGS", "-O0 -g"),
+                ("HEXMAKE_TEST_TOKEN", "hunter2"),
+            ]
+        );
+        assert!(
+            env.secrets
+                .contains_key(&Arc::new("HEXMAKE_TEST_TOKEN".to_string()))
+        );
+
+        // Only declared variables can be set
+        assert_eq!(
+            BuildEnvironment::from_hexmake_file(&hexmake_file, &[setting("PATH", "/tmp")]),
+            Err("Cannot set `PATH` with --env, because it is not listed in `env`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let text = "\
+# Settings shared by the team
+CFLAGS=-O2 -Wall
+
+export CC = gcc
+GREETING=\"  hello  \"
+EMPTY=
+URL='https://example.com/?a=b'
+";
+        let vars = parse_env_file(text).unwrap();
+        assert_eq!(
+            vars,
+            BTreeMap::from([
+                ("CC".to_string(), "gcc".to_string()),
+                ("CFLAGS".to_string(), "-O2 -Wall".to_string()),
+                ("EMPTY".to_string(), String::new()),
+                ("GREETING".to_string(), "  hello  ".to_string()),
+                ("URL".to_string(), "https://example.com/?a=b".to_string()),
+            ])
+        );
+
+        assert_eq!(
+            parse_env_file("A=1\nnot a setting\n"),
+            Err("line 2 is not of the form KEY=VALUE".to_string())
+        );
+        assert_eq!(
+            parse_env_file("=1\n"),
+            Err("line 1 has no valid variable name".to_string())
+        );
+    }
+}
@@ -2,12 +2,44 @@ use std::fmt::{self, Display, Formatter};
 use std::io;
 
 /// An enum for the different kinds of errors that can happen in this tool
+#[derive(Debug)]
 pub enum Error {
     /// An error that Hexmake generates from its own code
     Hexmake(String),
 
     /// An IO error
     Io(io::Error),
+
+    /// The Hexmake file could not be read, or is not valid JSON of the
+    /// right shape
+    Parse(String),
+
+    /// The Hexmake file was parsed, but it does not make sense, such as a
+    /// rule that depends on one that does not exist
+    Check(String),
+
+    /// The requested targets could not be planned, such as because one of
+    /// them is not a rule, alias, or output
+    Plan(String),
+
+    /// The build ran, but a rule failed, the build timed out, or the cache
+    /// audit found outputs that differ from the cache
+    Build(String),
+}
+
+impl Error {
+    /// The code that Hexmake exits with for this error, as listed under
+    /// "Exit codes" in the user manual. Usage errors exit with 2, but clap
+    /// reports those itself.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Build(_) => 1,
+            Error::Parse(_) => 3,
+            Error::Check(_) => 4,
+            Error::Plan(_) => 5,
+            Error::Hexmake(_) | Error::Io(_) => 6,
+        }
+    }
 }
 
 impl Display for Error {
@@ -15,6 +47,10 @@ impl Display for Error {
         match self {
             Error::Hexmake(error) => write!(f, "{error}"),
             Error::Io(error) => write!(f, "{error}"),
+            Error::Parse(error)
+            | Error::Check(error)
+            | Error::Plan(error)
+            | Error::Build(error) => write!(f, "{error}"),
         }
     }
 }
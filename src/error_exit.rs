@@ -1,8 +1,8 @@
-/// Print an error message and exit out
+/// Print an error message and exit out with the given exit code
 macro_rules! error_exit {
-    ($fmt:literal $($arg:tt)*) => {{
+    ($code:expr, $fmt:literal $($arg:tt)*) => {{
         println!($fmt $($arg)*);
-        std::process::exit(1)
+        std::process::exit($code)
     }};
 }
 
@@ -0,0 +1,58 @@
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use fs_err::{metadata, read_dir};
+
+use crate::ast::hex_path::HexPath;
+
+/// The directory that holds one directory of records per build, named by
+/// the build's ID
+pub const BUILD_LOG_DIR: &str = ".hex/log";
+
+/// The name of the file in a build's log directory that says what went
+/// into the hash of each rule that the build hashed
+pub const HASH_BREAKDOWNS_FILE: &str = "hashes.json";
+
+/// The name of the file in a build's log directory that says what
+/// resources each rule that the build ran used
+pub const RESOURCE_USAGE_FILE: &str = "usage.json";
+
+/// Where the hash breakdowns of a build are saved. A build whose ID cannot
+/// be part of a path has nowhere to save them.
+pub fn hash_breakdowns_path(build_id: &str) -> Option<HexPath> {
+    HexPath::try_from(format!("{BUILD_LOG_DIR}/{build_id}/{HASH_BREAKDOWNS_FILE}")).ok()
+}
+
+/// Where the resource usage of a build's rules is saved, like
+/// [hash_breakdowns_path]
+pub fn resource_usage_path(build_id: &str) -> Option<HexPath> {
+    HexPath::try_from(format!("{BUILD_LOG_DIR}/{build_id}/{RESOURCE_USAGE_FILE}")).ok()
+}
+
+/// Where the output of a rule's failed command is saved, in the log
+/// directory of the build, like [hash_breakdowns_path]
+pub fn failure_log_path(build_id: &str, rule_name: &str) -> Option<HexPath> {
+    HexPath::try_from(format!("{BUILD_LOG_DIR}/{build_id}/{rule_name}.log")).ok()
+}
+
+/// The ID of the most recent build whose log directory has the given
+/// file, if any build has one
+pub fn latest_build_with(log_dir: impl AsRef<Path>, file_name: &str) -> io::Result<Option<String>> {
+    let log_dir = log_dir.as_ref();
+    if !log_dir.exists() {
+        return Ok(None);
+    }
+    let mut latest: Option<(SystemTime, String)> = None;
+    for entry in read_dir(log_dir)? {
+        let entry = entry?;
+        let Ok(file) = metadata(entry.path().join(file_name)) else {
+            continue;
+        };
+        let modified = file.modified()?;
+        if latest.as_ref().is_none_or(|(time, _)| modified > *time) {
+            latest = Some((modified, entry.file_name().to_string_lossy().to_string()));
+        }
+    }
+    Ok(latest.map(|(_, build_id)| build_id))
+}
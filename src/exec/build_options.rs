@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::sync::Arc;
+use std::thread::available_parallelism;
+use std::time::Duration;
+
+use crate::ast::hexmake_file::{RuleName, SourceTreeSettings};
+use crate::exec::command_logger::Verbosity;
+use crate::exec::events::BuildEvents;
+use crate::exec::work_list::Schedule;
+use crate::file_system::output_times::OutputTimes;
+
+/// Options that control how the conductor runs a build
+#[derive(Default)]
+pub struct BuildOptions {
+    /// The ID of this build, for matching up its logs and events with
+    /// those of other builds
+    pub build_id: Arc<String>,
+
+    /// Rules that must be rebuilt even if the cache has their outputs
+    pub forced_rules: BTreeSet<RuleName>,
+
+    /// Whether every rule must be rebuilt, ignoring the cache
+    pub force_all: bool,
+
+    /// How many tasks to run at once, or None for one per CPU
+    pub jobs: Option<u32>,
+
+    /// The system load average above which no more tasks are started
+    /// while others are running
+    pub load_average: Option<f64>,
+
+    /// How long the build may run before it is cancelled
+    pub timeout: Option<Duration>,
+
+    /// Which task to start first among those that are ready
+    pub schedule: Schedule,
+
+    /// Which files in a directory input are copied into work directories
+    pub source_trees: SourceTreeSettings,
+
+    /// What modification time outputs get when they are copied out of
+    /// work directories
+    pub output_times: OutputTimes,
+
+    /// The maximum number of tasks that can run at once in each pool
+    pub pools: BTreeMap<Arc<String>, usize>,
+
+    /// A shell command to run if a build command fails
+    pub on_failure: Option<String>,
+
+    /// Whether to keep building the rules that do not depend on a failed
+    /// one, instead of stopping at the first failure
+    pub keep_going: bool,
+
+    /// The percentage of cache hits to rebuild and compare with the
+    /// cache, or None to trust every hit
+    pub audit_cache: Option<u32>,
+
+    /// The observers that are told about the progress of the build
+    pub events: BuildEvents,
+
+    /// How much to print about each rule
+    pub verbosity: Verbosity,
+
+    /// Whether to leave showing progress to the observers, instead of
+    /// printing a line for each step
+    pub quiet: bool,
+}
+
+/// The environment variable that holds the ID of the build. If it is
+/// already set when Hexmake starts, such as by a CI job or by an outer
+/// Hexmake build, that ID is used for this build too.
+pub const BUILD_ID_VAR: &str = "HEXMAKE_BUILD_ID";
+
+/// Find the ID for a new build: the one in [BUILD_ID_VAR] if it is set,
+/// or else a new random one
+pub fn new_build_id() -> Arc<String> {
+    if let Ok(build_id) = env::var(BUILD_ID_VAR)
+        && !build_id.is_empty()
+    {
+        return Arc::new(build_id);
+    }
+    let bytes: [u8; 8] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .map(|random| random.expose())
+        .unwrap_or_default();
+    Arc::new(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+impl BuildOptions {
+    /// How many tasks to run at once: the requested number, or else one
+    /// per logical CPU
+    pub fn job_count(&self) -> u32 {
+        match self.jobs {
+            Some(jobs) => jobs,
+            None => available_parallelism().map_or(1, |cpus| cpus.get() as u32),
+        }
+    }
+
+    /// Whether the given rule should skip the cache lookup and be rebuilt
+    pub fn is_forced(&self, rule_name: &RuleName) -> bool {
+        self.force_all || self.forced_rules.contains(rule_name)
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use flate2::read::GzDecoder;
+use fs_err::{File, create_dir_all, read_dir, read_to_string, set_permissions, write};
+
+use crate::ast::hexmake_file::{HexRule, RuleKind};
+use crate::cache::build_hash::sha256_hex;
+use crate::download::download;
+use crate::environment::BuildEnvironment;
+use crate::exec::command_logger::CommandLogger;
+use crate::exec::work_dir::copy_tree;
+use crate::file_system::reflink::copy_file;
+
+/// Build a rule whose kind is built into Hexmake, rather than running
+/// shell commands. The rule's inputs are available under `root`, and
+/// its outputs are written there.
+pub fn run_builtin(
+    rule: &HexRule,
+    root: &Path,
+    env: &BuildEnvironment,
+    command_logger: &CommandLogger,
+) -> io::Result<()> {
+    match rule.kind {
+        RuleKind::Command => unreachable!("Rule `{}` runs commands", rule.name),
+        RuleKind::HttpFile => fetch_http_file(rule, root, command_logger),
+        RuleKind::Extract => extract_archives(rule, root, command_logger),
+        RuleKind::Copy => copy_input(rule, root),
+        RuleKind::Write => write(root.join(&rule.outputs[0]), field(&rule.content)),
+        RuleKind::Concat => concat_inputs(rule, root),
+        RuleKind::Mkdir => create_dir_all(root.join(&rule.outputs[0])),
+        RuleKind::Template => fill_template(rule, root, env),
+    }
+}
+
+/// Describe what a built-in rule does, in place of listing its commands
+pub fn describe_builtin(rule: &HexRule) -> String {
+    match rule.kind {
+        RuleKind::Command => String::new(),
+        RuleKind::HttpFile => format!(
+            "Download {} (SHA-256 {})",
+            field(&rule.url),
+            field(&rule.sha256)
+        ),
+        RuleKind::Extract => format!(
+            "Extract {} into {}",
+            itertools::join(&rule.inputs, " "),
+            rule.outputs[0]
+        ),
+        RuleKind::Copy => format!("Copy {} to {}", rule.inputs[0], rule.outputs[0]),
+        RuleKind::Write => format!("Write {:?} to {}", field(&rule.content), rule.outputs[0]),
+        RuleKind::Concat => format!(
+            "Concatenate {} into {}",
+            itertools::join(&rule.inputs, " "),
+            rule.outputs[0]
+        ),
+        RuleKind::Mkdir => format!("Make directory {}", rule.outputs[0]),
+        RuleKind::Template => format!(
+            "Fill in template {} to make {}",
+            rule.inputs[0], rule.outputs[0]
+        ),
+    }
+}
+
+/// Download a URL into the rule's output, checking its hash
+fn fetch_http_file(rule: &HexRule, root: &Path, command_logger: &CommandLogger) -> io::Result<()> {
+    let url = field(&rule.url);
+    let expected = field(&rule.sha256).to_ascii_lowercase();
+
+    command_logger.log_message(&format!("Downloading {url}"), &rule.name);
+    let contents = download(url)?;
+
+    let actual = sha256_hex(&contents);
+    if actual != expected {
+        return Err(io::Error::other(format!(
+            "Download of {url} has SHA-256 {actual}, but the rule expects {expected}"
+        )));
+    }
+
+    write(root.join(&rule.outputs[0]), contents)
+}
+
+/// Copy the rule's input, which may be a file or a directory tree, to
+/// its output
+fn copy_input(rule: &HexRule, root: &Path) -> io::Result<()> {
+    let source = root.join(&rule.inputs[0]);
+    let destination = root.join(&rule.outputs[0]);
+    if source.is_dir() {
+        copy_tree(&source, &destination)
+    } else {
+        copy_file(&source, &destination)
+    }
+}
+
+/// Write the contents of the rule's inputs, one after another, to its output
+fn concat_inputs(rule: &HexRule, root: &Path) -> io::Result<()> {
+    let mut output = File::create(root.join(&rule.outputs[0]))?;
+    for input in &rule.inputs {
+        io::copy(&mut File::open(root.join(input))?, &mut output)?;
+    }
+    Ok(())
+}
+
+/// Fill in the rule's template input to make its output
+fn fill_template(rule: &HexRule, root: &Path, env: &BuildEnvironment) -> io::Result<()> {
+    let template = read_to_string(root.join(&rule.inputs[0]))?;
+    let output = expand_template(&template, &rule.substitutions, env);
+    write(root.join(&rule.outputs[0]), output)
+}
+
+/// Replace each `${name}` in a template with its substitution, and each
+/// `$ENV{NAME}` with the value of an environment variable from the
+/// Hexmake file's `env` field. Anything else, including a `${name}`
+/// with no substitution, is left as it is.
+fn expand_template(
+    template: &str,
+    substitutions: &BTreeMap<String, String>,
+    env: &BuildEnvironment,
+) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        result.push_str(&rest[..dollar]);
+        rest = &rest[dollar..];
+
+        match template_reference(rest, substitutions, env) {
+            Some((value, length)) => {
+                result.push_str(value);
+                rest = &rest[length..];
+            }
+            None => {
+                result.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// If `text` starts with a reference that a template can fill in, return
+/// the value to fill in and the length of the reference
+fn template_reference<'a>(
+    text: &str,
+    substitutions: &'a BTreeMap<String, String>,
+    env: &'a BuildEnvironment,
+) -> Option<(&'a str, usize)> {
+    let end = text.find('}')?;
+    if let Some(name) = text[..end].strip_prefix("$ENV{") {
+        let value = env.vars.get(&String::from(name));
+        Some((value.map_or("", |value| value.as_str()), end + 1))
+    } else if let Some(name) = text[..end].strip_prefix("${") {
+        Some((substitutions.get(name)?.as_str(), end + 1))
+    } else {
+        None
+    }
+}
+
+/// Unpack each of the rule's archive inputs into its output directory.
+/// Permissions and timestamps are normalized, so that unpacking the same
+/// archives always gives the same tree.
+fn extract_archives(rule: &HexRule, root: &Path, command_logger: &CommandLogger) -> io::Result<()> {
+    let output_dir = root.join(&rule.outputs[0]);
+    create_dir_all(&output_dir)?;
+
+    for input in &rule.inputs {
+        command_logger.log_message(&format!("Extracting {input}"), &rule.name);
+        let archive = File::open(root.join(input))?;
+        let extractor = Extractor {
+            output_dir: &output_dir,
+            strip_components: rule.strip_components,
+        };
+        if input.ends_with(".zip") {
+            extractor.extract_zip(archive)?;
+        } else if input.ends_with(".tar.gz") || input.ends_with(".tgz") {
+            extractor.extract_tar(GzDecoder::new(archive))?;
+        } else if input.ends_with(".tar") {
+            extractor.extract_tar(archive)?;
+        } else {
+            return Err(io::Error::other(format!(
+                "Input `{input}` is not a .tar, .tar.gz, .tgz, or .zip archive"
+            )));
+        }
+    }
+
+    set_times_to_epoch(&output_dir)
+}
+
+/// Unpacks archives into one directory
+struct Extractor<'a> {
+    output_dir: &'a Path,
+    strip_components: usize,
+}
+
+impl Extractor<'_> {
+    /// Unpack a tar archive, which may be compressed
+    fn extract_tar(&self, archive: impl Read) -> io::Result<()> {
+        let mut archive = tar::Archive::new(archive);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let executable = entry.header().mode()? & 0o111 != 0;
+                    self.write_file(&path, &mut entry, executable)?;
+                }
+                tar::EntryType::Directory => self.make_dir(&path)?,
+                // Extended headers that apply to the whole archive, such
+                // as the commit ID that `git archive` records
+                tar::EntryType::XGlobalHeader => {}
+                entry_type => {
+                    return Err(io::Error::other(format!(
+                        "Archive entry `{}` has type {entry_type:?}, which cannot be extracted",
+                        path.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unpack a zip archive
+    fn extract_zip(&self, archive: File) -> io::Result<()> {
+        let mut archive = zip::ZipArchive::new(archive).map_err(io::Error::other)?;
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index).map_err(io::Error::other)?;
+            let path = PathBuf::from(file.name());
+            if file.is_symlink() {
+                return Err(io::Error::other(format!(
+                    "Archive entry `{}` is a symbolic link, which cannot be extracted",
+                    path.display()
+                )));
+            }
+            if file.is_dir() {
+                self.make_dir(&path)?;
+            } else {
+                let executable = file.unix_mode().unwrap_or(0) & 0o111 != 0;
+                self.write_file(&path, &mut file, executable)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write one file from an archive
+    fn write_file(
+        &self,
+        path: &Path,
+        contents: &mut impl Read,
+        executable: bool,
+    ) -> io::Result<()> {
+        let Some(destination) = self.destination(path)? else {
+            return Ok(());
+        };
+        if let Some(parent) = destination.parent() {
+            create_dir_all(parent)?;
+        }
+        io::copy(contents, &mut File::create(&destination)?)?;
+        let mode = if executable { 0o755 } else { 0o644 };
+        set_permissions(&destination, PermissionsExt::from_mode(mode))
+    }
+
+    /// Make one directory from an archive
+    fn make_dir(&self, path: &Path) -> io::Result<()> {
+        match self.destination(path)? {
+            Some(destination) => create_dir_all(destination),
+            None => Ok(()),
+        }
+    }
+
+    /// Where an entry in an archive should be written, after removing
+    /// `strip_components` leading directories. Returns None for entries
+    /// that are removed completely.
+    fn destination(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => components.push(name),
+                Component::CurDir => {}
+                _ => {
+                    return Err(io::Error::other(format!(
+                        "Archive entry `{}` is outside of the output directory",
+                        path.display()
+                    )));
+                }
+            }
+        }
+
+        if components.len() <= self.strip_components {
+            return Ok(None);
+        }
+        let mut destination = self.output_dir.to_path_buf();
+        destination.extend(&components[self.strip_components..]);
+        Ok(Some(destination))
+    }
+}
+
+/// Set the modification time of everything in a tree to the Unix epoch
+fn set_times_to_epoch(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        for entry in read_dir(path)? {
+            set_times_to_epoch(&entry?.path())?;
+        }
+    }
+    File::open(path)?.file().set_modified(UNIX_EPOCH)
+}
+
+/// The value of a field that `check_file` has already made sure is present
+fn field(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve one HTTP response with the given body, and return the URL
+    /// to fetch it from
+    fn serve_once(body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.txt", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let count = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..count]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_fetch_http_file() {
+        let test_dir = ".hex/test/fetch_http_file";
+        let _ = std::fs::remove_dir_all(test_dir);
+        std::fs::create_dir_all(format!("{test_dir}/out")).unwrap();
+
+        let hello_hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let mut rule = HexRule::new("hello".into());
+        rule.kind = RuleKind::HttpFile;
+        rule.outputs = vec![HexPath::try_from("out/hello.txt").unwrap()];
+        rule.url = Some(serve_once(b"hello"));
+        rule.sha256 = Some(hello_hash.to_string());
+
+        run_builtin(
+            &rule,
+            Path::new(test_dir),
+            &BuildEnvironment::default(),
+            &CommandLogger::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read(format!("{test_dir}/out/hello.txt")).unwrap(),
+            b"hello"
+        );
+
+        // A download that does not match its hash is an error
+        rule.url = Some(serve_once(b"goodbye"));
+        let error = run_builtin(
+            &rule,
+            Path::new(test_dir),
+            &BuildEnvironment::default(),
+            &CommandLogger::default(),
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with(&format!("but the rule expects {hello_hash}")),
+            "unexpected error: {error}"
+        );
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_extract_archives() {
+        use std::io::Cursor;
+        use std::os::unix::fs::MetadataExt;
+
+        let test_dir = ".hex/test/extract_archives";
+        let _ = std::fs::remove_dir_all(test_dir);
+        std::fs::create_dir_all(test_dir).unwrap();
+
+        // A gzipped tarball with everything under a top-level directory
+        let mut tar_builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        for (path, mode, contents) in [
+            ("zlib-1.3/README", 0o600, &b"readme"[..]),
+            ("zlib-1.3/configure", 0o700, &b"#!/bin/sh"[..]),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(mode);
+            header.set_mtime(1234567890);
+            tar_builder
+                .append_data(&mut header, path, Cursor::new(contents))
+                .unwrap();
+        }
+        let tarball = tar_builder.into_inner().unwrap().finish().unwrap();
+        std::fs::write(format!("{test_dir}/zlib.tar.gz"), tarball).unwrap();
+
+        // A zip file
+        let mut zip_writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip_writer
+            .start_file("extra/notes.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip_writer.write_all(b"notes").unwrap();
+        let zip_file = zip_writer.finish().unwrap().into_inner();
+        std::fs::write(format!("{test_dir}/extra.zip"), zip_file).unwrap();
+
+        let mut rule = HexRule::new("zlib".into());
+        rule.kind = RuleKind::Extract;
+        rule.inputs = vec![
+            HexPath::try_from("zlib.tar.gz").unwrap(),
+            HexPath::try_from("extra.zip").unwrap(),
+        ];
+        rule.outputs = vec![HexPath::try_from("out/zlib").unwrap()];
+        rule.strip_components = 1;
+        run_builtin(
+            &rule,
+            Path::new(test_dir),
+            &BuildEnvironment::default(),
+            &CommandLogger::default(),
+        )
+        .unwrap();
+
+        let output_dir = format!("{test_dir}/out/zlib");
+        let metadata = |path: &str| std::fs::metadata(format!("{output_dir}/{path}")).unwrap();
+        assert_eq!(
+            std::fs::read(format!("{output_dir}/README")).unwrap(),
+            b"readme"
+        );
+        assert_eq!(
+            std::fs::read(format!("{output_dir}/notes.txt")).unwrap(),
+            b"notes"
+        );
+        assert_eq!(metadata("README").mode() & 0o777, 0o644);
+        assert_eq!(metadata("configure").mode() & 0o777, 0o755);
+        assert_eq!(metadata("README").mtime(), 0);
+        assert_eq!(metadata("").mtime(), 0);
+
+        // Archives cannot write outside of the output directory
+        let mut zip_writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip_writer
+            .start_file("../evil.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        let zip_file = zip_writer.finish().unwrap().into_inner();
+        std::fs::write(format!("{test_dir}/evil.zip"), zip_file).unwrap();
+        rule.inputs = vec![HexPath::try_from("evil.zip").unwrap()];
+        rule.strip_components = 0;
+        assert_eq!(
+            run_builtin(
+                &rule,
+                Path::new(test_dir),
+                &BuildEnvironment::default(),
+                &CommandLogger::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "Archive entry `../evil.txt` is outside of the output directory"
+        );
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_file_primitives() {
+        let test_dir = ".hex/test/file_primitives";
+        let _ = std::fs::remove_dir_all(test_dir);
+        std::fs::create_dir_all(format!("{test_dir}/out")).unwrap();
+        std::fs::write(format!("{test_dir}/a.txt"), b"a\n").unwrap();
+        std::fs::write(format!("{test_dir}/b.txt"), b"b\n").unwrap();
+
+        let run = |kind: RuleKind, inputs: &[&str], output: &str, content: Option<&str>| {
+            let mut rule = HexRule::new(output.into());
+            rule.kind = kind;
+            rule.inputs = inputs
+                .iter()
+                .map(|input| HexPath::try_from(*input).unwrap())
+                .collect();
+            rule.outputs = vec![HexPath::try_from(output).unwrap()];
+            rule.content = content.map(str::to_string);
+            run_builtin(
+                &rule,
+                Path::new(test_dir),
+                &BuildEnvironment::default(),
+                &CommandLogger::default(),
+            )
+            .unwrap();
+        };
+        let read = |path: &str| std::fs::read_to_string(format!("{test_dir}/{path}")).unwrap();
+
+        run(RuleKind::Copy, &["a.txt"], "out/copy.txt", None);
+        assert_eq!(read("out/copy.txt"), "a\n");
+
+        run(RuleKind::Write, &[], "out/version.txt", Some("1.2.3"));
+        assert_eq!(read("out/version.txt"), "1.2.3");
+
+        run(RuleKind::Concat, &["a.txt", "b.txt"], "out/both.txt", None);
+        assert_eq!(read("out/both.txt"), "a\nb\n");
+
+        run(RuleKind::Mkdir, &[], "out/empty", None);
+        assert!(
+            std::fs::read_dir(format!("{test_dir}/out/empty"))
+                .unwrap()
+                .next()
+                .is_none()
+        );
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let substitutions = BTreeMap::from([("VERSION".to_string(), "1.2.3".to_string())]);
+        let mut env = BuildEnvironment::default();
+        env.vars
+            .insert("USER".to_string().into(), "alice".to_string().into());
+
+        assert_eq!(
+            expand_template(
+                "#define VERSION \"${VERSION}\"\n#define BUILT_BY \"$ENV{USER}\"\n",
+                &substitutions,
+                &env
+            ),
+            "#define VERSION \"1.2.3\"\n#define BUILT_BY \"alice\"\n"
+        );
+
+        // Unknown names and other uses of `$` are left alone, and unset
+        // variables are empty
+        assert_eq!(
+            expand_template("$HOME ${OTHER} $ENV{UNSET}. $", &substitutions, &env),
+            "$HOME ${OTHER} . $"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use fs_err::{read, read_dir};
+
+use crate::ast::hex_path::HexPath;
+use crate::cache::build_hash::sha256_hex;
+
+/// Decide at random whether to audit a cache hit, so that about `percent`
+/// out of every hundred hits are audited. Over many builds, every rule
+/// gets audited now and then.
+pub fn is_sampled(percent: u32) -> bool {
+    let random: [u8; 4] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .map(|random| random.expose())
+        .unwrap_or_default();
+    u32::from_le_bytes(random) % 100 < percent
+}
+
+/// The files of a rule's outputs whose SHA-256 hashes differ between the
+/// directory where the rule was rebuilt, usually a work directory, and
+/// the one that holds the outputs from the cache, usually the workspace.
+/// A file that is only in one of them differs too.
+pub fn differing_outputs(
+    rebuilt_dir: &Path,
+    cached_dir: &Path,
+    outputs: &[HexPath],
+) -> io::Result<Vec<String>> {
+    let mut result = Vec::new();
+    for output in outputs {
+        let rebuilt = output_hashes(&rebuilt_dir.join(output.as_ref()), output)?;
+        let cached = output_hashes(&cached_dir.join(output.as_ref()), output)?;
+        for path in rebuilt.keys().chain(cached.keys()) {
+            if rebuilt.get(path) != cached.get(path) && !result.contains(path) {
+                result.push(path.clone());
+            }
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// The SHA-256 hash of each file of an output, which may be a file or a
+/// directory, by its path in the workspace. An output that does not
+/// exist has no files.
+fn output_hashes(path: &Path, name: &str) -> io::Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    if path.is_dir() {
+        for entry in read_dir(path)? {
+            let entry = entry?;
+            let name = format!("{name}/{}", entry.file_name().to_string_lossy());
+            hashes.extend(output_hashes(&entry.path(), &name)?);
+        }
+    } else if path.exists() {
+        hashes.insert(name.to_string(), sha256_hex(&read(path)?));
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sampled() {
+        assert!((0..100).all(|_| is_sampled(100)));
+        assert!((0..100).all(|_| !is_sampled(0)));
+    }
+
+    #[test]
+    fn test_differing_outputs() {
+        let test_dir = Path::new(".hex/test/cache_audit");
+        let _ = std::fs::remove_dir_all(test_dir);
+        let write = |path: &str, contents: &str| {
+            let path = test_dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+
+        // The cached outputs, and the rebuilt ones in a work directory
+        write("out/main", "binary");
+        write("out/docs/index.html", "<html>");
+        write("out/docs/built-at.txt", "12:00");
+        write("work/out/main", "binary");
+        write("work/out/docs/index.html", "<html>");
+        write("work/out/docs/built-at.txt", "12:05");
+        write("work/out/docs/extra.html", "");
+
+        let outputs = [
+            HexPath::try_from("out/main").unwrap(),
+            HexPath::try_from("out/docs").unwrap(),
+        ];
+        assert_eq!(
+            differing_outputs(&test_dir.join("work"), test_dir, &outputs).unwrap(),
+            vec!["out/docs/built-at.txt", "out/docs/extra.html"]
+        );
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, spawn};
+
+use crossbeam_channel::{Sender, unbounded};
+
+use crate::ast::hexmake_file::HexRule;
+use crate::cache::build_cache::BuildCache;
+use crate::exec::command_logger::CommandLogger;
+
+/// Inserts the outputs of built rules into the cache on a background
+/// thread. The outputs are already in `out/`, so the tasks that depend on
+/// a rule can start right away, while its outputs are hashed and copied
+/// into the cache alongside the rest of the build.
+pub struct CacheInserter {
+    /// Rules to insert, or None to stop
+    sender: Sender<Option<Arc<HexRule>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl CacheInserter {
+    pub fn start(build_cache: Arc<BuildCache>, command_logger: CommandLogger) -> CacheInserter {
+        let (sender, receiver) = unbounded::<Option<Arc<HexRule>>>();
+        let thread = spawn(move || {
+            while let Ok(Some(rule)) = receiver.recv() {
+                // The rule has already been built, so a failure here only
+                // means that it will have to be built again next time
+                if let Err(error) = build_cache.insert_outputs(&rule) {
+                    command_logger.log_error(
+                        &format!("Warning: could not add the outputs to the cache: {error}"),
+                        &rule.name,
+                    );
+                }
+            }
+        });
+        CacheInserter {
+            sender,
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    /// Queue the outputs of a rule that was just built
+    pub fn insert(&self, rule: Arc<HexRule>) {
+        let _ = self.sender.send(Some(rule));
+    }
+
+    /// Wait for every rule queued so far to be inserted, and stop the
+    /// background thread
+    pub fn finish(&self) {
+        let _ = self.sender.send(None);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use crate::cache::build_cache::CacheRetrieval;
+    use crate::cache::cache_options::CacheOptions;
+    use crate::environment::BuildEnvironment;
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::file_system::vfs::VirtualFileSystem;
+
+    #[test]
+    fn test_cache_inserter() {
+        let vfs = FakeFileSystem::default();
+        let output = HexPath::try_from("out/test.txt").unwrap();
+        vfs.write(&output, b"result").unwrap();
+        let env = Arc::new(BuildEnvironment::default());
+        let build_cache =
+            Arc::new(BuildCache::new(env, Box::new(vfs), CacheOptions::default()).unwrap());
+
+        let mut rule = HexRule::new("test".into());
+        rule.outputs = vec![output];
+        let rule = Arc::new(rule);
+
+        let cache_inserter = CacheInserter::start(build_cache.clone(), CommandLogger::default());
+        cache_inserter.insert(rule.clone());
+        cache_inserter.finish();
+        assert_eq!(
+            build_cache.retrieve_outputs(&rule).unwrap(),
+            CacheRetrieval::Hit
+        );
+
+        // Finishing twice is harmless
+        cache_inserter.finish();
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use crate::cache::build_cache::BuildCache;
+use crate::exec::build_options::BuildOptions;
+use crate::exec::cache_audit::is_sampled;
+use crate::graph::planner::BuildPlan;
+use crate::graph::task::Task;
+
+/// Look up every task of a plan in the cache before any of them run, and
+/// leave out the tasks whose outputs are not needed. A task's outputs are
+/// not needed if every task that uses them is a cache hit and the task is
+/// not a target itself. For example, if the final link of a program is
+/// cached, then its object files do not have to be compiled or even
+/// retrieved from the cache.
+///
+/// A task can only be looked up if its inputs are known. Inputs that
+/// other tasks produce are known if those tasks are cache hits, in which
+/// case their cached contents are used. The tasks that are hits record
+/// the hash they were found under, so they can be retrieved without their
+/// inputs.
+///
+/// The hits that `--audit-cache` will rebuild are chosen here too, since
+/// they do need their inputs.
+pub fn prune_cached_subtrees(
+    plan: &BuildPlan,
+    build_cache: &BuildCache,
+    options: &BuildOptions,
+) -> Result<BuildPlan, io::Error> {
+    let order = plan.topological_order();
+
+    // Look up each task, after the tasks it depends on. If one of those
+    // is not a hit, then the files in the workspace say nothing about what
+    // its outputs will be.
+    let mut cached_outputs = BTreeMap::new();
+    let mut hits = BTreeSet::new();
+    for rule_name in &order {
+        if options.is_forced(rule_name) {
+            continue;
+        }
+        let mut task = plan.tasks[rule_name].lock().unwrap();
+        task.audit = options.audit_cache.is_some_and(is_sampled);
+        let inputs_known = task
+            .depends_on
+            .iter()
+            .all(|dep| hits.contains(&dep.lock().unwrap().rule_name()));
+        if !inputs_known {
+            continue;
+        }
+        if let Some(cached) = build_cache.lookup_outputs(&task.rule, &cached_outputs)? {
+            cached_outputs.extend(cached.outputs);
+            task.cached_hash = Some(cached.rule_hash);
+            hits.insert(rule_name.clone());
+        }
+    }
+
+    // A task is needed if it is a target, or if a task that will run its
+    // commands uses it, including a hit that will be audited. Every task
+    // that uses a task comes after it in the order, so walk it backwards.
+    let mut needed = plan.target_rules.clone();
+    for rule_name in order.iter().rev() {
+        let task = plan.tasks[rule_name].lock().unwrap();
+        if !needed.contains(rule_name) || (hits.contains(rule_name) && !task.audit) {
+            continue;
+        }
+        for dep in &task.depends_on {
+            needed.insert(dep.lock().unwrap().rule_name());
+        }
+    }
+
+    let mut tasks = BTreeMap::new();
+    for (rule_name, task) in &plan.tasks {
+        if needed.contains(rule_name) {
+            tasks.insert(rule_name.clone(), task.clone());
+            continue;
+        }
+
+        // Only hits can be left out. Their entries still count as used.
+        if let Some(rule_hash) = &task.lock().unwrap().cached_hash {
+            build_cache.touch_entry(rule_hash)?;
+        }
+        Task::remove_from_graph(task);
+    }
+
+    Ok(BuildPlan {
+        target_rules: plan.target_rules.clone(),
+        tasks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile, RuleName};
+    use crate::cache::cache_options::CacheOptions;
+    use crate::environment::BuildEnvironment;
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::file_system::vfs::VirtualFileSystem;
+    use crate::graph::planner::plan_build;
+
+    /// The names of the tasks in a plan
+    fn task_names(plan: &BuildPlan) -> Vec<RuleName> {
+        plan.tasks.keys().cloned().collect()
+    }
+
+    #[test]
+    fn test_prune_cached_subtrees() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "foo".into(),
+                    outputs: vec![HexPath::try_from("out/foo").unwrap()],
+                    inputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    commands: vec!["gcc -o out/foo out/foo.o".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "foo.o".into(),
+                    outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    inputs: vec![HexPath::try_from("foo.c").unwrap()],
+                    commands: vec!["gcc -o out/foo.o foo.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        let plan = || plan_build(&hexmake_file, &vec!["foo".to_string().into()]).unwrap();
+
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+        fake_vfs
+            .write(&HexPath::try_from("foo.c").unwrap(), b"int main;")
+            .unwrap();
+        let build_cache = BuildCache::new(
+            Arc::new(BuildEnvironment::default()),
+            vfs,
+            CacheOptions::default(),
+        )
+        .unwrap();
+        let options = BuildOptions::default();
+
+        // Nothing is cached, so everything runs
+        let pruned = prune_cached_subtrees(&plan(), &build_cache, &options).unwrap();
+        assert_eq!(task_names(&pruned), vec!["foo".into(), "foo.o".into()]);
+
+        // Cache both rules, and then lose the object file
+        let rule = |name: &str| hexmake_file.rules.iter().find(|r| *r.name.name == name);
+        let object = HexPath::try_from("out/foo.o").unwrap();
+        fake_vfs.write(&object, b"object").unwrap();
+        build_cache.insert_outputs(rule("foo.o").unwrap()).unwrap();
+        fake_vfs
+            .write(&HexPath::try_from("out/foo").unwrap(), b"binary")
+            .unwrap();
+        build_cache.insert_outputs(rule("foo").unwrap()).unwrap();
+        fake_vfs.remove_file(&object).unwrap();
+
+        // The program is a hit, so the object file is not needed
+        let pruned = prune_cached_subtrees(&plan(), &build_cache, &options).unwrap();
+        assert_eq!(task_names(&pruned), vec!["foo".into()]);
+        let foo = pruned.tasks[&RuleName::from("foo")].lock().unwrap();
+        assert!(foo.ready_to_run());
+        assert!(foo.depends_on.is_empty());
+        assert!(foo.cached_hash.is_some());
+        drop(foo);
+
+        // A stale object file in the workspace does not matter either
+        fake_vfs.write(&object, b"stale").unwrap();
+        let pruned = prune_cached_subtrees(&plan(), &build_cache, &options).unwrap();
+        assert_eq!(task_names(&pruned), vec!["foo".into()]);
+
+        // If the object file will be rebuilt, the one in the workspace
+        // cannot be used to look up the program, even if it matches
+        fake_vfs.write(&object, b"object").unwrap();
+        let options = BuildOptions {
+            forced_rules: BTreeSet::from(["foo.o".into()]),
+            ..Default::default()
+        };
+        let pruned = prune_cached_subtrees(&plan(), &build_cache, &options).unwrap();
+        assert_eq!(task_names(&pruned), vec!["foo".into(), "foo.o".into()]);
+        assert!(
+            pruned.tasks[&RuleName::from("foo")]
+                .lock()
+                .unwrap()
+                .cached_hash
+                .is_none()
+        );
+
+        // Forcing the program to rebuild needs the object file again
+        let options = BuildOptions {
+            forced_rules: BTreeSet::from(["foo".into()]),
+            ..Default::default()
+        };
+        let pruned = prune_cached_subtrees(&plan(), &build_cache, &options).unwrap();
+        assert_eq!(task_names(&pruned), vec!["foo".into(), "foo.o".into()]);
+        let foo = pruned.tasks[&RuleName::from("foo")].lock().unwrap();
+        assert_eq!(foo.depends_on.len(), 1);
+        assert!(foo.cached_hash.is_none());
+        drop(foo);
+
+        // Auditing the program rebuilds it, so it needs the object file,
+        // which can still come from the cache
+        let options = BuildOptions {
+            audit_cache: Some(100),
+            ..Default::default()
+        };
+        let pruned = prune_cached_subtrees(&plan(), &build_cache, &options).unwrap();
+        assert_eq!(task_names(&pruned), vec!["foo".into(), "foo.o".into()]);
+        let foo = pruned.tasks[&RuleName::from("foo")].lock().unwrap();
+        assert!(foo.audit);
+        assert!(foo.cached_hash.is_some());
+        assert!(foo.depends_on[0].lock().unwrap().cached_hash.is_some());
+    }
+}
@@ -1,27 +1,134 @@
 use crate::ast::hexmake_file::RuleName;
+use crate::exec::events::{BuildEvent, BuildEvents};
 use std::cell::RefCell;
 use std::io;
 use std::process::Output;
 use std::sync::{Arc, Mutex};
 
+/// How much the logger prints about a build
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors, warnings, and a summary at the end
+    Quiet,
+
+    /// Each command, messages about each rule, and the output of commands
+    #[default]
+    Normal,
+
+    /// Also the cache key of each rule, the worker that runs it, and the
+    /// resources it used
+    Verbose,
+
+    /// Also the output of commands that succeed after another one failed
+    VeryVerbose,
+}
+
 /// Logging facility for command output
 #[derive(Clone, Default)]
 pub struct CommandLogger {
     state: Arc<Mutex<RefCell<CommandLoggerState>>>,
+
+    /// Where every command and line of output is also reported
+    events: BuildEvents,
 }
 
 #[derive(Default)]
 struct CommandLoggerState {
     // Whether an error has occurred so far
     error_occurred: bool,
+
+    // How much to print
+    verbosity: Verbosity,
+
+    // Whether to leave printing to the observers, such as the TUI
+    quiet: bool,
 }
 
 impl CommandLogger {
+    pub fn new(events: BuildEvents, verbosity: Verbosity, quiet: bool) -> CommandLogger {
+        CommandLogger {
+            state: Arc::new(Mutex::new(RefCell::new(CommandLoggerState {
+                error_occurred: false,
+                verbosity,
+                quiet,
+            }))),
+            events,
+        }
+    }
+
+    /// Log that a command is about to run
+    pub fn log_command(&self, command: &str, rule_name: &RuleName) {
+        if self.printed_verbosity() >= Some(Verbosity::Normal) {
+            println!("[{rule_name}] Running: {}", command);
+        }
+        self.events.emit(BuildEvent::CommandStarted {
+            rule: rule_name.to_string(),
+            command: command.to_string(),
+        });
+    }
+
+    /// Log a message from Hexmake itself about a rule
+    pub fn log_message(&self, message: &str, rule_name: &RuleName) {
+        self.log_at(Verbosity::Normal, message, rule_name);
+    }
+
+    /// Log an error or warning about a rule, which is printed even when
+    /// the build is quiet
+    pub fn log_error(&self, message: &str, rule_name: &RuleName) {
+        self.log_at(Verbosity::Quiet, message, rule_name);
+    }
+
+    /// Log a detail about a rule that is only printed when the build is
+    /// verbose
+    pub fn log_detail(&self, message: &str, rule_name: &RuleName) {
+        self.log_at(Verbosity::Verbose, message, rule_name);
+    }
+
+    /// Log the summary at the end of a build. Only a quiet build prints
+    /// it, because otherwise each step has been printed already.
+    pub fn log_summary(&self, summary: &str) {
+        if self.printed_verbosity() == Some(Verbosity::Quiet) {
+            println!("{summary}");
+        }
+    }
+
+    /// Log a message about a rule, printing it if the verbosity is at
+    /// least `level`. Observers see every message.
+    fn log_at(&self, level: Verbosity, message: &str, rule_name: &RuleName) {
+        if self.printed_verbosity() >= Some(level) {
+            println!("[{rule_name}] {message}");
+        }
+        self.events.emit(BuildEvent::Output {
+            rule: rule_name.to_string(),
+            line: message.to_string(),
+        });
+    }
+
+    /// The verbosity of the printed output, or None if printing is left
+    /// to the observers
+    fn printed_verbosity(&self) -> Option<Verbosity> {
+        let state = self.state.lock().unwrap();
+        let state = state.borrow();
+        (!state.quiet).then_some(state.verbosity)
+    }
+
     /// Log the output that results from the given command. Suppress
     /// output from successful commands if there have been any non-successful commands.
     pub fn log_output(&self, output: &Output, rule_name: &RuleName) -> Result<(), io::Error> {
         let state = self.state.lock().unwrap();
-        state.borrow_mut().log_output(output, rule_name)
+        state.borrow_mut().log_output(output, rule_name)?;
+
+        // Observers see all of the output, even when it is not printed
+        for bytes in [&output.stderr, &output.stdout] {
+            for line in String::from_utf8_lossy(bytes).lines() {
+                self.events.emit(BuildEvent::Output {
+                    rule: rule_name.to_string(),
+                    line: line.to_string(),
+                });
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -31,8 +138,13 @@ impl CommandLoggerState {
         self.error_occurred |= !output.status.success();
 
         // Print this command if either there are no errors at all,
-        // or if this command was itself an error.
-        if !self.error_occurred || !output.status.success() {
+        // or if this command was itself an error. A quiet build only
+        // prints the output of commands that failed, and a very verbose
+        // one prints everything.
+        let printed = !output.status.success()
+            || (!self.error_occurred && self.verbosity != Verbosity::Quiet)
+            || self.verbosity == Verbosity::VeryVerbose;
+        if !self.quiet && printed {
             // Print all buffered output
             for line in str::from_utf8(&output.stderr)
                 .map_err(|_| io::Error::other("Bad UTF-8"))?
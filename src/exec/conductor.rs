@@ -1,23 +1,72 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::spawn;
+use std::time::{Duration, Instant};
 use std::{fs, io};
 
-use crate::cache::build_cache::BuildCache;
+use crate::ast::hexmake_file::RuleName;
+use crate::cache::build_cache::{BuildCache, CacheRetrieval};
+use crate::exec::build_log::{hash_breakdowns_path, resource_usage_path};
+use crate::exec::build_options::BuildOptions;
+use crate::exec::cache_inserter::CacheInserter;
+use crate::exec::cache_probe::prune_cached_subtrees;
 use crate::exec::command_logger::CommandLogger;
-use crate::exec::rule_builder::build_rule;
+use crate::exec::events::{BuildEvent, BuildEvents, TaskSummary};
+use crate::exec::failure_hook::run_failure_hook;
+use crate::exec::memory::{MEMORY_HISTORY_FILE, MemoryHistory, available_memory};
+use crate::exec::rule_builder::{CommandFailed, WorkerContext, audit_rule, build_rule};
+use crate::exec::running_commands::{ResourceUsage, RunningCommands};
+use crate::exec::volatile_status::{VOLATILE_STATUS_FILE, write_volatile_status};
 use crate::exec::work_dir::WorkDirManager;
-use crate::exec::work_list::WorkList;
+use crate::exec::work_list::{Schedule, WorkList};
 use crate::graph::planner::BuildPlan;
-use crate::graph::task::Task;
+use crate::graph::task::{Task, TaskState};
+
+/// How often a worker that is waiting for the system load to drop checks
+/// it again
+const LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Run a build plan to completion.
-pub fn conduct_build(plan: &BuildPlan, build_cache: &Arc<BuildCache>) -> Result<(), io::Error> {
-    let command_logger = CommandLogger::default();
+pub fn conduct_build(
+    plan: &BuildPlan,
+    build_cache: &Arc<BuildCache>,
+    options: &Arc<BuildOptions>,
+) -> Result<(), io::Error> {
+    let start_time = Instant::now();
+    let command_logger =
+        CommandLogger::new(options.events.clone(), options.verbosity, options.quiet);
 
     fs::create_dir_all("out")?;
+    let plan = &prune_cached_subtrees(plan, build_cache, options)?;
+    write_volatile_status(plan, VOLATILE_STATUS_FILE)?;
+
+    let workers = worker_count(options.job_count(), plan.tasks.len());
+    options.events.emit(BuildEvent::BuildStarted {
+        build_id: options.build_id.to_string(),
+        tasks: task_summaries(plan),
+        workers,
+    });
 
-    let work_list = Arc::new(Mutex::new(WorkList::default()));
+    let mut memory_history = MemoryHistory::load(MEMORY_HISTORY_FILE);
+    let work_list = Arc::new(Mutex::new(WorkList {
+        pool_limits: options.pools.clone(),
+        memory_limit: available_memory(),
+        memory_estimates: memory_estimates(plan, &memory_history),
+        schedule: options.schedule,
+        critical_path_lengths: match options.schedule {
+            Schedule::CriticalPath => plan.critical_path_lengths(),
+            Schedule::Depth | Schedule::Breadth => BTreeMap::new(),
+        },
+        ..Default::default()
+    }));
     let work_list_condvar = Arc::new(Condvar::new());
+    let cache_inserter = Arc::new(CacheInserter::start(
+        build_cache.clone(),
+        command_logger.clone(),
+    ));
+    let running_commands = RunningCommands::default();
 
     // Schedule tasks that have no dependencies
     {
@@ -30,67 +79,359 @@ pub fn conduct_build(plan: &BuildPlan, build_cache: &Arc<BuildCache>) -> Result<
     }
 
     // Start workers
-    for i in 0..4 {
+    for i in 0..workers {
         let work_list = work_list.clone();
         let work_list_condvar = work_list_condvar.clone();
         let build_cache = build_cache.clone();
+        let options = options.clone();
         let command_logger = command_logger.clone();
+        let cache_inserter = cache_inserter.clone();
+        let running_commands = running_commands.clone();
         spawn(move || {
             run_worker(
                 i,
                 work_list,
                 work_list_condvar,
                 build_cache,
+                &cache_inserter,
+                &running_commands,
+                &options,
                 &command_logger,
             )
         });
     }
 
-    wait_for_workers(work_list, work_list_condvar)?;
+    // A timeout too long to be a point in time is no timeout at all
+    let deadline = options
+        .timeout
+        .and_then(|timeout| Some((start_time.checked_add(timeout)?, timeout)));
+    let result = wait_for_workers(&work_list, work_list_condvar, deadline, &running_commands);
+    if result.is_err() {
+        report_unfinished_tasks(plan, &options.events, &command_logger);
+    }
+    cache_inserter.finish();
+    options.events.emit(BuildEvent::BuildFinished {
+        succeeded: result.is_ok(),
+        elapsed_seconds: start_time.elapsed().as_secs_f64(),
+    });
+    command_logger.log_summary(&build_summary(
+        result.is_ok(),
+        start_time.elapsed(),
+        &work_list.lock().unwrap(),
+    ));
+    if result.is_err()
+        && let Some(hook) = &options.on_failure
+        && let Some(failure) = &work_list.lock().unwrap().command_failure
+    {
+        run_failure_hook(hook, failure, &options.build_id);
+    }
+    save_measured_memory(&mut memory_history, &work_list.lock().unwrap());
+    save_resource_usage(&options.build_id, &work_list.lock().unwrap());
+    build_cache.save_touched_entries()?;
+    if let Some(path) = hash_breakdowns_path(&options.build_id) {
+        build_cache.save_hash_breakdowns(&path)?;
+    }
+    result?;
     build_cache.maybe_gc()?;
 
+    let divergences = &work_list.lock().unwrap().audit_divergences;
+    if !divergences.is_empty() {
+        return Err(io::Error::other(BuildFailed(format!(
+            "The cache audit rebuilt {} and got different outputs from the cache",
+            divergences
+                .iter()
+                .map(|rule_name| format!("`{rule_name}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))));
+    }
+
     Ok(())
 }
 
+/// The error for a build that ran but did not succeed, because a rule
+/// failed, the build timed out, or the cache audit found outputs that
+/// differ. This is wrapped in an io::Error, and it can be recovered with
+/// `downcast_ref` to tell it apart from errors that stopped Hexmake itself.
+#[derive(Debug)]
+pub struct BuildFailed(pub String);
+
+impl Display for BuildFailed {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BuildFailed {}
+
+/// The line that sums up a build: whether it succeeded, how long it took,
+/// and how many rules failed, were built, and came from the cache
+fn build_summary(succeeded: bool, elapsed: Duration, work_list: &WorkList) -> String {
+    let rules = |count: usize| match count {
+        1 => "1 rule".to_string(),
+        _ => format!("{count} rules"),
+    };
+    let mut counts = Vec::new();
+    if !work_list.failed_tasks.is_empty() {
+        counts.push(format!("{} failed", rules(work_list.failed_tasks.len())));
+    }
+    counts.push(format!("{} built", rules(work_list.built_tasks)));
+    counts.push(format!("{} from the cache", work_list.cache_hits));
+    let seconds = elapsed.as_secs_f64();
+    if succeeded {
+        format!("Build succeeded in {seconds:.1}s: {}", counts.join(", "))
+    } else {
+        format!("Build failed after {seconds:.1}s: {}", counts.join(", "))
+    }
+}
+
+/// What a worker did with a task that succeeded
+struct TaskRun {
+    /// Whether the rule's outputs came from the cache
+    cached: bool,
+
+    /// The resources that the rule's commands used, if it was built
+    usage: Option<ResourceUsage>,
+
+    /// Whether the rule was a cache hit that was audited and rebuilt with
+    /// different outputs
+    diverged: bool,
+}
+
+/// The number of workers to start: one per job, but no more than there
+/// are tasks, and at least one
+fn worker_count(jobs: u32, task_count: usize) -> u32 {
+    (jobs as usize).min(task_count).max(1) as u32
+}
+
+/// How much memory each task of a plan is expected to need
+fn memory_estimates(plan: &BuildPlan, memory_history: &MemoryHistory) -> BTreeMap<RuleName, u64> {
+    plan.tasks
+        .iter()
+        .filter_map(|(rule_name, task)| {
+            let memory = memory_history.estimate(&task.lock().unwrap().rule)?;
+            Some((rule_name.clone(), memory))
+        })
+        .collect()
+}
+
+/// Remember how much memory the rules that were just built needed, for
+/// the next build
+fn save_measured_memory(memory_history: &mut MemoryHistory, work_list: &WorkList) {
+    if work_list.measured_usage.is_empty() {
+        return;
+    }
+    for (rule_name, usage) in &work_list.measured_usage {
+        memory_history
+            .peaks
+            .insert(rule_name.to_string(), usage.peak_memory);
+    }
+    if let Err(error) = memory_history.save(MEMORY_HISTORY_FILE) {
+        eprintln!("Warning: could not write {MEMORY_HISTORY_FILE}: {error}");
+    }
+}
+
+/// Record the resources that each rule that was built used in the build's
+/// log directory, so that they can be compared from build to build
+fn save_resource_usage(build_id: &str, work_list: &WorkList) {
+    let Some(path) = resource_usage_path(build_id) else {
+        return;
+    };
+    if work_list.measured_usage.is_empty() {
+        return;
+    }
+    let usage: BTreeMap<String, ResourceUsage> = work_list
+        .measured_usage
+        .iter()
+        .map(|(rule_name, usage)| (rule_name.to_string(), *usage))
+        .collect();
+    let json = serde_json::to_string_pretty(&usage).unwrap() + "\n";
+    let result = fs::create_dir_all(Path::new(&*path).parent().unwrap())
+        .and_then(|()| fs::write(&*path, json));
+    if let Err(error) = result {
+        eprintln!("Warning: could not write {path}: {error}");
+    }
+}
+
+/// Mark the tasks that never ran because the build stopped early. A task
+/// is skipped if a task it depends on failed or was itself skipped, and
+/// otherwise it is cancelled.
+fn report_unfinished_tasks(plan: &BuildPlan, events: &BuildEvents, command_logger: &CommandLogger) {
+    // The failed task that each skipped task was waiting on
+    let mut failed_dependencies: BTreeMap<RuleName, RuleName> = BTreeMap::new();
+
+    for rule_name in plan.topological_order() {
+        let mut task = plan.tasks[&rule_name].lock().unwrap();
+        if task.state != TaskState::Pending {
+            continue;
+        }
+        let failed_dependency = task.depends_on.iter().find_map(|dep| {
+            let dep = dep.lock().unwrap();
+            match dep.state {
+                TaskState::Failed => Some(dep.rule_name()),
+                TaskState::Skipped => failed_dependencies.get(&dep.rule_name()).cloned(),
+                _ => None,
+            }
+        });
+
+        match failed_dependency {
+            Some(failed_dependency) => {
+                task.finish(TaskState::Skipped);
+                command_logger.log_message(
+                    &format!("Skipped because `{failed_dependency}` failed"),
+                    &rule_name,
+                );
+                events.emit(BuildEvent::TaskSkipped {
+                    rule: rule_name.to_string(),
+                    failed_dependency: failed_dependency.to_string(),
+                });
+                failed_dependencies.insert(rule_name, failed_dependency);
+            }
+            None => {
+                task.finish(TaskState::Cancelled);
+                command_logger.log_message("Cancelled", &rule_name);
+                events.emit(BuildEvent::TaskCancelled {
+                    rule: rule_name.to_string(),
+                });
+            }
+        }
+    }
+}
+
+/// Summarize the tasks of a plan for [BuildEvent::BuildStarted]
+fn task_summaries(plan: &BuildPlan) -> Vec<TaskSummary> {
+    plan.topological_order()
+        .iter()
+        .map(|rule_name| {
+            let task = plan.tasks[rule_name].lock().unwrap();
+            TaskSummary {
+                rule: rule_name.to_string(),
+                depends_on: task
+                    .depends_on
+                    .iter()
+                    .map(|dep| dep.lock().unwrap().rule_name().to_string())
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
 /// Run a worker that builds tasks. It will grab tasks from the WorkList,
 /// build them, and schedule new tasks that then become possible.
+#[allow(clippy::too_many_arguments)]
 fn run_worker(
     worker_id: u32,
     work_list: Arc<Mutex<WorkList>>,
     work_list_condvar: Arc<Condvar>,
     build_cache: Arc<BuildCache>,
+    cache_inserter: &CacheInserter,
+    running_commands: &RunningCommands,
+    options: &BuildOptions,
     command_logger: &CommandLogger,
 ) {
-    let work_dir = WorkDirManager::new(worker_id);
+    let worker = WorkerContext {
+        worker_id,
+        jobs: options.job_count(),
+        build_id: options.build_id.clone(),
+        work_dir: WorkDirManager::new(worker_id, options.source_trees, options.output_times),
+    };
 
     loop {
         // Grab a task from the pending list
-        let task = match get_task_from_worklist(&work_list, &work_list_condvar) {
+        let task = match get_task_from_worklist(&work_list, &work_list_condvar, options) {
             Some(value) => value,
             None => return,
         };
         let mut task = task.lock().unwrap();
+        options.events.emit(BuildEvent::TaskStarted {
+            rule: task.rule_name().to_string(),
+            worker: worker_id,
+        });
+        command_logger.log_detail(&format!("Started on worker {worker_id}"), &task.rule_name());
 
-        let build_result =
-            check_cache_or_build_now(&mut task, &build_cache, &work_dir, command_logger);
+        let build_result = check_cache_or_build_now(
+            &mut task,
+            &build_cache,
+            cache_inserter,
+            running_commands,
+            options,
+            &worker,
+            command_logger,
+        );
 
         // Remove from running tasks
         let mut work_list = work_list.lock().unwrap();
-        work_list.running_tasks.remove(&task.rule_name());
+        work_list.finish_task(&task);
 
         // Shut down if an error happened
-        if let Err(error) = build_result {
-            println!("[{}] {error}", &task.rule_name());
+        let run = match build_result {
+            Ok(run) => run,
+            Err(_) if running_commands.is_cancelled() => {
+                // The command was killed because the build was stopped
+                task.finish(TaskState::Cancelled);
+                command_logger.log_message("Cancelled", &task.rule_name());
+                options.events.emit(BuildEvent::TaskCancelled {
+                    rule: task.rule_name().to_string(),
+                });
+                work_list_condvar.notify_all();
+                return;
+            }
+            Err(error) => {
+                task.finish(TaskState::Failed);
+                command_logger.log_error(&error.to_string(), &task.rule_name());
+                options.events.emit(BuildEvent::TaskFailed {
+                    rule: task.rule_name().to_string(),
+                    error: error.to_string(),
+                });
 
-            work_list.error_occurred = true;
-            work_list.pending_tasks.clear();
+                work_list.error_occurred = true;
+                work_list.failed_tasks.push(task.rule_name());
+                if !options.keep_going {
+                    work_list.pending_tasks.clear();
+                }
+                if work_list.command_failure.is_none() {
+                    work_list.command_failure = error
+                        .get_ref()
+                        .and_then(|error| error.downcast_ref::<CommandFailed>())
+                        .cloned();
+                }
 
-            work_list_condvar.notify_all();
+                work_list_condvar.notify_all();
 
-            return;
+                // With --keep-going, the rules that depend on this one are
+                // never ready to run, but the others still are
+                if options.keep_going {
+                    continue;
+                }
+                return;
+            }
         };
 
-        // Add dependent tasks that are now ready to run
+        if run.diverged {
+            work_list.audit_divergences.push(task.rule_name());
+        }
+        if run.cached {
+            work_list.cache_hits += 1;
+        } else {
+            work_list.built_tasks += 1;
+        }
+
+        // Remember the resources that the rule's commands used. Built-in
+        // rules run no commands, so there is nothing to measure.
+        if let Some(usage) = run.usage
+            && usage.peak_memory > 0
+        {
+            command_logger.log_detail(&format!("Used {usage}"), &task.rule_name());
+            work_list.measured_usage.insert(task.rule_name(), usage);
+        }
+
+        // Add dependent tasks that are now ready to run, unless the build
+        // is stopping because a task failed or it timed out, even with
+        // --keep-going. The tasks left waiting are reported as cancelled.
+        if running_commands.is_cancelled() || (work_list.error_occurred && !options.keep_going) {
+            work_list_condvar.notify_all();
+            continue;
+        }
         for used_by in &task.used_by {
             let mut used_by_locked = used_by.lock().unwrap();
             if used_by_locked.dependency_finished() == 0 {
@@ -106,19 +447,112 @@ fn run_worker(
 fn check_cache_or_build_now(
     task: &mut Task,
     build_cache: &Arc<BuildCache>,
-    work_dir: &WorkDirManager,
+    cache_inserter: &CacheInserter,
+    running_commands: &RunningCommands,
+    options: &BuildOptions,
+    worker: &WorkerContext,
     command_logger: &CommandLogger,
-) -> Result<(), io::Error> {
-    if build_cache.retrieve_outputs(&task.rule)? {
-        println!("[{}] Retrieved outputs from cache", task.rule.name);
+) -> Result<TaskRun, io::Error> {
+    // Forced rules skip the lookup but still insert their fresh outputs
+    let retrieval = if options.is_forced(&task.rule.name) {
+        CacheRetrieval::Miss
+    } else if let Some(rule_hash) = &task.cached_hash {
+        match build_cache.retrieve_outputs_with_hash(&task.rule, rule_hash)? {
+            // The entry went away since the build started, so look up the
+            // rule again with the inputs that are in the workspace
+            CacheRetrieval::Miss => build_cache.retrieve_outputs(&task.rule)?,
+            retrieval => retrieval,
+        }
     } else {
-        build_rule(&task.rule, work_dir, command_logger, build_cache.env())?;
-        build_cache.insert_outputs(&task.rule)?;
+        build_cache.retrieve_outputs(&task.rule)?
+    };
+    match &retrieval {
+        CacheRetrieval::Evicted { error } => command_logger.log_error(
+            &format!("Warning: removed an unreadable cache entry ({error})"),
+            &task.rule.name,
+        ),
+        CacheRetrieval::Denied { error } => command_logger.log_error(
+            &format!("Warning: not allowed to read the cache entry ({error})"),
+            &task.rule.name,
+        ),
+        CacheRetrieval::Hit | CacheRetrieval::Miss => {}
+    }
+    let cached = retrieval == CacheRetrieval::Hit;
+    if let Some(rule_hash) = task
+        .cached_hash
+        .clone()
+        .or_else(|| build_cache.last_rule_hash(&task.rule))
+    {
+        command_logger.log_detail(&format!("Cache key {}", rule_hash.0), &task.rule.name);
     }
+    let mut diverged = false;
+    let outcome = if cached {
+        command_logger.log_message("Retrieved outputs from cache", &task.rule.name);
+        if task.audit {
+            diverged =
+                audit_cache_hit(task, running_commands, worker, command_logger, build_cache)?;
+        }
+        None
+    } else {
+        let outcome = build_rule(
+            &task.rule,
+            worker,
+            command_logger,
+            running_commands,
+            build_cache.env(),
+        )?;
+        if outcome.outputs_unchanged && !task.rule.outputs.is_empty() {
+            // The rules that use these outputs will find themselves in
+            // the cache, as long as their other inputs did not change
+            command_logger.log_message("Outputs are unchanged", &task.rule.name);
+        }
+        cache_inserter.insert(task.rule.clone());
+        Some(outcome)
+    };
 
-    task.build_finished();
+    task.finish(TaskState::Succeeded);
+    options.events.emit(BuildEvent::TaskFinished {
+        rule: task.rule.name.to_string(),
+        cached,
+        unchanged: outcome.is_some_and(|outcome| outcome.outputs_unchanged),
+    });
 
-    Ok(())
+    Ok(TaskRun {
+        cached,
+        usage: outcome.map(|outcome| outcome.usage),
+        diverged,
+    })
+}
+
+/// Rebuild a task whose outputs were retrieved from the cache, and report
+/// any output that came out different. Return whether any did.
+fn audit_cache_hit(
+    task: &Task,
+    running_commands: &RunningCommands,
+    worker: &WorkerContext,
+    command_logger: &CommandLogger,
+    build_cache: &BuildCache,
+) -> Result<bool, io::Error> {
+    command_logger.log_message("Auditing the cache by building again", &task.rule.name);
+    let differing = audit_rule(
+        &task.rule,
+        worker,
+        command_logger,
+        running_commands,
+        build_cache.env(),
+    )?;
+    if differing.is_empty() {
+        command_logger.log_message("Audit passed: the outputs match the cache", &task.rule.name);
+        return Ok(false);
+    }
+    command_logger.log_error(
+        &format!(
+            "Warning: audit failed: these outputs differ from the cache, and the rebuilt ones are used: {}",
+            differing.join(", ")
+        ),
+        &task.rule.name,
+    );
+    Ok(true)
 }
 
 /// Retrieve a task from the worklist. Return None if there are no more tasks
@@ -127,6 +561,7 @@ fn check_cache_or_build_now(
 fn get_task_from_worklist(
     work_list: &Arc<Mutex<WorkList>>,
     work_list_condvar: &Arc<Condvar>,
+    options: &BuildOptions,
 ) -> Option<Arc<Mutex<crate::graph::task::Task>>> {
     let mut work_list = work_list.lock().unwrap();
 
@@ -136,36 +571,197 @@ fn get_task_from_worklist(
             return None;
         }
 
-        if !work_list.pending_tasks.is_empty() {
-            // There are tasks in the list, now. Take one and return it.
-            let last_index = work_list.pending_tasks.len() - 1;
-            let task = work_list.pending_tasks.remove(last_index);
-            work_list
-                .running_tasks
-                .insert(task.lock().unwrap().rule_name());
+        if over_load_limit(options.load_average, &work_list) {
+            // The system is too busy to start another task. Check again
+            // later, or sooner if the work list changes.
+            work_list = work_list_condvar
+                .wait_timeout(work_list, LOAD_CHECK_INTERVAL)
+                .unwrap()
+                .0;
+            continue;
+        }
+
+        if let Some(task) = work_list.take_next_task() {
+            // There is a task that can run now. Return it.
             return Some(task);
         }
 
-        // There are no available tasks. Go to sleep and wait for
+        // There are no available tasks, or their pools are full. Go to sleep and wait for
         // the work list to change.
         work_list = work_list_condvar.wait(work_list).unwrap();
     }
 }
 
+/// Whether a worker should hold off on starting a task because the system
+/// load is above the limit from `--load-average`. A task can always start
+/// when no others are running, so that the build keeps making progress.
+fn over_load_limit(load_limit: Option<f64>, work_list: &WorkList) -> bool {
+    match load_limit {
+        Some(limit) if !work_list.running_tasks.is_empty() => {
+            load_average().is_some_and(|load| load > limit)
+        }
+        _ => false,
+    }
+}
+
+/// The system load average over the last minute, if it can be read
+fn load_average() -> Option<f64> {
+    let mut loads = [0.0];
+    // SAFETY: getloadavg() writes at most the given number of samples
+    let count = unsafe { libc::getloadavg(loads.as_mut_ptr(), 1) };
+    (count == 1).then_some(loads[0])
+}
+
 /// Wait for all workers to be finished. This is done by
 /// checking the work list for active and pending work.
+///
+/// If the build is still going at the deadline, which is given along with
+/// the timeout it came from, no more tasks are started and the running
+/// commands are killed, and the error names the tasks that were running.
 fn wait_for_workers(
-    work_list: Arc<Mutex<WorkList>>,
+    work_list: &Arc<Mutex<WorkList>>,
     work_list_condvar: Arc<Condvar>,
+    deadline: Option<(Instant, Duration)>,
+    running_commands: &RunningCommands,
 ) -> Result<(), io::Error> {
     let mut work_list = work_list.lock().unwrap();
+    let mut timed_out_tasks = None;
     while !work_list.pending_tasks.is_empty() || !work_list.running_tasks.is_empty() {
-        work_list = work_list_condvar.wait(work_list).unwrap();
+        let Some((deadline, _)) = deadline.filter(|_| timed_out_tasks.is_none()) else {
+            work_list = work_list_condvar.wait(work_list).unwrap();
+            continue;
+        };
+        let now = Instant::now();
+        if now < deadline {
+            work_list = work_list_condvar
+                .wait_timeout(work_list, deadline - now)
+                .unwrap()
+                .0;
+            continue;
+        }
+
+        // Time is up. Stop the build, and wait for the workers to notice.
+        timed_out_tasks = Some(
+            work_list
+                .running_tasks
+                .iter()
+                .map(|rule_name| rule_name.to_string())
+                .collect::<Vec<_>>(),
+        );
+        work_list.error_occurred = true;
+        work_list.pending_tasks.clear();
+        running_commands.cancel();
+        work_list_condvar.notify_all();
     }
 
-    if work_list.error_occurred {
-        Err(io::Error::other("BUILD FAILED"))
+    let failure = if let (Some(tasks), Some((_, timeout))) = (timed_out_tasks, deadline) {
+        format!(
+            "The build timed out after {}s. These tasks were still running: {}",
+            timeout.as_secs(),
+            tasks.join(", ")
+        )
+    } else if work_list.failed_tasks.len() > 1 {
+        format!(
+            "BUILD FAILED: {} rules failed: {}",
+            work_list.failed_tasks.len(),
+            work_list
+                .failed_tasks
+                .iter()
+                .map(|rule_name| format!("`{rule_name}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    } else if work_list.error_occurred {
+        "BUILD FAILED".to_string()
     } else {
-        Ok(())
+        return Ok(());
+    };
+    Err(io::Error::other(BuildFailed(failure)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+    use crate::exec::command_logger::Verbosity;
+    use crate::graph::planner::plan_build;
+
+    #[test]
+    fn test_build_summary() {
+        let mut work_list = WorkList {
+            built_tasks: 3,
+            cache_hits: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            build_summary(true, Duration::from_millis(2500), &work_list),
+            "Build succeeded in 2.5s: 3 rules built, 1 from the cache"
+        );
+
+        work_list.failed_tasks.push(RuleName::from("link"));
+        assert_eq!(
+            build_summary(false, Duration::from_secs(4), &work_list),
+            "Build failed after 4.0s: 1 rule failed, 3 rules built, 1 from the cache"
+        );
+    }
+
+    #[test]
+    fn test_over_load_limit() {
+        let mut work_list = WorkList::default();
+        assert!(load_average().is_some_and(|load| load >= 0.0));
+
+        // Nothing is running, so a task can start however busy the system is
+        assert!(!over_load_limit(Some(-1.0), &work_list));
+
+        work_list.running_tasks.insert("test".into());
+        assert!(over_load_limit(Some(-1.0), &work_list));
+        assert!(!over_load_limit(Some(f64::MAX), &work_list));
+        assert!(!over_load_limit(None, &work_list));
+    }
+
+    #[test]
+    fn test_report_unfinished_tasks() {
+        // `link` needs `compile`, which failed, and `test` needs `link`.
+        // `docs` was waiting for a worker.
+        let rule = |name: &str, deps: &[&str]| {
+            HexRule {
+                name: name.into(),
+                outputs: vec![HexPath::try_from(format!("out/{name}")).unwrap()],
+                deps: deps.iter().map(|&dep| dep.into()).collect(),
+                ..Default::default()
+            }
+            .into()
+        };
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                rule("compile", &[]),
+                rule("link", &["compile"]),
+                rule("test", &["link"]),
+                rule("docs", &[]),
+            ],
+            ..Default::default()
+        };
+        let plan = plan_build(
+            &hexmake_file,
+            &vec!["test".to_string().into(), "docs".to_string().into()],
+        )
+        .unwrap();
+        {
+            let mut compile = plan.tasks[&RuleName::from("compile")].lock().unwrap();
+            compile.start();
+            compile.finish(TaskState::Failed);
+        }
+
+        report_unfinished_tasks(
+            &plan,
+            &BuildEvents::default(),
+            &CommandLogger::new(BuildEvents::default(), Verbosity::Normal, true),
+        );
+        let state = |name: &str| plan.tasks[&RuleName::from(name)].lock().unwrap().state;
+        assert_eq!(state("compile"), TaskState::Failed);
+        assert_eq!(state("link"), TaskState::Skipped);
+        assert_eq!(state("test"), TaskState::Skipped);
+        assert_eq!(state("docs"), TaskState::Cancelled);
     }
 }
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::env::consts::{ARCH, OS};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use fs_err::{create_dir_all, read_dir, remove_dir_all, write};
+use serde::Serialize;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::HexmakeFile;
+use crate::environment::BuildEnvironment;
+use crate::toolchain::ResolvedTool;
+
+/// The name of the snapshot file in a build's log directory
+const SNAPSHOT_FILE: &str = "env.json";
+
+/// How many builds keep their snapshot. The snapshots of older builds are
+/// removed when a new one is written.
+const KEPT_SNAPSHOTS: usize = 20;
+
+/// What a build saw of the machine it ran on: everything that comes from
+/// outside the workspace and can make the same sources build differently.
+/// Comparing the snapshot of a CI build with `hexmake env` on another
+/// machine shows why the two builds differ.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct EnvSnapshot {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    pub hexmake_version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+
+    /// The variables listed in `env`, with null for ones that are not set
+    pub env: BTreeMap<String, Option<String>>,
+
+    /// The variables listed in `secret_environ`, with their values
+    /// redacted, and null for ones that are not set
+    pub secret_environ: BTreeMap<String, Option<&'static str>>,
+
+    /// The values of the build parameters in `defines`
+    pub defines: BTreeMap<String, String>,
+
+    /// Where each tool in `toolchains` is, and its hash
+    pub toolchains: BTreeMap<String, ResolvedTool>,
+}
+
+impl EnvSnapshot {
+    pub fn new(
+        hexmake_file: &HexmakeFile,
+        env: &BuildEnvironment,
+        tools: &BTreeMap<Arc<String>, ResolvedTool>,
+    ) -> EnvSnapshot {
+        EnvSnapshot {
+            build_id: None,
+            hexmake_version: env!("CARGO_PKG_VERSION"),
+            os: OS,
+            arch: ARCH,
+            env: hexmake_file
+                .env
+                .iter()
+                .map(|name| (name.to_string(), env.vars.get(name).map(|v| v.to_string())))
+                .collect(),
+            secret_environ: hexmake_file
+                .secret_environ
+                .iter()
+                .map(|name| {
+                    let value = env.secrets.contains_key(name).then_some("[REDACTED]");
+                    (name.to_string(), value)
+                })
+                .collect(),
+            defines: hexmake_file
+                .defines
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            toolchains: tools
+                .iter()
+                .map(|(name, tool)| (name.to_string(), tool.clone()))
+                .collect(),
+        }
+    }
+
+    /// The snapshot as pretty-printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Write the snapshot to `<log_dir>/<build-id>/env.json`, and remove
+    /// the snapshots of all but the most recent builds. A build whose ID
+    /// is not a plain directory name, such as `..` or an absolute path,
+    /// has nowhere to save its snapshot.
+    pub fn write(&self, log_dir: impl AsRef<Path>) -> io::Result<()> {
+        let log_dir = log_dir.as_ref();
+        let build_id = self.build_id.as_deref().unwrap_or("unknown");
+        if build_id.contains('/') || HexPath::try_from(build_id).is_err() {
+            return Ok(());
+        }
+        let build_dir = log_dir.join(build_id);
+        create_dir_all(&build_dir)?;
+        write(build_dir.join(SNAPSHOT_FILE), self.to_json())?;
+        prune_snapshots(log_dir, KEPT_SNAPSHOTS)
+    }
+}
+
+/// Remove the log directories of old builds, along with the logs of their
+/// failed commands, keeping the `keep` ones whose snapshots were written
+/// most recently. Files directly in the log directory are left alone.
+fn prune_snapshots(log_dir: &Path, keep: usize) -> io::Result<()> {
+    let mut build_dirs = Vec::new();
+    for entry in read_dir(log_dir)? {
+        let path = entry?.path();
+        if let Ok(metadata) = fs_err::metadata(path.join(SNAPSHOT_FILE)) {
+            build_dirs.push((metadata.modified()?, path));
+        }
+    }
+    build_dirs.sort();
+    let excess = build_dirs.len().saturating_sub(keep);
+    for (_, path) in &build_dirs[..excess] {
+        remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn test_env_snapshot() {
+        let hexmake_file = HexmakeFile {
+            env: vec![Arc::new("CC".into()), Arc::new("CFLAGS".into())],
+            secret_environ: vec![Arc::new("TOKEN".into()), Arc::new("PASSWORD".into())],
+            defines: [(Arc::new("mode".into()), "release".into())].into(),
+            ..Default::default()
+        };
+        let env = BuildEnvironment {
+            vars: [(Arc::new("CC".into()), Arc::new("clang".into()))].into(),
+            secrets: [(Arc::new("TOKEN".into()), Arc::new("hunter2".into()))].into(),
+        };
+        let tools = [(
+            Arc::new("protoc".to_string()),
+            ResolvedTool {
+                path: "/work/.hex/tools/protoc".into(),
+                sha256: "abc123".into(),
+            },
+        )]
+        .into();
+
+        let snapshot = EnvSnapshot::new(&hexmake_file, &env, &tools);
+        assert_eq!(
+            snapshot.env,
+            [
+                ("CC".to_string(), Some("clang".to_string())),
+                ("CFLAGS".to_string(), None)
+            ]
+            .into()
+        );
+        assert_eq!(
+            snapshot.secret_environ,
+            [
+                ("PASSWORD".to_string(), None),
+                ("TOKEN".to_string(), Some("[REDACTED]"))
+            ]
+            .into()
+        );
+        assert!(!snapshot.to_json().contains("hunter2"));
+        assert!(!snapshot.to_json().contains("build_id"));
+        assert_eq!(snapshot.defines["mode"], "release");
+        assert_eq!(snapshot.toolchains["protoc"].sha256, "abc123");
+    }
+
+    #[test]
+    fn test_write_with_unsafe_build_id() {
+        let test_dir = Path::new(".hex/test/unsafe_build_id");
+        let _ = std::fs::remove_dir_all(test_dir);
+        let log_dir = test_dir.join("log");
+        let outside = std::env::temp_dir().join("hexmake-unsafe-build-id");
+        let _ = std::fs::remove_dir_all(&outside);
+
+        let mut snapshot = EnvSnapshot::new(
+            &HexmakeFile::default(),
+            &Default::default(),
+            &Default::default(),
+        );
+        for build_id in ["../escaped", "..", outside.to_str().unwrap(), "a/b"] {
+            snapshot.build_id = Some(build_id.to_string());
+            snapshot.write(&log_dir).unwrap();
+        }
+        assert!(!test_dir.join("escaped").exists());
+        assert!(!test_dir.join(SNAPSHOT_FILE).exists());
+        assert!(!outside.exists());
+        assert!(!log_dir.join("a").exists());
+
+        snapshot.build_id = Some("ci-42".to_string());
+        snapshot.write(&log_dir).unwrap();
+        assert!(log_dir.join("ci-42").join(SNAPSHOT_FILE).exists());
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_prune_snapshots() {
+        let log_dir = Path::new(".hex/test/prune_snapshots");
+        let _ = std::fs::remove_dir_all(log_dir);
+        std::fs::create_dir_all(log_dir).unwrap();
+        std::fs::write(log_dir.join("compile.log"), "error").unwrap();
+
+        let start = SystemTime::now() - Duration::from_secs(100);
+        for (i, build_id) in ["b1", "b2", "b3"].iter().enumerate() {
+            std::fs::create_dir_all(log_dir.join(build_id)).unwrap();
+            let file = std::fs::File::create(log_dir.join(build_id).join(SNAPSHOT_FILE)).unwrap();
+            file.set_modified(start + Duration::from_secs(i as u64))
+                .unwrap();
+        }
+
+        prune_snapshots(log_dir, 2).unwrap();
+        assert!(!log_dir.join("b1").exists());
+        assert!(log_dir.join("b2").exists());
+        assert!(log_dir.join("b3").exists());
+        assert!(log_dir.join("compile.log").exists());
+
+        let _ = std::fs::remove_dir_all(log_dir);
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+/// Something that happens during a build. Events are sent to every
+/// observer of the build, such as the web UI, and they serialize to
+/// JSON with an `event` field saying which kind they are.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BuildEvent {
+    /// The build is starting, with these tasks in dependency order
+    BuildStarted {
+        build_id: String,
+        tasks: Vec<TaskSummary>,
+        workers: u32,
+    },
+
+    /// A worker has started on a task
+    TaskStarted { rule: String, worker: u32 },
+
+    /// A task is running one of its commands
+    CommandStarted { rule: String, command: String },
+
+    /// A line of output from one of a task's commands
+    Output { rule: String, line: String },
+
+    /// A task finished, either by building it or by retrieving its
+    /// outputs from the cache. A task that was built is `unchanged` if
+    /// its outputs came out the same as the ones it replaced.
+    TaskFinished {
+        rule: String,
+        cached: bool,
+        unchanged: bool,
+    },
+
+    /// A task failed, which stops the build
+    TaskFailed { rule: String, error: String },
+
+    /// A task was not run, because a task it depends on failed
+    TaskSkipped {
+        rule: String,
+        failed_dependency: String,
+    },
+
+    /// A task was stopped, or never started, because the build was
+    /// interrupted
+    TaskCancelled { rule: String },
+
+    /// The build is over
+    BuildFinished {
+        succeeded: bool,
+        elapsed_seconds: f64,
+    },
+}
+
+/// A task in the build plan, for [BuildEvent::BuildStarted]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TaskSummary {
+    pub rule: String,
+    pub depends_on: Vec<String>,
+}
+
+/// Something that is told about every event in a build
+pub trait BuildObserver: Send + Sync {
+    fn event(&self, event: &BuildEvent);
+}
+
+/// The observers of a build
+#[derive(Clone, Default)]
+pub struct BuildEvents {
+    observers: Vec<Arc<dyn BuildObserver>>,
+}
+
+impl BuildEvents {
+    pub fn add(&mut self, observer: Arc<dyn BuildObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Send an event to every observer
+    pub fn emit(&self, event: BuildEvent) {
+        for observer in &self.observers {
+            observer.event(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<BuildEvent>>,
+    }
+
+    impl BuildObserver for Recorder {
+        fn event(&self, event: &BuildEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_build_events() {
+        let recorder = Arc::new(Recorder::default());
+        let mut events = BuildEvents::default();
+        events.add(recorder.clone());
+
+        let event = BuildEvent::TaskFinished {
+            rule: "main".to_string(),
+            cached: true,
+            unchanged: false,
+        };
+        events.emit(event.clone());
+        assert_eq!(*recorder.events.lock().unwrap(), vec![event.clone()]);
+
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"task_finished","rule":"main","cached":true,"unchanged":false}"#
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use std::env;
+use std::process::Command;
+
+use crate::exec::build_options::BUILD_ID_VAR;
+use crate::exec::rule_builder::CommandFailed;
+
+/// Run the Hexmake file's `on_failure` command after a build fails. The
+/// details of the failure are passed in environment variables. Problems
+/// running the hook are reported as warnings, so that they do not hide
+/// the build failure itself.
+pub fn run_failure_hook(hook: &str, failure: &CommandFailed, build_id: &str) {
+    println!("Running on_failure hook: {hook}");
+
+    let shell = env::var("SHELL").unwrap_or("sh".to_string());
+    let exit_code = match failure.exit_code {
+        Some(exit_code) => exit_code.to_string(),
+        None => String::new(),
+    };
+    let result = Command::new(shell)
+        .arg("-c")
+        .arg(hook)
+        .env("HEXMAKE_FAILED_RULE", failure.rule_name.as_str())
+        .env("HEXMAKE_FAILED_COMMAND", &failure.command)
+        .env("HEXMAKE_EXIT_CODE", exit_code)
+        .env("HEXMAKE_LOG", &failure.log_path)
+        .env(BUILD_ID_VAR, build_id)
+        .status();
+
+    match result {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!("Warning: on_failure hook failed: {status}"),
+        Err(error) => eprintln!("Warning: could not run on_failure hook: {error}"),
+    }
+}
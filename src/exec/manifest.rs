@@ -0,0 +1,158 @@
+use std::io;
+
+use serde::Serialize;
+
+use crate::ast::hex_path::HexPath;
+use crate::cache::build_hash::sha256_hex;
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::graph::planner::BuildPlan;
+
+/// Where `--manifest` writes the manifest of a build's outputs
+pub const MANIFEST_FILE: &str = "out/MANIFEST.json";
+
+/// A list of every file that a build produced, for release pipelines to
+/// check and sign
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Manifest {
+    pub build_id: String,
+    pub outputs: Vec<ManifestEntry>,
+}
+
+/// One file in the manifest. A directory output gets an entry for each
+/// file inside it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    pub path: String,
+
+    /// The rule that produced the file
+    pub rule: String,
+    pub size: u64,
+
+    /// The SHA-256 hash of the file's contents, as lowercase hex
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// List the outputs of every task in a plan, sorted by path. Outputs
+    /// that do not exist, and the manifest itself, are left out.
+    pub fn of_plan(
+        plan: &BuildPlan,
+        build_id: &str,
+        vfs: &dyn VirtualFileSystem,
+    ) -> Result<Manifest, io::Error> {
+        let manifest_path = HexPath::try_from(MANIFEST_FILE).unwrap();
+        let mut outputs = Vec::new();
+        for (rule_name, task) in &plan.tasks {
+            for output in &task.lock().unwrap().rule.outputs {
+                if !vfs.exists(output)? {
+                    continue;
+                }
+                for path in vfs.tree_walk(output)? {
+                    if path == manifest_path || !vfs.is_file(&path)? {
+                        continue;
+                    }
+                    let contents = vfs.read(&path)?;
+                    outputs.push(ManifestEntry {
+                        path: path.to_string(),
+                        rule: rule_name.to_string(),
+                        size: contents.len() as u64,
+                        sha256: sha256_hex(&contents),
+                    });
+                }
+            }
+        }
+        outputs.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Manifest {
+            build_id: build_id.to_string(),
+            outputs,
+        })
+    }
+
+    /// Write the manifest to `out/MANIFEST.json`
+    pub fn write(&self, vfs: &dyn VirtualFileSystem) -> Result<(), io::Error> {
+        let json = serde_json::to_string_pretty(self).unwrap() + "\n";
+        vfs.write(&HexPath::try_from(MANIFEST_FILE).unwrap(), json.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::graph::planner::plan_build;
+
+    #[test]
+    fn test_manifest() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "docs".into(),
+                    outputs: vec![HexPath::try_from("out/docs").unwrap()],
+                    commands: vec!["make-docs out/docs".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "main".into(),
+                    inputs: vec![HexPath::try_from("out/docs").unwrap()],
+                    outputs: vec![
+                        HexPath::try_from("out/main").unwrap(),
+                        HexPath::try_from("out/main.map").unwrap(),
+                    ],
+                    commands: vec!["cc -o out/main main.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        let plan = plan_build(&hexmake_file, &vec![Arc::new("main".into())]).unwrap();
+
+        let vfs = FakeFileSystem::default();
+        for (path, contents) in [
+            ("out/main", "binary"),
+            ("out/docs/index.html", "<html>"),
+            ("out/MANIFEST.json", "{}"),
+        ] {
+            vfs.write(&HexPath::try_from(path).unwrap(), contents.as_bytes())
+                .unwrap();
+        }
+
+        let manifest = Manifest::of_plan(&plan, "build-1", &vfs).unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                build_id: "build-1".to_string(),
+                outputs: vec![
+                    ManifestEntry {
+                        path: "out/docs/index.html".to_string(),
+                        rule: "docs".to_string(),
+                        size: 6,
+                        sha256: sha256_hex(b"<html>"),
+                    },
+                    ManifestEntry {
+                        path: "out/main".to_string(),
+                        rule: "main".to_string(),
+                        size: 6,
+                        sha256: sha256_hex(b"binary"),
+                    },
+                ]
+            }
+        );
+
+        manifest.write(&vfs).unwrap();
+        let written = vfs
+            .read(&HexPath::try_from(MANIFEST_FILE).unwrap())
+            .unwrap();
+        assert!(
+            String::from_utf8(written)
+                .unwrap()
+                .contains("\"rule\": \"docs\"")
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use fs_err::{create_dir_all, read_to_string, write};
+
+use crate::ast::hexmake_file::HexRule;
+
+/// Where the peak memory of each rule is remembered between builds
+pub const MEMORY_HISTORY_FILE: &str = ".hex/memory.json";
+
+/// The most memory that each rule needed the last time it was built, so
+/// that the conductor can avoid starting more rules at once than fit in
+/// memory
+#[derive(Debug, Default, PartialEq)]
+pub struct MemoryHistory {
+    /// The peak memory of each rule, in bytes
+    pub peaks: BTreeMap<String, u64>,
+}
+
+impl MemoryHistory {
+    /// Load the history, or start an empty one if there is none or it
+    /// cannot be read
+    pub fn load(path: impl AsRef<Path>) -> MemoryHistory {
+        let peaks = read_to_string(path.as_ref())
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        MemoryHistory { peaks }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(
+            path,
+            serde_json::to_string_pretty(&self.peaks).unwrap() + "\n",
+        )
+    }
+
+    /// How much memory a rule is expected to need, in bytes. A rule's
+    /// declared `memory_mb` takes precedence over what it used before.
+    pub fn estimate(&self, rule: &HexRule) -> Option<u64> {
+        match rule.memory_mb {
+            Some(memory_mb) => Some(memory_mb * 1024 * 1024),
+            None => self.peaks.get(rule.name.as_str()).copied(),
+        }
+    }
+}
+
+/// How much memory the system can give to new processes without
+/// swapping, in bytes, if it can be found out
+pub fn available_memory() -> Option<u64> {
+    parse_available_memory(&read_to_string("/proc/meminfo").ok()?)
+}
+
+/// Find `MemAvailable` in the contents of `/proc/meminfo`
+fn parse_available_memory(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let kilobytes = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_history() {
+        let test_dir = ".hex/test/memory_history";
+        let _ = std::fs::remove_dir_all(test_dir);
+        let path = format!("{test_dir}/memory.json");
+
+        // A missing history is empty
+        let mut history = MemoryHistory::load(&path);
+        assert_eq!(history, MemoryHistory::default());
+
+        history.peaks.insert("link".to_string(), 3 << 30);
+        history.save(&path).unwrap();
+        let history = MemoryHistory::load(&path);
+        assert_eq!(history.peaks["link"], 3 << 30);
+
+        // A declared amount takes precedence over the history
+        let mut rule = HexRule::new("link".into());
+        assert_eq!(history.estimate(&rule), Some(3 << 30));
+        rule.memory_mb = Some(512);
+        assert_eq!(history.estimate(&rule), Some(512 << 20));
+        assert_eq!(history.estimate(&HexRule::new("compile".into())), None);
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_parse_available_memory() {
+        let meminfo = "MemTotal:       16318852 kB\n\
+                       MemFree:         1151260 kB\n\
+                       MemAvailable:    9815308 kB\n";
+        assert_eq!(parse_available_memory(meminfo), Some(9815308 * 1024));
+        assert_eq!(parse_available_memory("MemTotal: 16318852 kB\n"), None);
+    }
+}
@@ -1,7 +1,25 @@
 //! Execution of a build
 
+pub mod build_log;
+pub mod build_options;
+pub mod builtin;
+pub mod cache_audit;
+pub mod cache_inserter;
+pub mod cache_probe;
 pub mod command_logger;
 pub mod conductor;
+pub mod env_snapshot;
+pub mod events;
+pub mod failure_hook;
+pub mod manifest;
+pub mod memory;
+pub mod notify;
 pub mod rule_builder;
+pub mod running_commands;
+pub mod status_file;
+pub mod summary_file;
+pub mod tui;
+pub mod volatile_status;
+pub mod web_ui;
 pub mod work_dir;
 pub mod work_list;
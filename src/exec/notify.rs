@@ -0,0 +1,89 @@
+use std::env;
+use std::process::Command;
+use std::time::Duration;
+
+/// A summary of a finished build, for notifying the user
+pub struct BuildNotification {
+    pub succeeded: bool,
+    pub elapsed: Duration,
+}
+
+impl BuildNotification {
+    /// A one-line human-readable message about the build
+    pub fn message(&self) -> String {
+        let seconds = self.elapsed.as_secs_f64();
+        if self.succeeded {
+            format!("Build succeeded in {seconds:.1}s")
+        } else {
+            format!("Build failed after {seconds:.1}s")
+        }
+    }
+
+    /// Send a notification. If a command is given, run it with the shell,
+    /// with the details in environment variables. Otherwise, show a desktop
+    /// notification. Problems are reported as warnings, because the build
+    /// itself is already finished.
+    pub fn send(&self, notify_command: Option<&str>) {
+        let result = match notify_command {
+            Some(notify_command) => {
+                let shell = env::var("SHELL").unwrap_or("sh".to_string());
+                Command::new(shell)
+                    .arg("-c")
+                    .arg(notify_command)
+                    .env("HEXMAKE_RESULT", self.result_name())
+                    .env(
+                        "HEXMAKE_ELAPSED_SECONDS",
+                        format!("{:.1}", self.elapsed.as_secs_f64()),
+                    )
+                    .env("HEXMAKE_MESSAGE", self.message())
+                    .status()
+            }
+            None => desktop_notification_command(&self.message()).status(),
+        };
+
+        match result {
+            Ok(status) if status.success() => (),
+            Ok(status) => eprintln!("Warning: notification command failed: {status}"),
+            Err(error) => eprintln!("Warning: could not send notification: {error}"),
+        }
+    }
+
+    fn result_name(&self) -> &'static str {
+        if self.succeeded { "success" } else { "failure" }
+    }
+}
+
+/// The command for showing a desktop notification on this platform
+fn desktop_notification_command(message: &str) -> Command {
+    if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {message:?} with title \"Hexmake\""
+        ));
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.arg("Hexmake").arg(message);
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let notification = BuildNotification {
+            succeeded: true,
+            elapsed: Duration::from_millis(12345),
+        };
+        assert_eq!(notification.message(), "Build succeeded in 12.3s");
+
+        let notification = BuildNotification {
+            succeeded: false,
+            elapsed: Duration::from_millis(500),
+        };
+        assert_eq!(notification.message(), "Build failed after 0.5s");
+    }
+}
@@ -1,68 +1,374 @@
 use std::collections::BTreeMap;
-use std::process::{Command, Stdio};
+use std::fmt::{self, Display, Formatter};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
 use std::sync::Arc;
 use std::{env, io};
 
-use crate::ast::hexmake_file::HexRule;
+use fs_err::{create_dir_all, write};
+
+use crate::ast::hexmake_file::{HexRule, RuleCommand, RuleKind, RuleLimits, RuleName};
+use crate::environment::BuildEnvironment;
+use crate::exec::build_log::{BUILD_LOG_DIR, failure_log_path};
+use crate::exec::build_options::BUILD_ID_VAR;
+use crate::exec::builtin::run_builtin;
+use crate::exec::cache_audit::differing_outputs;
 use crate::exec::command_logger::CommandLogger;
+use crate::exec::running_commands::{ResourceUsage, RunningCommands};
+use crate::exec::volatile_status::VOLATILE_STATUS_FILE;
 use crate::exec::work_dir::WorkDirManager;
 
+/// What building a rule did, besides writing its outputs
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BuildOutcome {
+    /// The resources that the rule's commands used
+    pub usage: ResourceUsage,
+
+    /// Whether the outputs came out the same as the ones that were
+    /// already in the workspace
+    pub outputs_unchanged: bool,
+}
+
 /// Build the given rule right now. Assume that all of its
 /// dependencies have been built and are available in `out`.
 pub fn build_rule(
     rule: &HexRule,
-    work_dir: &WorkDirManager,
+    worker: &WorkerContext,
     command_logger: &CommandLogger,
-    env_vars: &Arc<BTreeMap<Arc<String>, Arc<String>>>,
-) -> io::Result<()> {
-    let rule_name = &rule.name;
+    running_commands: &RunningCommands,
+    env: &BuildEnvironment,
+) -> io::Result<BuildOutcome> {
+    let usage = build_in_work_dir(rule, worker, command_logger, running_commands, env)?;
+
+    // Copy output files back to the main workspace
+    let outputs_unchanged = worker.work_dir.copy_outputs(&rule.outputs)?;
 
-    // Clean the work directory for this build
-    work_dir.clean()?;
+    Ok(BuildOutcome {
+        usage,
+        outputs_unchanged,
+    })
+}
 
-    // Create the work directory
+/// Build a rule again whose outputs were just retrieved from the cache,
+/// and return the output files whose contents came out different. If any
+/// did, the rebuilt outputs replace the cached ones in the workspace, so
+/// that the rest of the build does not use outputs that may be bad.
+pub fn audit_rule(
+    rule: &HexRule,
+    worker: &WorkerContext,
+    command_logger: &CommandLogger,
+    running_commands: &RunningCommands,
+    env: &BuildEnvironment,
+) -> io::Result<Vec<String>> {
+    build_in_work_dir(rule, worker, command_logger, running_commands, env)?;
+    let work_dir = &worker.work_dir;
+    let differing = differing_outputs(Path::new(work_dir.root()), Path::new(""), &rule.outputs)?;
+    if !differing.is_empty() {
+        work_dir.copy_outputs(&rule.outputs)?;
+    }
+    Ok(differing)
+}
+
+/// Build a rule's outputs in the worker's work directory, leaving them
+/// there
+fn build_in_work_dir(
+    rule: &HexRule,
+    worker: &WorkerContext,
+    command_logger: &CommandLogger,
+    running_commands: &RunningCommands,
+    env: &BuildEnvironment,
+) -> io::Result<ResourceUsage> {
+    let work_dir = &worker.work_dir;
+
+    // Create the work directory, or reuse the one from the worker's
+    // previous task
     work_dir.create_root()?;
 
-    // Copy input files into the work directory
+    // Copy input files into the work directory, and remove anything else
     work_dir.copy_inputs(&rule.inputs)?;
 
     // Prepare output directories in the work directory
     work_dir.prepare_output_directories(&rule.outputs)?;
 
-    // Run the build commands in the work directory
+    // Build the outputs in the work directory
+    match rule.kind {
+        RuleKind::Command => run_commands(rule, worker, command_logger, running_commands, env),
+        _ => {
+            run_builtin(rule, Path::new(work_dir.root()), env, command_logger)?;
+            Ok(ResourceUsage::default())
+        }
+    }
+}
+
+/// Run a rule's shell commands in the work directory, stopping at the
+/// first one that fails. The commands run one at a time, so the peak
+/// memory of the rule is the largest peak of any command, while its CPU
+/// time and I/O are the totals of all of them.
+fn run_commands(
+    rule: &HexRule,
+    worker: &WorkerContext,
+    command_logger: &CommandLogger,
+    running_commands: &RunningCommands,
+    env: &BuildEnvironment,
+) -> io::Result<ResourceUsage> {
+    let rule_name = &rule.name;
+    let work_dir = &worker.work_dir;
     let shell = env::var("SHELL").unwrap_or("sh".to_string());
+    let context_vars = worker.command_vars(rule)?;
+    let mut captured_stdout = Vec::new();
+    let mut usage = ResourceUsage::default();
 
     for command in &rule.commands {
-        println!("[{rule_name}] Running: {}", command);
+        let command_line = command.to_string();
+        command_logger.log_command(&command_line, rule_name);
 
         // Spawn the command and buffer its output
-        let output = Command::new(&shell)
-            .arg("-c")
-            .arg(command)
+        let mut process = match command {
+            RuleCommand::Shell(command) => {
+                let mut process = Command::new(&shell);
+                process.arg("-c").arg(command);
+                process
+            }
+            RuleCommand::Argv(args) => {
+                let mut process = Command::new(&args[0]);
+                process.args(&args[1..]);
+                process
+            }
+        };
+        process
             .current_dir(work_dir.root())
             .env_clear()
-            .envs(env_vars.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+            .envs(env.command_vars())
+            .envs(&context_vars)
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
+            .stderr(Stdio::piped());
+        set_limits(&mut process, &rule.limits);
+        let (output, command_usage) = running_commands.output(&mut process).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("Could not run `{command_line}`: {error}"),
+            )
+        })?;
+        usage.add(&command_usage);
+
+        // Standard output that goes into a file is not printed, but it
+        // is still logged if the command fails
+        let captured = rule.stdout_output.is_some() && output.status.success();
+        if captured {
+            captured_stdout.extend_from_slice(&output.stdout);
+        }
+        let output = Output {
+            stdout: if captured {
+                Vec::new()
+            } else {
+                env.redact(&output.stdout)
+            },
+            stderr: env.redact(&output.stderr),
+            ..output
+        };
 
         // Print output
         command_logger.log_output(&output, rule_name)?;
 
         if !output.status.success() {
             // Leave the work directory intact for inspection on failure
-            let work_dir_path = work_dir.root();
-            return Err(io::Error::other(format!(
-                "Command failed!\n  Command: {command}\n  Work directory: {work_dir_path}"
-            )));
+            let log_path = write_failure_log(rule_name, &worker.build_id, &command_line, &output)?;
+            return Err(io::Error::other(CommandFailed {
+                rule_name: rule_name.clone(),
+                command: command_line,
+                work_dir: work_dir.root().to_string(),
+                exit_code: output.status.code(),
+                log_path,
+            }));
         }
     }
 
-    // Copy output files back to the main workspace
-    work_dir.copy_outputs(&rule.outputs)?;
+    if let Some(stdout_output) = &rule.stdout_output {
+        write(
+            Path::new(work_dir.root()).join(stdout_output),
+            captured_stdout,
+        )?;
+    }
+
+    Ok(usage)
+}
 
-    // Clean up the work directory after successful build
-    work_dir.clean()?;
+/// The worker that is building a rule. The rule's commands can find out
+/// about it from `HEXMAKE_*` environment variables.
+pub struct WorkerContext {
+    pub worker_id: u32,
 
-    Ok(())
+    /// How many tasks the build runs at once
+    pub jobs: u32,
+
+    /// The ID of the build
+    pub build_id: Arc<String>,
+
+    pub work_dir: WorkDirManager,
+}
+
+impl WorkerContext {
+    /// The `HEXMAKE_*` variables for the commands of a rule
+    fn command_vars(&self, rule: &HexRule) -> io::Result<BTreeMap<&'static str, String>> {
+        let work_dir = std::path::absolute(self.work_dir.root())?;
+        let mut vars = BTreeMap::from([
+            ("HEXMAKE_RULE_NAME", rule.name.to_string()),
+            (
+                "HEXMAKE_OUT_DIR",
+                work_dir.join("out").to_string_lossy().to_string(),
+            ),
+            ("HEXMAKE_WORK_DIR", work_dir.to_string_lossy().to_string()),
+            ("HEXMAKE_WORKER_ID", self.worker_id.to_string()),
+            ("HEXMAKE_JOBS", self.jobs.to_string()),
+            (BUILD_ID_VAR, self.build_id.to_string()),
+        ]);
+        if rule.stamp {
+            let status = std::path::absolute(VOLATILE_STATUS_FILE)?;
+            vars.insert(
+                "HEXMAKE_VOLATILE_STATUS",
+                status.to_string_lossy().to_string(),
+            );
+        }
+        Ok(vars)
+    }
+}
+
+/// Apply a rule's resource limits to a command before it starts
+fn set_limits(command: &mut Command, limits: &RuleLimits) {
+    let megabyte = 1024 * 1024;
+    let limits = [
+        (
+            libc::RLIMIT_AS,
+            limits.max_memory_mb.map(|mb| mb * megabyte),
+        ),
+        (libc::RLIMIT_CPU, limits.max_cpu_seconds),
+        (libc::RLIMIT_NOFILE, limits.max_open_files),
+        (
+            libc::RLIMIT_CORE,
+            limits
+                .core_dumps
+                .and_then(|enabled| (!enabled).then_some(0)),
+        ),
+    ];
+    let limits: Vec<_> = limits
+        .into_iter()
+        .filter_map(|(resource, limit)| Some((resource, limit?)))
+        .collect();
+    if limits.is_empty() {
+        return;
+    }
+
+    // Both the soft and the hard limit are set, so that the commands
+    // cannot raise them again. A limit can only be lowered, though, so
+    // one above the current hard limit is left at the hard limit.
+    //
+    // SAFETY: the closure runs in the child between fork() and exec(), so
+    // it only calls getrlimit() and setrlimit(), which are
+    // async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            for &(resource, limit) in &limits {
+                let mut rlimit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if libc::getrlimit(resource, &mut rlimit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let limit = (limit as libc::rlim_t).min(rlimit.rlim_max);
+                rlimit.rlim_cur = limit;
+                rlimit.rlim_max = limit;
+                if libc::setrlimit(resource, &rlimit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// The error for a build command that exits unsuccessfully. This is
+/// wrapped in an io::Error, and it can be recovered with `downcast_ref`
+/// to find out the details of the failure.
+#[derive(Clone, Debug)]
+pub struct CommandFailed {
+    pub rule_name: RuleName,
+    pub command: String,
+    pub work_dir: String,
+
+    /// The exit code, or None if the command was killed by a signal
+    pub exit_code: Option<i32>,
+
+    /// A file with the output of the failed command
+    pub log_path: String,
+}
+
+impl Display for CommandFailed {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Command failed!\n  Command: {}\n  Work directory: {}\n  Log: {}",
+            self.command, self.work_dir, self.log_path
+        )
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Save the output of a failed command to `.hex/log/<build-id>/<rule>.log`,
+/// so that it can be read after the build finishes. A build whose ID cannot
+/// be part of a path saves it to `.hex/log/<rule>.log` instead. Return the
+/// path of the log.
+fn write_failure_log(
+    rule_name: &RuleName,
+    build_id: &str,
+    command: &str,
+    output: &Output,
+) -> io::Result<String> {
+    let log_path = match failure_log_path(build_id, rule_name) {
+        Some(log_path) => log_path.to_string(),
+        None => format!("{BUILD_LOG_DIR}/{rule_name}.log"),
+    };
+    if let Some(parent) = Path::new(&log_path).parent() {
+        create_dir_all(parent)?;
+    }
+
+    let mut contents = format!(
+        "Build ID: {build_id}\nCommand: {command}\nStatus: {}\n",
+        output.status
+    )
+    .into_bytes();
+    contents.extend_from_slice(b"\nStandard output:\n");
+    contents.extend_from_slice(&output.stdout);
+    contents.extend_from_slice(b"\nStandard error:\n");
+    contents.extend_from_slice(&output.stderr);
+    write(&log_path, contents)?;
+
+    Ok(log_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_limits() {
+        let run = |limits: &RuleLimits| {
+            let mut command = Command::new("sh");
+            command.args(["-c", "ulimit -v; ulimit -t; ulimit -n; ulimit -c"]);
+            set_limits(&mut command, limits);
+            String::from_utf8(command.output().unwrap().stdout).unwrap()
+        };
+
+        let unlimited = run(&RuleLimits::default());
+        let limited = run(&RuleLimits {
+            max_memory_mb: Some(512),
+            max_cpu_seconds: Some(60),
+            max_open_files: Some(32),
+            core_dumps: Some(false),
+        });
+        assert_eq!(limited, "524288\n60\n32\n0\n");
+        assert_ne!(unlimited, limited);
+    }
 }
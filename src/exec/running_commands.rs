@@ -0,0 +1,263 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::Serialize;
+
+/// The build commands that are running, so that they can all be killed
+/// if the build is cancelled
+#[derive(Clone, Default)]
+pub struct RunningCommands {
+    state: Arc<Mutex<RunningState>>,
+}
+
+#[derive(Default)]
+struct RunningState {
+    /// The process IDs of the running commands. Each command leads its
+    /// own process group, so its ID is also the ID of the group.
+    processes: BTreeSet<u32>,
+
+    /// Whether the build has been cancelled
+    cancelled: bool,
+}
+
+/// The resources that a finished command used, including the commands
+/// that it started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ResourceUsage {
+    /// The most memory that the command had resident at once, in bytes.
+    /// For a command that starts others, this is the largest of them.
+    pub peak_memory: u64,
+
+    /// The CPU time spent running the command's own code
+    pub user_seconds: f64,
+
+    /// The CPU time that the system spent working for the command
+    pub system_seconds: f64,
+
+    /// How many times the file system had to read from the disk
+    pub blocks_read: u64,
+
+    /// How many times the file system wrote to the disk
+    pub blocks_written: u64,
+}
+
+impl ResourceUsage {
+    /// Add in what another command used, for the total of a rule whose
+    /// commands run one after another. The peak memory is the larger of
+    /// the two.
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.peak_memory = self.peak_memory.max(other.peak_memory);
+        self.user_seconds += other.user_seconds;
+        self.system_seconds += other.system_seconds;
+        self.blocks_read += other.blocks_read;
+        self.blocks_written += other.blocks_written;
+    }
+}
+
+impl Display for ResourceUsage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.2}s CPU ({:.2}s user, {:.2}s system), {:.1} MB peak memory, {} blocks read, {} blocks written",
+            self.user_seconds + self.system_seconds,
+            self.user_seconds,
+            self.system_seconds,
+            self.peak_memory as f64 / (1024.0 * 1024.0),
+            self.blocks_read,
+            self.blocks_written
+        )
+    }
+}
+
+impl RunningCommands {
+    /// Run a command to completion and collect its output and the
+    /// resources it used, unless the build has been cancelled
+    pub fn output(&self, command: &mut Command) -> io::Result<(Output, ResourceUsage)> {
+        // Put the command in its own process group, so that anything it
+        // starts can be killed along with it
+        command.process_group(0);
+
+        let mut child = {
+            let mut state = self.state.lock().unwrap();
+            if state.cancelled {
+                return Err(io::Error::other("The build was cancelled"));
+            }
+            let child = command.spawn()?;
+            state.processes.insert(child.id());
+            child
+        };
+
+        // Read the output while waiting, so that a command with a lot
+        // of output does not block on a full pipe
+        let id = child.id();
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let (result, stdout, stderr) = thread::scope(|scope| {
+            let stdout = scope.spawn(|| read_all(stdout));
+            let stderr = scope.spawn(|| read_all(stderr));
+            let result = wait_for_process(id);
+            (result, stdout.join().unwrap(), stderr.join().unwrap())
+        });
+        self.state.lock().unwrap().processes.remove(&id);
+
+        let (status, usage) = result?;
+        let output = Output {
+            status,
+            stdout: stdout?,
+            stderr: stderr?,
+        };
+        Ok((output, usage))
+    }
+
+    /// Whether [RunningCommands::cancel] has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().unwrap().cancelled
+    }
+
+    /// Kill every running command, and refuse to start any more
+    pub fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.cancelled = true;
+        for &id in &state.processes {
+            // SAFETY: kill() only sends a signal, and a negative ID sends
+            // it to the whole process group
+            unsafe {
+                libc::kill(-(id as libc::pid_t), libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Read everything from a pipe of a child process, if it has one
+fn read_all(pipe: Option<impl Read>) -> io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut result)?;
+    }
+    Ok(result)
+}
+
+/// Wait for a child process to exit, and find out what it used. This is
+/// done with wait4(), because the standard library cannot report the
+/// resource usage of a single child.
+fn wait_for_process(id: u32) -> io::Result<(ExitStatus, ResourceUsage)> {
+    let mut status = 0;
+    // SAFETY: rusage is plain data, for which all zeros is valid
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: the pointers are to live locals of the right types
+        let result = unsafe { libc::wait4(id as libc::pid_t, &mut status, 0, &mut usage) };
+        if result >= 0 {
+            break;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    // The peak is in bytes on macOS and in kilobytes elsewhere
+    let peak_memory = usage.ru_maxrss.max(0) as u64;
+    let peak_memory = if cfg!(target_os = "macos") {
+        peak_memory
+    } else {
+        peak_memory * 1024
+    };
+    let seconds = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1e6;
+    Ok((
+        ExitStatus::from_raw(status),
+        ResourceUsage {
+            peak_memory,
+            user_seconds: seconds(usage.ru_utime),
+            system_seconds: seconds(usage.ru_stime),
+            blocks_read: usage.ru_inblock.max(0) as u64,
+            blocks_written: usage.ru_oublock.max(0) as u64,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_cancel() {
+        let running_commands = RunningCommands::default();
+        let start_time = Instant::now();
+        let sleeper = {
+            let running_commands = running_commands.clone();
+            spawn(move || running_commands.output(Command::new("sh").args(["-c", "sleep 30"])))
+        };
+        while running_commands.state.lock().unwrap().processes.is_empty() {
+            sleep(Duration::from_millis(10));
+        }
+
+        running_commands.cancel();
+        let (output, _) = sleeper.join().unwrap().unwrap();
+        assert!(!output.status.success());
+        assert!(start_time.elapsed() < Duration::from_secs(10));
+
+        // No more commands can start
+        assert!(running_commands.output(&mut Command::new("true")).is_err());
+    }
+
+    #[test]
+    fn test_output() {
+        let running_commands = RunningCommands::default();
+        let (output, usage) = running_commands
+            .output(
+                Command::new("sh")
+                    .args(["-c", "echo out; echo err >&2; exit 3"])
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped()),
+            )
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+        assert!(usage.peak_memory > 0);
+    }
+
+    #[test]
+    fn test_cpu_time() {
+        let running_commands = RunningCommands::default();
+        let (output, usage) = running_commands
+            .output(
+                Command::new("sh")
+                    .args(["-c", "i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done"]),
+            )
+            .unwrap();
+        assert!(output.status.success());
+        assert!(usage.user_seconds + usage.system_seconds > 0.0);
+    }
+
+    #[test]
+    fn test_add_usage() {
+        let mut total = ResourceUsage {
+            peak_memory: 100 << 20,
+            user_seconds: 1.5,
+            system_seconds: 0.25,
+            blocks_read: 10,
+            blocks_written: 2,
+        };
+        total.add(&ResourceUsage {
+            peak_memory: 50 << 20,
+            user_seconds: 0.5,
+            system_seconds: 0.25,
+            blocks_read: 5,
+            blocks_written: 0,
+        });
+        assert_eq!(
+            total.to_string(),
+            "2.50s CPU (2.00s user, 0.50s system), 100.0 MB peak memory, 15 blocks read, 2 blocks written"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use fs_err::{create_dir_all, rename, write};
+use serde::Serialize;
+
+use crate::exec::events::{BuildEvent, BuildObserver};
+
+/// Where the status of the current build is written
+pub const STATUS_FILE: &str = ".hex/status.json";
+
+/// Keeps a small JSON file up to date with the progress of the build, so
+/// that editors and status bars can show it without parsing the output.
+/// The file is rewritten whenever a task starts or stops.
+pub struct StatusFile {
+    path: PathBuf,
+    status: Mutex<BuildStatus>,
+}
+
+/// The contents of the status file
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct BuildStatus {
+    build_id: String,
+
+    /// `running`, `succeeded`, or `failed`
+    state: &'static str,
+    total_tasks: usize,
+    completed_tasks: usize,
+    running_rules: BTreeSet<String>,
+    failed_rules: BTreeSet<String>,
+    skipped_rules: BTreeSet<String>,
+    cancelled_rules: BTreeSet<String>,
+}
+
+impl StatusFile {
+    pub fn new(path: impl Into<PathBuf>) -> StatusFile {
+        StatusFile {
+            path: path.into(),
+            status: Mutex::default(),
+        }
+    }
+
+    /// Write the file under a temporary name and then rename it, so that
+    /// a reader never sees it half written
+    fn write(&self, status: &BuildStatus) -> Result<(), io::Error> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        let temp_path = self.path.with_extension("json.tmp");
+        write(
+            &temp_path,
+            serde_json::to_string_pretty(status).unwrap() + "\n",
+        )?;
+        rename(&temp_path, &self.path)
+    }
+}
+
+impl BuildObserver for StatusFile {
+    fn event(&self, event: &BuildEvent) {
+        let mut status = self.status.lock().unwrap();
+        match event {
+            BuildEvent::BuildStarted {
+                build_id, tasks, ..
+            } => {
+                *status = BuildStatus {
+                    build_id: build_id.clone(),
+                    state: "running",
+                    total_tasks: tasks.len(),
+                    ..Default::default()
+                };
+            }
+            BuildEvent::TaskStarted { rule, .. } => {
+                status.running_rules.insert(rule.clone());
+            }
+            BuildEvent::TaskFinished { rule, .. } => {
+                status.running_rules.remove(rule);
+                status.completed_tasks += 1;
+            }
+            BuildEvent::TaskFailed { rule, .. } => {
+                status.running_rules.remove(rule);
+                status.failed_rules.insert(rule.clone());
+            }
+            BuildEvent::TaskSkipped { rule, .. } => {
+                status.skipped_rules.insert(rule.clone());
+            }
+            BuildEvent::TaskCancelled { rule } => {
+                status.running_rules.remove(rule);
+                status.cancelled_rules.insert(rule.clone());
+            }
+            BuildEvent::BuildFinished { succeeded, .. } => {
+                status.state = if *succeeded { "succeeded" } else { "failed" };
+            }
+            BuildEvent::CommandStarted { .. } | BuildEvent::Output { .. } => return,
+        }
+
+        if let Err(error) = self.write(&status) {
+            eprintln!("Warning: could not write {}: {error}", self.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::events::TaskSummary;
+
+    #[test]
+    fn test_status_file() {
+        let test_dir = ".hex/test/status_file";
+        let _ = std::fs::remove_dir_all(test_dir);
+        let status_file = StatusFile::new(format!("{test_dir}/status.json"));
+        let read_status = || -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(&status_file.path).unwrap()).unwrap()
+        };
+
+        let task = |rule: &str| TaskSummary {
+            rule: rule.to_string(),
+            depends_on: Vec::new(),
+        };
+        status_file.event(&BuildEvent::BuildStarted {
+            build_id: "1234abcd".to_string(),
+            tasks: vec![task("main"), task("main.o"), task("util.o")],
+            workers: 4,
+        });
+        status_file.event(&BuildEvent::TaskStarted {
+            rule: "main.o".to_string(),
+            worker: 0,
+        });
+        status_file.event(&BuildEvent::TaskStarted {
+            rule: "util.o".to_string(),
+            worker: 1,
+        });
+        status_file.event(&BuildEvent::TaskFinished {
+            rule: "util.o".to_string(),
+            cached: false,
+            unchanged: false,
+        });
+        assert_eq!(
+            read_status(),
+            serde_json::json!({
+                "build_id": "1234abcd",
+                "state": "running",
+                "total_tasks": 3,
+                "completed_tasks": 1,
+                "running_rules": ["main.o"],
+                "failed_rules": [],
+                "skipped_rules": [],
+                "cancelled_rules": []
+            })
+        );
+
+        status_file.event(&BuildEvent::TaskFailed {
+            rule: "main.o".to_string(),
+            error: "Command failed!".to_string(),
+        });
+        status_file.event(&BuildEvent::TaskSkipped {
+            rule: "main".to_string(),
+            failed_dependency: "main.o".to_string(),
+        });
+        status_file.event(&BuildEvent::BuildFinished {
+            succeeded: false,
+            elapsed_seconds: 1.0,
+        });
+        assert_eq!(
+            read_status(),
+            serde_json::json!({
+                "build_id": "1234abcd",
+                "state": "failed",
+                "total_tasks": 3,
+                "completed_tasks": 1,
+                "running_rules": [],
+                "failed_rules": ["main.o"],
+                "skipped_rules": ["main"],
+                "cancelled_rules": []
+            })
+        );
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+}
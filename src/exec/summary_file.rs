@@ -0,0 +1,229 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use fs_err::write;
+use serde::Serialize;
+
+use crate::exec::events::{BuildEvent, BuildObserver};
+
+/// Writes a summary of the build to a file when it finishes, for
+/// `--summary-file`. A path ending in `.md` gets Markdown, for CI job
+/// summaries, and any other path gets JSON.
+pub struct SummaryFile {
+    path: PathBuf,
+    summary: Mutex<BuildSummary>,
+}
+
+/// The contents of the summary file
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct BuildSummary {
+    build_id: String,
+
+    /// `success` or `failure`
+    result: &'static str,
+    duration_seconds: f64,
+    total_tasks: usize,
+    cached_tasks: usize,
+    built_tasks: usize,
+
+    /// The fraction of finished tasks that came from the cache, or None
+    /// if no task finished
+    cache_hit_rate: Option<f64>,
+    failed_rules: Vec<String>,
+    skipped_rules: Vec<String>,
+    cancelled_rules: Vec<String>,
+}
+
+impl SummaryFile {
+    pub fn new(path: impl Into<PathBuf>) -> SummaryFile {
+        SummaryFile {
+            path: path.into(),
+            summary: Mutex::default(),
+        }
+    }
+
+    fn is_markdown(&self) -> bool {
+        self.path
+            .extension()
+            .is_some_and(|extension| extension == "md")
+    }
+}
+
+impl BuildObserver for SummaryFile {
+    fn event(&self, event: &BuildEvent) {
+        let mut summary = self.summary.lock().unwrap();
+        match event {
+            BuildEvent::BuildStarted {
+                build_id, tasks, ..
+            } => {
+                summary.build_id = build_id.clone();
+                summary.total_tasks = tasks.len();
+            }
+            BuildEvent::TaskFinished { cached: true, .. } => summary.cached_tasks += 1,
+            BuildEvent::TaskFinished { cached: false, .. } => summary.built_tasks += 1,
+            BuildEvent::TaskFailed { rule, .. } => summary.failed_rules.push(rule.clone()),
+            BuildEvent::TaskSkipped { rule, .. } => summary.skipped_rules.push(rule.clone()),
+            BuildEvent::TaskCancelled { rule } => summary.cancelled_rules.push(rule.clone()),
+            BuildEvent::BuildFinished {
+                succeeded,
+                elapsed_seconds,
+            } => {
+                summary.result = if *succeeded { "success" } else { "failure" };
+                summary.duration_seconds = *elapsed_seconds;
+                let finished = summary.cached_tasks + summary.built_tasks;
+                if finished > 0 {
+                    summary.cache_hit_rate = Some(summary.cached_tasks as f64 / finished as f64);
+                }
+
+                let text = if self.is_markdown() {
+                    summary.to_markdown()
+                } else {
+                    serde_json::to_string_pretty(&*summary).unwrap() + "\n"
+                };
+                if let Err(error) = write(&self.path, text) {
+                    eprintln!("Warning: could not write the build summary: {error}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl BuildSummary {
+    fn to_markdown(&self) -> String {
+        let outcome = if self.result == "success" {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        let cache_hit_rate = match self.cache_hit_rate {
+            Some(rate) => format!("{:.0}%", rate * 100.0),
+            None => "-".to_string(),
+        };
+
+        let mut result = format!("## Hexmake build {outcome}\n\n");
+        result.push_str("| | |\n|---|---|\n");
+        result.push_str(&format!("| Build ID | `{}` |\n", self.build_id));
+        result.push_str(&format!("| Duration | {:.1}s |\n", self.duration_seconds));
+        result.push_str(&format!(
+            "| Tasks | {} ({} from cache, {} built) |\n",
+            self.total_tasks, self.cached_tasks, self.built_tasks
+        ));
+        result.push_str(&format!("| Cache hit rate | {cache_hit_rate} |\n"));
+        for (title, rules) in [
+            ("Failed rules", &self.failed_rules),
+            ("Skipped because a dependency failed", &self.skipped_rules),
+            ("Cancelled", &self.cancelled_rules),
+        ] {
+            if !rules.is_empty() {
+                result.push_str(&format!("\n{title}:\n\n"));
+                for rule in rules {
+                    result.push_str(&format!("* `{rule}`\n"));
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::events::TaskSummary;
+    use indoc::indoc;
+
+    /// Send the events of a small build that fails
+    fn send_events(summary_file: &SummaryFile) {
+        let task = |rule: &str| TaskSummary {
+            rule: rule.to_string(),
+            depends_on: Vec::new(),
+        };
+        summary_file.event(&BuildEvent::BuildStarted {
+            build_id: "1234abcd".to_string(),
+            tasks: vec![
+                task("main"),
+                task("main.o"),
+                task("util.o"),
+                task("lib.o"),
+                task("test"),
+            ],
+            workers: 4,
+        });
+        for (rule, cached) in [("util.o", true), ("lib.o", true), ("main.o", false)] {
+            summary_file.event(&BuildEvent::TaskFinished {
+                rule: rule.to_string(),
+                cached,
+                unchanged: false,
+            });
+        }
+        summary_file.event(&BuildEvent::TaskFailed {
+            rule: "main".to_string(),
+            error: "Command failed!".to_string(),
+        });
+        summary_file.event(&BuildEvent::TaskSkipped {
+            rule: "test".to_string(),
+            failed_dependency: "main".to_string(),
+        });
+        summary_file.event(&BuildEvent::BuildFinished {
+            succeeded: false,
+            elapsed_seconds: 2.25,
+        });
+    }
+
+    #[test]
+    fn test_summary_file() {
+        let test_dir = ".hex/test/summary_file";
+        let _ = std::fs::remove_dir_all(test_dir);
+        std::fs::create_dir_all(test_dir).unwrap();
+
+        let json_file = SummaryFile::new(format!("{test_dir}/summary.json"));
+        send_events(&json_file);
+        assert_eq!(
+            std::fs::read_to_string(&json_file.path).unwrap(),
+            indoc! {r#"
+                {
+                  "build_id": "1234abcd",
+                  "result": "failure",
+                  "duration_seconds": 2.25,
+                  "total_tasks": 5,
+                  "cached_tasks": 2,
+                  "built_tasks": 1,
+                  "cache_hit_rate": 0.6666666666666666,
+                  "failed_rules": [
+                    "main"
+                  ],
+                  "skipped_rules": [
+                    "test"
+                  ],
+                  "cancelled_rules": []
+                }
+            "#}
+        );
+
+        let markdown_file = SummaryFile::new(format!("{test_dir}/summary.md"));
+        send_events(&markdown_file);
+        assert_eq!(
+            std::fs::read_to_string(&markdown_file.path).unwrap(),
+            indoc! {"
+                ## Hexmake build failed
+
+                | | |
+                |---|---|
+                | Build ID | `1234abcd` |
+                | Duration | 2.2s |
+                | Tasks | 5 (2 from cache, 1 built) |
+                | Cache hit rate | 67% |
+
+                Failed rules:
+
+                * `main`
+
+                Skipped because a dependency failed:
+
+                * `test`
+            "}
+        );
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+}
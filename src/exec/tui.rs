@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, MoveToNextLine, Show};
+use crossterm::style::Print;
+use crossterm::terminal::{Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, size};
+use crossterm::{execute, queue};
+
+use crate::exec::events::{BuildEvent, BuildObserver};
+
+/// How many lines of output the TUI keeps for its output pane
+const MAX_OUTPUT_LINES: usize = 1000;
+
+/// How often the screen is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A full-screen view of a build for `--tui`. It shows one row per worker,
+/// with the rule the worker is building and for how long, and below them
+/// the most recent lines of output. The screen is taken over when the
+/// build starts and given back when it finishes, and then the output of
+/// any rule that failed is printed.
+#[derive(Default)]
+pub struct Tui {
+    state: Arc<Mutex<TuiState>>,
+}
+
+#[derive(Default)]
+struct TuiState {
+    start_time: Option<Instant>,
+    total_tasks: usize,
+    finished_tasks: usize,
+    cached_tasks: usize,
+    skipped_tasks: usize,
+    cancelled_tasks: usize,
+
+    /// What each worker is doing, indexed by worker ID
+    workers: Vec<Option<WorkerActivity>>,
+
+    /// Recent output of all rules, prefixed by the rule name
+    output: VecDeque<String>,
+
+    /// The output of each rule that is running or has failed
+    rule_output: BTreeMap<String, Vec<String>>,
+
+    /// The rules that failed
+    failed_rules: Vec<String>,
+
+    /// Whether the TUI has the screen
+    active: bool,
+}
+
+/// A rule that a worker is building
+struct WorkerActivity {
+    rule: String,
+    start_time: Instant,
+}
+
+impl BuildObserver for Tui {
+    fn event(&self, event: &BuildEvent) {
+        let mut state = self.state.lock().unwrap();
+        state.update(event);
+
+        match event {
+            BuildEvent::BuildStarted { .. }
+                if execute!(io::stdout(), EnterAlternateScreen, Hide).is_ok() =>
+            {
+                state.active = true;
+                leave_on_interrupt();
+                spawn_redraw(self.state.clone());
+            }
+            BuildEvent::BuildFinished {
+                elapsed_seconds, ..
+            } => {
+                if state.active {
+                    state.active = false;
+                    let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+                }
+                print!("{}", state.report(*elapsed_seconds));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl TuiState {
+    fn update(&mut self, event: &BuildEvent) {
+        match event {
+            BuildEvent::BuildStarted { tasks, workers, .. } => {
+                self.start_time = Some(Instant::now());
+                self.total_tasks = tasks.len();
+                self.workers = (0..*workers).map(|_| None).collect();
+            }
+            BuildEvent::TaskStarted { rule, worker } => {
+                if let Some(activity) = self.workers.get_mut(*worker as usize) {
+                    *activity = Some(WorkerActivity {
+                        rule: rule.clone(),
+                        start_time: Instant::now(),
+                    });
+                }
+                self.rule_output.insert(rule.clone(), Vec::new());
+            }
+            BuildEvent::CommandStarted { rule, command } => {
+                self.add_output(rule, &format!("Running: {command}"));
+            }
+            BuildEvent::Output { rule, line } => self.add_output(rule, line),
+            BuildEvent::TaskFinished { rule, cached, .. } => {
+                self.finished_tasks += 1;
+                if *cached {
+                    self.cached_tasks += 1;
+                }
+                self.rule_output.remove(rule);
+                self.set_idle(rule);
+            }
+            BuildEvent::TaskFailed { rule, .. } => {
+                self.failed_rules.push(rule.clone());
+                self.set_idle(rule);
+            }
+            BuildEvent::TaskSkipped { .. } => self.skipped_tasks += 1,
+            BuildEvent::TaskCancelled { rule } => {
+                self.cancelled_tasks += 1;
+                self.rule_output.remove(rule);
+                self.set_idle(rule);
+            }
+            BuildEvent::BuildFinished { .. } => {}
+        }
+    }
+
+    fn add_output(&mut self, rule: &str, line: &str) {
+        if self.output.len() == MAX_OUTPUT_LINES {
+            self.output.pop_front();
+        }
+        self.output.push_back(format!("[{rule}] {line}"));
+        if let Some(rule_output) = self.rule_output.get_mut(rule) {
+            rule_output.push(line.to_string());
+        }
+    }
+
+    fn set_idle(&mut self, rule: &str) {
+        for activity in &mut self.workers {
+            if activity
+                .as_ref()
+                .is_some_and(|activity| activity.rule == rule)
+            {
+                *activity = None;
+            }
+        }
+    }
+
+    /// The lines of the screen, cut to fit its width and height
+    fn render(&self, now: Instant, width: usize, height: usize) -> Vec<String> {
+        let elapsed = match self.start_time {
+            Some(start_time) => now.duration_since(start_time).as_secs_f64(),
+            None => 0.0,
+        };
+        let mut lines = vec![format!(
+            "Hexmake: {} of {} tasks done, {} from cache, {elapsed:.1}s",
+            self.finished_tasks, self.total_tasks, self.cached_tasks
+        )];
+        for (worker, activity) in self.workers.iter().enumerate() {
+            lines.push(match activity {
+                Some(activity) => format!(
+                    "  Worker {worker}: {} ({:.1}s)",
+                    activity.rule,
+                    now.duration_since(activity.start_time).as_secs_f64()
+                ),
+                None => format!("  Worker {worker}: idle"),
+            });
+        }
+        lines.push("-".repeat(width));
+
+        let room = height.saturating_sub(lines.len());
+        let skip = self.output.len().saturating_sub(room);
+        lines.extend(self.output.iter().skip(skip).cloned());
+
+        lines.truncate(height);
+        for line in &mut lines {
+            if let Some((index, _)) = line.char_indices().nth(width) {
+                line.truncate(index);
+            }
+        }
+        lines
+    }
+
+    /// What to print after the build, once the screen is given back
+    fn report(&self, elapsed_seconds: f64) -> String {
+        let mut result = String::new();
+        for rule in &self.failed_rules {
+            for line in self.rule_output.get(rule).into_iter().flatten() {
+                result.push_str(&format!("[{rule}] {line}\n"));
+            }
+        }
+        result.push_str(&format!(
+            "{} of {} tasks done, {} from cache",
+            self.finished_tasks, self.total_tasks, self.cached_tasks
+        ));
+        if self.skipped_tasks > 0 {
+            result.push_str(&format!(", {} skipped", self.skipped_tasks));
+        }
+        if self.cancelled_tasks > 0 {
+            result.push_str(&format!(", {} cancelled", self.cancelled_tasks));
+        }
+        result.push_str(&format!(", in {elapsed_seconds:.1}s\n"));
+        result
+    }
+
+    fn draw(&self) -> Result<(), io::Error> {
+        let (width, height) = size().unwrap_or((80, 24));
+        let mut stdout = io::stdout().lock();
+        queue!(stdout, MoveTo(0, 0))?;
+        for line in self.render(Instant::now(), width.into(), height.into()) {
+            queue!(
+                stdout,
+                Print(line),
+                Clear(ClearType::UntilNewLine),
+                MoveToNextLine(1)
+            )?;
+        }
+        queue!(stdout, Clear(ClearType::FromCursorDown))?;
+        stdout.flush()
+    }
+}
+
+/// Redraw the screen regularly, so that the elapsed times keep counting,
+/// until the build finishes
+fn spawn_redraw(state: Arc<Mutex<TuiState>>) {
+    spawn(move || {
+        loop {
+            {
+                let state = state.lock().unwrap();
+                if !state.active {
+                    return;
+                }
+                let _ = state.draw();
+            }
+            sleep(REDRAW_INTERVAL);
+        }
+    });
+}
+
+/// Give the screen back if the build is interrupted with Ctrl-C, because
+/// the terminal would otherwise be left on the alternate screen
+fn leave_on_interrupt() {
+    #[cfg(unix)]
+    if let Ok(mut signals) = signal_hook::iterator::Signals::new([signal_hook::consts::SIGINT]) {
+        spawn(move || {
+            if signals.forever().next().is_some() {
+                let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+                std::process::exit(130);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::events::TaskSummary;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_render() {
+        let mut state = TuiState::default();
+        let task = |rule: &str| TaskSummary {
+            rule: rule.to_string(),
+            depends_on: Vec::new(),
+        };
+        let output = |line: &str| BuildEvent::Output {
+            rule: "main.o".to_string(),
+            line: line.to_string(),
+        };
+        state.update(&BuildEvent::BuildStarted {
+            build_id: "1234abcd".to_string(),
+            tasks: vec![task("main.o"), task("util.o"), task("main")],
+            workers: 2,
+        });
+        state.update(&BuildEvent::TaskStarted {
+            rule: "util.o".to_string(),
+            worker: 1,
+        });
+        state.update(&BuildEvent::TaskFinished {
+            rule: "util.o".to_string(),
+            cached: true,
+            unchanged: false,
+        });
+        state.update(&BuildEvent::TaskStarted {
+            rule: "main.o".to_string(),
+            worker: 0,
+        });
+        state.update(&BuildEvent::CommandStarted {
+            rule: "main.o".to_string(),
+            command: "cc -c main.c".to_string(),
+        });
+        state.update(&output("main.c:1: warning: unused variable"));
+        state.update(&output("main.c:2: error: expected `;`"));
+
+        let now = state.start_time.unwrap();
+        assert_eq!(
+            state.render(now, 40, 6),
+            vec![
+                "Hexmake: 1 of 3 tasks done, 1 from cache",
+                "  Worker 0: main.o (0.0s)",
+                "  Worker 1: idle",
+                "----------------------------------------",
+                "[main.o] main.c:1: warning: unused varia",
+                "[main.o] main.c:2: error: expected `;`",
+            ]
+        );
+
+        state.update(&BuildEvent::TaskFailed {
+            rule: "main.o".to_string(),
+            error: "Command failed!".to_string(),
+        });
+        state.update(&BuildEvent::TaskSkipped {
+            rule: "main".to_string(),
+            failed_dependency: "main.o".to_string(),
+        });
+        assert_eq!(
+            state.report(1.5),
+            "[main.o] Running: cc -c main.c\n\
+             [main.o] main.c:1: warning: unused variable\n\
+             [main.o] main.c:2: error: expected `;`\n\
+             1 of 3 tasks done, 1 from cache, 1 skipped, in 1.5s\n"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fs_err::{create_dir_all, write};
+
+use crate::graph::planner::BuildPlan;
+
+/// Where the volatile status of the current build is written. Rules
+/// with `stamp` set can read it, but its contents are not part of any
+/// cache key.
+pub const VOLATILE_STATUS_FILE: &str = ".hex/volatile-status.txt";
+
+/// Write the volatile status file, if any rule in the plan reads it.
+/// It is rewritten for every build, so that a rule that runs sees the
+/// time and revision of the build that ran it.
+pub fn write_volatile_status(plan: &BuildPlan, path: impl AsRef<Path>) -> io::Result<()> {
+    let stamped = plan
+        .tasks
+        .values()
+        .any(|task| task.lock().unwrap().rule.stamp);
+    if !stamped {
+        return Ok(());
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    write(
+        path,
+        format_volatile_status(timestamp, git_revision().as_deref()),
+    )
+}
+
+/// The contents of the volatile status file: one `KEY value` line per
+/// fact about the build. The revision is left out outside of a git
+/// checkout.
+fn format_volatile_status(timestamp: u64, git_revision: Option<&str>) -> String {
+    let mut result = format!("BUILD_TIMESTAMP {timestamp}\n");
+    if let Some(revision) = git_revision {
+        result.push_str(&format!("BUILD_GIT_REVISION {revision}\n"));
+    }
+    result
+}
+
+/// The commit that the workspace has checked out, if it is a git checkout
+fn git_revision() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let revision = String::from_utf8(output.stdout).ok()?;
+    Some(revision.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_volatile_status() {
+        assert_eq!(
+            format_volatile_status(1700000000, Some("0123abcd")),
+            "BUILD_TIMESTAMP 1700000000\nBUILD_GIT_REVISION 0123abcd\n"
+        );
+        assert_eq!(
+            format_volatile_status(1700000000, None),
+            "BUILD_TIMESTAMP 1700000000\n"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+
+use crate::exec::events::{BuildEvent, BuildObserver};
+
+/// The page that the web UI serves. It polls `/events` and draws the
+/// state of the build from them.
+const PAGE: &str = include_str!("web_ui.html");
+
+/// A small web server for `--web-ui`, which shows the progress of a build
+/// in a browser. It keeps every event of the build, and the page fetches
+/// the ones it has not seen yet from `/events?since=N`.
+pub struct WebUi {
+    events: Arc<Mutex<Vec<BuildEvent>>>,
+    port: u16,
+}
+
+impl WebUi {
+    /// Start serving on the given port of the loopback interface. Port 0
+    /// picks any free port.
+    pub fn start(port: u16) -> Result<Arc<WebUi>, io::Error> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let web_ui = Arc::new(WebUi {
+            events: Arc::default(),
+            port: listener.local_addr()?.port(),
+        });
+
+        let events = web_ui.events.clone();
+        spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let events = events.clone();
+                spawn(move || {
+                    // A browser that goes away is not a problem for the build
+                    let _ = serve(stream, &events);
+                });
+            }
+        });
+
+        Ok(web_ui)
+    }
+
+    /// The address to open in a browser
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}/", self.port)
+    }
+}
+
+impl BuildObserver for WebUi {
+    fn event(&self, event: &BuildEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+/// Answer one HTTP request
+fn serve(stream: TcpStream, events: &Mutex<Vec<BuildEvent>>) -> Result<(), io::Error> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // The headers are not needed, but they have to be read
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, content_type, body) = respond(path, events);
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )?;
+    (&stream).write_all(body.as_bytes())
+}
+
+/// The status, content type, and body of the response to a request
+fn respond(path: &str, events: &Mutex<Vec<BuildEvent>>) -> (&'static str, &'static str, String) {
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    match path {
+        "/" => ("200 OK", "text/html; charset=utf-8", PAGE.to_string()),
+        "/events" => {
+            let since = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("since="))
+                .and_then(|since| since.parse().ok())
+                .unwrap_or(0);
+            let events = events.lock().unwrap();
+            let new_events = &events[since.min(events.len())..];
+            (
+                "200 OK",
+                "application/json",
+                serde_json::to_string(new_events).unwrap(),
+            )
+        }
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_web_ui() {
+        let web_ui = WebUi::start(0).unwrap();
+        web_ui.event(&BuildEvent::TaskStarted {
+            rule: "main".to_string(),
+            worker: 0,
+        });
+        web_ui.event(&BuildEvent::TaskFinished {
+            rule: "main".to_string(),
+            cached: false,
+            unchanged: false,
+        });
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(("127.0.0.1", web_ui.port)).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/events?since=1");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.ends_with(
+                r#"[{"event":"task_finished","rule":"main","cached":false,"unchanged":false}]"#
+            ),
+            "{response}"
+        );
+
+        assert!(get("/").contains("<title>Hexmake</title>"));
+        assert!(get("/events?since=5").ends_with("\r\n\r\n[]"));
+        assert!(get("/favicon.ico").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
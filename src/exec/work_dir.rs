@@ -1,23 +1,35 @@
-use fs_err::{copy, create_dir_all, remove_dir_all};
-use std::io;
+use fs_err::{File, create_dir_all, read_dir, remove_dir_all, remove_file};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
 
 use crate::ast::hex_path::HexPath;
-use ignore::WalkBuilder;
+use crate::ast::hexmake_file::SourceTreeSettings;
+use crate::file_system::output_times::OutputTimes;
+use crate::file_system::posix::{modtime_of, set_modtime, source_walker};
+use crate::file_system::reflink::copy_file;
 
 /// A utility for managing a worker's isolated work directory. Commands are run
 /// in a side directory so that if an input file is not listed in the Hexmake
 /// file, the command will tend to fail.
 pub struct WorkDirManager {
     root_dir: String,
+    source_trees: SourceTreeSettings,
+    output_times: OutputTimes,
 }
 
 impl WorkDirManager {
     /// Create a new work directory manager for the given worker ID.
     /// The work directory will be at `.hex/work/{worker_id}`.
-    pub fn new(worker_id: u32) -> WorkDirManager {
+    pub fn new(
+        worker_id: u32,
+        source_trees: SourceTreeSettings,
+        output_times: OutputTimes,
+    ) -> WorkDirManager {
         WorkDirManager {
             root_dir: format!(".hex/work/{}", worker_id),
+            source_trees,
+            output_times,
         }
     }
 
@@ -32,43 +44,164 @@ impl WorkDirManager {
         Ok(())
     }
 
-    /// Clean the work directory by removing it entirely.
-    pub fn clean(&self) -> io::Result<()> {
-        // Remove the entire directory if it exists
-        if Path::new(&self.root_dir).exists() {
-            remove_dir_all(&self.root_dir)?;
+    /// Make the work directory hold exactly the given inputs, with the
+    /// same directory structure as the workspace, e.g., `src/foo.c` ->
+    /// `{workdir}/src/foo.c`. If an input is a directory, the entire tree
+    /// is copied recursively, leaving out the files that the source tree
+    /// settings exclude.
+    ///
+    /// The work directory is reused from one task to the next, so this
+    /// only copies the files that are missing or have changed since the
+    /// last copy, and it removes everything that is not an input, such as
+    /// the inputs and outputs of the worker's previous task. A file whose
+    /// modification time changed but whose contents did not, such as one
+    /// that was rebuilt or checked out again, is not copied either.
+    pub fn copy_inputs(&self, inputs: &[HexPath]) -> io::Result<()> {
+        let input_set = InputSet::new(inputs, &self.source_trees)?;
+        let root = Path::new(&self.root_dir);
+        remove_non_inputs(root, Path::new(""), &input_set)?;
+
+        for dir in &input_set.dirs {
+            create_dir_all(root.join(dir))?;
+        }
+        for file in &input_set.files {
+            let dst = root.join(file);
+            match sync_state(file, &dst)? {
+                SyncState::UpToDate => {}
+                SyncState::SameContents => copy_metadata(file, &dst)?,
+                SyncState::Changed => copy_one_file(file, &dst)?,
+            }
         }
         Ok(())
     }
 
-    /// Copy input files from the main workspace into the work directory.
-    /// Directory structure is preserved, e.g., `src/foo.c` -> `{workdir}/src/foo.c`.
-    /// If an input is a directory, the entire tree is copied recursively, respecting
-    /// .gitignore files.
-    pub fn copy_inputs(&self, inputs: &[HexPath]) -> io::Result<()> {
+    /// Ensure that the parent directory is made for each output file
+    pub fn prepare_output_directories(&self, outputs: &[HexPath]) -> io::Result<()> {
+        for output in outputs {
+            let dst = Path::new(&self.root_dir).join(output.as_ref());
+
+            // Create parent directories if needed
+            if let Some(parent) = dst.parent() {
+                create_dir_all(parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy output files from the work directory back to the main output
+    /// directory. A file that is the same as the one already in the
+    /// workspace is left alone, so that it keeps its modification time.
+    /// Return whether every output was left alone this way.
+    pub fn copy_outputs(&self, outputs: &[HexPath]) -> io::Result<bool> {
+        let mut all_unchanged = true;
+        for output in outputs {
+            let src = Path::new(&self.root_dir).join(output.as_ref());
+            let dst = Path::new(output.as_ref());
+            if is_unchanged_output(&src, dst)? {
+                continue;
+            }
+            all_unchanged = false;
+
+            // Create parent directories if needed
+            if let Some(parent) = dst.parent() {
+                create_dir_all(parent)?;
+            }
+
+            // Remove any existing file rather than overwriting it in place,
+            // because it may be a hard link into the build cache
+            if dst.is_dir() {
+                remove_dir_all(dst)?;
+            } else if dst.exists() {
+                remove_file(dst)?;
+            }
+
+            if src.is_dir() {
+                copy_tree(&src, dst)?;
+            } else {
+                copy_file(&src, dst)?;
+            }
+            set_output_times(&src, dst, self.output_times)?;
+        }
+        Ok(all_unchanged)
+    }
+}
+
+/// Give the files of an output that was copied from `src` to `dst` the
+/// modification times that the options ask for
+fn set_output_times(src: &Path, dst: &Path, output_times: OutputTimes) -> io::Result<()> {
+    if dst.is_dir() {
+        for entry in read_dir(dst)? {
+            let entry = entry?;
+            set_output_times(&src.join(entry.file_name()), &entry.path(), output_times)?;
+        }
+    } else if let Some(modtime) = output_times.time_for(modtime_of(&src.metadata()?)) {
+        set_modtime(dst, modtime)?;
+    }
+    Ok(())
+}
+
+/// Whether a file output in the work directory has the same contents and
+/// permissions as the one in the workspace. Directory outputs are always
+/// copied.
+fn is_unchanged_output(src: &Path, dst: &Path) -> io::Result<bool> {
+    let Ok(dst_metadata) = dst.symlink_metadata() else {
+        return Ok(false);
+    };
+    let src_metadata = src.metadata()?;
+    Ok(src_metadata.is_file()
+        && dst_metadata.is_file()
+        && dst_metadata.len() == src_metadata.len()
+        && dst_metadata.permissions() == src_metadata.permissions()
+        && same_contents(src, dst)?)
+}
+
+/// Copy a directory tree, keeping the modification times of the files
+pub fn copy_tree(src: &Path, dst: &Path) -> Result<(), io::Error> {
+    create_dir_all(dst)?;
+    for entry in read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.join(entry.file_name());
+        if entry.path().is_dir() {
+            copy_tree(&entry.path(), &dst)?;
+        } else {
+            copy_file(&entry.path(), &dst)?;
+            // The copy may be read-only, and only its owner may change its
+            // time anyway, so it is not opened for writing
+            File::open(&dst)?
+                .file()
+                .set_modified(entry.metadata()?.modified()?)?;
+        }
+    }
+    Ok(())
+}
+
+/// The files and directories that a work directory should hold, as
+/// paths relative to both the workspace and the work directory
+#[derive(Debug, Default, PartialEq)]
+struct InputSet {
+    files: BTreeSet<PathBuf>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl InputSet {
+    fn new(inputs: &[HexPath], source_trees: &SourceTreeSettings) -> io::Result<InputSet> {
+        let mut input_set = InputSet::default();
         for input in inputs {
             let src = Path::new(input.as_ref());
 
             if src.is_file() {
-                let dst = Path::new(&self.root_dir).join(input.as_ref());
-                copy_one_file(src, dst)?;
+                input_set.add_parents(src);
+                input_set.files.insert(src.to_path_buf());
             } else if src.is_dir() {
-                for entry in WalkBuilder::new(src).hidden(false).build() {
+                input_set.add_parents(src);
+                input_set.dirs.insert(src.to_path_buf());
+                for entry in source_walker(src, source_trees).build() {
                     let entry = entry.map_err(io::Error::other)?;
                     let entry_path = entry.path();
-
-                    // Skip the root directory itself
-                    if entry_path == src {
-                        continue;
-                    }
-
-                    let dst = Path::new(&self.root_dir).join(entry_path);
-
                     if entry_path.is_dir() {
-                        // Create the directory
-                        create_dir_all(&dst)?;
+                        input_set.dirs.insert(entry_path.to_path_buf());
                     } else if entry_path.is_file() {
-                        copy_one_file(entry_path, dst)?;
+                        input_set.files.insert(entry_path.to_path_buf());
                     }
                 }
             } else {
@@ -81,48 +214,124 @@ impl WorkDirManager {
                 ));
             }
         }
-        Ok(())
+        Ok(input_set)
     }
 
-    /// Ensure that the parent directory is made for each output file
-    pub fn prepare_output_directories(&self, outputs: &[HexPath]) -> io::Result<()> {
-        for output in outputs {
-            let dst = Path::new(&self.root_dir).join(output.as_ref());
+    /// Add the directories that contain a path
+    fn add_parents(&mut self, path: &Path) {
+        for parent in path.ancestors().skip(1) {
+            if !parent.as_os_str().is_empty() {
+                self.dirs.insert(parent.to_path_buf());
+            }
+        }
+    }
+}
 
-            // Create parent directories if needed
-            if let Some(parent) = dst.parent() {
-                create_dir_all(parent)?;
+/// Remove everything under `dir`, a directory at the path `relative`
+/// within the work directory, that is not in the input set. Anything that
+/// is not a plain file or directory is removed as well, so that copying
+/// an input never writes through a symbolic link.
+fn remove_non_inputs(dir: &Path, relative: &Path, input_set: &InputSet) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if input_set.dirs.contains(&relative) {
+                remove_non_inputs(&entry.path(), &relative, input_set)?;
+            } else {
+                remove_dir_all(entry.path())?;
             }
+        } else if !file_type.is_file() || !input_set.files.contains(&relative) {
+            remove_file(entry.path())?;
         }
-        Ok(())
     }
+    Ok(())
+}
 
-    /// Copy output files from the work directory back to the main output
-    /// directory.
-    pub fn copy_outputs(&self, outputs: &[HexPath]) -> io::Result<()> {
-        for output in outputs {
-            let src = Path::new(&self.root_dir).join(output.as_ref());
-            let dst = Path::new(output.as_ref());
+/// How a file that was copied into the work directory by an earlier task
+/// compares to its source
+#[derive(Debug, PartialEq)]
+enum SyncState {
+    /// The copy matches the source
+    UpToDate,
 
-            // Create parent directories if needed
-            if let Some(parent) = dst.parent() {
-                create_dir_all(parent)?;
-            }
+    /// The copy has the right contents, but not the right modification
+    /// time or permissions
+    SameContents,
+
+    /// The copy is missing or has different contents
+    Changed,
+}
+
+/// Compare a file in the work directory to its source. Copies keep the
+/// modification time of the source, so checking the metadata is enough
+/// unless the modification times differ, and only then are the contents
+/// compared.
+fn sync_state(src: &Path, dst: &Path) -> io::Result<SyncState> {
+    let Ok(dst_metadata) = dst.symlink_metadata() else {
+        return Ok(SyncState::Changed);
+    };
+    let src_metadata = src.metadata()?;
+    if !dst_metadata.is_file() || dst_metadata.len() != src_metadata.len() {
+        return Ok(SyncState::Changed);
+    }
+    if dst_metadata.modified()? == src_metadata.modified()?
+        && dst_metadata.permissions() == src_metadata.permissions()
+    {
+        return Ok(SyncState::UpToDate);
+    }
+    if same_contents(src, dst)? {
+        Ok(SyncState::SameContents)
+    } else {
+        Ok(SyncState::Changed)
+    }
+}
 
-            // Copy the file
-            copy(&src, dst)?;
+/// Whether two files of the same size have the same contents
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let a_chunk = a.fill_buf()?;
+        let b_chunk = b.fill_buf()?;
+        let length = a_chunk.len().min(b_chunk.len());
+        if length == 0 {
+            return Ok(a_chunk.is_empty() && b_chunk.is_empty());
         }
-        Ok(())
+        if a_chunk[..length] != b_chunk[..length] {
+            return Ok(false);
+        }
+        a.consume(length);
+        b.consume(length);
     }
 }
 
-/// Copy one file
-fn copy_one_file(src: &Path, dst: PathBuf) -> Result<(), io::Error> {
+/// Give a copy the modification time and permissions of its source,
+/// without copying the contents
+fn copy_metadata(src: &Path, dst: &Path) -> io::Result<()> {
+    let metadata = src.metadata()?;
+    let file = File::open(dst)?;
+    file.file().set_modified(metadata.modified()?)?;
+    file.file().set_permissions(metadata.permissions())?;
+    Ok(())
+}
+
+/// Copy one file, keeping its permissions and modification time. Any
+/// stale copy is removed first, because it may be read-only.
+fn copy_one_file(src: &Path, dst: &Path) -> Result<(), io::Error> {
     if let Some(parent) = dst.parent() {
         create_dir_all(parent)?;
     }
-    copy(src, &dst)?;
-    Ok(())
+    if dst.symlink_metadata().is_ok() {
+        remove_file(dst)?;
+    }
+
+    copy_file(src, dst)?;
+    copy_metadata(src, dst)
 }
 
 #[cfg(test)]
@@ -131,6 +340,7 @@ mod tests {
     use fs_err as fs;
     use fs_err::{self, File};
     use std::io::Write;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
     /// Create a test directory structure and clean it up after the test
     fn with_test_dir<F>(test_name: &str, f: F)
@@ -148,6 +358,8 @@ mod tests {
         with_test_dir("create_root", |test_dir| {
             let work_dir = WorkDirManager {
                 root_dir: test_dir.to_string(),
+                source_trees: SourceTreeSettings::default(),
+                output_times: OutputTimes::default(),
             };
 
             // Directory should not exist initially
@@ -161,27 +373,74 @@ mod tests {
     }
 
     #[test]
-    fn test_clean() {
-        with_test_dir("clean", |test_dir| {
+    fn test_reuse_work_dir() {
+        with_test_dir("reuse_work_dir", |test_dir| {
             let work_dir = WorkDirManager {
                 root_dir: test_dir.to_string(),
+                source_trees: SourceTreeSettings::default(),
+                output_times: OutputTimes::default(),
             };
-
-            // Create the directory and add a file
-            fs::create_dir_all(test_dir).unwrap();
-            let test_file = format!("{}/test.txt", test_dir);
-            File::create(&test_file)
-                .unwrap()
-                .write_all(b"test")
+            let input_dir = format!("{}_input", test_dir);
+            let _ = fs::remove_dir_all(&input_dir);
+            fs::create_dir_all(format!("{input_dir}/tree/sub")).unwrap();
+            fs::write(format!("{input_dir}/a.txt"), "a").unwrap();
+            fs::write(format!("{input_dir}/b.txt"), "b").unwrap();
+            fs::write(format!("{input_dir}/tree/sub/c.txt"), "c").unwrap();
+            let input = |path: &str| HexPath::try_from(format!("{input_dir}/{path}")).unwrap();
+            let copied = |path: &str| format!("{test_dir}/{input_dir}/{path}");
+
+            // The first task copies everything, and leaves an output
+            work_dir.create_root().unwrap();
+            work_dir
+                .copy_inputs(&[input("a.txt"), input("tree")])
                 .unwrap();
-            assert!(Path::new(&test_file).exists());
+            fs::create_dir_all(format!("{test_dir}/out")).unwrap();
+            fs::write(format!("{test_dir}/out/result.o"), "result").unwrap();
+            assert_eq!(fs::read_to_string(copied("tree/sub/c.txt")).unwrap(), "c");
 
-            // Clean should remove the directory
-            work_dir.clean().unwrap();
-            assert!(!Path::new(test_dir).exists());
+            // Note the copy of an unchanged file, to see that it is kept
+            let a_inode = fs::metadata(copied("a.txt")).unwrap().ino();
 
-            // Clean on non-existent directory should succeed
-            work_dir.clean().unwrap();
+            // The next task gets its own inputs and nothing else
+            fs::write(format!("{input_dir}/tree/sub/c.txt"), "changed").unwrap();
+            work_dir
+                .copy_inputs(&[input("a.txt"), input("b.txt"), input("tree/sub")])
+                .unwrap();
+            assert_eq!(fs::metadata(copied("a.txt")).unwrap().ino(), a_inode);
+            assert_eq!(fs::read_to_string(copied("b.txt")).unwrap(), "b");
+            assert_eq!(
+                fs::read_to_string(copied("tree/sub/c.txt")).unwrap(),
+                "changed"
+            );
+            assert!(!Path::new(&format!("{test_dir}/out")).exists());
+
+            // A file that was rewritten with the same contents is kept. The
+            // times are set by hand because the clock may be coarse.
+            let a_source = PathBuf::from(format!("{input_dir}/a.txt"));
+            let a_state = || sync_state(&a_source, Path::new(&copied("a.txt"))).unwrap();
+            let rewrite_a = |contents: &str, seconds: u64| {
+                fs::write(&a_source, contents).unwrap();
+                File::options()
+                    .write(true)
+                    .open(&a_source)
+                    .unwrap()
+                    .file()
+                    .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+                    .unwrap();
+            };
+            rewrite_a("a", 1000);
+            assert_eq!(a_state(), SyncState::SameContents);
+            work_dir.copy_inputs(&[input("a.txt")]).unwrap();
+            assert_eq!(fs::metadata(copied("a.txt")).unwrap().ino(), a_inode);
+            assert_eq!(a_state(), SyncState::UpToDate);
+            rewrite_a("A", 2000);
+            assert_eq!(a_state(), SyncState::Changed);
+
+            // A task with no inputs gets an empty work directory
+            work_dir.copy_inputs(&[]).unwrap();
+            assert_eq!(fs::read_dir(test_dir).unwrap().count(), 0);
+
+            fs::remove_dir_all(&input_dir).unwrap();
         });
     }
 
@@ -190,6 +449,8 @@ mod tests {
         with_test_dir("root", |test_dir| {
             let work_dir = WorkDirManager {
                 root_dir: test_dir.to_string(),
+                source_trees: SourceTreeSettings::default(),
+                output_times: OutputTimes::default(),
             };
 
             assert_eq!(work_dir.root(), test_dir);
@@ -201,6 +462,8 @@ mod tests {
         with_test_dir("copy_inputs", |test_dir| {
             let work_dir = WorkDirManager {
                 root_dir: test_dir.to_string(),
+                source_trees: SourceTreeSettings::default(),
+                output_times: OutputTimes::default(),
             };
 
             // Create test input files
@@ -224,6 +487,9 @@ mod tests {
                 .unwrap()
                 .write_all(b"nested2")
                 .unwrap();
+            let scratch_file = format!("{}/subdir2/nested.txt.swp", input_dir);
+            fs::write(&scratch_file, b"scratch").unwrap();
+            fs::write(format!("{}/subdir2/.hexignore", input_dir), b"*.swp\n").unwrap();
 
             // Create work directory
             work_dir.create_root().unwrap();
@@ -251,6 +517,9 @@ mod tests {
             let content = fs::read_to_string(&copied_nested2).unwrap();
             assert_eq!(content, "nested2");
 
+            // Files excluded by .hexignore are left out
+            assert!(!Path::new(&format!("{}/{}", test_dir, scratch_file)).exists());
+
             // Clean up input directory
             fs::remove_dir_all(&input_dir).unwrap();
         });
@@ -261,6 +530,8 @@ mod tests {
         with_test_dir("prepare_outputs", |test_dir| {
             let work_dir = WorkDirManager {
                 root_dir: test_dir.to_string(),
+                source_trees: SourceTreeSettings::default(),
+                output_times: OutputTimes::default(),
             };
 
             work_dir.create_root().unwrap();
@@ -283,6 +554,8 @@ mod tests {
         with_test_dir("copy_outputs", |test_dir| {
             let work_dir = WorkDirManager {
                 root_dir: test_dir.to_string(),
+                source_trees: SourceTreeSettings::default(),
+                output_times: OutputTimes::default(),
             };
 
             // Create work directory and output files
@@ -297,21 +570,64 @@ mod tests {
 
             // Copy outputs back to workspace
             let outputs = vec![HexPath::try_from("out/result.txt").unwrap()];
-            work_dir.copy_outputs(&outputs).unwrap();
+            assert!(!work_dir.copy_outputs(&outputs).unwrap());
 
             // Verify file was copied to correct location
             assert!(Path::new("out/result.txt").exists());
             let content = fs::read_to_string("out/result.txt").unwrap();
             assert_eq!(content, "result");
 
+            // The same output again is left alone
+            let copied_time = fs::metadata("out/result.txt").unwrap().modified().unwrap();
+            fs::write(&output_file, "result").unwrap();
+            assert!(work_dir.copy_outputs(&outputs).unwrap());
+            assert_eq!(
+                fs::metadata("out/result.txt").unwrap().modified().unwrap(),
+                copied_time
+            );
+
+            // A different output replaces it
+            fs::write(&output_file, "second result").unwrap();
+            assert!(!work_dir.copy_outputs(&outputs).unwrap());
+            assert_eq!(
+                fs::read_to_string("out/result.txt").unwrap(),
+                "second result"
+            );
+
             // Clean up
             fs_err::remove_dir_all("out").unwrap();
         });
     }
 
+    #[test]
+    fn test_copy_tree() {
+        with_test_dir("copy_tree", |test_dir| {
+            let src = Path::new(test_dir).join("src");
+            let dst = Path::new(test_dir).join("dst");
+            fs::create_dir_all(src.join("sub")).unwrap();
+            let file = src.join("sub/read_only.txt");
+            fs::write(&file, "vendored").unwrap();
+            let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+            File::open(&file)
+                .unwrap()
+                .file()
+                .set_modified(time)
+                .unwrap();
+            fs::set_permissions(&file, std::fs::Permissions::from_mode(0o444)).unwrap();
+
+            // A read-only file is copied with its time
+            copy_tree(&src, &dst).unwrap();
+            let copied = dst.join("sub/read_only.txt");
+            assert_eq!(fs::read_to_string(&copied).unwrap(), "vendored");
+            assert_eq!(fs::metadata(&copied).unwrap().modified().unwrap(), time);
+            assert_eq!(fs::metadata(&copied).unwrap().mode() & 0o777, 0o444);
+        });
+    }
+
     #[test]
     fn test_new() {
-        let work_dir = WorkDirManager::new(42);
+        let work_dir =
+            WorkDirManager::new(42, SourceTreeSettings::default(), OutputTimes::default());
         assert_eq!(work_dir.root(), ".hex/work/42");
     }
 }
@@ -1,8 +1,28 @@
 use crate::ast::hexmake_file::RuleName;
+use crate::exec::rule_builder::CommandFailed;
+use crate::exec::running_commands::ResourceUsage;
 use crate::graph::task::Task;
-use std::collections::BTreeSet;
+use clap::ValueEnum;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex};
 
+/// Which of the ready tasks with the same priority to start first
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Schedule {
+    /// The task that became ready most recently, which tends to finish
+    /// one target before starting on the next
+    #[default]
+    Depth,
+
+    /// The task that became ready first, which works through the graph a
+    /// level at a time
+    Breadth,
+
+    /// The task with the longest chain of tasks waiting on it, which
+    /// keeps the workers busy near the end of the build
+    CriticalPath,
+}
+
 /// A work list of things the conductor has in progress.
 /// This is shared inside a mutex among the conductor and
 /// all the workers.
@@ -16,4 +36,292 @@ pub struct WorkList {
 
     /// Whether an error has occurred or not
     pub error_occurred: bool,
+
+    /// The rules that failed, in the order they failed
+    pub failed_tasks: Vec<RuleName>,
+
+    /// The number of rules that were built by running them
+    pub built_tasks: usize,
+
+    /// The number of rules whose outputs came from the cache
+    pub cache_hits: usize,
+
+    /// The first command that failed, if any
+    pub command_failure: Option<CommandFailed>,
+
+    /// The maximum number of tasks that can run at once in each pool
+    pub pool_limits: BTreeMap<Arc<String>, usize>,
+
+    /// The number of tasks currently running in each pool
+    pub pool_usage: BTreeMap<Arc<String>, usize>,
+
+    /// The memory that running tasks may use between them, in bytes, or
+    /// None if there is no limit
+    pub memory_limit: Option<u64>,
+
+    /// How much memory each rule is expected to need, in bytes
+    pub memory_estimates: BTreeMap<RuleName, u64>,
+
+    /// The memory expected to be in use by the running tasks, in bytes
+    pub memory_usage: u64,
+
+    /// The resources that each rule that was built used
+    pub measured_usage: BTreeMap<RuleName, ResourceUsage>,
+
+    /// The rules whose cache hits were audited and came out different
+    /// when they were rebuilt
+    pub audit_divergences: Vec<RuleName>,
+
+    /// Which task to start first among those with the same priority
+    pub schedule: Schedule,
+
+    /// The length of the longest chain of tasks that starts with each
+    /// task, used by [Schedule::CriticalPath]
+    pub critical_path_lengths: BTreeMap<RuleName, usize>,
+}
+
+impl WorkList {
+    /// Remove and return the pending task that should run next, and mark
+    /// it as running. This is the one with the highest priority, and among
+    /// those, the one that the [Schedule] picks. Tasks whose pool is
+    /// full are skipped, and so are tasks that would need more memory
+    /// than is left, unless nothing else is running.
+    pub fn take_next_task(&mut self) -> Option<Arc<Mutex<Task>>> {
+        let mut best: Option<(usize, (i64, usize))> = None;
+        for (index, task) in self.pending_tasks.iter().enumerate() {
+            let task = task.lock().unwrap();
+            if let Some(pool) = &task.rule.pool
+                && self.pool_usage.get(pool).copied().unwrap_or(0) >= self.pool_limits[pool]
+            {
+                continue;
+            }
+            if let Some(memory_limit) = self.memory_limit
+                && !self.running_tasks.is_empty()
+                && self.memory_usage + self.memory_estimate(&task) > memory_limit
+            {
+                continue;
+            }
+            let rank = (task.rule.priority, self.path_length(&task));
+            let better = match best {
+                None => true,
+                Some((_, best_rank)) if self.schedule == Schedule::Breadth => rank > best_rank,
+                Some((_, best_rank)) => rank >= best_rank,
+            };
+            if better {
+                best = Some((index, rank));
+            }
+        }
+
+        let (index, _) = best?;
+        let task = self.pending_tasks.remove(index);
+        {
+            let mut task = task.lock().unwrap();
+            task.start();
+            self.running_tasks.insert(task.rule_name());
+            self.memory_usage += self.memory_estimate(&task);
+            if let Some(pool) = &task.rule.pool {
+                *self.pool_usage.entry(pool.clone()).or_default() += 1;
+            }
+        }
+        Some(task)
+    }
+
+    /// Record that a task taken with [WorkList::take_next_task] is no
+    /// longer running
+    pub fn finish_task(&mut self, task: &Task) {
+        self.running_tasks.remove(&task.rule_name());
+        self.memory_usage -= self.memory_estimate(task);
+        if let Some(pool) = &task.rule.pool {
+            *self.pool_usage.get_mut(pool).unwrap() -= 1;
+        }
+    }
+
+    /// The critical path length of a task, if the schedule uses it
+    fn path_length(&self, task: &Task) -> usize {
+        match self.schedule {
+            Schedule::CriticalPath => self
+                .critical_path_lengths
+                .get(&task.rule.name)
+                .copied()
+                .unwrap_or(0),
+            Schedule::Depth | Schedule::Breadth => 0,
+        }
+    }
+
+    fn memory_estimate(&self, task: &Task) -> u64 {
+        self.memory_estimates
+            .get(&task.rule.name)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hexmake_file::HexRule;
+
+    #[test]
+    fn test_take_next_task() {
+        let task = |name: &str, priority: i64| {
+            let rule = HexRule {
+                name: name.into(),
+                priority,
+                ..Default::default()
+            };
+            Arc::new(Mutex::new(Task::new(rule.into())))
+        };
+
+        let mut work_list = WorkList {
+            pending_tasks: vec![
+                task("a", 0),
+                task("slow", 10),
+                task("b", 0),
+                task("negative", -1),
+            ],
+            ..Default::default()
+        };
+
+        let mut order = Vec::new();
+        while let Some(task) = work_list.take_next_task() {
+            order.push(task.lock().unwrap().rule_name().to_string());
+        }
+
+        assert_eq!(order, vec!["slow", "b", "a", "negative"]);
+        assert_eq!(work_list.running_tasks.len(), 4);
+    }
+
+    #[test]
+    fn test_schedules() {
+        let task = |name: &str, priority: i64| {
+            let rule = HexRule {
+                name: name.into(),
+                priority,
+                ..Default::default()
+            };
+            Arc::new(Mutex::new(Task::new(rule.into())))
+        };
+        let order = |schedule: Schedule| {
+            let mut work_list = WorkList {
+                pending_tasks: vec![
+                    task("a", 0),
+                    task("long", 0),
+                    task("b", 0),
+                    task("urgent", 1),
+                ],
+                schedule,
+                critical_path_lengths: BTreeMap::from([
+                    ("a".into(), 1),
+                    ("long".into(), 5),
+                    ("b".into(), 2),
+                    ("urgent".into(), 1),
+                ]),
+                ..Default::default()
+            };
+            let mut order = Vec::new();
+            while let Some(task) = work_list.take_next_task() {
+                order.push(task.lock().unwrap().rule_name().to_string());
+            }
+            order
+        };
+
+        // The priority always comes first
+        assert_eq!(order(Schedule::Depth), vec!["urgent", "b", "long", "a"]);
+        assert_eq!(order(Schedule::Breadth), vec!["urgent", "a", "long", "b"]);
+        assert_eq!(
+            order(Schedule::CriticalPath),
+            vec!["urgent", "long", "b", "a"]
+        );
+    }
+
+    #[test]
+    fn test_pools() {
+        let task = |name: &str, pool: Option<&str>| {
+            let rule = HexRule {
+                name: name.into(),
+                pool: pool.map(|pool| Arc::new(pool.to_string())),
+                ..Default::default()
+            };
+            Arc::new(Mutex::new(Task::new(rule.into())))
+        };
+
+        let mut work_list = WorkList {
+            pending_tasks: vec![
+                task("compile", None),
+                task("link2", Some("link")),
+                task("link1", Some("link")),
+            ],
+            pool_limits: BTreeMap::from([(Arc::new("link".to_string()), 1)]),
+            ..Default::default()
+        };
+
+        let take_name = |work_list: &mut WorkList| {
+            work_list
+                .take_next_task()
+                .map(|task| task.lock().unwrap().rule_name().to_string())
+        };
+
+        // Only one link can run at a time
+        assert_eq!(take_name(&mut work_list).as_deref(), Some("link1"));
+        assert_eq!(take_name(&mut work_list).as_deref(), Some("compile"));
+        assert_eq!(take_name(&mut work_list), None);
+
+        // Once the first link finishes, the second one can start
+        let link1 = Task::new(
+            HexRule {
+                name: "link1".into(),
+                pool: Some(Arc::new("link".to_string())),
+                ..Default::default()
+            }
+            .into(),
+        );
+        work_list.finish_task(&link1);
+        assert_eq!(take_name(&mut work_list).as_deref(), Some("link2"));
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let task = |name: &str| {
+            let rule = HexRule {
+                name: name.into(),
+                ..Default::default()
+            };
+            Arc::new(Mutex::new(Task::new(rule.into())))
+        };
+
+        let mut work_list = WorkList {
+            pending_tasks: vec![task("link2"), task("link1"), task("compile")],
+            memory_limit: Some(1000),
+            memory_estimates: BTreeMap::from([
+                ("link1".into(), 600),
+                ("link2".into(), 600),
+                ("compile".into(), 100),
+            ]),
+            ..Default::default()
+        };
+
+        let take_name = |work_list: &mut WorkList| {
+            work_list
+                .take_next_task()
+                .map(|task| task.lock().unwrap().rule_name().to_string())
+        };
+
+        // Only one link fits in memory, but a compile fits beside it
+        assert_eq!(take_name(&mut work_list).as_deref(), Some("compile"));
+        assert_eq!(take_name(&mut work_list).as_deref(), Some("link1"));
+        assert_eq!(take_name(&mut work_list), None);
+        assert_eq!(work_list.memory_usage, 700);
+
+        // Once the first link finishes, the second one can start
+        let finished = |name: &str| Task::new(HexRule::new(name.into()).into());
+        work_list.finish_task(&finished("link1"));
+        assert_eq!(take_name(&mut work_list).as_deref(), Some("link2"));
+
+        // A task that needs more than the limit still runs on its own
+        work_list.finish_task(&finished("compile"));
+        work_list.finish_task(&finished("link2"));
+        work_list.pending_tasks.push(task("huge"));
+        work_list.memory_estimates.insert("huge".into(), 5000);
+        assert_eq!(take_name(&mut work_list).as_deref(), Some("huge"));
+    }
 }
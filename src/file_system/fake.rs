@@ -1,10 +1,18 @@
 #![cfg(test)]
 
 use std::sync::{Arc, Mutex};
-use std::{collections::BTreeMap, io};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use ignore::Match;
+use ignore::gitignore::GitignoreBuilder;
 
 use crate::ast::hex_path::HexPath;
-use crate::file_system::vfs::VirtualFileSystem;
+use crate::ast::hexmake_file::SourceTreeSettings;
+use crate::file_system::posix::HEXIGNORE_FILE;
+use crate::file_system::vfs::{VirtualFileSystem, WalkEntry};
 
 #[derive(Default)]
 pub struct FakeFileSystem {
@@ -15,6 +23,11 @@ pub struct FakeFileSystem {
 struct State {
     files: BTreeMap<HexPath, Arc<Mutex<FakeFile>>>,
     clock: u64,
+    source_trees: SourceTreeSettings,
+
+    /// Files that reading, writing, touching, or removing fails on, as
+    /// if another user owned them
+    denied: BTreeSet<HexPath>,
 }
 
 impl Clone for FakeFileSystem {
@@ -24,6 +37,8 @@ impl Clone for FakeFileSystem {
     fn clone(&self) -> Self {
         let old_state = self.state.lock().unwrap();
         let clock = old_state.clock;
+        let source_trees = old_state.source_trees;
+        let denied = old_state.denied.clone();
         let mut files = BTreeMap::new();
         for (path, file) in &old_state.files {
             files.insert(
@@ -32,7 +47,12 @@ impl Clone for FakeFileSystem {
             );
         }
 
-        let new_state = State { clock, files };
+        let new_state = State {
+            clock,
+            files,
+            source_trees,
+            denied,
+        };
 
         Self {
             state: Arc::new(Mutex::new(new_state)),
@@ -43,7 +63,9 @@ impl Clone for FakeFileSystem {
 impl VirtualFileSystem for FakeFileSystem {
     fn copy(&self, source: &HexPath, destination: &HexPath) -> Result<(), io::Error> {
         let contents = self.read(source)?;
+        let mode = self.get_file(source)?.lock().unwrap().mode;
         self.write(destination, &contents)?;
+        self.get_file(destination)?.lock().unwrap().mode = mode;
         Ok(())
     }
 
@@ -52,13 +74,36 @@ impl VirtualFileSystem for FakeFileSystem {
         Ok(())
     }
 
+    fn hard_link(&self, source: &HexPath, destination: &HexPath) -> Result<(), io::Error> {
+        let file = self.get_file(source)?;
+        let mut state = self.state.lock().unwrap();
+        if state.files.contains_key(destination) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("File exists: {}", destination),
+            ));
+        }
+        state.files.insert(destination.clone(), file);
+        Ok(())
+    }
+
     fn is_file(&self, path: &HexPath) -> Result<bool, io::Error> {
         let state = self.state.lock().unwrap();
         Ok(state.files.contains_key(path))
     }
 
+    fn remove_dir_all(&self, path: &HexPath) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        let prefix = format!("{path}/");
+        state
+            .files
+            .retain(|file_path, _| !file_path.starts_with(&prefix));
+        Ok(())
+    }
+
     fn remove_file(&self, path: &HexPath) -> Result<(), io::Error> {
         let mut state = self.state.lock().unwrap();
+        state.check_access(path)?;
         state.files.remove(path);
         Ok(())
     }
@@ -84,7 +129,19 @@ impl VirtualFileSystem for FakeFileSystem {
         Ok(file.lock().unwrap().modtime)
     }
 
+    fn mode(&self, path: &HexPath) -> Result<u32, io::Error> {
+        let file = self.get_file(path)?;
+        Ok(file.lock().unwrap().mode.unwrap_or(0o644))
+    }
+
+    fn created(&self, path: &HexPath) -> Result<u64, io::Error> {
+        let file = self.get_file(path)?;
+
+        Ok(file.lock().unwrap().created)
+    }
+
     fn read(&self, path: &HexPath) -> Result<Vec<u8>, io::Error> {
+        self.state.lock().unwrap().check_access(path)?;
         let file = self.get_file(path)?;
         Ok(file.lock().unwrap().contents.to_bytes())
     }
@@ -97,18 +154,60 @@ impl VirtualFileSystem for FakeFileSystem {
     fn rename(&self, old_path: &HexPath, new_path: &HexPath) -> Result<(), io::Error> {
         let mut state = self.state.lock().unwrap();
 
-        let file = state
+        if let Some(file) = state.files.remove(old_path) {
+            state.files.insert(new_path.clone(), file);
+            return Ok(());
+        }
+
+        // Renaming a directory moves every file underneath it
+        let old_prefix = format!("{old_path}/");
+        let moved: Vec<HexPath> = state
             .files
-            .remove(old_path)
-            .ok_or_else(|| file_not_found(old_path))?;
+            .keys()
+            .filter(|path| path.starts_with(&old_prefix))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Err(file_not_found(old_path));
+        }
+        for path in moved {
+            let file = state.files.remove(&path).unwrap();
+            let new_file_path = new_path
+                .child(&path[old_prefix.len()..])
+                .map_err(io::Error::other)?;
+            state.files.insert(new_file_path, file);
+        }
+
+        Ok(())
+    }
 
-        state.files.insert(new_path.clone(), file);
+    fn now(&self) -> Result<u64, io::Error> {
+        Ok(self.state.lock().unwrap().clock)
+    }
 
+    fn set_modtime(&self, path: &HexPath, modtime: u64) -> Result<(), io::Error> {
+        self.get_file(path)?.lock().unwrap().modtime = modtime;
+        Ok(())
+    }
+
+    fn set_permissions(
+        &self,
+        path: &HexPath,
+        mode: u32,
+        group: Option<u32>,
+    ) -> Result<(), io::Error> {
+        // Directories are implicit, so only files have permissions
+        if let Ok(file) = self.get_file(path) {
+            let mut file = file.lock().unwrap();
+            file.mode = Some(mode);
+            file.group = group.or(file.group);
+        }
         Ok(())
     }
 
     fn touch(&self, path: &HexPath) -> Result<(), io::Error> {
         let mut state = self.state.lock().unwrap();
+        state.check_access(path)?;
         let clock = state.clock;
 
         state
@@ -119,6 +218,8 @@ impl VirtualFileSystem for FakeFileSystem {
                 Arc::new(Mutex::new(FakeFile {
                     contents: FakeFileContent::default(),
                     modtime: clock,
+                    created: clock,
+                    ..FakeFile::default()
                 }))
             });
 
@@ -129,6 +230,7 @@ impl VirtualFileSystem for FakeFileSystem {
 
     fn write(&self, path: &HexPath, contents: &[u8]) -> Result<(), io::Error> {
         let mut state = self.state.lock().unwrap();
+        state.check_access(path)?;
 
         let modtime = state.clock;
         state.files.insert(
@@ -136,6 +238,8 @@ impl VirtualFileSystem for FakeFileSystem {
             Arc::new(Mutex::new(FakeFile {
                 contents: FakeFileContent::Binary(contents.to_vec()),
                 modtime,
+                created: modtime,
+                ..FakeFile::default()
             })),
         );
 
@@ -155,18 +259,58 @@ impl VirtualFileSystem for FakeFileSystem {
         }
 
         // Otherwise, walk all files under this directory
-        let prefix = format!("{}/", path);
-        for file_path in state.files.keys() {
-            if file_path.starts_with(&prefix) {
-                result.push(file_path.clone());
+        for (file_path, _) in state.walk(path) {
+            result.push(file_path.clone());
+        }
+
+        Ok(result)
+    }
+
+    fn tree_walk_metadata(&self, path: &HexPath) -> Result<Vec<WalkEntry>, io::Error> {
+        let state = self.state.lock().unwrap();
+
+        // Directories are implicit, but the walk reports them like the
+        // Posix one does, including the walked directory itself
+        let mut result = Vec::new();
+        let mut dirs = BTreeSet::new();
+        for (file_path, file) in state.walk(path) {
+            let file = file.lock().unwrap();
+            if file_path != path {
+                let mut dir = file_path.parent();
+                while let Some(parent) = dir.filter(|dir| dir.len() >= path.len()) {
+                    dir = parent.parent();
+                    dirs.insert(parent);
+                }
             }
+            result.push(WalkEntry {
+                path: file_path.clone(),
+                size: file.contents.size(),
+                modtime: file.modtime,
+                is_file: true,
+            });
+        }
+        for dir in dirs {
+            result.push(WalkEntry {
+                path: dir,
+                size: 0,
+                modtime: 0,
+                is_file: false,
+            });
         }
+        result.sort_by(|a, b| a.path.cmp(&b.path));
 
         Ok(result)
     }
 
     fn exists(&self, path: &HexPath) -> Result<bool, io::Error> {
-        self.is_file(path)
+        // Directories are implicit, so a directory exists if any file is
+        // underneath it
+        let state = self.state.lock().unwrap();
+        let prefix = format!("{}/", path);
+        Ok(state
+            .files
+            .keys()
+            .any(|file_path| file_path == path || file_path.starts_with(&prefix)))
     }
 }
 
@@ -182,7 +326,29 @@ impl FakeFileSystem {
             .ok_or_else(|| file_not_found(path))
     }
 
+    /// Leave files out of tree walks as the settings say, like
+    /// [PosixFileSystem::new](crate::file_system::posix::PosixFileSystem::new)
+    pub fn set_source_trees(&self, source_trees: SourceTreeSettings) {
+        self.state.lock().unwrap().source_trees = source_trees;
+    }
+
+    /// Move the clock forward, as if time passed
+    pub fn advance_clock(&self, ticks: u64) {
+        self.state.lock().unwrap().clock += ticks;
+    }
+
     /// Write a simulated large file (for testing without using lots of memory)
+    /// Make reading, writing, touching, or removing a file fail with
+    /// a permission error
+    pub fn deny_access(&self, path: &HexPath) {
+        self.state.lock().unwrap().denied.insert(path.clone());
+    }
+
+    /// The group that was set on a file, if any
+    pub fn group(&self, path: &HexPath) -> Result<Option<u32>, io::Error> {
+        Ok(self.get_file(path)?.lock().unwrap().group)
+    }
+
     pub fn write_all_zeros(&self, path: &HexPath, size: u64) -> Result<(), io::Error> {
         let mut state = self.state.lock().unwrap();
 
@@ -192,6 +358,8 @@ impl FakeFileSystem {
             Arc::new(Mutex::new(FakeFile {
                 contents: FakeFileContent::AllZeros(size),
                 modtime,
+                created: modtime,
+                ..FakeFile::default()
             })),
         );
 
@@ -201,6 +369,88 @@ impl FakeFileSystem {
     }
 }
 
+impl State {
+    /// Fail with a permission error if access to a file is denied
+    fn check_access(&self, path: &HexPath) -> Result<(), io::Error> {
+        if self.denied.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Permission denied: {path}"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The files in the tree at `path`, leaving out the ones that the
+    /// source tree settings exclude
+    fn walk(&self, path: &HexPath) -> Vec<(&HexPath, &Arc<Mutex<FakeFile>>)> {
+        let prefix = format!("{}/", path);
+        self.files
+            .iter()
+            .filter(|(file_path, _)| {
+                *file_path == path
+                    || (file_path.starts_with(&prefix) && self.is_included(&prefix, file_path))
+            })
+            .collect()
+    }
+
+    /// Whether a file below a walked directory is part of the tree. As
+    /// with the Posix walker, each directory between the walked one and
+    /// the file can exclude it, but the walked directory itself cannot.
+    fn is_included(&self, prefix: &str, file_path: &str) -> bool {
+        let mut below = Vec::new();
+        for (index, _) in file_path[prefix.len()..].match_indices('/') {
+            below.push((&file_path[..prefix.len() + index], true));
+        }
+        below.push((file_path, false));
+
+        below.into_iter().all(|(path, is_dir)| {
+            let name = path.rsplit('/').next().unwrap();
+            (self.source_trees.include_hidden || !name.starts_with('.'))
+                && (self.source_trees.include_ignored || !self.is_ignored(path, is_dir))
+        })
+    }
+
+    /// Whether the ignore files in the directories above a path exclude
+    /// it. The ignore file deepest in the tree that mentions the path
+    /// decides, and in one directory, `.hexignore` comes before
+    /// `.gitignore`.
+    fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let mut dirs = Vec::new();
+        let mut rest = path;
+        while let Some((parent, _)) = rest.rsplit_once('/') {
+            dirs.push(parent);
+            rest = parent;
+        }
+        dirs.push("");
+
+        for dir in dirs {
+            for name in [HEXIGNORE_FILE, ".gitignore"] {
+                let (ignore_path, relative) = if dir.is_empty() {
+                    (name.to_string(), path)
+                } else {
+                    (format!("{dir}/{name}"), &path[dir.len() + 1..])
+                };
+                let Some(file) = self.files.get(&HexPath::try_from(ignore_path).unwrap()) else {
+                    continue;
+                };
+                let contents = file.lock().unwrap().contents.to_bytes();
+                let mut builder = GitignoreBuilder::new(dir);
+                for line in String::from_utf8_lossy(&contents).lines() {
+                    // Like the Posix walker, skip lines that do not parse
+                    let _ = builder.add_line(None, line);
+                }
+                match builder.build().unwrap().matched(relative, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
+}
+
 /// Construct an IO error corresponding to a file not existing
 fn file_not_found(path: &HexPath) -> io::Error {
     io::Error::new(io::ErrorKind::NotFound, format!("File not found: {}", path))
@@ -241,6 +491,10 @@ impl FakeFileContent {
 #[derive(Clone, Default)]
 struct FakeFile {
     contents: FakeFileContent,
-    #[allow(unused)]
     modtime: u64,
+    created: u64,
+
+    /// The mode and group that were set with `set_permissions`
+    mode: Option<u32>,
+    group: Option<u32>,
 }
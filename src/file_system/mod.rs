@@ -1,3 +1,5 @@
 pub mod fake;
+pub mod output_times;
 pub mod posix;
+pub mod reflink;
 pub mod vfs;
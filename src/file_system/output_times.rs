@@ -0,0 +1,68 @@
+use std::env;
+
+use clap::ValueEnum;
+
+/// The environment variable that sets the time of outputs with
+/// [OutputTimes::Fixed], as seconds since the Unix epoch. Many other
+/// tools that make reproducible builds read the same variable.
+const SOURCE_DATE_EPOCH_VAR: &str = "SOURCE_DATE_EPOCH";
+
+/// The time of outputs with [OutputTimes::Fixed] when
+/// [SOURCE_DATE_EPOCH_VAR] is not set: the start of 1980, which is the
+/// earliest time that zip files can hold
+const DEFAULT_FIXED_TIME: u64 = 315_532_800;
+
+/// What modification time an output gets when it is copied into `out/`,
+/// either from a rule's work directory or from the cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputTimes {
+    /// The time the output was copied into `out/`
+    #[default]
+    Now,
+
+    /// The time the output's contents were written, either by the rule or,
+    /// for an output from the cache, when the cache first stored them
+    Preserve,
+
+    /// The same fixed time for every output, from SOURCE_DATE_EPOCH or
+    /// else the start of 1980
+    Fixed,
+}
+
+impl OutputTimes {
+    /// The modification time for a copy of a file that was last modified
+    /// at `original`, or None to leave the copy with the time it got when
+    /// it was written
+    pub fn time_for(self, original: u64) -> Option<u64> {
+        match self {
+            OutputTimes::Now => None,
+            OutputTimes::Preserve => Some(original),
+            OutputTimes::Fixed => Some(fixed_time(env::var(SOURCE_DATE_EPOCH_VAR).ok())),
+        }
+    }
+}
+
+/// The time for [OutputTimes::Fixed], given the value of
+/// [SOURCE_DATE_EPOCH_VAR]. A value that is not a number is ignored.
+fn fixed_time(source_date_epoch: Option<String>) -> u64 {
+    source_date_epoch
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(DEFAULT_FIXED_TIME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_for() {
+        assert_eq!(OutputTimes::Now.time_for(1700000000), None);
+        assert_eq!(OutputTimes::Preserve.time_for(1700000000), Some(1700000000));
+        assert_eq!(fixed_time(Some("1600000000".to_string())), 1600000000);
+        assert_eq!(
+            fixed_time(Some("yesterday".to_string())),
+            DEFAULT_FIXED_TIME
+        );
+        assert_eq!(fixed_time(None), DEFAULT_FIXED_TIME);
+    }
+}
@@ -1,20 +1,58 @@
 use std::{
-    fs::{self, OpenOptions},
+    fs::{self, Metadata, OpenOptions, Permissions},
     io,
-    time::UNIX_EPOCH,
+    os::unix::fs::{PermissionsExt, chown},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{ast::hex_path::HexPath, file_system::vfs::VirtualFileSystem};
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::SourceTreeSettings;
+use crate::file_system::reflink::copy_file;
+use crate::file_system::vfs::{VirtualFileSystem, WalkEntry, side_file_name};
 use ignore::WalkBuilder;
 
+/// The name of the files that list paths to leave out of source trees, in
+/// the same syntax as `.gitignore`
+pub const HEXIGNORE_FILE: &str = ".hexignore";
+
+/// A walker over a source tree, which skips the files that the settings
+/// leave out. Only the `.gitignore` and `.hexignore` files in the tree and
+/// its parent directories are read, not the user's global excludes, so
+/// that the same tree has the same files on every machine, and whether or
+/// not it is a git checkout.
+pub fn source_walker(path: impl AsRef<Path>, settings: &SourceTreeSettings) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(path);
+    builder
+        .standard_filters(false)
+        .hidden(!settings.include_hidden);
+    if !settings.include_ignored {
+        builder
+            .git_ignore(true)
+            .require_git(false)
+            .parents(true)
+            .add_custom_ignore_filename(HEXIGNORE_FILE);
+    }
+    builder
+}
+
 /// The underlying Posix filesystem
 #[derive(Default)]
-pub struct PosixFileSystem {}
+pub struct PosixFileSystem {
+    source_trees: SourceTreeSettings,
+}
+
+impl PosixFileSystem {
+    /// A file system whose tree walks include the files that the settings
+    /// say are part of a source tree
+    pub fn new(source_trees: SourceTreeSettings) -> PosixFileSystem {
+        PosixFileSystem { source_trees }
+    }
+}
 
 impl VirtualFileSystem for PosixFileSystem {
     fn copy(&self, source: &HexPath, destination: &HexPath) -> Result<(), io::Error> {
-        fs::copy(source, destination)?;
-        Ok(())
+        copy_file(source.as_ref(), destination.as_ref())
     }
 
     fn create_dir_all(&self, path: &HexPath) -> Result<(), io::Error> {
@@ -25,6 +63,10 @@ impl VirtualFileSystem for PosixFileSystem {
         fs::metadata(path).map(|metadata| metadata.len())
     }
 
+    fn hard_link(&self, source: &HexPath, destination: &HexPath) -> Result<(), io::Error> {
+        fs::hard_link(source, destination)
+    }
+
     fn is_file(&self, path: &HexPath) -> Result<bool, io::Error> {
         if !fs::exists(path)? {
             return Ok(false);
@@ -48,11 +90,30 @@ impl VirtualFileSystem for PosixFileSystem {
     }
 
     fn modtime(&self, path: &HexPath) -> Result<u64, io::Error> {
-        Ok(fs::metadata(path)?
-            .modified()
-            .unwrap()
+        Ok(modtime_of(&fs::metadata(path)?))
+    }
+
+    fn mode(&self, path: &HexPath) -> Result<u32, io::Error> {
+        Ok(fs::metadata(path)?.permissions().mode() & 0o7777)
+    }
+
+    fn created(&self, path: &HexPath) -> Result<u64, io::Error> {
+        // Not every file system records when a file was created. For
+        // those, fall back to when it was last modified.
+        let metadata = fs::metadata(path)?;
+        match metadata.created() {
+            Ok(created) => Ok(created
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?
+                .as_secs()),
+            Err(_) => Ok(modtime_of(&metadata)),
+        }
+    }
+
+    fn now(&self) -> Result<u64, io::Error> {
+        Ok(SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .unwrap()
+            .map_err(io::Error::other)?
             .as_secs())
     }
 
@@ -60,6 +121,10 @@ impl VirtualFileSystem for PosixFileSystem {
         fs::read(path)
     }
 
+    fn remove_dir_all(&self, path: &HexPath) -> Result<(), io::Error> {
+        fs::remove_dir_all(path)
+    }
+
     fn remove_file(&self, path: &HexPath) -> Result<(), io::Error> {
         fs::remove_file(path)
     }
@@ -68,16 +133,34 @@ impl VirtualFileSystem for PosixFileSystem {
         fs::rename(old_path, new_path)
     }
 
+    fn set_modtime(&self, path: &HexPath, modtime: u64) -> Result<(), io::Error> {
+        set_modtime(path.as_ref(), modtime)
+    }
+
+    fn set_permissions(
+        &self,
+        path: &HexPath,
+        mode: u32,
+        group: Option<u32>,
+    ) -> Result<(), io::Error> {
+        // Changing the group can clear the set-group-ID bit, so the mode
+        // is set afterwards
+        if group.is_some() {
+            chown(path, None, group)?;
+        }
+        fs::set_permissions(path, Permissions::from_mode(mode))
+    }
+
     fn touch(&self, path: &HexPath) -> Result<(), io::Error> {
-        // Open the file in append mode. This should update the modification
-        // time.
-        let _ = OpenOptions::new().append(true).create(true).open(path)?;
-        Ok(())
+        // Opening the file creates it if needed, but only writing to it
+        // would change its time, so the time is set explicitly
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        file.set_modified(SystemTime::now())
     }
 
     fn write(&self, path: &HexPath, contents: &[u8]) -> Result<(), io::Error> {
         // So that the write is atomic, write to a side file and then rename it
-        let side_file = format!("{}.tmp", path);
+        let side_file = side_file_name(path);
 
         fs::write(&side_file, contents)?;
         fs::rename(side_file, path)?;
@@ -87,7 +170,7 @@ impl VirtualFileSystem for PosixFileSystem {
 
     fn tree_walk(&self, path: &HexPath) -> Result<Vec<HexPath>, io::Error> {
         let mut result = Vec::new();
-        for entry in WalkBuilder::new(path).hidden(false).build() {
+        for entry in source_walker(path, &self.source_trees).build() {
             let entry = entry.map_err(|e| io::Error::other(e.to_string()))?;
             let entry_path = entry.path();
             result.push(HexPath::try_from(entry_path.to_str().unwrap()).unwrap());
@@ -96,7 +179,210 @@ impl VirtualFileSystem for PosixFileSystem {
         Ok(result)
     }
 
+    fn tree_walk_metadata(&self, path: &HexPath) -> Result<Vec<WalkEntry>, io::Error> {
+        let mut result = Vec::new();
+        for entry in source_walker(path, &self.source_trees).build() {
+            let entry = entry.map_err(|e| io::Error::other(e.to_string()))?;
+
+            // The walker does not follow symlinks, so look through them
+            // explicitly to match what is_file() and file_size() report
+            let metadata = if entry.path_is_symlink() {
+                fs::metadata(entry.path())?
+            } else {
+                entry
+                    .metadata()
+                    .map_err(|e| io::Error::other(e.to_string()))?
+            };
+
+            result.push(WalkEntry {
+                path: HexPath::try_from(entry.path().to_str().unwrap()).unwrap(),
+                size: metadata.len(),
+                modtime: modtime_of(&metadata),
+                is_file: metadata.is_file(),
+            });
+        }
+        result.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(result)
+    }
+
     fn exists(&self, path: &HexPath) -> Result<bool, io::Error> {
         fs::exists(path)
     }
 }
+
+/// Set the modification time of a file, in seconds since the Unix epoch.
+/// The file is only opened for reading, so that outputs that are read-only
+/// can be changed too.
+pub fn set_modtime(path: &Path, modtime: u64) -> Result<(), io::Error> {
+    fs::File::open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(modtime))
+}
+
+/// The modification time of a file, in seconds since the Unix epoch
+pub fn modtime_of(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::fake::FakeFileSystem;
+
+    #[test]
+    fn test_tree_walk_metadata() {
+        let test_dir = ".hex/test/tree_walk_metadata";
+        let _ = fs::remove_dir_all(test_dir);
+        fs::create_dir_all(format!("{test_dir}/subdir")).unwrap();
+        fs::write(format!("{test_dir}/a.txt"), b"hello").unwrap();
+        fs::write(format!("{test_dir}/subdir/b.txt"), b"hi").unwrap();
+
+        let vfs = PosixFileSystem::default();
+        let entries = vfs
+            .tree_walk_metadata(&HexPath::try_from(test_dir).unwrap())
+            .unwrap();
+
+        let summary: Vec<(String, bool)> = entries
+            .iter()
+            .map(|entry| (entry.path.to_string(), entry.is_file))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (test_dir.to_string(), false),
+                (format!("{test_dir}/a.txt"), true),
+                (format!("{test_dir}/subdir"), false),
+                (format!("{test_dir}/subdir/b.txt"), true),
+            ]
+        );
+        assert_eq!(entries[1].size, 5);
+        assert_eq!(entries[3].size, 2);
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_tree_walk_hexignore() {
+        let test_dir = ".hex/test/tree_walk_hexignore";
+        let _ = fs::remove_dir_all(test_dir);
+        fs::create_dir_all(format!("{test_dir}/scratch")).unwrap();
+        fs::write(format!("{test_dir}/.hexignore"), b"*~\nscratch/\n").unwrap();
+        fs::write(format!("{test_dir}/main.c"), b"int main;").unwrap();
+        fs::write(format!("{test_dir}/main.c~"), b"int main").unwrap();
+        fs::write(format!("{test_dir}/scratch/notes.txt"), b"todo").unwrap();
+
+        let vfs = PosixFileSystem::default();
+        let paths: Vec<String> = vfs
+            .tree_walk(&HexPath::try_from(test_dir).unwrap())
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                test_dir.to_string(),
+                format!("{test_dir}/.hexignore"),
+                format!("{test_dir}/main.c"),
+            ]
+        );
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_source_tree_settings() {
+        let test_dir = ".hex/test/source_tree_settings";
+        let _ = fs::remove_dir_all(test_dir);
+        let files = [
+            (".gitignore", "*.log\n!keep.log\nbuild/\n"),
+            (".hexignore", "*.swp\n"),
+            (".config", "color=on"),
+            ("build/app.o", "object"),
+            ("keep.log", "kept"),
+            ("main.c", "int main;"),
+            ("main.c.swp", "swap"),
+            ("other.log", "other"),
+            ("sub/.gitignore", "!debug.log\n"),
+            ("sub/debug.log", "debug"),
+            ("sub/trace.log", "trace"),
+        ];
+        let fake = FakeFileSystem::default();
+        for (path, contents) in files {
+            let path = format!("{test_dir}/{path}");
+            fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            fake.write(&HexPath::try_from(path).unwrap(), contents.as_bytes())
+                .unwrap();
+        }
+
+        // The files of the tree, as the Posix and fake file systems see it
+        let walk = |source_trees: SourceTreeSettings| {
+            let root = HexPath::try_from(test_dir).unwrap();
+            let posix: Vec<String> = PosixFileSystem::new(source_trees)
+                .tree_walk_metadata(&root)
+                .unwrap()
+                .into_iter()
+                .filter(|entry| entry.is_file)
+                .map(|entry| entry.path[test_dir.len() + 1..].to_string())
+                .collect();
+            fake.set_source_trees(source_trees);
+            let fake: Vec<String> = fake
+                .tree_walk(&root)
+                .unwrap()
+                .iter()
+                .map(|path| path[test_dir.len() + 1..].to_string())
+                .collect();
+            assert_eq!(posix, fake);
+            posix
+        };
+
+        assert_eq!(
+            walk(SourceTreeSettings::default()),
+            vec![
+                ".config",
+                ".gitignore",
+                ".hexignore",
+                "keep.log",
+                "main.c",
+                "sub/.gitignore",
+                "sub/debug.log",
+            ]
+        );
+        assert_eq!(
+            walk(SourceTreeSettings {
+                include_ignored: false,
+                include_hidden: false,
+            }),
+            vec!["keep.log", "main.c", "sub/debug.log"]
+        );
+        assert_eq!(
+            walk(SourceTreeSettings {
+                include_ignored: true,
+                include_hidden: false,
+            }),
+            vec![
+                "build/app.o",
+                "keep.log",
+                "main.c",
+                "main.c.swp",
+                "other.log",
+                "sub/debug.log",
+                "sub/trace.log",
+            ]
+        );
+        assert_eq!(
+            walk(SourceTreeSettings {
+                include_ignored: true,
+                include_hidden: true,
+            })
+            .len(),
+            files.len()
+        );
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::path::Path;
+
+/// Copy a file, like `fs::copy`. On file systems that support
+/// copy-on-write clones, such as btrfs, XFS, and APFS, the copy shares
+/// the blocks of the original, so it takes no time or space no matter how
+/// large the file is. Elsewhere, the file is copied normally.
+pub fn copy_file(src: &Path, dst: &Path) -> io::Result<()> {
+    if !clone_file(src, dst)? {
+        fs_err::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Try to clone a file with the FICLONE ioctl. Return whether it worked.
+#[cfg(target_os = "linux")]
+pub fn clone_file(src: &Path, dst: &Path) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let source = fs_err::File::open(src)?;
+    let permissions = source.metadata()?.permissions();
+    let destination = fs_err::File::create(dst)?;
+    // SAFETY: both file descriptors stay open for the whole call
+    let result = unsafe {
+        libc::ioctl(
+            destination.file().as_raw_fd(),
+            libc::FICLONE,
+            source.file().as_raw_fd(),
+        )
+    };
+    if result != 0 {
+        return Ok(false);
+    }
+    destination.file().set_permissions(permissions)?;
+    Ok(true)
+}
+
+/// Try to clone a file with clonefile(). Return whether it worked.
+#[cfg(target_os = "macos")]
+pub fn clone_file(src: &Path, dst: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Unlike a copy, a clone cannot replace an existing file
+    if dst.symlink_metadata().is_ok() {
+        fs_err::remove_file(dst)?;
+    }
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dst = CString::new(dst.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid C strings for the whole call
+    let result = unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) };
+    Ok(result == 0)
+}
+
+/// Clones are not supported on this system
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn clone_file(_src: &Path, _dst: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_copy_file() {
+        let test_dir = Path::new(".hex/test/reflink");
+        let _ = std::fs::remove_dir_all(test_dir);
+        std::fs::create_dir_all(test_dir).unwrap();
+        let src = test_dir.join("src.sh");
+        let dst = test_dir.join("dst.sh");
+        std::fs::write(&src, "echo hello\n").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(&dst, "old contents that are longer\n").unwrap();
+
+        // The copy replaces the old file, whether or not it is a clone
+        copy_file(&src, &dst).unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "echo hello\n");
+        assert_eq!(
+            std::fs::metadata(&dst).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        assert!(copy_file(&test_dir.join("missing"), &dst).is_err());
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+}
@@ -1,21 +1,64 @@
 #![allow(unused)]
 use std::io;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::ast::hex_path::HexPath;
 
+/// One entry found while walking a file tree, along with the
+/// metadata that callers most often need about it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalkEntry {
+    pub path: HexPath,
+    pub size: u64,
+    pub modtime: u64,
+    pub is_file: bool,
+}
+
+/// A name to write a file under before renaming it into place, which is
+/// different for every write, so that processes that write the same file
+/// at once, possibly as different users, never share one
+pub fn side_file_name(path: &HexPath) -> String {
+    static NEXT_SIDE_FILE: AtomicUsize = AtomicUsize::new(0);
+    let count = NEXT_SIDE_FILE.fetch_add(1, Ordering::Relaxed);
+    format!("{path}.{}-{count}.tmp", process::id())
+}
+
+/// Whether a path is a side file that [side_file_name] made
+pub fn is_side_file(path: &HexPath) -> bool {
+    path.ends_with(".tmp")
+}
+
 /// An abstract file system that can be faked out for testing.
 pub trait VirtualFileSystem: Send + Sync {
     fn copy(&self, source: &HexPath, destination: &HexPath) -> Result<(), io::Error>;
+    fn created(&self, path: &HexPath) -> Result<u64, io::Error>;
     fn create_dir_all(&self, path: &HexPath) -> Result<(), io::Error>;
     fn exists(&self, path: &HexPath) -> Result<bool, io::Error>;
     fn file_size(&self, path: &HexPath) -> Result<u64, io::Error>;
+    fn hard_link(&self, source: &HexPath, destination: &HexPath) -> Result<(), io::Error>;
     fn is_file(&self, path: &HexPath) -> Result<bool, io::Error>;
     fn list_dir(&self, path: &HexPath) -> Result<Vec<HexPath>, io::Error>;
     fn modtime(&self, path: &HexPath) -> Result<u64, io::Error>;
+
+    /// The permission bits of a file or directory
+    fn mode(&self, path: &HexPath) -> Result<u32, io::Error>;
+    fn now(&self) -> Result<u64, io::Error>;
     fn read(&self, path: &HexPath) -> Result<Vec<u8>, io::Error>;
+    fn remove_dir_all(&self, path: &HexPath) -> Result<(), io::Error>;
     fn remove_file(&self, path: &HexPath) -> Result<(), io::Error>;
     fn rename(&self, old_path: &HexPath, new_path: &HexPath) -> Result<(), io::Error>;
+    fn set_modtime(&self, path: &HexPath, modtime: u64) -> Result<(), io::Error>;
+
+    /// Give a file or directory a mode and, if one is given, a group
+    fn set_permissions(
+        &self,
+        path: &HexPath,
+        mode: u32,
+        group: Option<u32>,
+    ) -> Result<(), io::Error>;
     fn touch(&self, path: &HexPath) -> Result<(), io::Error>;
     fn tree_walk(&self, path: &HexPath) -> Result<Vec<HexPath>, io::Error>;
+    fn tree_walk_metadata(&self, path: &HexPath) -> Result<Vec<WalkEntry>, io::Error>;
     fn write(&self, path: &HexPath, contents: &[u8]) -> Result<(), io::Error>;
 }
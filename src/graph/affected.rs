@@ -0,0 +1,116 @@
+use std::collections::BTreeSet;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::{HexmakeFile, RuleName};
+
+/// Find the rules that are affected by changes to the given paths.
+/// A rule is affected if one of its inputs is a changed path, is inside
+/// a changed directory, or is a directory that contains a changed path.
+/// A rule is also affected if it uses an output of an affected rule or
+/// lists an affected rule in its `deps`. Changes to a rule's runfiles
+/// count as changes to the rule, since they are staged along with its
+/// outputs.
+pub fn affected_rules(hex_file: &HexmakeFile, changed_paths: &[HexPath]) -> BTreeSet<RuleName> {
+    let mut affected: BTreeSet<RuleName> = BTreeSet::new();
+    let mut changed_paths = changed_paths.to_vec();
+
+    // Keep sweeping until no more rules are added, since each newly
+    // affected rule changes its outputs
+    loop {
+        let mut new_changes = Vec::new();
+        for rule in &hex_file.rules {
+            if affected.contains(&rule.name) {
+                continue;
+            }
+            let is_affected = rule.deps.iter().any(|dep| affected.contains(dep))
+                || rule.inputs.iter().chain(&rule.runfiles).any(|input| {
+                    changed_paths.iter().any(|changed| {
+                        path_contains(input, changed) || path_contains(changed, input)
+                    })
+                });
+            if is_affected {
+                affected.insert(rule.name.clone());
+                new_changes.extend(rule.outputs.iter().cloned());
+            }
+        }
+
+        if new_changes.is_empty() {
+            return affected;
+        }
+        changed_paths.extend(new_changes);
+    }
+}
+
+/// Whether `path` is the same as `tree` or is located underneath it
+fn path_contains(tree: &HexPath, path: &HexPath) -> bool {
+    match path.strip_prefix(&**tree) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hexmake_file::HexRule;
+
+    #[test]
+    fn test_affected_rules() {
+        let rule = |name: &str, inputs: &[&str], output: &str| {
+            HexRule {
+                name: name.into(),
+                outputs: vec![HexPath::try_from(output).unwrap()],
+                inputs: inputs
+                    .iter()
+                    .map(|input| HexPath::try_from(*input).unwrap())
+                    .collect(),
+                commands: vec![],
+                ..Default::default()
+            }
+            .into()
+        };
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                rule("lib.o", &["lib.c", "include"], "out/lib.o"),
+                rule("main.o", &["main.c", "include"], "out/main.o"),
+                rule("main", &["out/lib.o", "out/main.o"], "out/main"),
+                rule("docs", &["doc"], "out/docs.html"),
+                HexRule {
+                    name: "package".into(),
+                    outputs: vec![HexPath::try_from("out/package.tar").unwrap()],
+                    deps: vec!["docs".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+
+        let affected = |paths: &[&str]| {
+            let paths: Vec<HexPath> = paths
+                .iter()
+                .map(|path| HexPath::try_from(*path).unwrap())
+                .collect();
+            affected_rules(&hexmake_file, &paths)
+                .iter()
+                .map(|rule_name| rule_name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // A file that is an input, and everything downstream of it
+        assert_eq!(affected(&["lib.c"]), vec!["lib.o", "main"]);
+
+        // A file inside an input directory
+        assert_eq!(
+            affected(&["include/lib.h"]),
+            vec!["lib.o", "main", "main.o"]
+        );
+
+        // A directory containing an input, and a rule that depends on
+        // the affected rule by name
+        assert_eq!(affected(&["doc"]), vec!["docs", "package"]);
+
+        // Similar names that are not the same path
+        assert_eq!(affected(&["lib.cc", "documents"]), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt::{self, Display, Formatter};
+
+/// A label names a rule unambiguously across workspaces, in the form
+/// `//package:name`. The package is the directory of the workspace that
+/// defines the rule, relative to the workspace root, so rules in the root
+/// workspace look like `//:name`. The short form `:name` also means a
+/// rule in the root workspace.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Label {
+    pub package: String,
+    pub name: String,
+}
+
+impl Label {
+    /// Parse a target as a label. Return None if it does not have the
+    /// syntax of a label, so it should be treated as a rule name, output,
+    /// or alias instead.
+    pub fn parse(target: &str) -> Result<Option<Label>, String> {
+        let (package, name) = if let Some(rest) = target.strip_prefix("//") {
+            match rest.split_once(':') {
+                Some((package, name)) => (package, name),
+                None => return Err(format!("Label `{target}` needs a `:` before the rule name")),
+            }
+        } else if let Some(name) = target.strip_prefix(':') {
+            ("", name)
+        } else {
+            return Ok(None);
+        };
+
+        if name.is_empty() || package.ends_with('/') {
+            return Err(format!(
+                "Label `{target}` is not of the form `//package:name`"
+            ));
+        }
+        Ok(Some(Label {
+            package: package.to_string(),
+            name: name.to_string(),
+        }))
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "//{}:{}", self.package, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let label = |package: &str, name: &str| {
+            Ok(Some(Label {
+                package: package.to_string(),
+                name: name.to_string(),
+            }))
+        };
+
+        assert_eq!(Label::parse("//:main"), label("", "main"));
+        assert_eq!(Label::parse(":main"), label("", "main"));
+        assert_eq!(
+            Label::parse("//vendor/zlib:libz"),
+            label("vendor/zlib", "libz")
+        );
+        assert_eq!(Label::parse("main"), Ok(None));
+        assert_eq!(Label::parse("out/main"), Ok(None));
+        assert_eq!(
+            Label::parse("//vendor/zlib"),
+            Err("Label `//vendor/zlib` needs a `:` before the rule name".to_string())
+        );
+        assert_eq!(
+            Label::parse("//:"),
+            Err("Label `//:` is not of the form `//package:name`".to_string())
+        );
+
+        assert_eq!(
+            Label::parse("//vendor/zlib:libz")
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "//vendor/zlib:libz"
+        );
+    }
+}
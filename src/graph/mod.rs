@@ -1,2 +1,4 @@
+pub mod affected;
+pub mod label;
 pub mod planner;
 pub mod task;
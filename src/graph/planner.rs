@@ -3,30 +3,91 @@ use std::sync::{Arc, Mutex};
 
 use crate::ast::hex_path::HexPath;
 use crate::ast::hexmake_file::{HexRule, HexmakeFile, RuleName};
+use crate::graph::label::Label;
 use crate::graph::task::Task;
 
+/// The built-in target that builds every rule. A rule or alias
+/// with the same name takes precedence over it.
+pub const ALL_TARGET: &str = "all";
+
 /// Make a plan for building the given targets.
-/// The targets can be either the names of outputs or
-/// the names of rules.
+/// The targets can be the names of outputs, the names of
+/// rules, the names of aliases, or [ALL_TARGET].
 pub fn plan_build(hex_file: &HexmakeFile, targets: &Vec<Arc<String>>) -> Result<BuildPlan, String> {
-    Planner::new(hex_file).plan(targets)
+    Planner::new(hex_file)?.plan(targets)
 }
 
 pub struct BuildPlan {
-    #[allow(unused)]
     pub target_rules: BTreeSet<RuleName>,
     pub tasks: BTreeMap<RuleName, Arc<Mutex<Task>>>,
 }
 
+impl BuildPlan {
+    /// The names of all the tasks, ordered so that every task comes after
+    /// the tasks it depends on. Ties are broken by rule name.
+    pub fn topological_order(&self) -> Vec<RuleName> {
+        let mut result = Vec::new();
+        let mut visited = BTreeSet::new();
+        for rule_name in self.tasks.keys() {
+            self.visit_in_order(rule_name, &mut visited, &mut result);
+        }
+        result
+    }
+
+    fn visit_in_order(
+        &self,
+        rule_name: &RuleName,
+        visited: &mut BTreeSet<RuleName>,
+        result: &mut Vec<RuleName>,
+    ) {
+        if !visited.insert(rule_name.clone()) {
+            return;
+        }
+        let mut dependencies: Vec<RuleName> = self.tasks[rule_name]
+            .lock()
+            .unwrap()
+            .depends_on
+            .iter()
+            .map(|dep| dep.lock().unwrap().rule_name())
+            .collect();
+        dependencies.sort();
+        for dependency in &dependencies {
+            self.visit_in_order(dependency, visited, result);
+        }
+        result.push(rule_name.clone());
+    }
+
+    /// For each task, the number of tasks on the longest chain that starts
+    /// with it and follows the tasks that use its outputs, counting the
+    /// task itself. A task with a long chain after it should start early,
+    /// or the build will wait on that chain at the end.
+    pub fn critical_path_lengths(&self) -> BTreeMap<RuleName, usize> {
+        let mut lengths = BTreeMap::new();
+        for rule_name in self.topological_order().iter().rev() {
+            let longest_user = self.tasks[rule_name]
+                .lock()
+                .unwrap()
+                .used_by
+                .iter()
+                .map(|user| lengths[&user.lock().unwrap().rule_name()])
+                .max()
+                .unwrap_or(0);
+            lengths.insert(rule_name.clone(), longest_user + 1);
+        }
+        lengths
+    }
+}
+
 struct Planner {
     target_rules: BTreeSet<RuleName>,
     rule_map: BTreeMap<RuleName, Arc<HexRule>>,
     rule_by_output: BTreeMap<HexPath, RuleName>,
+    aliases: BTreeMap<Arc<String>, Vec<Arc<String>>>,
     task_for_rule: BTreeMap<RuleName, Arc<Mutex<Task>>>,
 }
 
 impl Planner {
-    fn new(hex_file: &HexmakeFile) -> Self {
+    fn new(hex_file: &HexmakeFile) -> Result<Self, String> {
         let target_rules: BTreeSet<RuleName> = BTreeSet::new();
         let mut rule_map = BTreeMap::new();
         let mut rule_by_output = BTreeMap::new();
@@ -38,17 +99,30 @@ impl Planner {
             }
         }
 
+        // Resolve dependencies on rule names, and runfiles, into inputs
+        let mut resolved_rules = BTreeMap::new();
+        for rule in rule_map.values() {
+            let resolved_rule = resolve_rule(rule, &rule_map, &rule_by_output)?;
+            resolved_rules.insert(rule.name.clone(), resolved_rule);
+        }
+
         let task_for_rule = BTreeMap::new();
-        Self {
+        Ok(Self {
             target_rules,
-            rule_map,
+            rule_map: resolved_rules,
             rule_by_output,
+            aliases: hex_file.aliases.clone(),
             task_for_rule,
-        }
+        })
     }
 
     fn plan(mut self, targets: &Vec<Arc<String>>) -> Result<BuildPlan, String> {
+        let mut expanded_targets = Vec::new();
         for target in targets {
+            self.expand_alias(target, &BTreeSet::new(), &mut expanded_targets)?;
+        }
+
+        for target in &expanded_targets {
             let target_rule_name = self.plan_one_target(target, &BTreeSet::new())?;
             self.target_rules.insert(target_rule_name);
         }
@@ -59,6 +133,49 @@ impl Planner {
         })
     }
 
+    /// Replace a target by its members if it is an alias, recursively.
+    /// The built-in [ALL_TARGET] is replaced by every rule, and labels
+    /// are replaced by the names they refer to. Other targets are passed
+    /// through unchanged.
+    fn expand_alias(
+        &self,
+        target: &Arc<String>,
+        aliases_in_progress: &BTreeSet<Arc<String>>,
+        expanded_targets: &mut Vec<Arc<String>>,
+    ) -> Result<(), String> {
+        if let Some(label) = Label::parse(target)? {
+            if !label.package.is_empty() {
+                return Err(format!(
+                    "Label `{label}` is not in this workspace or one of its sub-workspaces"
+                ));
+            }
+            let name = Arc::new(label.name);
+            return self.expand_alias(&name, aliases_in_progress, expanded_targets);
+        }
+
+        let Some(members) = self.aliases.get(target) else {
+            if target.as_str() == ALL_TARGET && !self.rule_map.contains_key(&target.into()) {
+                expanded_targets
+                    .extend(self.rule_map.keys().map(|rule_name| rule_name.name.clone()));
+            } else {
+                expanded_targets.push(target.clone());
+            }
+            return Ok(());
+        };
+
+        if aliases_in_progress.contains(target) {
+            return Err(format!("Alias cycle involving alias `{target}`"));
+        }
+        let mut aliases_in_progress = aliases_in_progress.clone();
+        aliases_in_progress.insert(target.clone());
+
+        for member in members {
+            self.expand_alias(member, &aliases_in_progress, expanded_targets)?;
+        }
+
+        Ok(())
+    }
+
     /// Plan the build for one target, updating the fields of the
     /// planner as it goes. Return the rule name for building the
     /// one requested target.
@@ -98,8 +215,10 @@ impl Planner {
         };
         let task = Arc::new(Mutex::new(Task::new(rule.clone())));
 
-        // Add subtasks for inputs
-        for input in &rule.inputs {
+        // Add subtasks for inputs, and for runfiles that are outputs of
+        // other rules so that they are available along with this rule's
+        // outputs
+        for input in rule.inputs.iter().chain(&rule.runfiles) {
             if input.is_output() {
                 let input_rule_name = self.plan_one_target(&input.path, &targets_in_progress)?;
                 let sub_task = &self.task_for_rule[&input_rule_name];
@@ -113,6 +232,81 @@ impl Planner {
     }
 }
 
+/// Resolve the parts of a rule that add more inputs to it. The outputs
+/// of the rules named in `deps` are added, and then the runfiles of the
+/// rules that produce any of the inputs. Inputs are not added twice.
+fn resolve_rule(
+    rule: &Arc<HexRule>,
+    rule_map: &BTreeMap<RuleName, Arc<HexRule>>,
+    rule_by_output: &BTreeMap<HexPath, RuleName>,
+) -> Result<Arc<HexRule>, String> {
+    let mut inputs = rule.inputs.clone();
+    for dep in &rule.deps {
+        let Some(dep_rule) = rule_map.get(dep) else {
+            return Err(format!(
+                "Rule `{}` depends on `{dep}`, but no rule exists with that name",
+                rule.name
+            ));
+        };
+        for output in &dep_rule.outputs {
+            if !inputs.contains(output) {
+                inputs.push(output.clone());
+            }
+        }
+    }
+
+    let mut runfiles = Vec::new();
+    let mut visited = BTreeSet::new();
+    for input in &inputs {
+        if let Some(producer) = rule_by_output.get(input) {
+            collect_runfiles(
+                producer,
+                rule_map,
+                rule_by_output,
+                &mut visited,
+                &mut runfiles,
+            );
+        }
+    }
+    for runfile in runfiles {
+        if !inputs.contains(&runfile) {
+            inputs.push(runfile);
+        }
+    }
+
+    if inputs == rule.inputs {
+        return Ok(rule.clone());
+    }
+    Ok(Arc::new(HexRule {
+        inputs,
+        ..(**rule).clone()
+    }))
+}
+
+/// Collect the runfiles of a rule, along with the runfiles of the rules
+/// that produce those runfiles, and so on
+fn collect_runfiles(
+    rule_name: &RuleName,
+    rule_map: &BTreeMap<RuleName, Arc<HexRule>>,
+    rule_by_output: &BTreeMap<HexPath, RuleName>,
+    visited: &mut BTreeSet<RuleName>,
+    runfiles: &mut Vec<HexPath>,
+) {
+    if !visited.insert(rule_name.clone()) {
+        return;
+    }
+    let Some(rule) = rule_map.get(rule_name) else {
+        return;
+    };
+
+    for runfile in &rule.runfiles {
+        runfiles.push(runfile.clone());
+        if let Some(producer) = rule_by_output.get(runfile) {
+            collect_runfiles(producer, rule_map, rule_by_output, visited, runfiles);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -195,7 +389,6 @@ mod tests {
     #[test]
     fn test_rule_with_multiple_outputs() {
         let hexmake_file = HexmakeFile {
-            env: vec![],
             rules: vec![
                 HexRule {
                     name: "foo".into(),
@@ -205,6 +398,7 @@ mod tests {
                         HexPath::try_from("out/bar.c").unwrap(),
                     ],
                     commands: vec!["gcc -o out/foo out/foo.c out/bar.c".into()],
+                    ..Default::default()
                 }
                 .into(),
                 HexRule {
@@ -215,9 +409,11 @@ mod tests {
                     ],
                     inputs: vec![],
                     commands: vec!["scripts/gensources".into()],
+                    ..Default::default()
                 }
                 .into(),
             ],
+            ..Default::default()
         };
 
         let build_plan = plan_build(&hexmake_file, &vec!["foo".to_string().into()]);
@@ -266,13 +462,13 @@ mod tests {
     #[test]
     fn test_cycle() {
         let hexmake_file = HexmakeFile {
-            env: vec![],
             rules: vec![
                 HexRule {
                     name: "foo".into(),
                     outputs: vec![HexPath::try_from("out/foo").unwrap()],
                     inputs: vec![HexPath::try_from("out/bar").unwrap()],
                     commands: vec!["echo foo".into()],
+                    ..Default::default()
                 }
                 .into(),
                 HexRule {
@@ -280,9 +476,11 @@ mod tests {
                     outputs: vec![HexPath::try_from("out/bar").unwrap()],
                     inputs: vec![HexPath::try_from("out/foo").unwrap()],
                     commands: vec!["echo bar".into()],
+                    ..Default::default()
                 }
                 .into(),
             ],
+            ..Default::default()
         };
 
         let build_plan = plan_build(&hexmake_file, &vec!["foo".to_string().into()]);
@@ -293,16 +491,330 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_topological_order() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.rules.push(
+            HexRule {
+                name: "all".into(),
+                outputs: vec![HexPath::try_from("out/all").unwrap()],
+                inputs: vec![
+                    HexPath::try_from("out/foo").unwrap(),
+                    HexPath::try_from("out/bar").unwrap(),
+                ],
+                commands: vec!["touch out/all".into()],
+                ..Default::default()
+            }
+            .into(),
+        );
+
+        let build_plan = plan_build(&hexmake_file, &vec!["all".to_string().into()]).unwrap();
+
+        assert_eq!(
+            join(build_plan.topological_order(), " "),
+            "bar.o bar foo.o foo all"
+        );
+    }
+
+    #[test]
+    fn test_critical_path_lengths() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.rules.push(
+            HexRule {
+                name: "foo.tar".into(),
+                outputs: vec![HexPath::try_from("out/foo.tar").unwrap()],
+                inputs: vec![HexPath::try_from("out/foo").unwrap()],
+                commands: vec!["tar cf out/foo.tar out/foo".into()],
+                ..Default::default()
+            }
+            .into(),
+        );
+        hexmake_file.rules.push(
+            HexRule {
+                name: "all".into(),
+                outputs: vec![HexPath::try_from("out/all").unwrap()],
+                inputs: vec![
+                    HexPath::try_from("out/foo.tar").unwrap(),
+                    HexPath::try_from("out/bar").unwrap(),
+                ],
+                commands: vec!["touch out/all".into()],
+                ..Default::default()
+            }
+            .into(),
+        );
+
+        let build_plan = plan_build(&hexmake_file, &vec!["all".to_string().into()]).unwrap();
+
+        let lengths: Vec<String> = build_plan
+            .critical_path_lengths()
+            .iter()
+            .map(|(rule_name, length)| format!("{rule_name}={length}"))
+            .collect();
+        assert_eq!(
+            join(lengths, " "),
+            "all=1 bar=2 bar.o=3 foo=3 foo.o=4 foo.tar=2"
+        );
+    }
+
+    #[test]
+    fn test_deps() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.rules.push(
+            HexRule {
+                name: "both".into(),
+                outputs: vec![HexPath::try_from("out/both.tar").unwrap()],
+                inputs: vec![HexPath::try_from("out/foo").unwrap()],
+                commands: vec!["tar cf out/both.tar out/foo out/bar".into()],
+                deps: vec!["foo".into(), "bar".into()],
+                ..Default::default()
+            }
+            .into(),
+        );
+
+        let build_plan = plan_build(&hexmake_file, &vec!["both".to_string().into()]);
+
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            indoc! {r"
+              Task: bar
+                Depends on tasks: bar.o
+                Used by tasks: both
+              Task: bar.o
+                Used by tasks: bar
+              Task: both
+                Depends on tasks: foo, bar
+              Task: foo
+                Depends on tasks: foo.o
+                Used by tasks: both
+              Task: foo.o
+                Used by tasks: foo
+            "}
+        );
+        check_build_plan(&build_plan);
+
+        // The outputs of the dependencies become inputs, without duplicates
+        let build_plan = build_plan.unwrap();
+        let both = build_plan.tasks[&RuleName::from("both")].lock().unwrap();
+        assert_eq!(join(&both.rule.inputs, " "), "out/foo out/bar");
+    }
+
+    #[test]
+    fn test_unknown_dep() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.rules.push(
+            HexRule {
+                name: "both".into(),
+                outputs: vec![HexPath::try_from("out/both.tar").unwrap()],
+                deps: vec!["bogus".into()],
+                ..Default::default()
+            }
+            .into(),
+        );
+
+        let build_plan = plan_build(&hexmake_file, &vec!["both".to_string().into()]);
+
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            "Rule `both` depends on `bogus`, but no rule exists with that name"
+        );
+    }
+
+    #[test]
+    fn test_runfiles() {
+        let rule = |name: &str, inputs: &[&str], output: &str, runfiles: &[&str]| {
+            let paths = |paths: &[&str]| {
+                paths
+                    .iter()
+                    .map(|path| HexPath::try_from(*path).unwrap())
+                    .collect()
+            };
+            HexRule {
+                name: name.into(),
+                outputs: paths(&[output]),
+                inputs: paths(inputs),
+                runfiles: paths(runfiles),
+                ..Default::default()
+            }
+            .into()
+        };
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                rule("libfoo", &["foo.c"], "out/libfoo.so", &["foo.dat"]),
+                rule("tool", &["tool.c"], "out/tool", &["out/libfoo.so"]),
+                rule("generated", &["out/tool"], "out/gen.c", &[]),
+            ],
+            ..Default::default()
+        };
+
+        let build_plan = plan_build(&hexmake_file, &vec!["generated".to_string().into()]);
+
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            indoc! {r"
+              Task: generated
+                Depends on tasks: tool, libfoo
+              Task: libfoo
+                Used by tasks: tool, generated
+              Task: tool
+                Depends on tasks: libfoo
+                Used by tasks: generated
+            "}
+        );
+        check_build_plan(&build_plan);
+
+        // The runfiles of the tool, and of the library it uses, are
+        // staged for the rule that runs the tool
+        let build_plan = build_plan.unwrap();
+        let generated = build_plan.tasks[&RuleName::from("generated")]
+            .lock()
+            .unwrap();
+        assert_eq!(
+            join(&generated.rule.inputs, " "),
+            "out/tool out/libfoo.so foo.dat"
+        );
+    }
+
+    #[test]
+    fn test_alias() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.aliases.insert(
+            Arc::new("all".to_string()),
+            vec![
+                Arc::new("foo".to_string()),
+                Arc::new("binaries".to_string()),
+            ],
+        );
+        hexmake_file.aliases.insert(
+            Arc::new("binaries".to_string()),
+            vec![Arc::new("out/bar".to_string())],
+        );
+
+        let build_plan = plan_build(&hexmake_file, &vec!["all".to_string().into()]);
+
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            indoc! {r"
+              Task: bar
+                Depends on tasks: bar.o
+              Task: bar.o
+                Used by tasks: bar
+              Task: foo
+                Depends on tasks: foo.o
+              Task: foo.o
+                Used by tasks: foo
+            "}
+        );
+        assert_eq!(
+            build_plan.unwrap().target_rules,
+            BTreeSet::from(["bar".into(), "foo".into()])
+        );
+    }
+
+    #[test]
+    fn test_alias_cycle() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.aliases.insert(
+            Arc::new("a".to_string()),
+            vec![Arc::new("foo".to_string()), Arc::new("b".to_string())],
+        );
+        hexmake_file
+            .aliases
+            .insert(Arc::new("b".to_string()), vec![Arc::new("a".to_string())]);
+
+        let build_plan = plan_build(&hexmake_file, &vec!["a".to_string().into()]);
+
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            "Alias cycle involving alias `a`"
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.aliases.insert(
+            Arc::new("binaries".to_string()),
+            vec![Arc::new("//:bar".to_string())],
+        );
+
+        let build_plan = plan_build(
+            &hexmake_file,
+            &vec![":foo.o".to_string().into(), "binaries".to_string().into()],
+        );
+        assert_eq!(
+            build_plan.unwrap().target_rules,
+            BTreeSet::from(["bar".into(), "foo.o".into()])
+        );
+
+        let build_plan = plan_build(&hexmake_file, &vec!["//lib:foo".to_string().into()]);
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            "Label `//lib:foo` is not in this workspace or one of its sub-workspaces"
+        );
+    }
+
+    #[test]
+    fn test_all() {
+        let hexmake_file = foo_bar_hexmake_file();
+
+        let build_plan = plan_build(&hexmake_file, &vec![ALL_TARGET.to_string().into()]);
+
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            indoc! {r"
+              Task: bar
+                Depends on tasks: bar.o
+              Task: bar.o
+                Used by tasks: bar
+              Task: foo
+                Depends on tasks: foo.o
+              Task: foo.o
+                Used by tasks: foo
+            "}
+        );
+        assert_eq!(build_plan.unwrap().target_rules.len(), 4);
+    }
+
+    #[test]
+    fn test_rule_named_all() {
+        let mut hexmake_file = foo_bar_hexmake_file();
+        hexmake_file.rules.push(
+            HexRule {
+                name: "all".into(),
+                outputs: vec![HexPath::try_from("out/all").unwrap()],
+                inputs: vec![HexPath::try_from("out/foo").unwrap()],
+                commands: vec!["touch out/all".into()],
+                ..Default::default()
+            }
+            .into(),
+        );
+
+        let build_plan = plan_build(&hexmake_file, &vec![ALL_TARGET.to_string().into()]);
+
+        assert_eq!(
+            build_plan_summary(&build_plan),
+            indoc! {r"
+              Task: all
+                Depends on tasks: foo
+              Task: foo
+                Depends on tasks: foo.o
+                Used by tasks: all
+              Task: foo.o
+                Used by tasks: foo
+            "}
+        );
+    }
+
     /// A Hexmake file that compiles two C files into two binaries
     fn foo_bar_hexmake_file() -> HexmakeFile {
         HexmakeFile {
-            env: vec![],
             rules: vec![
                 HexRule {
                     name: "foo".into(),
                     outputs: vec![HexPath::try_from("out/foo").unwrap()],
                     inputs: vec![HexPath::try_from("out/foo.o").unwrap()],
                     commands: vec!["gcc -o out/foo out/foo.o".into()],
+                    ..Default::default()
                 }
                 .into(),
                 HexRule {
@@ -310,6 +822,7 @@ mod tests {
                     outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
                     inputs: vec![HexPath::try_from("foo.c").unwrap()],
                     commands: vec!["gcc -o out/foo.o out/foo.c".into()],
+                    ..Default::default()
                 }
                 .into(),
                 HexRule {
@@ -317,6 +830,7 @@ mod tests {
                     outputs: vec![HexPath::try_from("out/bar").unwrap()],
                     inputs: vec![HexPath::try_from("out/bar.o").unwrap()],
                     commands: vec!["gcc -o out/bar out/bar.o".into()],
+                    ..Default::default()
                 }
                 .into(),
                 HexRule {
@@ -324,9 +838,11 @@ mod tests {
                     outputs: vec![HexPath::try_from("out/bar.o").unwrap()],
                     inputs: vec![HexPath::try_from("bar.c").unwrap()],
                     commands: vec!["gcc -o out/bar.o out/bar.c".into()],
+                    ..Default::default()
                 }
                 .into(),
             ],
+            ..Default::default()
         }
     }
 
@@ -1,7 +1,39 @@
 use std::fmt::{Display, Formatter};
+use std::mem::take;
 use std::sync::{Arc, Mutex};
 
 use crate::ast::hexmake_file::{HexRule, RuleName};
+use crate::cache::build_hash::BuildHash;
+
+/// Where a task is in the course of a build
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TaskState {
+    /// Waiting for its dependencies or for a worker
+    Pending,
+
+    /// A worker is building it
+    Running,
+
+    /// Its outputs were built or retrieved from the cache
+    Succeeded,
+
+    /// Building it failed
+    Failed,
+
+    /// It was never started, because a task it depends on failed
+    Skipped,
+
+    /// It was stopped, or never started, because the build was
+    /// interrupted
+    Cancelled,
+}
+
+impl TaskState {
+    /// Whether the task is done with, one way or another
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, TaskState::Pending | TaskState::Running)
+    }
+}
 
 /// A task to be executed, along with dependency and status information.
 pub struct Task {
@@ -10,8 +42,18 @@ pub struct Task {
     pub used_by: Vec<Arc<Mutex<Task>>>,
     unbuilt_dependencies: usize,
 
-    /// Whether the task has finished building
-    pub is_built: bool,
+    pub state: TaskState,
+
+    /// The hash under which the cache was found to have this task's
+    /// outputs before the build started. The outputs are retrieved under
+    /// this hash, because the task's inputs may never be written to the
+    /// workspace.
+    pub cached_hash: Option<BuildHash>,
+
+    /// Whether to rebuild this task if it is a cache hit, to check that
+    /// the cache gave the same outputs. This is decided before the build
+    /// starts, so that the tasks that produce its inputs are kept.
+    pub audit: bool,
 }
 
 impl Task {
@@ -21,7 +63,9 @@ impl Task {
             depends_on: Vec::new(),
             used_by: Vec::new(),
             unbuilt_dependencies: 0,
-            is_built: false,
+            state: TaskState::Pending,
+            cached_hash: None,
+            audit: false,
         }
     }
 
@@ -45,6 +89,24 @@ impl Task {
         to_task.lock().unwrap().used_by.push(from_task.clone());
     }
 
+    /// Take a task out of the graph, because it does not need to run. The
+    /// tasks that use it no longer wait for it.
+    pub fn remove_from_graph(task: &Arc<Mutex<Task>>) {
+        let (depends_on, used_by) = {
+            let mut task = task.lock().unwrap();
+            (take(&mut task.depends_on), take(&mut task.used_by))
+        };
+        for dep in depends_on {
+            let mut dep = dep.lock().unwrap();
+            dep.used_by.retain(|user| !Arc::ptr_eq(user, task));
+        }
+        for user in used_by {
+            let mut user = user.lock().unwrap();
+            user.depends_on.retain(|dep| !Arc::ptr_eq(dep, task));
+            user.unbuilt_dependencies -= 1;
+        }
+    }
+
     /// Whether this task depends on the given rule name
     pub fn depends_on_rule(&self, rule_name: &RuleName) -> bool {
         for dep in &self.depends_on {
@@ -69,19 +131,29 @@ impl Task {
         self.unbuilt_dependencies == 0
     }
 
-    /// Inform this task that it has now been built
-    pub fn build_finished(&mut self) {
-        assert!(!self.is_built);
-        self.is_built = true;
+    /// Inform this task that a worker has started on it
+    pub fn start(&mut self) {
+        assert_eq!(self.state, TaskState::Pending);
+        self.state = TaskState::Running;
+    }
+
+    /// Inform this task that it is done with, in the given state
+    pub fn finish(&mut self, state: TaskState) {
+        assert!(!self.state.is_finished());
+        assert!(state.is_finished());
+        self.state = state;
     }
 }
 
 impl Display for Task {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        let status: &str = if self.is_built {
-            "built"
-        } else {
-            &format!("{} unbuilt deps", self.unbuilt_dependencies)
+        let status: &str = match self.state {
+            TaskState::Pending => &format!("{} unbuilt deps", self.unbuilt_dependencies),
+            TaskState::Running => "running",
+            TaskState::Succeeded => "built",
+            TaskState::Failed => "failed",
+            TaskState::Skipped => "skipped",
+            TaskState::Cancelled => "cancelled",
         };
 
         write!(f, "{} ({})", self.rule.name, status)
@@ -2,98 +2,677 @@ mod args;
 mod ast;
 mod cache;
 mod check;
+mod download;
+mod environment;
 mod error;
 mod error_exit;
 mod exec;
 mod file_system;
 mod graph;
 mod lock;
+mod scan;
+mod source_lock;
+mod subcommand;
+mod subworkspace;
+mod toolchain;
+mod workspace;
 
-use clap::Parser;
-use fs_err::read_to_string;
-use std::collections::BTreeMap;
+use clap::{CommandFactory, Parser};
+use fs_err::{read_to_string, write};
+use std::collections::BTreeSet;
 use std::env;
+use std::io::{self, IsTerminal};
+use std::path::Path;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Instant;
 
-use crate::args::Args;
-use crate::ast::hexmake_file::HexmakeFile;
+use crate::args::{Args, CacheCommand, Command, Query};
+use crate::ast::expand::expand_file;
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::{HexmakeFile, SourceTreeSettings};
 use crate::cache::build_cache::BuildCache;
+use crate::cache::cache_options::CacheOptions;
 use crate::check::file::check_file;
+use crate::check::input_size::check_input_sizes;
+use crate::download::{is_url, read_url_or_path, write_url_or_path};
+use crate::environment::BuildEnvironment;
 use crate::error::Error;
 use crate::error_exit::error_exit;
-use crate::exec::conductor::conduct_build;
+use crate::exec::build_log::BUILD_LOG_DIR;
+use crate::exec::build_options::{BuildOptions, new_build_id};
+use crate::exec::command_logger::Verbosity;
+use crate::exec::conductor::{BuildFailed, conduct_build};
+use crate::exec::env_snapshot::EnvSnapshot;
+use crate::exec::events::BuildEvents;
+use crate::exec::manifest::{MANIFEST_FILE, Manifest};
+use crate::exec::notify::BuildNotification;
+use crate::exec::status_file::{STATUS_FILE, StatusFile};
+use crate::exec::summary_file::SummaryFile;
+use crate::exec::tui::Tui;
+use crate::exec::web_ui::WebUi;
 use crate::file_system::posix::PosixFileSystem;
+use crate::graph::affected::affected_rules;
 use crate::graph::planner::plan_build;
 use crate::lock::obtain_lock;
+use crate::scan::{add_discovered_inputs, builtin_scanners};
+use crate::source_lock::{LOCK_FILE, SourceLock};
+use crate::subcommand::bench::run_bench;
+use crate::subcommand::clean::{clean_plan, clean_stale};
+use crate::subcommand::completions::print_completions;
+use crate::subcommand::doctor::run_doctor;
+use crate::subcommand::edit_rules::{RuleEdit, edit_hexmake_file};
+use crate::subcommand::fmt::format_hexmake_file;
+use crate::subcommand::install::install_outputs;
+use crate::subcommand::list_targets::{ListOptions, format_labels, format_targets};
+use crate::subcommand::outputs::print_outputs;
+use crate::subcommand::plan::{print_dry_run, print_plan};
+use crate::subcommand::print_commands::format_commands;
+use crate::subcommand::query::print_sources;
+use crate::subcommand::trace_fs::trace_rule;
+use crate::subcommand::why_changed::print_why_changed;
+use crate::subworkspace::{SubworkspaceBuilds, load_subworkspaces};
+use crate::toolchain::{fetch_tools, resolve_toolchains};
+use crate::workspace::{STDIN_FILE, Workspace, hexmake_file_path, read_hexmake_source};
 
 fn main() {
     if let Err(error) = main_internal() {
-        error_exit!("Error: {}", error);
+        error_exit!(error.exit_code(), "Error: {}", error);
     }
 }
 
 fn main_internal() -> Result<(), Error> {
-    let args: Args = Args::parse();
-    let hexmake_file: HexmakeFile = load_hexmake_file();
-    check_file(&hexmake_file)?;
+    let no_arguments = env::args_os().len() == 1;
+    let mut args: Args = Args::parse();
+    if let Some(dir) = &args.directory {
+        env::set_current_dir(dir)
+            .map_err(|error| format!("Could not change to `{}`: {error}", dir.display()))?;
+    }
+    if let Some(Command::Completions { shell }) = &args.command {
+        return Ok(print_completions(*shell)?);
+    }
+    // A Hexmake file given with --file is used from the current directory
+    if args.file.is_none()
+        && let Some(workspace) = Workspace::find()?
+    {
+        workspace.enter()?;
+        translate_args(&mut args, &workspace)?;
+    }
+    let file = args.file.as_deref();
+    if let Some(Command::Fmt { check }) = &args.command {
+        return format_file(file, *check);
+    }
+    if let Some(Command::Doctor { remote }) = &args.command {
+        return run_doctor(file, remote.as_deref());
+    }
+    if let Some(edit) = rule_edit(&args) {
+        return edit_file(file, &edit);
+    }
+    // With no arguments, Hexmake builds the default targets, and shows
+    // its usage if there are none
+    if no_arguments && !hexmake_file_path(file).exists() {
+        show_usage();
+    }
+    let mut hexmake_file: HexmakeFile = load_hexmake_file(file)?;
+    check_file(&hexmake_file).map_err(Error::Check)?;
+    if no_arguments && hexmake_file.default_targets.is_empty() {
+        show_usage();
+    }
 
     if args.list_targets {
-        list_targets(&hexmake_file);
+        let options = ListOptions {
+            long: args.long,
+            json: args.json,
+            filter: match &args.filter {
+                Some(pattern) => Some(ListOptions::compile_filter(pattern)?),
+                None => None,
+            },
+        };
+        if args.labels {
+            let subworkspaces = load_subworkspaces(&hexmake_file)?;
+            print!("{}", format_labels(&hexmake_file, &subworkspaces, &options));
+        } else {
+            print!("{}", format_targets(&hexmake_file, &options));
+        }
+        exit(0);
+    }
+
+    let env = Arc::new(BuildEnvironment::from_hexmake_file(
+        &hexmake_file,
+        &args.env_overrides,
+    )?);
+    let tools = resolve_toolchains(&hexmake_file.toolchains)?;
+    hexmake_file.set_defines(&args.defines)?;
+    if let Some(Command::Env) = &args.command {
+        println!(
+            "{}",
+            EnvSnapshot::new(&hexmake_file, &env, &tools).to_json()
+        );
+        return Ok(());
+    }
+    expand_file(&mut hexmake_file, &env.vars, &tools).map_err(Error::Check)?;
+    let vfs = PosixFileSystem::new(hexmake_file.source_trees);
+    add_discovered_inputs(&mut hexmake_file, &builtin_scanners(), &vfs)?;
+
+    if let Some(rule_name) = &args.trace_fs {
+        let rule = hexmake_file
+            .rules
+            .iter()
+            .find(|rule| rule.name.name == *rule_name)
+            .ok_or_else(|| format!("There is no rule named `{rule_name}`"))?;
+        let _hex_lock = obtain_lock()?;
+        return trace_rule(rule, &env);
+    }
+
+    if let Some(Command::Bench { target, runs }) = &args.command {
+        let plan = plan_build(&hexmake_file, &vec![target.clone()]).map_err(Error::Plan)?;
+        return run_bench(&plan, target, *runs, file, &vfs);
+    }
+
+    if let Some(Command::WhyChanged { target, against }) = &args.command {
+        let plan = plan_build(&hexmake_file, &vec![target.clone()]).map_err(Error::Plan)?;
+        return print_why_changed(&plan, &env, &vfs, against.as_deref());
+    }
+
+    if let Some(Command::Outputs { targets, json }) = &args.command {
+        let plan = plan_build(&hexmake_file, targets).map_err(Error::Plan)?;
+        print_outputs(&plan, *json);
+        return Ok(());
+    }
+
+    if let Some(Command::Query {
+        query: Query::Sources { targets },
+    }) = &args.command
+    {
+        let plan = plan_build(&hexmake_file, targets).map_err(Error::Plan)?;
+        return Ok(print_sources(
+            &plan,
+            &PosixFileSystem::new(hexmake_file.source_trees),
+        )?);
     }
 
     let _hex_lock = obtain_lock()?;
-    let plan = plan_build(&hexmake_file, &args.targets)?;
-    let env = get_environment(&hexmake_file);
 
-    let vfs = Box::new(PosixFileSystem::default());
-    let build_cache = Arc::new(BuildCache::new(env, vfs)?);
+    if let Some(Command::Cache { command }) = &args.command {
+        let build_cache = lookup_cache(&hexmake_file, env)?;
+        return run_cache_command(command, &hexmake_file, &build_cache);
+    }
+
+    if let Some(Command::Clean {
+        targets,
+        cache,
+        stale,
+    }) = &args.command
+    {
+        if *stale {
+            clean_stale(&hexmake_file, &out_dir_file_system())?;
+        }
+        if targets.is_empty() {
+            return Ok(());
+        }
+        return clean(&hexmake_file, &env, targets, *cache);
+    }
+
+    if let Some(Command::Lock) = &args.command {
+        let lock = SourceLock::compute(
+            &hexmake_file,
+            &PosixFileSystem::new(hexmake_file.source_trees),
+        )?;
+        write(LOCK_FILE, lock.to_text())?;
+        println!(
+            "Wrote {LOCK_FILE} with {} sources and {} downloads",
+            lock.sources.len(),
+            lock.downloads.len()
+        );
+        return Ok(());
+    }
+
+    if args.locked {
+        check_locked(&hexmake_file)?;
+    }
+
+    if let Some(Command::Plan { targets, json }) = &args.command {
+        let plan = plan_build(&hexmake_file, targets).map_err(Error::Plan)?;
+        let build_cache = lookup_cache(&hexmake_file, env)?;
+        return Ok(print_plan(&plan, &build_cache, *json)?);
+    }
+
+    let mut targets = requested_targets(&args, &hexmake_file)?;
+    let mut subworkspace_builds = SubworkspaceBuilds::default();
+    subworkspace_builds.take_targets(&hexmake_file, &mut targets);
+    let plan = plan_build(&hexmake_file, &targets).map_err(Error::Plan)?;
+    subworkspace_builds.add_plan(&hexmake_file, &plan);
+
+    if args.print_commands {
+        print!("{}", format_commands(&plan, &env));
+        return Ok(());
+    }
+
+    if args.dry_run {
+        let build_cache = lookup_cache(&hexmake_file, env)?;
+        return Ok(print_dry_run(&plan, &build_cache)?);
+    }
+
+    fetch_tools(&hexmake_file.toolchains)?;
+    subworkspace_builds.run()?;
+
+    let vfs = Box::new(PosixFileSystem::new(hexmake_file.source_trees));
+    for warning in check_input_sizes(&plan, vfs.as_ref(), &hexmake_file.input_limits)? {
+        eprintln!("Warning: {warning}");
+    }
+    let cache_options = CacheOptions {
+        mode: args.cache,
+        hardlink_outputs: args.hardlink_outputs,
+        output_times: args.output_times,
+        settings: hexmake_file.cache.clone(),
+    };
+    let mut snapshot = EnvSnapshot::new(&hexmake_file, &env, &tools);
+    let build_cache = Arc::new(BuildCache::new(env, vfs, cache_options)?);
+
+    // The TUI needs a terminal, so it quietly falls back to lines of
+    // output when the output goes somewhere else
+    let tui = args.tui && io::stdout().is_terminal();
+    let mut events = BuildEvents::default();
+    events.add(Arc::new(StatusFile::new(STATUS_FILE)));
+    if tui {
+        events.add(Arc::new(Tui::default()));
+    }
+    if let Some(path) = &args.summary_file {
+        events.add(Arc::new(SummaryFile::new(path)));
+    }
+    if let Some(port) = args.web_ui {
+        let web_ui = WebUi::start(port)?;
+        println!("Showing progress at {}", web_ui.url());
+        events.add(web_ui);
+    }
+
+    let options = Arc::new(BuildOptions {
+        build_id: new_build_id(),
+        forced_rules: if args.force {
+            plan.target_rules.clone()
+        } else {
+            BTreeSet::new()
+        },
+        force_all: args.force_all,
+        jobs: args.jobs,
+        load_average: args.load_average,
+        timeout: args.timeout,
+        schedule: args.schedule,
+        source_trees: hexmake_file.source_trees,
+        output_times: args.output_times,
+        pools: hexmake_file.pools.clone(),
+        on_failure: hexmake_file.on_failure.clone(),
+        keep_going: args.keep_going,
+        audit_cache: args.audit_cache,
+        verbosity: match (args.quiet, args.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::VeryVerbose,
+        },
+        events,
+        quiet: tui,
+    });
+
+    snapshot.build_id = Some(options.build_id.to_string());
+    snapshot.write(BUILD_LOG_DIR)?;
+
+    let start_time = Instant::now();
+    let result = conduct_build(&plan, &build_cache, &options);
+    if result.is_ok() && args.manifest {
+        let vfs = out_dir_file_system();
+        let manifest = Manifest::of_plan(&plan, &options.build_id, &vfs)?;
+        manifest.write(&vfs)?;
+        println!(
+            "Wrote {MANIFEST_FILE} with {} outputs",
+            manifest.outputs.len()
+        );
+    }
+
+    if args.notify || args.notify_command.is_some() {
+        let notification = BuildNotification {
+            succeeded: result.is_ok(),
+            elapsed: start_time.elapsed(),
+        };
+        notification.send(args.notify_command.as_deref());
+    }
+    result.map_err(|error| {
+        match error
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<BuildFailed>())
+        {
+            Some(failure) => Error::Build(failure.to_string()),
+            None => Error::Io(error),
+        }
+    })?;
+
+    if let Some(Command::Install { prefix, .. }) = &args.command {
+        install_outputs(&plan, &hexmake_file.install, prefix, &out_dir_file_system())?;
+    }
+    Ok(())
+}
 
-    Ok(conduct_build(&plan, &build_cache)?)
+/// A file system for walking the files in out/, which sees every file
+/// whatever the ignore files say
+fn out_dir_file_system() -> PosixFileSystem {
+    PosixFileSystem::new(SourceTreeSettings {
+        include_ignored: true,
+        include_hidden: true,
+    })
 }
 
-/// Load and parse the Hexmake file
-fn load_hexmake_file() -> HexmakeFile {
-    let hexmake_source = match read_to_string("Hexmake") {
-        Ok(source) => source,
-        Err(error) => {
-            error_exit!("Could not open Hexmake file: {}", error)
+/// Convert the paths on the command line, which are relative to where
+/// Hexmake was started, so that they work from the workspace root
+fn translate_args(args: &mut Args, workspace: &Workspace) -> Result<(), Error> {
+    let translate_targets = |targets: &mut Vec<Arc<String>>| -> Result<(), Error> {
+        for target in targets {
+            *target = workspace.translate_target(target)?;
         }
+        Ok(())
     };
 
-    let hexmake_file: HexmakeFile = match serde_json::from_str(&hexmake_source) {
-        Ok(hexmake_file) => hexmake_file,
-        Err(error) => error_exit!("Could not parse Hexmake file: {}", error),
+    translate_targets(&mut args.targets)?;
+    for path in &mut args.affected_by {
+        *path = Arc::new(workspace.translate_path(path)?);
+    }
+    if let Some(path) = &mut args.summary_file {
+        *path = absolute_from_start(workspace, &*path).into();
+    }
+
+    match &mut args.command {
+        Some(Command::Clean { targets, .. })
+        | Some(Command::Cache {
+            command: CacheCommand::Pin { targets } | CacheCommand::Unpin { targets },
+        })
+        | Some(Command::Outputs { targets, .. })
+        | Some(Command::Plan { targets, .. })
+        | Some(Command::Query {
+            query: Query::Sources { targets },
+        }) => translate_targets(targets)?,
+        Some(Command::Bench { target, .. }) | Some(Command::WhyChanged { target, .. }) => {
+            *target = workspace.translate_target(target)?;
+        }
+        Some(Command::Install { target, prefix }) => {
+            *target = workspace.translate_target(target)?;
+            *prefix = absolute_from_start(workspace, &*prefix).into();
+        }
+        Some(Command::Cache {
+            command: CacheCommand::Pull { source: location },
+        })
+        | Some(Command::Cache {
+            command: CacheCommand::Push {
+                destination: location,
+            },
+        }) => {
+            if !is_url(location) {
+                *location = absolute_from_start(workspace, &*location);
+            }
+        }
+        Some(Command::Doctor {
+            remote: Some(location),
+        }) => {
+            if !is_url(location) {
+                *location = absolute_from_start(workspace, &*location);
+            }
+        }
+        Some(Command::AddRule { .. })
+        | Some(Command::Completions { .. })
+        | Some(Command::Doctor { remote: None })
+        | Some(Command::Env)
+        | Some(Command::Fmt { .. })
+        | Some(Command::Lock)
+        | Some(Command::RemoveRule { .. })
+        | Some(Command::SetInputs { .. })
+        | Some(Command::SetOutputs { .. })
+        | None => {}
+    }
+
+    Ok(())
+}
+
+/// Make a path given on the command line absolute, so that it still
+/// refers to the same file after moving to the workspace root
+fn absolute_from_start(workspace: &Workspace, path: impl AsRef<Path>) -> String {
+    let path = workspace.root.join(&workspace.prefix).join(path);
+    path.to_string_lossy().to_string()
+}
+
+/// The build cache, with the settings from the Hexmake file, for the
+/// commands that use the cache without building anything
+fn lookup_cache(
+    hexmake_file: &HexmakeFile,
+    env: Arc<BuildEnvironment>,
+) -> Result<BuildCache, Error> {
+    let vfs = Box::new(PosixFileSystem::new(hexmake_file.source_trees));
+    let cache_options = CacheOptions {
+        settings: hexmake_file.cache.clone(),
+        ..CacheOptions::default()
     };
-    hexmake_file
+    Ok(BuildCache::new(env, vfs, cache_options)?)
 }
 
-/// List available targets and then exit
-fn list_targets(hexmake_file: &HexmakeFile) -> ! {
-    let mut targets: Vec<String> = Vec::new();
-    for rule in &hexmake_file.rules {
-        targets.push(rule.name.to_string());
-        for output in &rule.outputs {
-            targets.push(output.to_string());
+/// Run a `hexmake cache` subcommand
+fn run_cache_command(
+    command: &CacheCommand,
+    hexmake_file: &HexmakeFile,
+    build_cache: &BuildCache,
+) -> Result<(), Error> {
+    match command {
+        CacheCommand::Pull { source } => {
+            // A cache server that cannot be reached only makes the next
+            // build slower, so it should not fail a CI job
+            let remote = &hexmake_file.cache.remote;
+            let archive = match read_url_or_path(source, remote) {
+                Ok(archive) => archive,
+                Err(error) if is_url(source) => {
+                    eprintln!("Warning: {error}. The build will not use the cache archive.");
+                    return Ok(());
+                }
+                Err(error) => return Err(error.into()),
+            };
+            let counts = build_cache.import_archive(&archive[..])?;
+            println!(
+                "Added {} files to the cache, and skipped {} that it already had",
+                counts.added, counts.skipped
+            );
+        }
+        CacheCommand::Push { destination } => {
+            let (archive, entries) = build_cache.export_archive()?;
+            let remote = &hexmake_file.cache.remote;
+            if let Err(error) = write_url_or_path(destination, &archive, remote) {
+                if !is_url(destination) {
+                    return Err(error.into());
+                }
+                eprintln!("Warning: {error}. The cache archive was not pushed.");
+                return Ok(());
+            }
+            println!(
+                "Pushed {entries} cache entries ({} bytes) to {destination}",
+                archive.len()
+            );
+        }
+        CacheCommand::Pin { targets } => {
+            let plan = plan_build(hexmake_file, targets).map_err(Error::Plan)?;
+            for task in plan.tasks.values() {
+                let task = task.lock().unwrap();
+                if build_cache.pin_entry(&task.rule)? {
+                    println!("[{}] Pinned cache entry", task.rule_name());
+                } else {
+                    println!("[{}] No cache entry to pin", task.rule_name());
+                }
+            }
+        }
+        CacheCommand::Unpin { targets } => {
+            let plan = plan_build(hexmake_file, targets).map_err(Error::Plan)?;
+            for task in plan.tasks.values() {
+                let task = task.lock().unwrap();
+                let unpinned = build_cache.unpin_entries(&task.rule.name.to_string())?;
+                if unpinned > 0 {
+                    println!("[{}] Unpinned {unpinned} cache entries", task.rule_name());
+                }
+            }
         }
     }
-    targets.sort();
-    for target in targets {
-        println!("{}", target);
+    Ok(())
+}
+
+/// Compute the targets to build, taking into account the options
+/// that select targets in bulk
+fn requested_targets(args: &Args, hexmake_file: &HexmakeFile) -> Result<Vec<Arc<String>>, Error> {
+    if args.all {
+        return Ok(hexmake_file
+            .rules
+            .iter()
+            .map(|rule| rule.name.name.clone())
+            .collect());
     }
 
-    exit(0)
-}
+    if !args.affected_by.is_empty() {
+        let mut changed_paths = Vec::new();
+        for path in &args.affected_by {
+            changed_paths.push(HexPath::try_from(path.as_str())?);
+        }
+        return Ok(affected_rules(hexmake_file, &changed_paths)
+            .into_iter()
+            .map(|rule_name| rule_name.name)
+            .collect());
+    }
 
-/// Make a map of the environment variables that should be passed through
-fn get_environment(hexmake_file: &HexmakeFile) -> Arc<BTreeMap<Arc<String>, Arc<String>>> {
-    let mut result = BTreeMap::new();
+    if let Some(Command::Install { target, .. }) = &args.command {
+        return Ok(vec![target.clone()]);
+    }
 
-    for variable in &hexmake_file.env {
-        if let Ok(value) = env::var(variable.as_str()) {
-            result.insert(variable.clone(), Arc::new(value));
+    if args.targets.is_empty() {
+        return Ok(hexmake_file.default_targets.clone());
+    }
+
+    Ok(args.targets.clone())
+}
+
+/// Check that the sources and downloads match the ones in the lock file
+fn check_locked(hexmake_file: &HexmakeFile) -> Result<(), Error> {
+    let locked = match read_to_string(LOCK_FILE) {
+        Ok(text) => SourceLock::parse(&text)?,
+        Err(error) => {
+            return Err(Error::Hexmake(format!(
+                "--locked needs a lock file, but {error}. Run `hexmake lock` to create one"
+            )));
         }
+    };
+
+    let actual = SourceLock::compute(
+        hexmake_file,
+        &PosixFileSystem::new(hexmake_file.source_trees),
+    )?;
+    let differences = locked.differences(&actual);
+    if !differences.is_empty() {
+        return Err(Error::Hexmake(format!(
+            "{LOCK_FILE} does not match the workspace:\n  {}\nRun `hexmake lock` to update it",
+            differences.join("\n  ")
+        )));
+    }
+    Ok(())
+}
+
+/// Remove the outputs of the given targets, and optionally their cache entries
+fn clean(
+    hexmake_file: &HexmakeFile,
+    env: &Arc<BuildEnvironment>,
+    targets: &Vec<Arc<String>>,
+    cache: bool,
+) -> Result<(), Error> {
+    let plan = plan_build(hexmake_file, targets).map_err(Error::Plan)?;
+
+    let build_cache = if cache {
+        Some(lookup_cache(hexmake_file, env.clone())?)
+    } else {
+        None
+    };
+
+    Ok(clean_plan(
+        &plan,
+        &PosixFileSystem::new(hexmake_file.source_trees),
+        build_cache.as_ref(),
+    )?)
+}
+
+/// Print the usage of Hexmake and exit, as for a usage error
+fn show_usage() -> ! {
+    eprint!("{}", Args::command().render_help());
+    exit(2);
+}
+
+/// Load and parse the Hexmake file
+fn load_hexmake_file(file: Option<&Path>) -> Result<HexmakeFile, Error> {
+    let source = read_hexmake_source(&hexmake_file_path(file))
+        .map_err(|error| Error::Parse(format!("Could not open Hexmake file: {error}")))?;
+    HexmakeFile::parse(&source).map_err(Error::Parse)
+}
+
+/// Rewrite the Hexmake file in its canonical form, or with `check`, only
+/// report whether it is in that form. A file read from standard input is
+/// written to standard output.
+fn format_file(file: Option<&Path>, check: bool) -> Result<(), Error> {
+    let path = hexmake_file_path(file);
+    let source = read_hexmake_source(&path)?;
+    let formatted = format_hexmake_file(&source)?;
+    if path == Path::new(STDIN_FILE) && !check {
+        print!("{formatted}");
+        return Ok(());
+    }
+    if formatted == source {
+        return Ok(());
     }
+    if check {
+        return Err(format!(
+            "{} is not formatted; run `hexmake fmt` to format it",
+            path.display()
+        )
+        .into());
+    }
+    write(&path, formatted)?;
+    println!("Formatted {}", path.display());
+    Ok(())
+}
+
+/// The edit to the Hexmake file that the command line asks for, if any
+fn rule_edit(args: &Args) -> Option<RuleEdit> {
+    let edit = match args.command.as_ref()? {
+        Command::AddRule {
+            name,
+            inputs,
+            outputs,
+            commands,
+        } => RuleEdit::Add {
+            name: name.clone(),
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            commands: commands.clone(),
+        },
+        Command::RemoveRule { name } => RuleEdit::Remove { name: name.clone() },
+        Command::SetInputs { name, paths } => RuleEdit::SetInputs {
+            name: name.clone(),
+            paths: paths.clone(),
+        },
+        Command::SetOutputs { name, paths } => RuleEdit::SetOutputs {
+            name: name.clone(),
+            paths: paths.clone(),
+        },
+        _ => return None,
+    };
+    Some(edit)
+}
 
-    Arc::new(result)
+/// Make an edit to the rules of the Hexmake file, and write it back
+fn edit_file(file: Option<&Path>, edit: &RuleEdit) -> Result<(), Error> {
+    let path = hexmake_file_path(file);
+    if path == Path::new(STDIN_FILE) {
+        return Err("A Hexmake file read from standard input cannot be edited"
+            .to_string()
+            .into());
+    }
+    let source = read_to_string(&path)?;
+    write(&path, edit_hexmake_file(&source, edit)?)?;
+    println!("Updated {}", path.display());
+    Ok(())
 }
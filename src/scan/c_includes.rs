@@ -0,0 +1,277 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::HexRule;
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::scan::DependencyScanner;
+
+/// The file extensions of C and C++ sources and headers
+const C_EXTENSIONS: [&str; 11] = [
+    "c", "cc", "cpp", "cxx", "c++", "m", "mm", "h", "hh", "hpp", "hxx",
+];
+
+/// One `#include` line of a C or C++ file
+#[derive(Debug, PartialEq)]
+struct Include {
+    /// The file name between the quotes or angle brackets
+    name: String,
+
+    /// Whether the name is in quotes, as opposed to angle brackets
+    quoted: bool,
+}
+
+/// Finds the headers that the C and C++ inputs of rules with
+/// `include_scan` include, directly or through other headers. Only
+/// headers that are source files are found. Generated headers in `out/`
+/// do not exist until the build runs, so they must still be listed.
+pub struct CIncludeScanner;
+
+impl DependencyScanner for CIncludeScanner {
+    fn name(&self) -> &str {
+        "include_scan"
+    }
+
+    fn scan(&self, rule: &HexRule, vfs: &dyn VirtualFileSystem) -> Result<Vec<HexPath>, io::Error> {
+        let Some(include_scan) = &rule.include_scan else {
+            return Ok(Vec::new());
+        };
+        included_headers(rule, &include_scan.include_dirs, vfs)
+    }
+}
+
+/// The headers that a rule's C and C++ inputs include, along with those
+/// inputs themselves
+fn included_headers(
+    rule: &HexRule,
+    include_dirs: &[HexPath],
+    vfs: &dyn VirtualFileSystem,
+) -> Result<Vec<HexPath>, io::Error> {
+    let mut pending = Vec::new();
+    for input in &rule.inputs {
+        if !input.is_output() && is_c_file(input) && vfs.is_file(input)? {
+            pending.push(input.clone());
+        }
+    }
+
+    let mut found: BTreeSet<HexPath> = pending.iter().cloned().collect();
+    while let Some(file) = pending.pop() {
+        let text = String::from_utf8_lossy(&vfs.read(&file)?).into_owned();
+        for include in parse_includes(&text) {
+            let header = resolve_include(&file, &include, include_dirs, vfs)?;
+            if let Some(header) = header
+                && found.insert(header.clone())
+            {
+                pending.push(header);
+            }
+        }
+    }
+
+    Ok(found.into_iter().collect())
+}
+
+/// Whether a file is a C or C++ source or header, by its extension
+fn is_c_file(path: &HexPath) -> bool {
+    path.rsplit_once('.')
+        .is_some_and(|(_, extension)| C_EXTENSIONS.contains(&extension))
+}
+
+/// The `#include` and `#import` lines of a C or C++ file. Conditional
+/// compilation is not evaluated, so every include is found even if the
+/// compiler would skip it. Includes of a macro rather than a literal file
+/// name are left out, and so are lines inside block comments.
+fn parse_includes(text: &str) -> Vec<Include> {
+    let mut includes = Vec::new();
+    let mut in_comment = false;
+    for line in text.lines() {
+        if in_comment {
+            in_comment = !line.contains("*/");
+            continue;
+        }
+        if let Some(start) = line.rfind("/*") {
+            in_comment = !line[start..].contains("*/");
+        }
+
+        let Some(directive) = line.trim_start().strip_prefix('#') else {
+            continue;
+        };
+        let directive = directive.trim_start();
+        let Some(rest) = directive
+            .strip_prefix("include")
+            .or_else(|| directive.strip_prefix("import"))
+        else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        let (close, quoted) = match rest.chars().next() {
+            Some('"') => ('"', true),
+            Some('<') => ('>', false),
+            _ => continue,
+        };
+        if let Some(end) = rest[1..].find(close) {
+            includes.push(Include {
+                name: rest[1..end + 1].to_string(),
+                quoted,
+            });
+        }
+    }
+    includes
+}
+
+/// Find the file that an include refers to, the way a compiler would: a
+/// quoted name is looked for next to the including file first, and then
+/// any name is looked for in each include directory in turn. Return None
+/// if it is not a source file in the workspace, such as a system header.
+fn resolve_include(
+    file: &HexPath,
+    include: &Include,
+    include_dirs: &[HexPath],
+    vfs: &dyn VirtualFileSystem,
+) -> Result<Option<HexPath>, io::Error> {
+    let including_dir = file.parent();
+    let mut dirs = Vec::new();
+    if include.quoted {
+        dirs.push(including_dir.as_deref());
+    }
+    dirs.extend(include_dirs.iter().map(|dir| Some(&**dir)));
+
+    for dir in dirs {
+        if let Some(path) = join_path(dir, &include.name)
+            && !path.is_output()
+            && vfs.is_file(&path)?
+        {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Join a relative file name onto a directory, or onto the workspace if
+/// there is no directory, and resolve its `.` and `..` components. Return
+/// None if the name is absolute or leads out of the workspace.
+fn join_path(dir: Option<&str>, name: &str) -> Option<HexPath> {
+    if name.starts_with('/') {
+        return None;
+    }
+    let mut parts: Vec<&str> = dir.map_or(Vec::new(), |dir| dir.split('/').collect());
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    HexPath::try_from(parts.join("/")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hexmake_file::IncludeScan;
+    use crate::file_system::fake::FakeFileSystem;
+
+    #[test]
+    fn test_parse_includes() {
+        let text = r#"
+#include "util.h"
+  #  include <vector>
+#import "legacy.h"
+#include HEADER_FOR(x)
+#define include "nothing.h"
+/* An old include:
+#include "old.h"
+*/
+#include_next <stdio.h>
+"#;
+        assert_eq!(
+            parse_includes(text),
+            vec![
+                Include {
+                    name: "util.h".to_string(),
+                    quoted: true
+                },
+                Include {
+                    name: "vector".to_string(),
+                    quoted: false
+                },
+                Include {
+                    name: "legacy.h".to_string(),
+                    quoted: true
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_path() {
+        let join = |dir, name| join_path(dir, name).map(|path| path.to_string());
+        assert_eq!(join(Some("src"), "util.h"), Some("src/util.h".to_string()));
+        assert_eq!(
+            join(Some("src/net"), "../common/./types.h"),
+            Some("src/common/types.h".to_string())
+        );
+        assert_eq!(join(None, "config.h"), Some("config.h".to_string()));
+        assert_eq!(join(Some("src"), "../../secret.h"), None);
+        assert_eq!(join(Some("src"), "/usr/include/stdio.h"), None);
+    }
+
+    #[test]
+    fn test_c_include_scanner() {
+        let vfs = FakeFileSystem::default();
+        for (path, contents) in [
+            (
+                "src/main.c",
+                "#include \"util.h\"\n#include <api.h>\n#include <stdio.h>\n",
+            ),
+            ("src/util.h", "#include \"../include/types.h\"\n"),
+            ("src/unused.h", ""),
+            (
+                "include/api.h",
+                "#include \"types.h\"\n#include \"version.h\"\n",
+            ),
+            ("include/types.h", "#include \"api.h\"\n"),
+            ("assets/logo.svg", "#include \"src/unused.h\"\n"),
+        ] {
+            vfs.write(&HexPath::try_from(path).unwrap(), contents.as_bytes())
+                .unwrap();
+        }
+
+        let mut rule = HexRule {
+            name: "main".into(),
+            outputs: vec![HexPath::try_from("out/main.o").unwrap()],
+            inputs: vec![
+                HexPath::try_from("src/main.c").unwrap(),
+                HexPath::try_from("assets").unwrap(),
+                HexPath::try_from("out/version.h").unwrap(),
+            ],
+            commands: vec!["cc -Iinclude -c -o out/main.o src/main.c".into()],
+            ..Default::default()
+        };
+
+        // Rules without `include_scan` are not scanned
+        assert_eq!(CIncludeScanner.scan(&rule, &vfs).unwrap(), vec![]);
+
+        // Headers are followed through other headers, and ones that are
+        // not in the workspace, such as system headers, are left out
+        rule.include_scan = Some(IncludeScan {
+            include_dirs: vec![HexPath::try_from("include").unwrap()],
+        });
+        let found: Vec<String> = CIncludeScanner
+            .scan(&rule, &vfs)
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string())
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                "include/api.h",
+                "include/types.h",
+                "src/main.c",
+                "src/util.h",
+            ]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Finding the inputs of rules that their Hexmake file does not list
+
+pub mod c_includes;
+
+use std::io;
+use std::sync::Arc;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::scan::c_includes::CIncludeScanner;
+
+/// Finds inputs of a rule by reading the inputs that it lists, such as
+/// the files that its sources import. Each scanner decides from the
+/// rule's fields whether to scan it, so a new kind of scanner only needs
+/// its own fields and an entry in [builtin_scanners].
+pub trait DependencyScanner: Send + Sync {
+    /// The field of a rule that turns this scanner on, for messages
+    fn name(&self) -> &str;
+
+    /// The inputs that the rule needs besides the ones it lists. The
+    /// rule's source inputs can be read from the file system, but its
+    /// inputs in `out/` have not been built yet. It is fine to return
+    /// paths that the rule's inputs already cover.
+    fn scan(&self, rule: &HexRule, vfs: &dyn VirtualFileSystem) -> Result<Vec<HexPath>, io::Error>;
+}
+
+/// The scanners that are built into Hexmake
+pub fn builtin_scanners() -> Vec<Box<dyn DependencyScanner>> {
+    vec![Box::new(CIncludeScanner)]
+}
+
+/// Add the inputs that the scanners find for each rule to its inputs, so
+/// that they are part of its cache key and are copied into its work
+/// directory. Inputs that the rule already covers, either by listing them
+/// or by listing a directory they are in, are not added again.
+pub fn add_discovered_inputs(
+    hexmake_file: &mut HexmakeFile,
+    scanners: &[Box<dyn DependencyScanner>],
+    vfs: &dyn VirtualFileSystem,
+) -> Result<(), io::Error> {
+    for rule in &mut hexmake_file.rules {
+        let mut discovered: Vec<HexPath> = Vec::new();
+        for scanner in scanners {
+            let found = scanner.scan(rule, vfs).map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!(
+                        "In rule `{}`, `{}` failed: {error}",
+                        rule.name,
+                        scanner.name()
+                    ),
+                )
+            })?;
+            for path in found {
+                let covered = rule
+                    .inputs
+                    .iter()
+                    .chain(&discovered)
+                    .any(|input| path == *input || path.starts_with(&format!("{input}/")));
+                if !covered {
+                    discovered.push(path);
+                }
+            }
+        }
+        if !discovered.is_empty() {
+            Arc::make_mut(rule).inputs.extend(discovered);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::fake::FakeFileSystem;
+
+    /// A scanner that finds the same paths for every rule
+    struct FixedScanner(Vec<&'static str>);
+
+    impl DependencyScanner for FixedScanner {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn scan(
+            &self,
+            _rule: &HexRule,
+            _vfs: &dyn VirtualFileSystem,
+        ) -> Result<Vec<HexPath>, io::Error> {
+            Ok(self
+                .0
+                .iter()
+                .map(|path| HexPath::try_from(*path).unwrap())
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_add_discovered_inputs() {
+        let mut hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "app".into(),
+                    outputs: vec![HexPath::try_from("out/app").unwrap()],
+                    inputs: vec![
+                        HexPath::try_from("src/main.py").unwrap(),
+                        HexPath::try_from("vendor").unwrap(),
+                    ],
+                    commands: vec!["pack out/app src/main.py".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        let scanners: Vec<Box<dyn DependencyScanner>> = vec![
+            Box::new(FixedScanner(vec![
+                "src/main.py",
+                "src/util.py",
+                "vendor/lib.py",
+            ])),
+            Box::new(FixedScanner(vec!["src/util.py", "src/config.py"])),
+        ];
+        add_discovered_inputs(&mut hexmake_file, &scanners, &FakeFileSystem::default()).unwrap();
+
+        // Each new input is added once, after the listed ones
+        let inputs: Vec<String> = hexmake_file.rules[0]
+            .inputs
+            .iter()
+            .map(|input| input.to_string())
+            .collect();
+        assert_eq!(
+            inputs,
+            vec!["src/main.py", "vendor", "src/util.py", "src/config.py"]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ast::hexmake_file::{HexmakeFile, RuleKind};
+use crate::cache::build_hash::BuildHash;
+use crate::file_system::vfs::VirtualFileSystem;
+
+/// The name of the lock file, which sits next to the Hexmake file
+pub const LOCK_FILE: &str = "Hexmake.lock";
+
+/// The contents of `Hexmake.lock`, which pins the exact contents of
+/// everything a build reads from outside of `out/`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SourceLock {
+    /// The hash of each source tree that a rule uses, as computed by
+    /// `BuildHash::hash_tree`
+    pub sources: BTreeMap<String, String>,
+
+    /// The SHA-256 hash of each file that is downloaded, by URL
+    pub downloads: BTreeMap<String, String>,
+}
+
+impl SourceLock {
+    /// Compute the lock for the current state of the workspace. Source
+    /// trees that do not exist are left out, because they are only needed
+    /// if the rules that use them are built.
+    pub fn compute(
+        hexmake_file: &HexmakeFile,
+        vfs: &dyn VirtualFileSystem,
+    ) -> Result<SourceLock, io::Error> {
+        let mut lock = SourceLock::default();
+
+        for rule in &hexmake_file.rules {
+            for path in rule.inputs.iter().chain(&rule.runfiles) {
+                if path.is_output() || lock.sources.contains_key(&path.to_string()) {
+                    continue;
+                }
+                if vfs.exists(path)? {
+                    let hash = BuildHash::hash_tree(&path, vfs)?;
+                    lock.sources.insert(path.to_string(), hash.0);
+                }
+            }
+
+            if rule.kind == RuleKind::HttpFile
+                && let (Some(url), Some(sha256)) = (&rule.url, &rule.sha256)
+            {
+                lock.downloads
+                    .insert(url.clone(), sha256.to_ascii_lowercase());
+            }
+        }
+
+        for toolchain in hexmake_file.toolchains.values() {
+            if let Some(url) = &toolchain.url {
+                lock.downloads
+                    .insert(url.clone(), toolchain.sha256.to_ascii_lowercase());
+            }
+        }
+
+        Ok(lock)
+    }
+
+    /// Parse the text of a lock file
+    pub fn parse(text: &str) -> Result<SourceLock, String> {
+        serde_json::from_str(text).map_err(|error| format!("Could not parse {LOCK_FILE}: {error}"))
+    }
+
+    /// Format the lock as the text of a lock file
+    pub fn to_text(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap() + "\n"
+    }
+
+    /// Describe every way that `actual` differs from this lock, one line
+    /// per difference. An empty list means they match.
+    pub fn differences(&self, actual: &SourceLock) -> Vec<String> {
+        let mut result = Vec::new();
+        compare(&self.sources, &actual.sources, "Source", &mut result);
+        compare(&self.downloads, &actual.downloads, "Download", &mut result);
+        result
+    }
+}
+
+/// Compare one section of two locks
+fn compare(
+    locked: &BTreeMap<String, String>,
+    actual: &BTreeMap<String, String>,
+    what: &str,
+    result: &mut Vec<String>,
+) {
+    for (name, hash) in actual {
+        match locked.get(name) {
+            None => result.push(format!("{what} `{name}` is not in {LOCK_FILE}")),
+            Some(locked_hash) if locked_hash != hash => {
+                result.push(format!("{what} `{name}` has changed"))
+            }
+            Some(_) => {}
+        }
+    }
+    for name in locked.keys() {
+        if !actual.contains_key(name) {
+            result.push(format!(
+                "{what} `{name}` is in {LOCK_FILE} but was not found"
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use crate::file_system::fake::FakeFileSystem;
+
+    #[test]
+    fn test_source_lock() {
+        let hexmake_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "name": "main",
+                        "inputs": ["main.c", "out/zlib.tar.gz", "missing.c"],
+                        "outputs": ["out/main"],
+                        "commands": ["cc -o out/main main.c"]
+                    },
+                    {
+                        "name": "zlib",
+                        "kind": "http_file",
+                        "outputs": ["out/zlib.tar.gz"],
+                        "url": "https://example.com/zlib.tar.gz",
+                        "sha256": "ABCD"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let vfs = FakeFileSystem::default();
+        vfs.write(&HexPath::try_from("main.c").unwrap(), b"int main;")
+            .unwrap();
+
+        let lock = SourceLock::compute(&hexmake_file, &vfs).unwrap();
+        assert_eq!(
+            lock.sources.keys().collect::<Vec<_>>(),
+            vec!["main.c"],
+            "outputs and missing sources are not locked"
+        );
+        assert_eq!(
+            lock.downloads,
+            BTreeMap::from([(
+                "https://example.com/zlib.tar.gz".to_string(),
+                "abcd".to_string()
+            )])
+        );
+
+        // The lock round-trips through its text form
+        assert_eq!(SourceLock::parse(&lock.to_text()), Ok(lock.clone()));
+        assert_eq!(lock.differences(&lock), Vec::<String>::new());
+
+        // Changing a source is reported
+        vfs.write(&HexPath::try_from("main.c").unwrap(), b"int main();")
+            .unwrap();
+        vfs.write(&HexPath::try_from("missing.c").unwrap(), b"")
+            .unwrap();
+        let actual = SourceLock::compute(&hexmake_file, &vfs).unwrap();
+        assert_eq!(
+            lock.differences(&actual),
+            vec![
+                "Source `main.c` has changed",
+                "Source `missing.c` is not in Hexmake.lock"
+            ]
+        );
+        assert_eq!(
+            actual.differences(&lock),
+            vec![
+                "Source `main.c` has changed",
+                "Source `missing.c` is in Hexmake.lock but was not found"
+            ]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::env::current_exe;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::graph::planner::BuildPlan;
+use crate::workspace::STDIN_FILE;
+
+/// The kinds of build that `hexmake bench` times
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BenchPhase {
+    /// Every rule runs, because the outputs are removed and the cache is
+    /// not read
+    Cold,
+
+    /// Every output is retrieved from the cache, because the outputs are
+    /// removed but the cache has them
+    Warm,
+
+    /// Nothing needs to be done, because the outputs are already built
+    NoOp,
+}
+
+impl BenchPhase {
+    const ALL: [BenchPhase; 3] = [BenchPhase::Cold, BenchPhase::Warm, BenchPhase::NoOp];
+
+    fn name(self) -> &'static str {
+        match self {
+            BenchPhase::Cold => "cold",
+            BenchPhase::Warm => "warm",
+            BenchPhase::NoOp => "no-op",
+        }
+    }
+
+    /// The `--cache` mode that the build of this phase uses
+    fn cache_mode(self) -> &'static str {
+        match self {
+            BenchPhase::Cold => "write-only",
+            BenchPhase::Warm | BenchPhase::NoOp => "read-write",
+        }
+    }
+}
+
+/// Build a target over and over, and print how long each kind of build
+/// took. Each run does a cold build, then a warm build, then a no-op
+/// build. Each build is a separate run of Hexmake, so the times include
+/// everything that a user would wait for. The cache is not cleared for
+/// the cold builds, only left unread, so benchmarking does not throw away
+/// any cached outputs.
+pub fn run_bench(
+    plan: &BuildPlan,
+    target: &str,
+    runs: u32,
+    file: Option<&Path>,
+    vfs: &dyn VirtualFileSystem,
+) -> Result<(), Error> {
+    if file == Some(Path::new(STDIN_FILE)) {
+        return Err(
+            "Each build of `hexmake bench` reads the Hexmake file again, so it cannot come from standard input"
+                .to_string()
+                .into(),
+        );
+    }
+    println!("Building `{target}` {runs} times in each phase");
+    let mut times: Vec<Vec<Duration>> = vec![Vec::new(); BenchPhase::ALL.len()];
+    for run in 1..=runs {
+        for (index, phase) in BenchPhase::ALL.iter().enumerate() {
+            if *phase != BenchPhase::NoOp {
+                remove_outputs(plan, vfs)?;
+            }
+            let elapsed = time_build(target, file, *phase).map_err(|error| {
+                format!("The {} build of run {run} failed: {error}", phase.name())
+            })?;
+            times[index].push(elapsed);
+        }
+    }
+
+    for (phase, times) in BenchPhase::ALL.iter().zip(&times) {
+        println!("{:<6} {}", phase.name(), TimingStats::of(times));
+    }
+    Ok(())
+}
+
+/// Remove the outputs of every task in a plan
+fn remove_outputs(plan: &BuildPlan, vfs: &dyn VirtualFileSystem) -> Result<(), io::Error> {
+    for task in plan.tasks.values() {
+        for output in &task.lock().unwrap().rule.outputs {
+            if vfs.is_file(output)? {
+                vfs.remove_file(output)?;
+            } else if vfs.exists(output)? {
+                vfs.remove_dir_all(output)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run one build of a target in a new Hexmake process, and return how
+/// long it took. The build's output is only shown if it fails.
+fn time_build(target: &str, file: Option<&Path>, phase: BenchPhase) -> Result<Duration, Error> {
+    let mut command = Command::new(current_exe()?);
+    if let Some(file) = file {
+        command.arg("--file").arg(file);
+    }
+    command.args(["--cache", phase.cache_mode(), target]);
+    let start = Instant::now();
+    let output = command.output()?;
+    let elapsed = start.elapsed();
+    if !output.status.success() {
+        print!("{}", String::from_utf8_lossy(&output.stdout));
+        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(format!("Hexmake exited with {}", output.status).into());
+    }
+    Ok(elapsed)
+}
+
+/// Summary statistics of some timings
+#[derive(Debug, PartialEq)]
+struct TimingStats {
+    mean: Duration,
+    min: Duration,
+    median: Duration,
+    max: Duration,
+}
+
+impl TimingStats {
+    /// The statistics of a list of timings, which must not be empty
+    fn of(times: &[Duration]) -> TimingStats {
+        let mut sorted = times.to_vec();
+        sorted.sort();
+        let middle = sorted.len() / 2;
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2
+        } else {
+            sorted[middle]
+        };
+        TimingStats {
+            mean: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            min: sorted[0],
+            median,
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+impl Display for TimingStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mean {:.3}s  min {:.3}s  median {:.3}s  max {:.3}s",
+            self.mean.as_secs_f64(),
+            self.min.as_secs_f64(),
+            self.median.as_secs_f64(),
+            self.max.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timing_stats() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            TimingStats::of(&[ms(300), ms(100), ms(200)]),
+            TimingStats {
+                mean: ms(200),
+                min: ms(100),
+                median: ms(200),
+                max: ms(300),
+            }
+        );
+        assert_eq!(TimingStats::of(&[ms(400), ms(100)]).median, ms(250));
+        assert_eq!(
+            TimingStats::of(&[ms(1500)]).to_string(),
+            "mean 1.500s  min 1.500s  median 1.500s  max 1.500s"
+        );
+    }
+}
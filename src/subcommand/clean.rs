@@ -0,0 +1,215 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::HexmakeFile;
+use crate::cache::build_cache::BuildCache;
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::graph::planner::BuildPlan;
+
+/// Remove the outputs of every task in the plan. The plan includes the
+/// requested targets along with everything they transitively depend on.
+/// If a build cache is supplied, then remove the cache entries for those
+/// tasks as well.
+pub fn clean_plan(
+    plan: &BuildPlan,
+    vfs: &dyn VirtualFileSystem,
+    build_cache: Option<&BuildCache>,
+) -> Result<(), io::Error> {
+    // Remove cache entries first, because computing a rule's hash
+    // needs its inputs, and some of those inputs are about to be removed
+    if let Some(build_cache) = build_cache {
+        for task in plan.tasks.values() {
+            let task = task.lock().unwrap();
+            if build_cache.remove_entry(&task.rule)? {
+                println!("[{}] Removed cache entry", task.rule_name());
+            }
+        }
+    }
+
+    for task in plan.tasks.values() {
+        let task = task.lock().unwrap();
+        for output in &task.rule.outputs {
+            if vfs.is_file(output)? {
+                vfs.remove_file(output)?;
+                println!("[{}] Removed {output}", task.rule_name());
+            } else if vfs.exists(output)? {
+                vfs.remove_dir_all(output)?;
+                println!("[{}] Removed {output}", task.rule_name());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove everything under `out/` that is not an output of any rule in
+/// the Hexmake file, such as the outputs of a rule that was renamed or
+/// deleted. Directories that lead to a declared output are kept, and so
+/// is everything inside a declared directory output. The file system must
+/// walk every file, including ones that ignore files would leave out.
+pub fn clean_stale(
+    hexmake_file: &HexmakeFile,
+    vfs: &dyn VirtualFileSystem,
+) -> Result<(), io::Error> {
+    let out_dir = HexPath::try_from("out").unwrap();
+    if !vfs.exists(&out_dir)? {
+        return Ok(());
+    }
+
+    let mut declared = BTreeSet::new();
+    let mut leading_dirs = BTreeSet::new();
+    for rule in &hexmake_file.rules {
+        for output in &rule.outputs {
+            declared.insert(output.clone());
+            let mut parent = output.parent();
+            while let Some(dir) = parent {
+                parent = dir.parent();
+                leading_dirs.insert(dir.to_string());
+            }
+        }
+    }
+    // A path is declared if it or a directory it is in is an output
+    let is_declared = |path: &HexPath| {
+        let mut ancestor = Some(path.clone());
+        while let Some(dir) = ancestor {
+            if declared.contains(&dir) {
+                return true;
+            }
+            ancestor = dir.parent();
+        }
+        false
+    };
+
+    // The walk lists each directory before its contents, so once a
+    // directory is removed, everything after it with its prefix is gone
+    let mut removed_dir: Option<String> = None;
+    for entry in vfs.tree_walk_metadata(&out_dir)? {
+        let path = entry.path.to_string();
+        if path == *out_dir
+            || removed_dir
+                .as_ref()
+                .is_some_and(|dir| path.starts_with(&format!("{dir}/")))
+            || is_declared(&entry.path)
+            || (!entry.is_file && leading_dirs.contains(&path))
+        {
+            continue;
+        }
+
+        if entry.is_file {
+            vfs.remove_file(&entry.path)?;
+        } else {
+            vfs.remove_dir_all(&entry.path)?;
+            removed_dir = Some(path.clone());
+        }
+        println!("Removed stale {path}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hexmake_file::HexRule;
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::graph::planner::plan_build;
+
+    #[test]
+    fn test_clean_plan() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "foo".into(),
+                    outputs: vec![HexPath::try_from("out/foo").unwrap()],
+                    inputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    commands: vec!["gcc -o out/foo out/foo.o".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "foo.o".into(),
+                    outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    inputs: vec![HexPath::try_from("foo.c").unwrap()],
+                    commands: vec!["gcc -o out/foo.o foo.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "bar".into(),
+                    outputs: vec![HexPath::try_from("out/bar").unwrap()],
+                    inputs: vec![],
+                    commands: vec!["touch out/bar".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+
+        let vfs = FakeFileSystem::default();
+        for path in ["foo.c", "out/foo.o", "out/foo", "out/bar"] {
+            vfs.write(&HexPath::try_from(path).unwrap(), b"data")
+                .unwrap();
+        }
+
+        let plan = plan_build(&hexmake_file, &vec!["foo".to_string().into()]).unwrap();
+        clean_plan(&plan, &vfs, None).unwrap();
+
+        // The target and its dependency are removed, but not the source
+        // file or the unrelated rule
+        let exists = |path: &str| vfs.exists(&HexPath::try_from(path).unwrap()).unwrap();
+        assert!(!exists("out/foo"));
+        assert!(!exists("out/foo.o"));
+        assert!(exists("foo.c"));
+        assert!(exists("out/bar"));
+    }
+
+    #[test]
+    fn test_clean_stale() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "main".into(),
+                    outputs: vec![HexPath::try_from("out/c/main.o").unwrap()],
+                    inputs: vec![HexPath::try_from("main.c").unwrap()],
+                    commands: vec!["cc -c -o out/c/main.o main.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "docs".into(),
+                    outputs: vec![HexPath::try_from("out/docs").unwrap()],
+                    commands: vec!["make-docs out/docs".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+
+        let vfs = FakeFileSystem::default();
+        for path in [
+            "main.c",
+            "out/c/main.o",
+            "out/c/old.o",
+            "out/docs/index.html",
+            "out/renamed/app",
+            "out/app.hexrestore",
+        ] {
+            vfs.write(&HexPath::try_from(path).unwrap(), b"data")
+                .unwrap();
+        }
+
+        clean_stale(&hexmake_file, &vfs).unwrap();
+
+        // Only the files that no rule declares are removed
+        let exists = |path: &str| vfs.exists(&HexPath::try_from(path).unwrap()).unwrap();
+        assert!(exists("main.c"));
+        assert!(exists("out/c/main.o"));
+        assert!(!exists("out/c/old.o"));
+        assert!(exists("out/docs/index.html"));
+        assert!(!exists("out/renamed/app"));
+        assert!(!exists("out/app.hexrestore"));
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::{self, Write};
+
+use clap::{CommandFactory, ValueEnum};
+use clap_complete::{Shell, generate};
+use itertools::Itertools;
+
+use crate::args::Args;
+
+/// The shells that `hexmake completions` can print a script for
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// The command that the completion scripts run to find the targets of
+/// the Hexmake file in the directory where the user is typing
+const LIST_TARGETS: &str = "hexmake --list-targets 2>/dev/null";
+
+/// Print a completion script for the given shell. The script completes
+/// subcommands and options the way clap_complete generates them, and
+/// also completes the arguments that are targets with the names of the
+/// rules, aliases, and outputs in the Hexmake file.
+pub fn print_completions(shell: CompletionShell) -> io::Result<()> {
+    io::stdout().write_all(completion_script(shell).as_bytes())
+}
+
+/// The completion script for a shell, as [print_completions] prints it
+fn completion_script(shell: CompletionShell) -> String {
+    let mut command = Args::command();
+    let mut script = Vec::new();
+    let generator = match shell {
+        CompletionShell::Bash => Shell::Bash,
+        CompletionShell::Zsh => Shell::Zsh,
+        CompletionShell::Fish => Shell::Fish,
+    };
+    generate(generator, &mut command, "hexmake", &mut script);
+    let script = String::from_utf8_lossy(&script).into_owned();
+
+    let command = Args::command();
+    let target_commands = target_commands(&command, &[]);
+    match shell {
+        CompletionShell::Bash => script + &bash_targets(&command, &target_commands),
+        CompletionShell::Zsh => zsh_targets(&script),
+        CompletionShell::Fish => script + &fish_targets(&target_commands),
+    }
+}
+
+/// The subcommands, each as the list of names that leads to it, whose
+/// positional arguments are targets. The top level, with an empty list,
+/// is one of them.
+fn target_commands(command: &clap::Command, path: &[String]) -> Vec<Vec<String>> {
+    let mut result = Vec::new();
+    if command.get_positionals().any(is_target_arg) {
+        result.push(path.to_vec());
+    }
+    for subcommand in command.get_subcommands() {
+        let mut path = path.to_vec();
+        path.push(subcommand.get_name().to_string());
+        result.extend(target_commands(subcommand, &path));
+    }
+    result
+}
+
+/// Whether an argument is a target or list of targets
+fn is_target_arg(arg: &clap::Arg) -> bool {
+    matches!(arg.get_id().as_str(), "target" | "targets")
+}
+
+/// A bash function that adds the targets to what the generated `_hexmake`
+/// function completes. A word is a target if it does not follow an
+/// option that takes a value, and if the words before it name a
+/// subcommand that takes targets, or no subcommand at all.
+fn bash_targets(command: &clap::Command, target_commands: &[Vec<String>]) -> String {
+    let value_options = value_options(command).join("|");
+    let subcommands = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name())
+        .join("|");
+    let target_patterns = target_commands
+        .iter()
+        .filter(|path| !path.is_empty())
+        .map(|path| format!("\"{}\"|\"{}\"\\ *", path.join(" "), path.join(" ")))
+        .join("|");
+    format!(
+        r#"
+_hexmake_with_targets() {{
+    _hexmake "$@"
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    if [[ "${{cur}}" == -* ]]; then
+        return 0
+    fi
+    case "${{prev}}" in
+        {value_options})
+            return 0
+            ;;
+    esac
+
+    # The words so far that are not options or their values
+    local words=() word skip=0
+    for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+        if [[ ${{skip}} -eq 1 ]]; then
+            skip=0
+            continue
+        fi
+        case "${{word}}" in
+            {value_options})
+                skip=1
+                ;;
+            -*)
+                ;;
+            *)
+                words+=("${{word}}")
+                ;;
+        esac
+    done
+    case "${{words[0]}}" in
+        {subcommands})
+            case "${{words[*]}}" in
+                {target_patterns})
+                    ;;
+                *)
+                    return 0
+                    ;;
+            esac
+            ;;
+    esac
+    COMPREPLY+=( $(compgen -W "$({LIST_TARGETS})" -- "${{cur}}") )
+}}
+
+if [[ "${{BASH_VERSINFO[0]}}" -eq 4 && "${{BASH_VERSINFO[1]}}" -ge 4 || "${{BASH_VERSINFO[0]}}" -gt 4 ]]; then
+    complete -F _hexmake_with_targets -o nosort -o bashdefault -o default hexmake
+else
+    complete -F _hexmake_with_targets -o bashdefault -o default hexmake
+fi
+"#
+    )
+}
+
+/// The spellings of every option, at any level, that takes a value
+fn value_options(command: &clap::Command) -> Vec<String> {
+    let mut result = Vec::new();
+    for arg in command.get_opts() {
+        if arg.get_action().takes_values() && !arg.is_require_equals_set() {
+            result.extend(arg.get_short().map(|short| format!("-{short}")));
+            result.extend(arg.get_long().map(|long| format!("--{long}")));
+        }
+    }
+    for subcommand in command.get_subcommands() {
+        result.extend(value_options(subcommand));
+    }
+    result.sort();
+    result.dedup();
+    result
+}
+
+/// Change the generated zsh script so that the arguments that are targets
+/// complete with `_hexmake_targets`, which it then defines, instead of
+/// with file names
+fn zsh_targets(script: &str) -> String {
+    let mut result = String::new();
+    for line in script.lines() {
+        let is_target_line = [
+            "'::target -- ",
+            "'*::targets -- ",
+            "'::targets -- ",
+            "':target -- ",
+        ]
+        .iter()
+        .any(|prefix| line.starts_with(prefix));
+        match line.strip_suffix(":_default' \\") {
+            Some(spec) if is_target_line => {
+                result.push_str(&format!("{spec}:_hexmake_targets' \\\n"));
+            }
+            _ => {
+                if line.starts_with("if [ \"$funcstack[1]\" = \"_hexmake\" ]") {
+                    result.push_str(&format!(
+                        "(( $+functions[_hexmake_targets] )) ||\n\
+                         _hexmake_targets() {{\n    \
+                         local -a targets\n    \
+                         targets=(${{(f)\"$({LIST_TARGETS})\"}})\n    \
+                         compadd -a targets\n\
+                         }}\n\n"
+                    ));
+                }
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+    }
+    result
+}
+
+/// Fish completions that offer the targets wherever a subcommand that
+/// takes them, or the top level, expects a positional argument
+fn fish_targets(target_commands: &[Vec<String>]) -> String {
+    let mut result = String::new();
+    for path in target_commands {
+        let condition = match path.as_slice() {
+            [] => "__fish_hexmake_needs_command".to_string(),
+            [subcommand] => format!("__fish_hexmake_using_subcommand {subcommand}"),
+            [subcommand, nested, ..] => format!(
+                "__fish_hexmake_using_subcommand {subcommand}; and __fish_seen_subcommand_from {nested}"
+            ),
+        };
+        result.push_str(&format!(
+            "complete -c hexmake -n \"{condition}\" -f -a \"({LIST_TARGETS})\"\n"
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_commands() {
+        let target_commands = target_commands(&Args::command(), &[]);
+        let names: Vec<String> = target_commands.iter().map(|path| path.join(" ")).collect();
+        assert_eq!(
+            names,
+            vec![
+                "",
+                "bench",
+                "cache pin",
+                "cache unpin",
+                "clean",
+                "install",
+                "outputs",
+                "plan",
+                "query sources",
+                "why-changed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_completion_script() {
+        let bash = completion_script(CompletionShell::Bash);
+        assert!(bash.contains("complete -F _hexmake_with_targets"));
+        assert!(bash.contains("-j|"));
+
+        let zsh = completion_script(CompletionShell::Zsh);
+        assert!(zsh.contains("'*::targets -- The rules or output files whose outputs should be removed:_hexmake_targets' \\"));
+        assert!(zsh.contains("_hexmake_targets() {"));
+        assert!(zsh.contains("':name -- The name of the new rule:_default' \\"));
+
+        let fish = completion_script(CompletionShell::Fish);
+        assert!(fish.contains(
+            "complete -c hexmake -n \"__fish_hexmake_using_subcommand cache; and __fish_seen_subcommand_from pin\" -f -a \"(hexmake --list-targets 2>/dev/null)\"\n"
+        ));
+    }
+}
@@ -0,0 +1,496 @@
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use fs_err::{create_dir_all, hard_link, metadata, read_dir, remove_file, write};
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::{CacheSettings, HexmakeFile, RemoteCacheSettings};
+use crate::check::file::check_file;
+use crate::download::{check_url, is_url};
+use crate::error::Error;
+use crate::file_system::reflink::clone_file;
+use crate::file_system::vfs::side_file_name;
+use crate::subcommand::fmt::format_hexmake_file;
+use crate::workspace::{hexmake_file_in, hexmake_file_path, read_hexmake_source};
+
+/// Where the cache keeps its files
+const CACHE_DIR: &str = ".hex/cache";
+
+/// How far the file system's clock may be from this machine's before it
+/// is worth a warning
+const ALLOWED_CLOCK_SKEW: Duration = Duration::from_secs(2);
+
+/// How a check came out
+#[derive(Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warning,
+    Problem,
+}
+
+/// The result of one check, with what to do about it when it is not ok
+#[derive(Debug, PartialEq)]
+struct Finding {
+    status: Status,
+    check: &'static str,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: impl Into<String>) -> Finding {
+        Finding {
+            status: Status::Ok,
+            check,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Finding {
+        Finding {
+            status: Status::Warning,
+            check,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn problem(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Finding {
+        Finding {
+            status: Status::Problem,
+            check,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Problem => "problem",
+        };
+        write!(f, "{status:<8} {}: {}", self.check, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n{:<8} Fix: {fix}", "")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that the workspace and this machine are set up so that builds
+/// can work well, and print what to do about anything that is not. Fails
+/// if any check found a problem, so that it can guard a CI job.
+pub fn run_doctor(file: Option<&Path>, remote: Option<&str>) -> Result<(), Error> {
+    let (mut findings, hexmake_file) = check_hexmake_file(file);
+    let in_workspace = file.is_some() || hexmake_file_in(Path::new("")).is_some();
+    let hexmake_file = hexmake_file.unwrap_or_default();
+    // Outside a workspace, there is no `.hex/` to test
+    if in_workspace {
+        findings.extend(check_cache(&hexmake_file.cache));
+    }
+    findings.push(check_shell());
+    if in_workspace {
+        findings.extend(check_file_system());
+    }
+    findings.push(match remote {
+        Some(location) => check_remote(location, &hexmake_file.cache.remote),
+        None => Finding::ok(
+            "Remote cache",
+            "not checked; give --remote with the URL that `hexmake cache pull` uses",
+        ),
+    });
+
+    for finding in &findings {
+        println!("{finding}");
+    }
+    let count = |status: Status| findings.iter().filter(|f| f.status == status).count();
+    let (problems, warnings) = (count(Status::Problem), count(Status::Warning));
+    println!("{problems} problems, {warnings} warnings");
+    if problems > 0 {
+        return Err(format!("hexmake doctor found {problems} problems").into());
+    }
+    Ok(())
+}
+
+/// Check that the Hexmake file parses, passes the checks that a build
+/// makes, and is formatted. Gives the file, if it could be loaded, so
+/// that later checks can use its settings.
+fn check_hexmake_file(file: Option<&Path>) -> (Vec<Finding>, Option<HexmakeFile>) {
+    if file.is_none() && hexmake_file_in(Path::new("")).is_none() {
+        return (
+            vec![Finding::problem(
+                "Hexmake file",
+                "there is no Hexmake file here or in any directory above",
+                "run hexmake inside a workspace, or create a Hexmake file at its root",
+            )],
+            None,
+        );
+    }
+    let path = hexmake_file_path(file);
+    match read_hexmake_source(&path) {
+        Ok(source) => check_hexmake_source(&path, &source),
+        Err(error) => (
+            vec![Finding::problem(
+                "Hexmake file",
+                format!("could not read it: {error}"),
+                format!("make {} readable", path.display()),
+            )],
+            None,
+        ),
+    }
+}
+
+/// The findings about the source of a Hexmake file
+fn check_hexmake_source(path: &Path, source: &str) -> (Vec<Finding>, Option<HexmakeFile>) {
+    let name = path.display();
+    let hexmake_file: HexmakeFile = match serde_json::from_str(source) {
+        Ok(hexmake_file) => hexmake_file,
+        Err(error) => {
+            return (
+                vec![Finding::problem(
+                    "Hexmake file",
+                    format!("{name} does not parse: {error}"),
+                    "correct the JSON at that line and column",
+                )],
+                None,
+            );
+        }
+    };
+
+    let mut findings = Vec::new();
+    match check_file(&hexmake_file) {
+        Ok(()) => findings.push(Finding::ok(
+            "Hexmake file",
+            format!(
+                "{name} has {} rules and passes its checks",
+                hexmake_file.rules.len()
+            ),
+        )),
+        Err(error) => findings.push(Finding::problem(
+            "Hexmake file",
+            error,
+            format!("edit {name} so that a build can use it"),
+        )),
+    }
+    if format_hexmake_file(source).is_ok_and(|formatted| formatted != source) {
+        findings.push(Finding::warning(
+            "Formatting",
+            format!("{name} is not formatted"),
+            "run `hexmake fmt`",
+        ));
+    }
+    (findings, Some(hexmake_file))
+}
+
+/// Check that new files can be written to the cache, and how much of its
+/// size limit it takes up
+fn check_cache(settings: &CacheSettings) -> Vec<Finding> {
+    let probe = HexPath::try_from(format!("{CACHE_DIR}/doctor")).unwrap();
+    let probe = PathBuf::from(side_file_name(&probe));
+    let writable = create_dir_all(CACHE_DIR).and_then(|()| write(&probe, b"doctor"));
+    let _ = remove_file(&probe);
+    let mut findings = vec![match writable {
+        Ok(()) => Finding::ok("Cache", format!("{CACHE_DIR} is writable")),
+        Err(error) => Finding::problem(
+            "Cache",
+            format!("cannot write to {CACHE_DIR}: {error}"),
+            match &settings.permissions.group {
+                Some(group) => {
+                    format!("ask to be added to the `{group}` group, which shares the cache")
+                }
+                None => format!("run `chmod -R u+w {CACHE_DIR}`, or remove it to start afresh"),
+            },
+        ),
+    }];
+
+    let size_mb = directory_size(Path::new(CACHE_DIR)) / (1024 * 1024);
+    findings.push(cache_size_finding(size_mb, settings.max_size_mb));
+    findings
+}
+
+/// How the size of the cache compares to its limit
+fn cache_size_finding(size_mb: u64, max_size_mb: u64) -> Finding {
+    let message = format!("holds {size_mb} MB, and the limit is {max_size_mb} MB");
+    if size_mb > max_size_mb {
+        Finding::warning(
+            "Cache size",
+            message,
+            "run a build, which removes the least recently used outputs, or raise `cache.max_size_mb`",
+        )
+    } else {
+        Finding::ok("Cache size", message)
+    }
+}
+
+/// The total size of the files in a directory and its subdirectories
+fn directory_size(dir: &Path) -> u64 {
+    let Ok(entries) = read_dir(dir) else {
+        return 0;
+    };
+    let mut total = 0;
+    for entry in entries.flatten() {
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => total += directory_size(&entry.path()),
+            Ok(_) => total += entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+            Err(_) => {}
+        }
+    }
+    total
+}
+
+/// Check that the shell that runs rule commands can be started
+fn check_shell() -> Finding {
+    let shell = env::var("SHELL").unwrap_or("sh".to_string());
+    match Command::new(&shell).args(["-c", "true"]).status() {
+        Ok(status) if status.success() => Finding::ok("Shell", format!("`{shell}` runs commands")),
+        Ok(status) => Finding::problem(
+            "Shell",
+            format!("`{shell} -c true` failed with {status}"),
+            "set SHELL to a working shell, such as /bin/sh",
+        ),
+        Err(error) => Finding::problem(
+            "Shell",
+            format!("could not run `{shell}`: {error}"),
+            "install it, or set SHELL to a shell that is installed, such as /bin/sh",
+        ),
+    }
+}
+
+/// Check whether files can be hard-linked from the cache to `out/` and
+/// cloned with copy-on-write, and whether the file system's clock agrees
+/// with this machine's
+fn check_file_system() -> Vec<Finding> {
+    let source = PathBuf::from(side_file_name(
+        &HexPath::try_from(format!("{CACHE_DIR}/doctor")).unwrap(),
+    ));
+    let out_dir = if Path::new("out").is_dir() {
+        "out"
+    } else {
+        ".hex"
+    };
+    let link = PathBuf::from(side_file_name(
+        &HexPath::try_from(format!("{out_dir}/doctor")).unwrap(),
+    ));
+    let clone = PathBuf::from(side_file_name(
+        &HexPath::try_from(format!("{out_dir}/doctor")).unwrap(),
+    ));
+    if let Err(error) = create_dir_all(CACHE_DIR).and_then(|()| write(&source, b"doctor")) {
+        return vec![Finding::problem(
+            "File system",
+            format!("could not write a file to test: {error}"),
+            format!("make {CACHE_DIR} writable"),
+        )];
+    }
+
+    let mut findings = vec![match hard_link(&source, &link) {
+        Ok(()) => Finding::ok(
+            "Hard links",
+            format!("outputs can be linked from the cache into {out_dir}/"),
+        ),
+        Err(error) => Finding::warning(
+            "Hard links",
+            format!(
+                "not supported from the cache into {out_dir}/ ({error}), so cached outputs are copied"
+            ),
+            "keep .hex/ and out/ on one file system that supports hard links",
+        ),
+    }];
+    findings.push(match clone_file(&source, &clone) {
+        Ok(true) => Finding::ok(
+            "Copy-on-write",
+            "files can be cloned without copying their data",
+        ),
+        _ => Finding::ok(
+            "Copy-on-write",
+            "not supported, so files copied out of the cache take their full size",
+        ),
+    });
+    let written = metadata(&source).and_then(|metadata| metadata.modified());
+    findings.push(match written {
+        Ok(written) => clock_skew_finding(written, SystemTime::now()),
+        Err(error) => Finding::warning(
+            "Clock",
+            format!("could not read the time of a new file: {error}"),
+            "check that the file system records modification times",
+        ),
+    });
+
+    for path in [&source, &link, &clone] {
+        let _ = remove_file(path);
+    }
+    findings
+}
+
+/// Compare the time that the file system gave a new file with the time
+/// on this machine. Network file systems can use the server's clock.
+fn clock_skew_finding(written: SystemTime, now: SystemTime) -> Finding {
+    let (skew, direction) = match written.duration_since(now) {
+        Ok(ahead) => (ahead, "ahead of"),
+        Err(behind) => (behind.duration(), "behind"),
+    };
+    if skew <= ALLOWED_CLOCK_SKEW {
+        return Finding::ok(
+            "Clock",
+            "the file system's clock agrees with this machine's",
+        );
+    }
+    Finding::warning(
+        "Clock",
+        format!(
+            "the file system's clock is {}s {direction} this machine's, which confuses timestamp checks",
+            skew.as_secs()
+        ),
+        "synchronize the clocks of this machine and the file server with NTP",
+    )
+}
+
+/// Check that a remote cache archive can be reached
+fn check_remote(location: &str, settings: &RemoteCacheSettings) -> Finding {
+    if !is_url(location) {
+        return if Path::new(location).is_file() {
+            Finding::ok("Remote cache", format!("{location} exists"))
+        } else {
+            Finding::warning(
+                "Remote cache",
+                format!("{location} does not exist yet"),
+                format!("run `hexmake cache push {location}` to create it"),
+            )
+        };
+    }
+    remote_finding(location, check_url(location, settings))
+}
+
+/// What the answer to a request for a remote cache archive means
+fn remote_finding(url: &str, result: Result<u16, ureq::Error>) -> Finding {
+    match result {
+        Ok(200..=299) => Finding::ok("Remote cache", format!("{url} is reachable")),
+        Ok(404) => Finding::warning(
+            "Remote cache",
+            format!("{url} is reachable, but has no archive yet"),
+            format!("run `hexmake cache push {url}` to upload one"),
+        ),
+        Ok(status @ (401 | 403)) => Finding::problem(
+            "Remote cache",
+            format!("{url} refused access with HTTP status {status}"),
+            "check the credentials named in `cache.remote`, or ask for access to the server",
+        ),
+        Ok(status) => Finding::problem(
+            "Remote cache",
+            format!("{url} answered with HTTP status {status}"),
+            "check that the URL is right and that the server is working",
+        ),
+        Err(error) => Finding::problem(
+            "Remote cache",
+            format!("{url} cannot be reached: {error}"),
+            "check the URL and the network, or raise `cache.remote.connect_timeout_seconds`",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_check_hexmake_source() {
+        let path = Path::new("Hexmake");
+        let (findings, hexmake_file) = check_hexmake_source(path, "{\"rules\": [}");
+        assert!(hexmake_file.is_none());
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].status, Status::Problem);
+        assert!(findings[0].message.starts_with("Hexmake does not parse"));
+
+        let unformatted =
+            r#"{"rules": [{"name": "main", "outputs": ["main"], "commands": ["cc"]}]}"#;
+        let (findings, hexmake_file) = check_hexmake_source(path, unformatted);
+        assert!(hexmake_file.is_some());
+        assert_eq!(
+            findings,
+            vec![
+                Finding::problem(
+                    "Hexmake file",
+                    "Output `main` is not in `out/`",
+                    "edit Hexmake so that a build can use it",
+                ),
+                Finding::warning(
+                    "Formatting",
+                    "Hexmake is not formatted",
+                    "run `hexmake fmt`"
+                ),
+            ]
+        );
+
+        let formatted =
+            format_hexmake_file(r#"{"rules": [{"name": "main", "outputs": ["out/main"]}]}"#)
+                .unwrap();
+        let (findings, _) = check_hexmake_source(path, &formatted);
+        assert_eq!(
+            findings,
+            vec![Finding::ok(
+                "Hexmake file",
+                "Hexmake has 1 rules and passes its checks"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_cache_size_finding() {
+        assert_eq!(cache_size_finding(150, 200).status, Status::Ok);
+        assert_eq!(cache_size_finding(250, 200).status, Status::Warning);
+    }
+
+    #[test]
+    fn test_clock_skew_finding() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(
+            clock_skew_finding(now + Duration::from_millis(500), now).status,
+            Status::Ok
+        );
+        assert_eq!(
+            clock_skew_finding(now - Duration::from_secs(90), now).message,
+            "the file system's clock is 90s behind this machine's, which confuses timestamp checks"
+        );
+        assert!(
+            clock_skew_finding(now + Duration::from_secs(30), now)
+                .message
+                .contains("30s ahead of")
+        );
+    }
+
+    #[test]
+    fn test_remote_finding() {
+        let url = "https://cache.example.com/hexmake.tar.gz";
+        assert_eq!(remote_finding(url, Ok(200)).status, Status::Ok);
+        assert_eq!(remote_finding(url, Ok(404)).status, Status::Warning);
+        assert_eq!(remote_finding(url, Ok(403)).status, Status::Problem);
+        assert_eq!(
+            remote_finding(url, Err(ureq::Error::HostNotFound)).status,
+            Status::Problem
+        );
+    }
+
+    #[test]
+    fn test_display_finding() {
+        let finding = Finding::warning(
+            "Formatting",
+            "Hexmake is not formatted",
+            "run `hexmake fmt`",
+        );
+        assert_eq!(
+            finding.to_string(),
+            indoc! {"
+                warning  Formatting: Hexmake is not formatted
+                         Fix: run `hexmake fmt`"}
+        );
+    }
+}
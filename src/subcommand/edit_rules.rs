@@ -0,0 +1,296 @@
+use serde_json::{Map, Value, json};
+
+use crate::ast::hexmake_file::HexmakeFile;
+use crate::check::file::check_file;
+use crate::subcommand::fmt::to_canonical_json;
+
+/// A change to the rules of a Hexmake file, for the subcommands that edit
+/// the file for scripts
+pub enum RuleEdit {
+    /// Add a new rule at the end of the file
+    Add {
+        name: String,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+        commands: Vec<String>,
+    },
+
+    /// Remove a rule that nothing else refers to
+    Remove { name: String },
+
+    /// Replace the inputs of a rule
+    SetInputs { name: String, paths: Vec<String> },
+
+    /// Replace the outputs of a rule
+    SetOutputs { name: String, paths: Vec<String> },
+}
+
+/// Apply an edit to the source of a Hexmake file, and return the new
+/// source. The result is written like `hexmake fmt` writes, except that
+/// the rules and inputs stay in their order, so editing a formatted file
+/// only changes the lines of the rule that was edited. The edit is
+/// refused if the file that it makes would not pass Hexmake's checks.
+pub fn edit_hexmake_file(source: &str, edit: &RuleEdit) -> Result<String, String> {
+    serde_json::from_str::<HexmakeFile>(source)
+        .map_err(|error| format!("Could not parse Hexmake file: {error}"))?;
+    let mut value: Value = serde_json::from_str(source)
+        .map_err(|error| format!("Could not parse Hexmake file: {error}"))?;
+    let Some(Value::Array(rules)) = value.get_mut("rules") else {
+        unreachable!("a Hexmake file always has rules");
+    };
+
+    match edit {
+        RuleEdit::Add {
+            name,
+            inputs,
+            outputs,
+            commands,
+        } => {
+            if find_rule(rules, name).is_some() {
+                return Err(format!("There is already a rule named `{name}`"));
+            }
+            let mut rule = Map::new();
+            rule.insert("name".into(), json!(name));
+            rule.insert("inputs".into(), json!(inputs));
+            rule.insert("outputs".into(), json!(outputs));
+            rule.insert("commands".into(), json!(commands));
+            rules.push(Value::Object(rule));
+        }
+        RuleEdit::Remove { name } => {
+            let index = find_rule(rules, name).ok_or_else(|| no_such_rule(name))?;
+            let removed = rules.remove(index);
+            check_unused(&value, &removed)?;
+        }
+        RuleEdit::SetInputs { name, paths } | RuleEdit::SetOutputs { name, paths } => {
+            let index = find_rule(rules, name).ok_or_else(|| no_such_rule(name))?;
+            let field = match edit {
+                RuleEdit::SetInputs { .. } => "inputs",
+                _ => "outputs",
+            };
+            rules[index][field] = json!(paths);
+        }
+    }
+
+    let result = to_canonical_json(&value);
+    let hexmake_file: HexmakeFile = serde_json::from_str(&result)
+        .map_err(|error| format!("The edited Hexmake file would not parse: {error}"))?;
+    check_file(&hexmake_file)
+        .map_err(|error| format!("The edited Hexmake file would not be valid: {error}"))?;
+    Ok(result)
+}
+
+/// The position of a rule in the `rules` list
+fn find_rule(rules: &[Value], name: &str) -> Option<usize> {
+    rules
+        .iter()
+        .position(|rule| rule.get("name").and_then(Value::as_str) == Some(name))
+}
+
+fn no_such_rule(name: &str) -> String {
+    format!("There is no rule named `{name}`")
+}
+
+/// Check that no rule or alias left in a Hexmake file refers to a rule
+/// that was removed from it, or to one of that rule's outputs
+fn check_unused(value: &Value, removed: &Value) -> Result<(), String> {
+    let name = removed["name"].as_str().unwrap_or_default();
+    let outputs = strings(removed.get("outputs"));
+    let refers_to_removed = |path: &str| {
+        path == name
+            || outputs
+                .iter()
+                .any(|output| path == output || path.starts_with(&format!("{output}/")))
+    };
+
+    for rule in value["rules"].as_array().into_iter().flatten() {
+        for input in strings(rule.get("inputs")) {
+            if refers_to_removed(&input) {
+                return Err(format!(
+                    "Cannot remove rule `{name}`, because rule `{}` has the input `{input}`",
+                    rule["name"].as_str().unwrap_or_default()
+                ));
+            }
+        }
+    }
+    if let Some(Value::Object(aliases)) = value.get("aliases") {
+        for (alias, members) in aliases {
+            if strings(Some(members))
+                .iter()
+                .any(|member| refers_to_removed(member))
+            {
+                return Err(format!(
+                    "Cannot remove rule `{name}`, because the alias `{alias}` refers to it"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The strings in a JSON array
+fn strings(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::Array(elements)) => elements
+            .iter()
+            .filter_map(|element| element.as_str().map(String::from))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const SOURCE: &str = indoc! {r#"
+        {
+          "aliases": {
+            "all": [
+              "link"
+            ]
+          },
+          "rules": [
+            {
+              "name": "compile",
+              "commands": [
+                "cc -c -o out/main.o main.c"
+              ],
+              "inputs": [
+                "main.c"
+              ],
+              "outputs": [
+                "out/main.o"
+              ]
+            },
+            {
+              "name": "link",
+              "commands": [
+                "cc -o out/main out/main.o"
+              ],
+              "inputs": [
+                "out/main.o"
+              ],
+              "outputs": [
+                "out/main"
+              ]
+            }
+          ]
+        }
+    "#};
+
+    #[test]
+    fn test_add_rule() {
+        let edit = RuleEdit::Add {
+            name: "test".into(),
+            inputs: vec!["out/main".into()],
+            outputs: vec!["out/test.log".into()],
+            commands: vec!["out/main > out/test.log".into()],
+        };
+        let result = edit_hexmake_file(SOURCE, &edit).unwrap();
+        assert!(result.starts_with(&SOURCE[..SOURCE.len() - "\n  ]\n}\n".len()]));
+        assert!(result.ends_with(indoc! {r#"
+                },
+                {
+                  "name": "test",
+                  "commands": [
+                    "out/main > out/test.log"
+                  ],
+                  "inputs": [
+                    "out/main"
+                  ],
+                  "outputs": [
+                    "out/test.log"
+                  ]
+                }
+              ]
+            }
+        "#}));
+
+        // The names of rules stay unique
+        let edit = RuleEdit::Add {
+            name: "link".into(),
+            inputs: vec![],
+            outputs: vec!["out/other".into()],
+            commands: vec![],
+        };
+        assert_eq!(
+            edit_hexmake_file(SOURCE, &edit),
+            Err("There is already a rule named `link`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_remove_rule() {
+        // A rule that others use stays
+        let edit = RuleEdit::Remove {
+            name: "compile".into(),
+        };
+        assert_eq!(
+            edit_hexmake_file(SOURCE, &edit),
+            Err(
+                "Cannot remove rule `compile`, because rule `link` has the input `out/main.o`"
+                    .to_string()
+            )
+        );
+        let edit = RuleEdit::Remove {
+            name: "link".into(),
+        };
+        assert_eq!(
+            edit_hexmake_file(SOURCE, &edit),
+            Err("Cannot remove rule `link`, because the alias `all` refers to it".to_string())
+        );
+
+        // Once nothing uses it, it can go
+        let source = edit_hexmake_file(
+            SOURCE,
+            &RuleEdit::SetInputs {
+                name: "link".into(),
+                paths: vec!["prebuilt/main.o".into()],
+            },
+        )
+        .unwrap();
+        let result = edit_hexmake_file(
+            &source,
+            &RuleEdit::Remove {
+                name: "compile".into(),
+            },
+        )
+        .unwrap();
+        assert!(!result.contains("compile"));
+        assert!(result.contains("prebuilt/main.o"));
+    }
+
+    #[test]
+    fn test_set_outputs() {
+        let edit = RuleEdit::SetOutputs {
+            name: "link".into(),
+            paths: vec!["out/bin/main".into()],
+        };
+        let result = edit_hexmake_file(SOURCE, &edit).unwrap();
+        assert_eq!(result, SOURCE.replace("\"out/main\"", "\"out/bin/main\""));
+
+        // The result has to be a valid Hexmake file
+        let edit = RuleEdit::SetOutputs {
+            name: "link".into(),
+            paths: vec!["bin/main".into()],
+        };
+        assert_eq!(
+            edit_hexmake_file(SOURCE, &edit),
+            Err(
+                "The edited Hexmake file would not be valid: Output `bin/main` is not in `out/`"
+                    .to_string()
+            )
+        );
+        let edit = RuleEdit::SetOutputs {
+            name: "missing".into(),
+            paths: vec![],
+        };
+        assert_eq!(
+            edit_hexmake_file(SOURCE, &edit),
+            Err("There is no rule named `missing`".to_string())
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use serde_json::{Map, Value};
+
+use crate::ast::expand::mentions_inputs;
+use crate::ast::hexmake_file::HexmakeFile;
+
+/// Rewrite the source of a Hexmake file in its canonical form, so that
+/// two files that say the same thing look the same. Objects are written
+/// with their keys sorted, except that `name` comes first, and with one
+/// key or array element per line. Rules are sorted by name, and each
+/// rule's inputs are sorted unless their order matters to the rule.
+pub fn format_hexmake_file(source: &str) -> Result<String, String> {
+    // Only rewrite a file that Hexmake can use
+    serde_json::from_str::<HexmakeFile>(source)
+        .map_err(|error| format!("Could not parse Hexmake file: {error}"))?;
+    let mut value: Value = serde_json::from_str(source)
+        .map_err(|error| format!("Could not parse Hexmake file: {error}"))?;
+
+    if let Some(Value::Array(rules)) = value.get_mut("rules") {
+        rules.sort_by(|a, b| rule_name(a).cmp(rule_name(b)));
+        for rule in rules.iter_mut() {
+            if inputs_are_unordered(rule)
+                && let Some(Value::Array(inputs)) = rule.get_mut("inputs")
+            {
+                inputs.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+            }
+        }
+    }
+
+    Ok(to_canonical_json(&value))
+}
+
+/// Write out a JSON value the way `hexmake fmt` does, but without sorting
+/// any arrays
+pub fn to_canonical_json(value: &Value) -> String {
+    let mut result = String::new();
+    write_value(value, 0, &mut result);
+    result.push('\n');
+    result
+}
+
+/// The name of a rule in a Hexmake file
+fn rule_name(rule: &Value) -> &str {
+    rule.get("name").and_then(Value::as_str).unwrap_or_default()
+}
+
+/// Whether the order of a rule's inputs makes no difference to what it
+/// builds. A `concat` rule joins its inputs in order, and a command that
+/// says `$inputs` or `$in` gets them in order.
+fn inputs_are_unordered(rule: &Value) -> bool {
+    if rule.get("kind").and_then(Value::as_str) == Some("concat") {
+        return false;
+    }
+    let command_mentions_inputs = |command: &Value| match command {
+        Value::String(command) => mentions_inputs(command),
+        Value::Array(args) => args
+            .iter()
+            .any(|arg| arg.as_str().is_some_and(mentions_inputs)),
+        _ => false,
+    };
+    match rule.get("commands") {
+        Some(Value::Array(commands)) => !commands.iter().any(command_mentions_inputs),
+        _ => true,
+    }
+}
+
+/// Write a JSON value, indented by two spaces per level
+fn write_value(value: &Value, indent: usize, out: &mut String) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            out.push_str("{\n");
+            let keys = sorted_keys(map);
+            for (i, key) in keys.iter().enumerate() {
+                push_indent(indent + 1, out);
+                out.push_str(&Value::String(key.to_string()).to_string());
+                out.push_str(": ");
+                write_value(&map[*key], indent + 1, out);
+                out.push_str(if i + 1 < keys.len() { ",\n" } else { "\n" });
+            }
+            push_indent(indent, out);
+            out.push('}');
+        }
+        Value::Array(elements) if !elements.is_empty() => {
+            out.push_str("[\n");
+            for (i, element) in elements.iter().enumerate() {
+                push_indent(indent + 1, out);
+                write_value(element, indent + 1, out);
+                out.push_str(if i + 1 < elements.len() { ",\n" } else { "\n" });
+            }
+            push_indent(indent, out);
+            out.push(']');
+        }
+        _ => out.push_str(&value.to_string()),
+    }
+}
+
+/// The keys of an object in the order they are written: `name` first,
+/// and then the rest in sorted order
+fn sorted_keys(map: &Map<String, Value>) -> Vec<&str> {
+    let mut keys: Vec<&str> = map.keys().map(String::as_str).collect();
+    keys.sort_by_key(|key| (*key != "name", *key));
+    keys
+}
+
+fn push_indent(indent: usize, out: &mut String) {
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_format_hexmake_file() {
+        let source = r#"{"rules": [
+            {"outputs": ["out/b"], "name": "b", "inputs": ["z.c", "a.c"],
+             "commands": ["cc -o out/b z.c a.c"]},
+            {"name": "a", "kind": "concat", "inputs": ["z.txt", "a.txt"], "outputs": ["out/a"]},
+            {"name": "c", "inputs": ["z.o", "a.o"], "outputs": ["out/c"],
+             "commands": [["ld", "-o", "$output0", "$inputs"]]}
+        ], "defines": {"mode": "debug"}, "env": []}"#;
+        let expected = indoc! {r#"
+            {
+              "defines": {
+                "mode": "debug"
+              },
+              "env": [],
+              "rules": [
+                {
+                  "name": "a",
+                  "inputs": [
+                    "z.txt",
+                    "a.txt"
+                  ],
+                  "kind": "concat",
+                  "outputs": [
+                    "out/a"
+                  ]
+                },
+                {
+                  "name": "b",
+                  "commands": [
+                    "cc -o out/b z.c a.c"
+                  ],
+                  "inputs": [
+                    "a.c",
+                    "z.c"
+                  ],
+                  "outputs": [
+                    "out/b"
+                  ]
+                },
+                {
+                  "name": "c",
+                  "commands": [
+                    [
+                      "ld",
+                      "-o",
+                      "$output0",
+                      "$inputs"
+                    ]
+                  ],
+                  "inputs": [
+                    "z.o",
+                    "a.o"
+                  ],
+                  "outputs": [
+                    "out/c"
+                  ]
+                }
+              ]
+            }
+        "#};
+        assert_eq!(format_hexmake_file(source).unwrap(), expected);
+
+        // Formatting is stable
+        assert_eq!(format_hexmake_file(expected).unwrap(), expected);
+
+        // A file that is not a Hexmake file is left alone
+        assert!(format_hexmake_file(r#"{"rules": [{"name": 5}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_inputs_are_unordered() {
+        let unordered = |commands: &str| {
+            inputs_are_unordered(
+                &serde_json::from_str(&format!(r#"{{"commands": {commands}}}"#)).unwrap(),
+            )
+        };
+        assert!(unordered(r#"["cc -o $output0 a.c b.c"]"#));
+        assert!(!unordered(r#"["cc -o $output0 $inputs"]"#));
+        assert!(!unordered(r#"["cc -o $out $in"]"#));
+        assert!(!unordered(r#"[["ld", "-o", "$out", "$in"]]"#));
+
+        // Shell variables that only start like a placeholder
+        assert!(unordered(r#"["echo $index $in_dir"]"#));
+        // Expansion reads `$iné` as `$in` followed by `é`
+        assert!(!unordered(r#"["cat $iné"]"#));
+    }
+}
@@ -0,0 +1,129 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use fs_err::{copy, create_dir_all, set_permissions};
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::InstallSetting;
+use crate::error::Error;
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::graph::planner::BuildPlan;
+
+/// Copy the outputs of the requested targets in a plan, which must
+/// already be built, into a prefix directory. Each file goes where the
+/// Hexmake file's `install` settings say, or to its path without `out/`.
+pub fn install_outputs(
+    plan: &BuildPlan,
+    settings: &BTreeMap<String, InstallSetting>,
+    prefix: &Path,
+    vfs: &dyn VirtualFileSystem,
+) -> Result<(), Error> {
+    let mut count = 0;
+    for rule_name in &plan.target_rules {
+        let task = plan.tasks[rule_name].lock().unwrap();
+        for output in &task.rule.outputs {
+            for path in vfs.tree_walk(output)? {
+                if !vfs.is_file(&path)? {
+                    continue;
+                }
+                let (destination, mode) = install_destination(&path, settings)?;
+                let destination = prefix.join(destination);
+                install_file(&path, &destination, mode)?;
+                println!("Installed {path} to {}", destination.display());
+                count += 1;
+            }
+        }
+    }
+    println!("Installed {count} files into {}", prefix.display());
+    Ok(())
+}
+
+/// Where an output file is installed, relative to the prefix, and the
+/// permissions it gets if they are set. Where settings for both the file
+/// and a directory that holds it give a path or mode, the more specific
+/// one wins.
+fn install_destination(
+    path: &HexPath,
+    settings: &BTreeMap<String, InstallSetting>,
+) -> Result<(PathBuf, Option<u32>), String> {
+    let mut matching: Vec<(&String, &InstallSetting)> = settings
+        .iter()
+        .filter(|(output, _)| &**path == output.as_str() || path.starts_with(&format!("{output}/")))
+        .collect();
+    matching.sort_by_key(|(output, _)| Reverse(output.len()));
+
+    let destination = matching
+        .iter()
+        .find_map(|(output, setting)| {
+            let install_path = setting.path.as_ref()?;
+            Some(PathBuf::from(format!(
+                "{install_path}{}",
+                &path[output.len()..]
+            )))
+        })
+        .unwrap_or_else(|| PathBuf::from(path.strip_prefix("out/").unwrap_or(path)));
+    let mut mode = None;
+    for (_, setting) in &matching {
+        if let Some(bits) = setting.mode_bits()? {
+            mode = Some(bits);
+            break;
+        }
+    }
+    Ok((destination, mode))
+}
+
+/// Copy one file into place, creating the directories that it goes in
+fn install_file(source: &HexPath, destination: &Path, mode: Option<u32>) -> io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        create_dir_all(parent)?;
+    }
+    copy(&**source, destination)?;
+    if let Some(mode) = mode {
+        set_permissions(destination, Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_destination() {
+        let settings: BTreeMap<String, InstallSetting> = serde_json::from_str(
+            r#"{
+                "out/app": {"path": "bin/app", "mode": "755"},
+                "out/docs": {"path": "share/doc/app", "mode": "644"},
+                "out/docs/run.sh": {"mode": "755"}
+            }"#,
+        )
+        .unwrap();
+        let destination =
+            |path: &str| install_destination(&HexPath::try_from(path).unwrap(), &settings).unwrap();
+
+        assert_eq!(
+            destination("out/app"),
+            (PathBuf::from("bin/app"), Some(0o755))
+        );
+        assert_eq!(
+            destination("out/docs/guide/index.html"),
+            (PathBuf::from("share/doc/app/guide/index.html"), Some(0o644))
+        );
+        assert_eq!(
+            destination("out/docs/run.sh"),
+            (PathBuf::from("share/doc/app/run.sh"), Some(0o755))
+        );
+        assert_eq!(
+            destination("out/lib/libapp.so"),
+            (PathBuf::from("lib/libapp.so"), None)
+        );
+        assert_eq!(
+            destination("out/application"),
+            (PathBuf::from("application"), None)
+        );
+    }
+}
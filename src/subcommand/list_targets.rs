@@ -0,0 +1,445 @@
+use globset::{Glob, GlobMatcher};
+use itertools::join;
+use serde::Serialize;
+
+use crate::ast::hexmake_file::HexmakeFile;
+use crate::graph::label::Label;
+
+/// Options for `--list-targets`
+#[derive(Default)]
+pub struct ListOptions {
+    /// Show one row per rule or alias, with aligned columns
+    pub long: bool,
+
+    /// Print a JSON array with one object per target
+    pub json: bool,
+
+    /// Only list targets whose names match this glob
+    pub filter: Option<GlobMatcher>,
+}
+
+impl ListOptions {
+    /// Compile a glob given on the command line into a filter
+    pub fn compile_filter(pattern: &str) -> Result<GlobMatcher, String> {
+        match Glob::new(pattern) {
+            Ok(glob) => Ok(glob.compile_matcher()),
+            Err(error) => Err(format!("Bad filter `{pattern}`: {error}")),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match &self.filter {
+            Some(filter) => filter.is_match(name),
+            None => true,
+        }
+    }
+}
+
+/// One entry in the JSON format of `--list-targets`
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum TargetEntry {
+    Rule {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        outputs: Vec<String>,
+        tags: Vec<String>,
+    },
+    Output {
+        name: String,
+        rule: String,
+    },
+    Alias {
+        name: String,
+        members: Vec<String>,
+    },
+}
+
+/// Format the targets of a Hexmake file for `--list-targets`.
+/// The short format is a sorted list of every rule name, output, and
+/// alias. The long format has one row per rule or alias, with aligned
+/// columns for the description, outputs, and tags. The JSON format
+/// has one object per target, saying which kind of target it is.
+pub fn format_targets(hexmake_file: &HexmakeFile, options: &ListOptions) -> String {
+    if options.json {
+        format_targets_json(hexmake_file, options)
+    } else if options.long {
+        format_targets_long(hexmake_file, options)
+    } else {
+        format_targets_short(hexmake_file, options)
+    }
+}
+
+/// Format the labels of every rule and alias for `--list-targets --labels`,
+/// as a sorted list. The rules and aliases of the main workspace are
+/// `//:name`, and those of a sub-workspace are `//package:name`.
+pub fn format_labels(
+    hexmake_file: &HexmakeFile,
+    subworkspaces: &[(String, HexmakeFile)],
+    options: &ListOptions,
+) -> String {
+    let mut labels: Vec<String> = Vec::new();
+    let packages = subworkspaces
+        .iter()
+        .map(|(package, file)| (package.as_str(), file));
+    for (package, file) in [("", hexmake_file)].into_iter().chain(packages) {
+        let names = file
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .chain(file.aliases.keys().map(|alias| alias.as_str()));
+        for name in names {
+            labels.push(
+                Label {
+                    package: package.to_string(),
+                    name: name.to_string(),
+                }
+                .to_string(),
+            );
+        }
+    }
+    labels.retain(|label| options.matches(label));
+    labels.sort();
+
+    let mut result = String::new();
+    for label in labels {
+        result.push_str(&format!("{label}\n"));
+    }
+    result
+}
+
+fn format_targets_short(hexmake_file: &HexmakeFile, options: &ListOptions) -> String {
+    let mut targets: Vec<String> = Vec::new();
+    for rule in &hexmake_file.rules {
+        targets.push(rule.name.to_string());
+        for output in &rule.outputs {
+            targets.push(output.to_string());
+        }
+    }
+    for alias in hexmake_file.aliases.keys() {
+        targets.push(alias.to_string());
+    }
+    targets.retain(|target| options.matches(target));
+    targets.sort();
+
+    let mut result = String::new();
+    for target in targets {
+        result.push_str(&format!("{target}\n"));
+    }
+    result
+}
+
+fn format_targets_long(hexmake_file: &HexmakeFile, options: &ListOptions) -> String {
+    let mut rows: Vec<[String; 4]> = Vec::new();
+    for rule in &hexmake_file.rules {
+        if !options.matches(&rule.name) {
+            continue;
+        }
+        rows.push([
+            rule.name.to_string(),
+            rule.description.clone().unwrap_or_default(),
+            join(&rule.outputs, " "),
+            join(&rule.tags, " "),
+        ]);
+    }
+    for (alias, members) in &hexmake_file.aliases {
+        if !options.matches(alias) {
+            continue;
+        }
+        rows.push([
+            alias.to_string(),
+            format!("Alias for {}", join(members, " ")),
+            String::new(),
+            String::new(),
+        ]);
+    }
+    rows.sort();
+    rows.insert(
+        0,
+        ["TARGET", "DESCRIPTION", "OUTPUTS", "TAGS"].map(String::from),
+    );
+
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut result = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (width, cell) in widths.iter().zip(row) {
+            line.push_str(&format!("{cell:width$}  "));
+        }
+        result.push_str(line.trim_end());
+        result.push('\n');
+    }
+    result
+}
+
+fn format_targets_json(hexmake_file: &HexmakeFile, options: &ListOptions) -> String {
+    let mut entries = Vec::new();
+    for rule in &hexmake_file.rules {
+        entries.push(TargetEntry::Rule {
+            name: rule.name.to_string(),
+            description: rule.description.clone(),
+            outputs: rule
+                .outputs
+                .iter()
+                .map(|output| output.to_string())
+                .collect(),
+            tags: rule.tags.clone(),
+        });
+        for output in &rule.outputs {
+            entries.push(TargetEntry::Output {
+                name: output.to_string(),
+                rule: rule.name.to_string(),
+            });
+        }
+    }
+    for (alias, members) in &hexmake_file.aliases {
+        entries.push(TargetEntry::Alias {
+            name: alias.to_string(),
+            members: members.iter().map(|member| member.to_string()).collect(),
+        });
+    }
+    entries.retain(|entry| options.matches(entry.name()));
+    entries.sort_by(|a, b| a.name().cmp(b.name()));
+
+    format!("{}\n", serde_json::to_string_pretty(&entries).unwrap())
+}
+
+impl TargetEntry {
+    fn name(&self) -> &str {
+        match self {
+            TargetEntry::Rule { name, .. } => name,
+            TargetEntry::Output { name, .. } => name,
+            TargetEntry::Alias { name, .. } => name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_format_targets() {
+        let hexmake_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "name": "main",
+                        "description": "Link the program",
+                        "outputs": ["out/main"],
+                        "inputs": ["out/main.o"],
+                        "commands": ["cc -o out/main out/main.o"],
+                        "tags": ["binary", "release"]
+                    },
+                    {
+                        "name": "main.o",
+                        "outputs": ["out/main.o", "out/main.d"],
+                        "inputs": ["main.c"],
+                        "commands": ["cc -c -o out/main.o main.c"]
+                    }
+                ],
+                "aliases": {
+                    "everything": ["main"]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            format_targets(&hexmake_file, &ListOptions::default()),
+            indoc! {"
+                everything
+                main
+                main.o
+                out/main
+                out/main.d
+                out/main.o
+            "}
+        );
+
+        let long = ListOptions {
+            long: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_targets(&hexmake_file, &long),
+            indoc! {"
+                TARGET      DESCRIPTION       OUTPUTS                TAGS
+                everything  Alias for main
+                main        Link the program  out/main               binary release
+                main.o                        out/main.o out/main.d
+            "}
+        );
+    }
+
+    #[test]
+    fn test_format_targets_json() {
+        let hexmake_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "name": "main",
+                        "description": "Link the program",
+                        "outputs": ["out/main"],
+                        "inputs": [],
+                        "commands": ["cc -o out/main main.c"],
+                        "tags": ["binary"]
+                    }
+                ],
+                "aliases": {
+                    "everything": ["main"]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let json = ListOptions {
+            json: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_targets(&hexmake_file, &json),
+            indoc! {r#"
+                [
+                  {
+                    "kind": "alias",
+                    "name": "everything",
+                    "members": [
+                      "main"
+                    ]
+                  },
+                  {
+                    "kind": "rule",
+                    "name": "main",
+                    "description": "Link the program",
+                    "outputs": [
+                      "out/main"
+                    ],
+                    "tags": [
+                      "binary"
+                    ]
+                  },
+                  {
+                    "kind": "output",
+                    "name": "out/main",
+                    "rule": "main"
+                  }
+                ]
+            "#}
+        );
+
+        let filtered = ListOptions {
+            json: true,
+            filter: Some(ListOptions::compile_filter("out/*").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_targets(&hexmake_file, &filtered),
+            indoc! {r#"
+                [
+                  {
+                    "kind": "output",
+                    "name": "out/main",
+                    "rule": "main"
+                  }
+                ]
+            "#}
+        );
+    }
+
+    #[test]
+    fn test_format_labels() {
+        let hexmake_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "subworkspaces": ["lib"],
+                "rules": [
+                    {
+                        "name": "main",
+                        "outputs": ["out/main"],
+                        "inputs": ["lib/out/lib.a"],
+                        "commands": ["cc -o out/main main.c lib/out/lib.a"]
+                    }
+                ],
+                "aliases": {
+                    "everything": ["main"]
+                }
+            }"#,
+        )
+        .unwrap();
+        let lib_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "name": "lib",
+                        "outputs": ["out/lib.a"],
+                        "inputs": [],
+                        "commands": ["ar rc out/lib.a"]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let subworkspaces = vec![("lib".to_string(), lib_file)];
+
+        assert_eq!(
+            format_labels(&hexmake_file, &subworkspaces, &ListOptions::default()),
+            indoc! {"
+                //:everything
+                //:main
+                //lib:lib
+            "}
+        );
+
+        let options = ListOptions {
+            filter: Some(ListOptions::compile_filter("//lib:*").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_labels(&hexmake_file, &subworkspaces, &options),
+            "//lib:lib\n"
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let hexmake_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "name": "test/a",
+                        "outputs": ["out/test/a"],
+                        "inputs": [],
+                        "commands": ["touch out/test/a"]
+                    },
+                    {
+                        "name": "lib",
+                        "outputs": ["out/lib.a"],
+                        "inputs": [],
+                        "commands": ["touch out/lib.a"]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let options = ListOptions {
+            filter: Some(ListOptions::compile_filter("test/*").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(format_targets(&hexmake_file, &options), "test/a\n");
+
+        assert_eq!(
+            ListOptions::compile_filter("[").unwrap_err(),
+            "Bad filter `[`: error parsing glob '[': unclosed character class; missing ']'"
+        );
+    }
+}
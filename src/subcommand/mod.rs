@@ -0,0 +1,16 @@
+//! Subcommands other than running a build
+
+pub mod bench;
+pub mod clean;
+pub mod completions;
+pub mod doctor;
+pub mod edit_rules;
+pub mod fmt;
+pub mod install;
+pub mod list_targets;
+pub mod outputs;
+pub mod plan;
+pub mod print_commands;
+pub mod query;
+pub mod trace_fs;
+pub mod why_changed;
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use crate::graph::planner::BuildPlan;
+
+/// Print the outputs of the requested targets in a plan, either one
+/// per line or as a JSON object mapping each rule name to its outputs
+pub fn print_outputs(plan: &BuildPlan, json: bool) {
+    let outputs = target_outputs(plan);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&outputs).unwrap());
+    } else {
+        for output in outputs.values().flatten() {
+            println!("{output}");
+        }
+    }
+}
+
+/// The outputs of each requested target in a plan, not including the
+/// outputs of the rules they depend on
+fn target_outputs(plan: &BuildPlan) -> BTreeMap<String, Vec<String>> {
+    plan.target_rules
+        .iter()
+        .map(|rule_name| {
+            let task = plan.tasks[rule_name].lock().unwrap();
+            let outputs = task.rule.outputs.iter().map(|output| output.to_string());
+            (rule_name.to_string(), outputs.collect())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+    use crate::graph::planner::plan_build;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_target_outputs() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "foo".into(),
+                    outputs: vec![
+                        HexPath::try_from("out/foo").unwrap(),
+                        HexPath::try_from("out/foo.map").unwrap(),
+                    ],
+                    inputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    commands: vec!["gcc -o out/foo out/foo.o".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "foo.o".into(),
+                    outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    inputs: vec![HexPath::try_from("foo.c").unwrap()],
+                    commands: vec!["gcc -o out/foo.o foo.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            aliases: BTreeMap::from([(
+                Arc::new("binaries".to_string()),
+                vec![Arc::new("foo".to_string())],
+            )]),
+            ..Default::default()
+        };
+
+        let plan = plan_build(&hexmake_file, &vec!["binaries".to_string().into()]).unwrap();
+
+        assert_eq!(
+            target_outputs(&plan),
+            BTreeMap::from([(
+                "foo".to_string(),
+                vec!["out/foo".to_string(), "out/foo.map".to_string()]
+            )])
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use itertools::join;
+use serde::Serialize;
+
+use crate::ast::hexmake_file::{RuleKind, RuleName};
+use crate::cache::build_cache::{BuildCache, CacheProbe};
+use crate::exec::builtin::describe_builtin;
+use crate::graph::planner::BuildPlan;
+
+/// What a build is expected to do for one task
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PredictedStatus {
+    /// The outputs will be retrieved from the cache
+    Hit,
+
+    /// The commands will be run
+    Miss,
+
+    /// It depends on how the task's dependencies turn out
+    Unknown,
+}
+
+impl PredictedStatus {
+    /// The name of the status, as it appears in the JSON output
+    fn name(&self) -> &'static str {
+        match self {
+            PredictedStatus::Hit => "hit",
+            PredictedStatus::Miss => "miss",
+            PredictedStatus::Unknown => "unknown",
+        }
+    }
+}
+
+/// One task in the printed schedule
+#[derive(Debug, PartialEq, Serialize)]
+struct ScheduledTask {
+    rule: String,
+    depends_on: Vec<String>,
+    outputs: Vec<String>,
+    cache: PredictedStatus,
+}
+
+/// Print the tasks of a plan in the order they can be run, along with
+/// their dependencies and whether each is expected to be a cache hit
+pub fn print_plan(plan: &BuildPlan, build_cache: &BuildCache, json: bool) -> Result<(), io::Error> {
+    let schedule = schedule(plan, build_cache)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&schedule).unwrap());
+    } else {
+        for task in &schedule {
+            let status = task.cache.name();
+            if task.depends_on.is_empty() {
+                println!("{} [{status}]", task.rule);
+            } else {
+                println!(
+                    "{} [{status}] depends on: {}",
+                    task.rule,
+                    join(&task.depends_on, ", ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the tasks of a plan in the order they can be run, each with the
+/// commands it would run and whether it is expected to be a cache hit,
+/// without running anything
+pub fn print_dry_run(plan: &BuildPlan, build_cache: &BuildCache) -> Result<(), io::Error> {
+    print!("{}", format_dry_run(plan, &schedule(plan, build_cache)?));
+    Ok(())
+}
+
+/// Format a schedule for `--dry-run`: a line for each task, like those of
+/// [print_plan], followed by its commands, indented
+fn format_dry_run(plan: &BuildPlan, schedule: &[ScheduledTask]) -> String {
+    let mut result = String::new();
+    for scheduled in schedule {
+        let task = plan.tasks[&RuleName::from(scheduled.rule.as_str())]
+            .lock()
+            .unwrap();
+        let rule = &task.rule;
+        result.push_str(&format!(
+            "{} [{}]\n",
+            scheduled.rule,
+            scheduled.cache.name()
+        ));
+        if rule.kind != RuleKind::Command {
+            result.push_str(&format!("    # Built-in: {}\n", describe_builtin(rule)));
+        }
+        for command in &rule.commands {
+            result.push_str(&format!("    {command}\n"));
+        }
+    }
+    result
+}
+
+/// Compute the schedule for a plan. A task is only predicted to be a hit
+/// if its dependencies will all be hits and the files in `out/` already
+/// match what they will retrieve from the cache; otherwise, its inputs
+/// are not known yet.
+fn schedule(plan: &BuildPlan, build_cache: &BuildCache) -> Result<Vec<ScheduledTask>, io::Error> {
+    let mut result = Vec::new();
+    let mut current_hits: BTreeMap<RuleName, bool> = BTreeMap::new();
+
+    for rule_name in plan.topological_order() {
+        let task = plan.tasks[&rule_name].lock().unwrap();
+        let mut depends_on: Vec<RuleName> = task
+            .depends_on
+            .iter()
+            .map(|dep| dep.lock().unwrap().rule_name())
+            .collect();
+        depends_on.sort();
+
+        let dependencies_current = depends_on.iter().all(|dep| current_hits[dep]);
+        let (cache, outputs_current) = if !dependencies_current {
+            (PredictedStatus::Unknown, false)
+        } else {
+            match build_cache.probe(&task.rule)? {
+                CacheProbe::Hit { outputs_current } => (PredictedStatus::Hit, outputs_current),
+                CacheProbe::Miss => (PredictedStatus::Miss, false),
+                CacheProbe::Unknown => (PredictedStatus::Unknown, false),
+            }
+        };
+        current_hits.insert(rule_name.clone(), outputs_current);
+
+        result.push(ScheduledTask {
+            rule: rule_name.to_string(),
+            depends_on: depends_on.iter().map(|dep| dep.to_string()).collect(),
+            outputs: task
+                .rule
+                .outputs
+                .iter()
+                .map(|output| output.to_string())
+                .collect(),
+            cache,
+        });
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+    use crate::cache::cache_options::CacheOptions;
+    use crate::environment::BuildEnvironment;
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::file_system::vfs::VirtualFileSystem;
+    use crate::graph::planner::plan_build;
+    use indoc::indoc;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_schedule() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "foo".into(),
+                    outputs: vec![HexPath::try_from("out/foo").unwrap()],
+                    inputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    commands: vec!["gcc -o out/foo out/foo.o".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "foo.o".into(),
+                    outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    inputs: vec![HexPath::try_from("foo.c").unwrap()],
+                    commands: vec!["gcc -o out/foo.o foo.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        let plan = plan_build(&hexmake_file, &vec!["foo".to_string().into()]).unwrap();
+
+        let vfs = Box::new(FakeFileSystem::default()) as Box<dyn VirtualFileSystem>;
+        let fake_vfs =
+            unsafe { &*(vfs.as_ref() as *const dyn VirtualFileSystem as *const FakeFileSystem) };
+        fake_vfs
+            .write(&HexPath::try_from("foo.c").unwrap(), b"int main;")
+            .unwrap();
+        let build_cache = BuildCache::new(
+            Arc::new(BuildEnvironment::default()),
+            vfs,
+            CacheOptions::default(),
+        )
+        .unwrap();
+
+        let statuses = |build_cache: &BuildCache| {
+            schedule(&plan, build_cache)
+                .unwrap()
+                .iter()
+                .map(|task| (task.rule.clone(), task.cache))
+                .collect::<Vec<_>>()
+        };
+
+        // Nothing is cached, so foo.o will run, and foo depends on the result
+        assert_eq!(
+            statuses(&build_cache),
+            vec![
+                ("foo.o".to_string(), PredictedStatus::Miss),
+                ("foo".to_string(), PredictedStatus::Unknown),
+            ]
+        );
+
+        // Once foo.o is cached and current, foo can be looked up
+        let foo_o = plan.tasks[&RuleName::from("foo.o")]
+            .lock()
+            .unwrap()
+            .rule
+            .clone();
+        let foo = plan.tasks[&RuleName::from("foo")]
+            .lock()
+            .unwrap()
+            .rule
+            .clone();
+        fake_vfs
+            .write(&HexPath::try_from("out/foo.o").unwrap(), b"object")
+            .unwrap();
+        build_cache.insert_outputs(&foo_o).unwrap();
+        assert_eq!(
+            statuses(&build_cache),
+            vec![
+                ("foo.o".to_string(), PredictedStatus::Hit),
+                ("foo".to_string(), PredictedStatus::Miss),
+            ]
+        );
+
+        fake_vfs
+            .write(&HexPath::try_from("out/foo").unwrap(), b"binary")
+            .unwrap();
+        build_cache.insert_outputs(&foo).unwrap();
+        assert_eq!(
+            statuses(&build_cache),
+            vec![
+                ("foo.o".to_string(), PredictedStatus::Hit),
+                ("foo".to_string(), PredictedStatus::Hit),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_dry_run() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "foo".into(),
+                    outputs: vec![HexPath::try_from("out/foo").unwrap()],
+                    inputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    commands: vec!["gcc -o out/foo out/foo.o".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "foo.o".into(),
+                    outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    inputs: vec![HexPath::try_from("foo.c").unwrap()],
+                    commands: vec!["mkdir -p out".into(), "gcc -c -o out/foo.o foo.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        let plan = plan_build(&hexmake_file, &vec!["foo".to_string().into()]).unwrap();
+        let scheduled = |rule: &str, cache: PredictedStatus| ScheduledTask {
+            rule: rule.to_string(),
+            depends_on: Vec::new(),
+            outputs: Vec::new(),
+            cache,
+        };
+
+        assert_eq!(
+            format_dry_run(
+                &plan,
+                &[
+                    scheduled("foo.o", PredictedStatus::Hit),
+                    scheduled("foo", PredictedStatus::Miss),
+                ]
+            ),
+            indoc! {"
+                foo.o [hit]
+                    mkdir -p out
+                    gcc -c -o out/foo.o foo.c
+                foo [miss]
+                    gcc -o out/foo out/foo.o
+            "}
+        );
+    }
+}
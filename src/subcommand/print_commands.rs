@@ -0,0 +1,107 @@
+use itertools::join;
+
+use crate::ast::hexmake_file::RuleKind;
+use crate::environment::BuildEnvironment;
+use crate::exec::builtin::describe_builtin;
+use crate::graph::planner::BuildPlan;
+
+/// Format the commands that a plan would run, in the order they would run.
+/// Each rule's commands are preceded by comments describing the work
+/// directory that the commands run in.
+pub fn format_commands(plan: &BuildPlan, env: &BuildEnvironment) -> String {
+    let mut result = String::new();
+    for rule_name in plan.topological_order() {
+        let task = plan.tasks[&rule_name].lock().unwrap();
+        let rule = &task.rule;
+
+        if !result.is_empty() {
+            result.push('\n');
+        }
+        result.push_str(&format!("# Rule {rule_name}\n"));
+        result.push_str(&format!(
+            "#   Inputs copied into the work directory: {}\n",
+            join(&rule.inputs, " ")
+        ));
+        result.push_str(&format!(
+            "#   Outputs copied back from the work directory: {}\n",
+            join(&rule.outputs, " ")
+        ));
+        result.push_str(&format!(
+            "#   Environment: {}\n",
+            join(env.command_vars().map(|(name, _)| name), " ")
+        ));
+        if rule.kind != RuleKind::Command {
+            result.push_str(&format!("#   Built-in: {}\n", describe_builtin(rule)));
+        }
+        for command in &rule.commands {
+            result.push_str(&format!("{command}\n"));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hex_path::HexPath;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+    use crate::graph::planner::plan_build;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_format_commands() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "foo".into(),
+                    outputs: vec![HexPath::try_from("out/foo").unwrap()],
+                    inputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    commands: vec!["gcc -o out/foo out/foo.o".into(), "strip out/foo".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "foo.o".into(),
+                    outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    inputs: vec![
+                        HexPath::try_from("foo.c").unwrap(),
+                        HexPath::try_from("include").unwrap(),
+                    ],
+                    commands: vec!["gcc -o out/foo.o -c foo.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        let plan = plan_build(&hexmake_file, &vec!["foo".to_string().into()]).unwrap();
+        let env = BuildEnvironment {
+            vars: BTreeMap::from([(
+                Arc::new("PATH".to_string()),
+                Arc::new("/usr/bin".to_string()),
+            )]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_commands(&plan, &env),
+            indoc! {"
+                # Rule foo.o
+                #   Inputs copied into the work directory: foo.c include
+                #   Outputs copied back from the work directory: out/foo.o
+                #   Environment: PATH
+                gcc -o out/foo.o -c foo.c
+
+                # Rule foo
+                #   Inputs copied into the work directory: out/foo.o
+                #   Outputs copied back from the work directory: out/foo
+                #   Environment: PATH
+                gcc -o out/foo out/foo.o
+                strip out/foo
+            "}
+        );
+    }
+}
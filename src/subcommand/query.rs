@@ -0,0 +1,102 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::ast::hex_path::HexPath;
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::graph::planner::BuildPlan;
+
+/// Print every source file that the plan reads, one per line
+pub fn print_sources(plan: &BuildPlan, vfs: &dyn VirtualFileSystem) -> Result<(), io::Error> {
+    for source in source_files(plan, vfs)? {
+        println!("{source}");
+    }
+    Ok(())
+}
+
+/// Find every source file that is an input to some task in the plan.
+/// Source trees are expanded into the files underneath them, and
+/// outputs of other rules are skipped.
+fn source_files(
+    plan: &BuildPlan,
+    vfs: &dyn VirtualFileSystem,
+) -> Result<BTreeSet<HexPath>, io::Error> {
+    let mut result = BTreeSet::new();
+    for task in plan.tasks.values() {
+        let task = task.lock().unwrap();
+        for input in &task.rule.inputs {
+            if input.is_output() {
+                continue;
+            }
+            if !vfs.exists(input)? {
+                return Err(io::Error::other(format!("{input} does not exist")));
+            }
+            for entry in vfs.tree_walk_metadata(input)? {
+                if entry.is_file {
+                    result.insert(entry.path);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::hexmake_file::{HexRule, HexmakeFile};
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::graph::planner::plan_build;
+
+    #[test]
+    fn test_source_files() {
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                HexRule {
+                    name: "foo".into(),
+                    outputs: vec![HexPath::try_from("out/foo").unwrap()],
+                    inputs: vec![
+                        HexPath::try_from("out/foo.o").unwrap(),
+                        HexPath::try_from("main.c").unwrap(),
+                    ],
+                    commands: vec!["gcc -o out/foo out/foo.o main.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "foo.o".into(),
+                    outputs: vec![HexPath::try_from("out/foo.o").unwrap()],
+                    inputs: vec![HexPath::try_from("src").unwrap()],
+                    commands: vec!["gcc -o out/foo.o src/foo.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+                HexRule {
+                    name: "bar".into(),
+                    outputs: vec![HexPath::try_from("out/bar").unwrap()],
+                    inputs: vec![HexPath::try_from("bar.c").unwrap()],
+                    commands: vec!["gcc -o out/bar bar.c".into()],
+                    ..Default::default()
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+
+        let vfs = FakeFileSystem::default();
+        for path in ["main.c", "src/foo.c", "src/include/foo.h", "bar.c"] {
+            vfs.write(&HexPath::try_from(path).unwrap(), b"data")
+                .unwrap();
+        }
+
+        let plan = plan_build(&hexmake_file, &vec!["foo".to_string().into()]).unwrap();
+
+        assert_eq!(
+            source_files(&plan, &vfs)
+                .unwrap()
+                .iter()
+                .map(|path| path.to_string())
+                .collect::<Vec<_>>(),
+            vec!["main.c", "src/foo.c", "src/include/foo.h"]
+        );
+    }
+}
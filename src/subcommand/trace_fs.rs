@@ -0,0 +1,266 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use fs_err::{create_dir_all, read_dir, read_to_string, remove_dir_all};
+use regex::Regex;
+
+use crate::ast::hexmake_file::{HexRule, RuleCommand, RuleKind};
+use crate::environment::BuildEnvironment;
+use crate::error::Error;
+
+/// Where the traces of the commands are written while they run
+const TRACE_DIR: &str = ".hex/trace-fs";
+
+/// The system calls that read a file or look it up by its path. Calls
+/// that change the file system, such as `unlink` or `rename`, are left out.
+const READ_CALLS: [&str; 16] = [
+    "open",
+    "openat",
+    "openat2",
+    "stat",
+    "stat64",
+    "lstat",
+    "lstat64",
+    "newfstatat",
+    "fstatat64",
+    "statx",
+    "access",
+    "faccessat",
+    "faccessat2",
+    "readlink",
+    "readlinkat",
+    "execve",
+];
+
+/// An access to a file by one of the traced processes
+#[derive(Debug, PartialEq)]
+struct FileAccess {
+    path: String,
+    writes: bool,
+}
+
+/// Run the commands of a rule in the workspace itself, rather than in a
+/// work directory, while `strace` records every file that they touch, and
+/// print the files in the workspace that they read as a suggested
+/// `inputs` list. This is meant for writing a rule around an existing
+/// script, whose inputs are not written down anywhere.
+pub fn trace_rule(rule: &HexRule, env: &BuildEnvironment) -> Result<(), Error> {
+    if rule.kind != RuleKind::Command {
+        return Err(format!(
+            "Rule `{}` is a `{}` rule, and only the commands of a `command` rule can be traced",
+            rule.name, rule.kind
+        )
+        .into());
+    }
+    let strace = find_program("strace")
+        .ok_or_else(|| "--trace-fs needs `strace`, which is not on the PATH".to_string())?;
+    let root = env::current_dir()?;
+    let shell = env::var("SHELL").unwrap_or("sh".to_string());
+
+    let _ = remove_dir_all(TRACE_DIR);
+    create_dir_all(TRACE_DIR)?;
+    for command in &rule.commands {
+        println!("[{}] Tracing: {command}", rule.name);
+        let mut process = Command::new(&strace);
+        process
+            .args(["-ff", "-qq", "-s", "4096", "-e", "trace=%file"])
+            .arg("-o")
+            .arg(Path::new(TRACE_DIR).join("trace"))
+            .arg("--");
+        match command {
+            RuleCommand::Shell(command) => process.arg(&shell).arg("-c").arg(command),
+            RuleCommand::Argv(args) => process.args(args),
+        };
+        let status = process
+            .env_clear()
+            .envs(env.command_vars())
+            .status()
+            .map_err(|error| format!("Could not run strace: {error}"))?;
+        if !status.success() {
+            eprintln!("Warning: `{command}` failed, so the inputs may be incomplete");
+        }
+    }
+
+    let mut accesses = Vec::new();
+    for entry in read_dir(TRACE_DIR)? {
+        accesses.extend(parse_trace(&read_to_string(entry?.path())?));
+    }
+    remove_dir_all(TRACE_DIR)?;
+
+    let inputs = suggested_inputs(&accesses, &root);
+    println!("Suggested inputs for `{}`:", rule.name);
+    println!(
+        "\"inputs\": {}",
+        serde_json::to_string_pretty(&inputs).unwrap()
+    );
+
+    let declared: BTreeSet<String> = rule.inputs.iter().map(|input| input.to_string()).collect();
+    let is_covered = |path: &String| {
+        declared
+            .iter()
+            .any(|input| path == input || path.starts_with(&format!("{input}/")))
+    };
+    for path in inputs.iter().filter(|path| !is_covered(path)) {
+        println!("Read but not an input now: {path}");
+    }
+    for input in &declared {
+        let was_read = inputs
+            .iter()
+            .any(|path| path == input || path.starts_with(&format!("{input}/")));
+        if !was_read {
+            println!("An input now but never read: {input}");
+        }
+    }
+    Ok(())
+}
+
+/// The path of a program on the PATH
+fn find_program(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Find the file accesses in the output of `strace`. Only calls that
+/// succeeded count, because a failed lookup, such as of a header in each
+/// directory of an include path, does not mean the file is needed.
+fn parse_trace(trace: &str) -> Vec<FileAccess> {
+    let line_pattern =
+        Regex::new(r#"^(\w+)\((?:[A-Z_]+, )?"([^"\\]*)"(.*)\)\s+=\s+(\d+)"#).unwrap();
+    let mut result = Vec::new();
+    for line in trace.lines() {
+        let Some(captures) = line_pattern.captures(line) else {
+            continue;
+        };
+        let call = &captures[1];
+        if !READ_CALLS.contains(&call) {
+            continue;
+        }
+        let flags = &captures[3];
+        if flags.contains("O_DIRECTORY") {
+            continue;
+        }
+        result.push(FileAccess {
+            path: captures[2].to_string(),
+            writes: ["O_WRONLY", "O_RDWR", "O_CREAT"]
+                .iter()
+                .any(|flag| flags.contains(flag)),
+        });
+    }
+    result
+}
+
+/// The files in the workspace that were read and never written, relative
+/// to the workspace root and sorted. Relative paths are taken to be
+/// relative to the root, which is where the commands start. Files in
+/// `.hex/`, and ones that no longer exist or are not files, are left out.
+fn suggested_inputs(accesses: &[FileAccess], root: &Path) -> Vec<String> {
+    let in_workspace = |path: &str| -> Option<String> {
+        let path = Path::new(path);
+        let relative = if path.is_absolute() {
+            path.strip_prefix(root).ok()?
+        } else {
+            path
+        };
+        let mut parts: Vec<&str> = Vec::new();
+        for component in relative.components() {
+            match component {
+                Component::Normal(part) => parts.push(part.to_str()?),
+                Component::ParentDir => {
+                    parts.pop()?;
+                }
+                Component::CurDir => {}
+                Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+        (!parts.is_empty()).then(|| parts.join("/"))
+    };
+
+    let written: BTreeSet<String> = accesses
+        .iter()
+        .filter(|access| access.writes)
+        .filter_map(|access| in_workspace(&access.path))
+        .collect();
+    let read: BTreeSet<String> = accesses
+        .iter()
+        .filter(|access| !access.writes)
+        .filter_map(|access| in_workspace(&access.path))
+        .filter(|path| !written.contains(path) && !path.starts_with(".hex/"))
+        .filter(|path| root.join(path).is_file())
+        .collect();
+    read.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trace() {
+        let trace = r#"execve("/usr/bin/cc", ["cc", "-c", "main.c"], 0x7ffd /* 3 vars */) = 0
+openat(AT_FDCWD, "main.c", O_RDONLY|O_NOCTTY) = 3
+openat(AT_FDCWD, "include/missing.h", O_RDONLY|O_NOCTTY) = -1 ENOENT (No such file or directory)
+newfstatat(AT_FDCWD, "util.h", {st_mode=S_IFREG|0644, st_size=10, ...}, 0) = 0
+openat(AT_FDCWD, "out/main.o", O_RDWR|O_CREAT|O_TRUNC, 0666) = 4
+openat(AT_FDCWD, "src", O_RDONLY|O_NONBLOCK|O_CLOEXEC|O_DIRECTORY) = 5
+unlink("out/old.o")                     = 0
++++ exited with 0 +++"#;
+        assert_eq!(
+            parse_trace(trace),
+            vec![
+                FileAccess {
+                    path: "/usr/bin/cc".into(),
+                    writes: false
+                },
+                FileAccess {
+                    path: "main.c".into(),
+                    writes: false
+                },
+                FileAccess {
+                    path: "util.h".into(),
+                    writes: false
+                },
+                FileAccess {
+                    path: "out/main.o".into(),
+                    writes: true
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_suggested_inputs() {
+        let root = env::current_dir().unwrap().join(".hex/test/trace_fs");
+        let _ = std::fs::remove_dir_all(&root);
+        for path in ["main.c", "include/util.h", "out/main.o", ".hex/status.json"] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let access = |path: &str, writes: bool| FileAccess {
+            path: path.to_string(),
+            writes,
+        };
+        let accesses = [
+            access("/usr/include/stdio.h", false),
+            access("main.c", false),
+            access(&root.join("include/util.h").to_string_lossy(), false),
+            access("./include/../main.c", false),
+            access("out/main.o", true),
+            access("out/main.o", false),
+            access(".hex/status.json", false),
+            access("include", false),
+            access("deleted.tmp", false),
+        ];
+        assert_eq!(
+            suggested_inputs(&accesses, &root),
+            vec!["include/util.h", "main.c"]
+        );
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use crate::ast::hex_path::HexPath;
+use crate::ast::hexmake_file::{HexRule, RuleName};
+use crate::cache::build_hash::{BuildHash, HashBreakdown};
+use crate::environment::BuildEnvironment;
+use crate::error::Error;
+use crate::exec::build_log::{BUILD_LOG_DIR, HASH_BREAKDOWNS_FILE, latest_build_with};
+use crate::file_system::vfs::VirtualFileSystem;
+use crate::graph::planner::BuildPlan;
+
+/// Print why the rules in a plan would not get the same hashes that they
+/// had in an earlier build, which is the most recent build if `against`
+/// is not given
+pub fn print_why_changed(
+    plan: &BuildPlan,
+    env: &BuildEnvironment,
+    vfs: &dyn VirtualFileSystem,
+    against: Option<&str>,
+) -> Result<(), Error> {
+    let build_id = match against {
+        Some(build_id) => build_id.to_string(),
+        None => latest_build_with(BUILD_LOG_DIR, HASH_BREAKDOWNS_FILE)?
+            .ok_or_else(|| "No build has recorded its hashes yet. Run a build first".to_string())?,
+    };
+    let path = format!("{BUILD_LOG_DIR}/{build_id}/{HASH_BREAKDOWNS_FILE}");
+    let previous: BTreeMap<String, HashBreakdown> = match fs_err::read_to_string(&path) {
+        Ok(json) => serde_json::from_str(&json)
+            .map_err(|error| format!("Could not parse {path}: {error}"))?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Err(format!("There is no record of the hashes of build `{build_id}`").into());
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let changes = explain_changes(plan, &current_breakdowns(plan, env, vfs)?, &previous);
+    if changes.is_empty() {
+        println!("Nothing has changed since build {build_id}");
+    } else {
+        println!("Changes since build {build_id}:");
+        for change in changes {
+            println!("{change}");
+        }
+    }
+    Ok(())
+}
+
+/// The breakdown of the hash that each rule in a plan would have if it
+/// were built now. Inputs that are not in the workspace are left out.
+fn current_breakdowns(
+    plan: &BuildPlan,
+    env: &BuildEnvironment,
+    vfs: &dyn VirtualFileSystem,
+) -> Result<BTreeMap<RuleName, HashBreakdown>, io::Error> {
+    let mut result = BTreeMap::new();
+    for (rule_name, task) in &plan.tasks {
+        let rule = &task.lock().unwrap().rule;
+        let mut inputs = BTreeMap::new();
+        for input in &rule.inputs {
+            if vfs.exists(input)? {
+                let hash = BuildHash::hash_tree(&input, vfs)?;
+                inputs.insert(input.to_string(), hash.0);
+            }
+        }
+        result.insert(rule_name.clone(), HashBreakdown::new(env, rule, inputs));
+    }
+    Ok(result)
+}
+
+/// Describe each difference between the current hash breakdowns of the
+/// rules in a plan and their breakdowns from an earlier build, as a line
+/// starting with the rule's name. Rules are listed in the order they
+/// would run, so the first rule listed is where the changes start. An
+/// input that a changed rule builds is reported as coming from that rule,
+/// because its new contents are not known until the rule runs again.
+fn explain_changes(
+    plan: &BuildPlan,
+    current: &BTreeMap<RuleName, HashBreakdown>,
+    previous: &BTreeMap<String, HashBreakdown>,
+) -> Vec<String> {
+    let mut changed_rules: BTreeSet<RuleName> = BTreeSet::new();
+    let mut result = Vec::new();
+    for rule_name in plan.topological_order() {
+        let task = plan.tasks[&rule_name].lock().unwrap();
+        let Some(old) = previous.get(rule_name.name.as_str()) else {
+            result.push(format!("[{rule_name}] Was not hashed by that build"));
+            changed_rules.insert(rule_name);
+            continue;
+        };
+        let new = &current[&rule_name];
+
+        let mut reasons = describe_list_changes("Command", &old.commands, &new.commands);
+        if old.outputs != new.outputs {
+            reasons.push(format!(
+                "Outputs changed from {} to {}",
+                quote_list(&old.outputs),
+                quote_list(&new.outputs)
+            ));
+        }
+        reasons.extend(describe_map_changes("Tool", &old.tools, &new.tools));
+        reasons.extend(describe_map_changes("Variable", &old.env, &new.env));
+        for name in old
+            .secrets
+            .iter()
+            .filter(|name| !new.secrets.contains(name))
+        {
+            reasons.push(format!("Secret `{name}` is no longer set"));
+        }
+        for name in new
+            .secrets
+            .iter()
+            .filter(|name| !old.secrets.contains(name))
+        {
+            reasons.push(format!("Secret `{name}` is now set"));
+        }
+
+        for input in &task.rule.inputs {
+            let name = input.to_string();
+            let Some(old_hash) = old.inputs.get(&name) else {
+                reasons.push(format!("Input `{input}` was added"));
+                continue;
+            };
+            let producer = task.depends_on.iter().find_map(|dependency| {
+                let dependency = dependency.lock().unwrap();
+                builds_input(&dependency.rule, input).then(|| dependency.rule_name())
+            });
+            match (producer, new.inputs.get(&name)) {
+                (Some(producer), _) if changed_rules.contains(&producer) => {
+                    reasons.push(format!(
+                        "Input `{input}` comes from `{producer}`, which changed"
+                    ));
+                }
+                (_, None) => reasons.push(format!("Input `{input}` is not in the workspace")),
+                (_, Some(new_hash)) if new_hash != old_hash => {
+                    reasons.push(format!("Input `{input}` changed"));
+                }
+                _ => {}
+            }
+        }
+        for input in old.inputs.keys() {
+            if !task
+                .rule
+                .inputs
+                .iter()
+                .any(|path| path.to_string() == *input)
+            {
+                reasons.push(format!("Input `{input}` was removed"));
+            }
+        }
+
+        if reasons.is_empty() && old.definition != new.definition {
+            reasons.push("Other settings of the rule changed".to_string());
+        }
+        if !reasons.is_empty() {
+            result.extend(
+                reasons
+                    .iter()
+                    .map(|reason| format!("[{rule_name}] {reason}")),
+            );
+            changed_rules.insert(rule_name.clone());
+        }
+    }
+    result
+}
+
+/// Whether one of a rule's outputs is the given input, or is inside it,
+/// or holds it
+fn builds_input(rule: &HexRule, input: &HexPath) -> bool {
+    rule.outputs.iter().any(|output| {
+        output == input
+            || output.starts_with(&format!("{input}/"))
+            || input.starts_with(&format!("{output}/"))
+    })
+}
+
+/// Describe the differences between two lists, such as of commands, that
+/// are compared by position
+fn describe_list_changes(kind: &str, old: &[String], new: &[String]) -> Vec<String> {
+    let mut result = Vec::new();
+    for index in 0..old.len().max(new.len()) {
+        match (old.get(index), new.get(index)) {
+            (Some(old), Some(new)) if old != new => result.push(format!(
+                "{kind} {} changed from `{old}` to `{new}`",
+                index + 1
+            )),
+            (Some(old), None) => result.push(format!("{kind} `{old}` was removed")),
+            (None, Some(new)) => result.push(format!("{kind} `{new}` was added")),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Describe the differences between two maps, such as of variables to
+/// their values
+fn describe_map_changes(
+    kind: &str,
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut result = Vec::new();
+    for (name, old_value) in old {
+        match new.get(name) {
+            Some(new_value) if new_value != old_value => result.push(format!(
+                "{kind} `{name}` changed from `{old_value}` to `{new_value}`"
+            )),
+            Some(_) => {}
+            None => result.push(format!(
+                "{kind} `{name}` was `{old_value}` and is now unset"
+            )),
+        }
+    }
+    for (name, new_value) in new {
+        if !old.contains_key(name) {
+            result.push(format!(
+                "{kind} `{name}` was unset and is now `{new_value}`"
+            ));
+        }
+    }
+    result
+}
+
+fn quote_list(items: &[String]) -> String {
+    let quoted: Vec<String> = items.iter().map(|item| format!("`{item}`")).collect();
+    quoted.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::ast::hexmake_file::HexmakeFile;
+    use crate::file_system::fake::FakeFileSystem;
+    use crate::graph::planner::plan_build;
+
+    #[test]
+    fn test_explain_changes() {
+        let rule = |name: &str, inputs: &[&str], output: &str, command: &str| -> Arc<HexRule> {
+            HexRule {
+                name: name.to_string().into(),
+                inputs: inputs
+                    .iter()
+                    .map(|i| HexPath::try_from(*i).unwrap())
+                    .collect(),
+                outputs: vec![HexPath::try_from(output).unwrap()],
+                commands: vec![command.into()],
+                ..Default::default()
+            }
+            .into()
+        };
+        let hexmake_file = HexmakeFile {
+            rules: vec![
+                rule(
+                    "compile",
+                    &["main.c", "util.h"],
+                    "out/main.o",
+                    "cc -c main.c",
+                ),
+                rule(
+                    "link",
+                    &["out/main.o"],
+                    "out/main",
+                    "cc -o out/main out/main.o",
+                ),
+                rule(
+                    "docs",
+                    &["README.md"],
+                    "out/README.html",
+                    "markdown README.md",
+                ),
+            ],
+            ..Default::default()
+        };
+        let plan = plan_build(
+            &hexmake_file,
+            &vec![Arc::new("link".into()), Arc::new("docs".into())],
+        )
+        .unwrap();
+
+        let vfs = FakeFileSystem::default();
+        for path in ["main.c", "util.h", "README.md", "out/main.o"] {
+            vfs.write(&HexPath::try_from(path).unwrap(), path.as_bytes())
+                .unwrap();
+        }
+        let mut env = BuildEnvironment::default();
+        env.vars
+            .insert(Arc::new("CC".into()), Arc::new("gcc".into()));
+        let previous: BTreeMap<String, HashBreakdown> = current_breakdowns(&plan, &env, &vfs)
+            .unwrap()
+            .into_iter()
+            .map(|(name, breakdown)| (name.to_string(), breakdown))
+            .collect();
+
+        // Nothing has changed yet
+        let current = current_breakdowns(&plan, &env, &vfs).unwrap();
+        assert_eq!(
+            explain_changes(&plan, &current, &previous),
+            Vec::<String>::new()
+        );
+
+        // A changed source file and variable change the rules that use
+        // them, and the rules that use those rules' outputs
+        vfs.write(&HexPath::try_from("util.h").unwrap(), b"changed")
+            .unwrap();
+        env.vars
+            .insert(Arc::new("CC".into()), Arc::new("clang".into()));
+        let current = current_breakdowns(&plan, &env, &vfs).unwrap();
+        assert_eq!(
+            explain_changes(&plan, &current, &previous),
+            vec![
+                "[compile] Variable `CC` changed from `gcc` to `clang`",
+                "[compile] Input `util.h` changed",
+                "[docs] Variable `CC` changed from `gcc` to `clang`",
+                "[link] Variable `CC` changed from `gcc` to `clang`",
+                "[link] Input `out/main.o` comes from `compile`, which changed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_describe_changes() {
+        let strings =
+            |items: &[&str]| -> Vec<String> { items.iter().map(|s| s.to_string()).collect() };
+        assert_eq!(
+            describe_list_changes(
+                "Command",
+                &strings(&["cc -O2 -c main.c", "strip main.o"]),
+                &strings(&["cc -O3 -c main.c"])
+            ),
+            vec![
+                "Command 1 changed from `cc -O2 -c main.c` to `cc -O3 -c main.c`",
+                "Command `strip main.o` was removed",
+            ]
+        );
+
+        let map = |items: &[(&str, &str)]| -> BTreeMap<String, String> {
+            items
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            describe_map_changes(
+                "Variable",
+                &map(&[("CC", "gcc"), ("LANG", "C")]),
+                &map(&[("CC", "gcc"), ("CFLAGS", "-g")])
+            ),
+            vec![
+                "Variable `LANG` was `C` and is now unset",
+                "Variable `CFLAGS` was unset and is now `-g`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::ast::hexmake_file::HexmakeFile;
+use crate::error::Error;
+use crate::graph::label::Label;
+use crate::graph::planner::BuildPlan;
+use crate::workspace::{HEXMAKE_FILES, hexmake_file_in};
+
+/// Outputs that have to be built in sub-workspaces before the main build
+/// can start. A sub-workspace is a directory with its own Hexmake file and
+/// its own `out/`, which the main Hexmake file lists in `subworkspaces`.
+#[derive(Debug, Default, PartialEq)]
+pub struct SubworkspaceBuilds {
+    /// The outputs to build in each sub-workspace, relative to it
+    pub targets: BTreeMap<Arc<String>, BTreeSet<String>>,
+}
+
+impl SubworkspaceBuilds {
+    /// Add the outputs of sub-workspaces that the tasks in a plan read
+    pub fn add_plan(&mut self, hexmake_file: &HexmakeFile, plan: &BuildPlan) {
+        for task in plan.tasks.values() {
+            let task = task.lock().unwrap();
+            for path in task.rule.inputs.iter().chain(&task.rule.runfiles) {
+                self.add(hexmake_file, path);
+            }
+        }
+    }
+
+    /// Remove the targets that are outputs of sub-workspaces, or labels of
+    /// rules in them, from a list of targets, and build them in their
+    /// sub-workspaces instead
+    pub fn take_targets(&mut self, hexmake_file: &HexmakeFile, targets: &mut Vec<Arc<String>>) {
+        targets.retain(|target| {
+            !self.add_label(hexmake_file, target) && !self.add(hexmake_file, target)
+        });
+    }
+
+    /// If a target is a label in a sub-workspace, add it to the builds,
+    /// relative to that sub-workspace. Return whether it was added.
+    fn add_label(&mut self, hexmake_file: &HexmakeFile, target: &str) -> bool {
+        let Ok(Some(label)) = Label::parse(target) else {
+            return false;
+        };
+        for dir in &hexmake_file.subworkspaces {
+            let target = if label.package == dir.as_str() {
+                label.name.clone()
+            } else if let Some(package) = label.package.strip_prefix(&format!("{dir}/")) {
+                format!("//{package}:{}", label.name)
+            } else {
+                continue;
+            };
+            self.targets.entry(dir.clone()).or_default().insert(target);
+            return true;
+        }
+        false
+    }
+
+    /// If a path is an output of a sub-workspace, add it to the builds.
+    /// Return whether it was added.
+    fn add(&mut self, hexmake_file: &HexmakeFile, path: &str) -> bool {
+        for dir in &hexmake_file.subworkspaces {
+            if let Some(output) = path.strip_prefix(&format!("{dir}/"))
+                && output.starts_with("out/")
+            {
+                self.targets
+                    .entry(dir.clone())
+                    .or_default()
+                    .insert(output.to_string());
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Run Hexmake in each sub-workspace to build the outputs needed from it
+    pub fn run(&self) -> Result<(), Error> {
+        for (dir, targets) in &self.targets {
+            println!("[{dir}] Building sub-workspace");
+            let status = Command::new(env::current_exe()?)
+                .args(targets)
+                .current_dir(dir.as_str())
+                .status()?;
+            // A build that fails in the sub-workspace fails this one too,
+            // and anything else is an error in running it
+            let message = format!("Sub-workspace `{dir}` failed to build");
+            match status.code() {
+                Some(0) => {}
+                Some(1) => return Err(Error::Build(message)),
+                _ => return Err(Error::Hexmake(message)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load the Hexmake file of each sub-workspace, and of theirs in turn.
+/// Each is returned with its package, which is its directory relative
+/// to the workspace root.
+pub fn load_subworkspaces(
+    hexmake_file: &HexmakeFile,
+) -> Result<Vec<(String, HexmakeFile)>, String> {
+    let mut result = Vec::new();
+    load_subworkspaces_under("", hexmake_file, &mut result)?;
+    Ok(result)
+}
+
+fn load_subworkspaces_under(
+    package: &str,
+    hexmake_file: &HexmakeFile,
+    result: &mut Vec<(String, HexmakeFile)>,
+) -> Result<(), String> {
+    for dir in &hexmake_file.subworkspaces {
+        let package = match package {
+            "" => dir.to_string(),
+            _ => format!("{package}/{dir}"),
+        };
+        let path = hexmake_file_in(Path::new(&package))
+            .unwrap_or_else(|| Path::new(&package).join(HEXMAKE_FILES[0]));
+        let sub_file = HexmakeFile::load(&path)
+            .map_err(|error| format!("In sub-workspace `{package}`: {error}"))?;
+        load_subworkspaces_under(&package, &sub_file, result)?;
+        result.push((package, sub_file));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::planner::plan_build;
+
+    #[test]
+    fn test_subworkspace_builds() {
+        let hexmake_file: HexmakeFile = serde_json::from_str(
+            r#"{
+                "subworkspaces": ["vendor/zlib"],
+                "rules": [
+                    {
+                        "name": "main",
+                        "inputs": ["main.c", "vendor/zlib/out/libz.a", "vendor/zlib/zlib.h"],
+                        "outputs": ["out/main"],
+                        "commands": ["cc -o out/main main.c vendor/zlib/out/libz.a"]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let mut targets = vec![
+            Arc::new("main".to_string()),
+            Arc::new("vendor/zlib/out/zlib.pc".to_string()),
+            Arc::new("//vendor/zlib:minizip".to_string()),
+            Arc::new("//vendor/zlib/contrib:puff".to_string()),
+        ];
+        let mut builds = SubworkspaceBuilds::default();
+        builds.take_targets(&hexmake_file, &mut targets);
+        assert_eq!(targets, vec![Arc::new("main".to_string())]);
+
+        let plan = plan_build(&hexmake_file, &targets).unwrap();
+        builds.add_plan(&hexmake_file, &plan);
+        assert_eq!(
+            builds.targets,
+            BTreeMap::from([(
+                Arc::new("vendor/zlib".to_string()),
+                BTreeSet::from([
+                    "//contrib:puff".to_string(),
+                    "minizip".to_string(),
+                    "out/libz.a".to_string(),
+                    "out/zlib.pc".to_string()
+                ])
+            )])
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use fs_err::{create_dir_all, read, rename, set_permissions, write};
+use serde::Serialize;
+
+use crate::ast::hexmake_file::Toolchain;
+use crate::cache::build_hash::sha256_hex;
+use crate::download::download;
+use crate::error::Error;
+
+/// The directory that downloaded tools are stored in
+const TOOLS_DIR: &str = ".hex/tools";
+
+/// A tool from the `toolchains` section, ready for commands to use
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ResolvedTool {
+    /// The absolute path of the tool's executable
+    pub path: String,
+
+    /// The SHA-256 hash of the executable, in lowercase hex
+    pub sha256: String,
+}
+
+/// Work out where each tool in the `toolchains` section lives. Tools on
+/// the local machine are verified against their expected hash right
+/// away. Tools that are downloaded are given a path under `.hex/tools`,
+/// but are not fetched until `fetch_tools` is called.
+pub fn resolve_toolchains(
+    toolchains: &BTreeMap<Arc<String>, Toolchain>,
+) -> Result<BTreeMap<Arc<String>, ResolvedTool>, Error> {
+    let current_dir = env::current_dir()?;
+
+    let mut result = BTreeMap::new();
+    for (name, toolchain) in toolchains {
+        let sha256 = toolchain.sha256.to_ascii_lowercase();
+        let path = match &toolchain.path {
+            Some(path) => {
+                let path = current_dir.join(path);
+                verify_tool(name, &path, &read(&path)?, &sha256)?;
+                path
+            }
+            None => current_dir.join(download_path(name, &sha256)),
+        };
+
+        result.insert(
+            name.clone(),
+            ResolvedTool {
+                path: path.to_string_lossy().to_string(),
+                sha256,
+            },
+        );
+    }
+    Ok(result)
+}
+
+/// Download every tool in the `toolchains` section that has a URL and
+/// has not already been downloaded
+pub fn fetch_tools(toolchains: &BTreeMap<Arc<String>, Toolchain>) -> Result<(), Error> {
+    for (name, toolchain) in toolchains {
+        let Some(url) = &toolchain.url else {
+            continue;
+        };
+        let sha256 = toolchain.sha256.to_ascii_lowercase();
+        let path = download_path(name, &sha256);
+        if Path::new(&path).exists() {
+            continue;
+        }
+
+        println!("[{name}] Downloading {url}");
+        let contents = download(url)?;
+        verify_tool(name, Path::new(url), &contents, &sha256)?;
+
+        // Write to a temporary file first, so that an interrupted
+        // download is never mistaken for a complete one
+        create_dir_all(Path::new(&path).parent().unwrap())?;
+        let temp_path = format!("{path}.tmp");
+        write(&temp_path, &contents)?;
+        set_permissions(&temp_path, PermissionsExt::from_mode(0o755))?;
+        rename(&temp_path, &path)?;
+    }
+    Ok(())
+}
+
+/// The place that a downloaded tool is stored, relative to the workspace
+fn download_path(name: &str, sha256: &str) -> String {
+    format!("{TOOLS_DIR}/{sha256}/{name}")
+}
+
+/// Check that the contents of a tool have the hash that the Hexmake file expects
+fn verify_tool(name: &str, location: &Path, contents: &[u8], expected: &str) -> Result<(), Error> {
+    let actual = sha256_hex(contents);
+    if actual != expected {
+        return Err(Error::Hexmake(format!(
+            "Tool `{name}` at {} has SHA-256 {actual}, but the Hexmake file expects {expected}",
+            location.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_toolchains() {
+        let test_dir = ".hex/test/resolve_toolchains";
+        let _ = std::fs::remove_dir_all(test_dir);
+        std::fs::create_dir_all(test_dir).unwrap();
+        std::fs::write(format!("{test_dir}/tool"), b"hello").unwrap();
+
+        let hello_hash = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824";
+        let toolchain = |path: &str, sha256: &str| Toolchain {
+            path: Some(path.to_string()),
+            url: None,
+            sha256: sha256.to_string(),
+        };
+
+        let toolchains = BTreeMap::from([(
+            Arc::new("tool".to_string()),
+            toolchain(&format!("{test_dir}/tool"), hello_hash),
+        )]);
+        let tools = resolve_toolchains(&toolchains).unwrap();
+        let tool = &tools[&"tool".to_string()];
+        assert!(Path::new(&tool.path).is_absolute());
+        assert!(tool.path.ends_with("resolve_toolchains/tool"));
+        assert_eq!(tool.sha256, hello_hash.to_ascii_lowercase());
+
+        // A tool that has changed since the Hexmake file was written is an error
+        let toolchains = BTreeMap::from([(
+            Arc::new("tool".to_string()),
+            toolchain(&format!("{test_dir}/tool"), "1234"),
+        )]);
+        let error = resolve_toolchains(&toolchains).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Tool `tool` at "),
+            "unexpected error: {error}"
+        );
+        assert!(
+            error.ends_with(&format!(
+                "has SHA-256 {}, but the Hexmake file expects 1234",
+                hello_hash.to_ascii_lowercase()
+            )),
+            "unexpected error: {error}"
+        );
+
+        // Downloaded tools are given a path without being fetched
+        let toolchains = BTreeMap::from([(
+            Arc::new("remote".to_string()),
+            Toolchain {
+                path: None,
+                url: Some("https://example.com/remote".to_string()),
+                sha256: "abcd".to_string(),
+            },
+        )]);
+        let tools = resolve_toolchains(&toolchains).unwrap();
+        assert!(
+            tools[&"remote".to_string()]
+                .path
+                .ends_with(".hex/tools/abcd/remote")
+        );
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+}
@@ -0,0 +1,203 @@
+use std::env;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The names that the file that marks the root of a workspace can have,
+/// in the order they are looked for. The JSON names suit editors that pick
+/// a mode by extension, and the lowercase one avoids surprises on file
+/// systems that ignore case.
+pub const HEXMAKE_FILES: [&str; 3] = ["Hexmake", "Hexmake.json", "hexmake.json"];
+
+/// What `--file` is given to read the Hexmake file from standard input
+pub const STDIN_FILE: &str = "-";
+
+/// The Hexmake file to build from: the one given with `--file`, or else
+/// the one in the current directory
+pub fn hexmake_file_path(file: Option<&Path>) -> PathBuf {
+    match file {
+        Some(file) => file.to_path_buf(),
+        None => hexmake_file_in(Path::new("")).unwrap_or_else(|| HEXMAKE_FILES[0].into()),
+    }
+}
+
+/// Read the source of a Hexmake file, from standard input if the path is
+/// [STDIN_FILE]
+pub fn read_hexmake_source(path: &Path) -> Result<String, io::Error> {
+    if path == Path::new(STDIN_FILE) {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        return Ok(source);
+    }
+    fs_err::read_to_string(path)
+}
+
+/// The Hexmake file in a directory, by the first of [HEXMAKE_FILES] that
+/// exists there
+pub fn hexmake_file_in(dir: &Path) -> Option<PathBuf> {
+    HEXMAKE_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// The directory tree that a Hexmake file builds, and where Hexmake was
+/// started from within it
+#[derive(Debug, PartialEq)]
+pub struct Workspace {
+    /// The directory that holds the Hexmake file
+    pub root: PathBuf,
+
+    /// The directory Hexmake was started from, relative to the root, with
+    /// components separated by slashes. This is empty at the root.
+    pub prefix: String,
+}
+
+impl Workspace {
+    /// Find the workspace that contains the current directory, by looking
+    /// for a Hexmake file in it and then in each of its parents
+    pub fn find() -> Result<Option<Workspace>, io::Error> {
+        Ok(Workspace::find_from(&env::current_dir()?))
+    }
+
+    /// Find the workspace that contains the given absolute directory
+    fn find_from(start: &Path) -> Option<Workspace> {
+        let mut components = Vec::new();
+        let mut dir = start;
+        loop {
+            if hexmake_file_in(dir).is_some() {
+                components.reverse();
+                return Some(Workspace {
+                    root: dir.to_path_buf(),
+                    prefix: components.join("/"),
+                });
+            }
+            components.push(dir.file_name()?.to_string_lossy().to_string());
+            dir = dir.parent()?;
+        }
+    }
+
+    /// Change the current directory to the root of the workspace, so
+    /// that paths in the Hexmake file can be used as they are
+    pub fn enter(&self) -> Result<(), io::Error> {
+        env::set_current_dir(&self.root)
+    }
+
+    /// Convert a path relative to the directory Hexmake was started from
+    /// into one relative to the workspace root
+    pub fn translate_path(&self, path: &str) -> Result<String, String> {
+        let mut components: Vec<&str> = self
+            .prefix
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .collect();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    if components.pop().is_none() {
+                        return Err(format!("Path `{path}` is outside of the workspace"));
+                    }
+                }
+                _ => components.push(component),
+            }
+        }
+        Ok(components.join("/"))
+    }
+
+    /// Convert a target given on the command line so that it works from
+    /// the workspace root. Targets that start with `./` or `../` are paths
+    /// relative to the directory Hexmake was started from. Anything else,
+    /// such as a rule name or an `out/` path, is already relative to the
+    /// root and is left alone.
+    pub fn translate_target(&self, target: &Arc<String>) -> Result<Arc<String>, String> {
+        let is_relative = target.starts_with("./")
+            || target.starts_with("../")
+            || matches!(target.as_str(), "." | "..");
+        if is_relative {
+            Ok(Arc::new(self.translate_path(target)?))
+        } else {
+            Ok(target.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_from() {
+        let test_dir = env::current_dir().unwrap().join(".hex/test/find_workspace");
+        let _ = std::fs::remove_dir_all(&test_dir);
+        std::fs::create_dir_all(test_dir.join("src/lib")).unwrap();
+        std::fs::write(test_dir.join("hexmake.json"), "{}").unwrap();
+
+        assert_eq!(
+            Workspace::find_from(&test_dir.join("src/lib")),
+            Some(Workspace {
+                root: test_dir.clone(),
+                prefix: "src/lib".to_string()
+            })
+        );
+        assert_eq!(
+            Workspace::find_from(&test_dir),
+            Some(Workspace {
+                root: test_dir.clone(),
+                prefix: String::new()
+            })
+        );
+
+        let _ = std::fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_hexmake_file_in() {
+        let test_dir = Path::new(".hex/test/hexmake_file_in");
+        let _ = std::fs::remove_dir_all(test_dir);
+        std::fs::create_dir_all(test_dir).unwrap();
+        assert_eq!(hexmake_file_in(test_dir), None);
+
+        std::fs::write(test_dir.join("hexmake.json"), "{}").unwrap();
+        assert_eq!(
+            hexmake_file_in(test_dir),
+            Some(test_dir.join("hexmake.json"))
+        );
+
+        // The plain name comes first
+        std::fs::write(test_dir.join("Hexmake"), "{}").unwrap();
+        assert_eq!(hexmake_file_in(test_dir), Some(test_dir.join("Hexmake")));
+
+        let _ = std::fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_translate() {
+        let workspace = Workspace {
+            root: PathBuf::from("/work"),
+            prefix: "src/lib".to_string(),
+        };
+        let translate = |target: &str| workspace.translate_target(&Arc::new(target.to_string()));
+
+        assert_eq!(translate("main"), Ok(Arc::new("main".to_string())));
+        assert_eq!(translate("out/main"), Ok(Arc::new("out/main".to_string())));
+        assert_eq!(
+            translate("./util.c"),
+            Ok(Arc::new("src/lib/util.c".to_string()))
+        );
+        assert_eq!(
+            translate("../../out/main"),
+            Ok(Arc::new("out/main".to_string()))
+        );
+        assert_eq!(translate(".."), Ok(Arc::new("src".to_string())));
+        assert_eq!(
+            translate("../../../etc"),
+            Err("Path `../../../etc` is outside of the workspace".to_string())
+        );
+
+        assert_eq!(
+            workspace.translate_path("util.c"),
+            Ok("src/lib/util.c".to_string())
+        );
+    }
+}
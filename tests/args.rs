@@ -78,23 +78,162 @@ when possible.
 
 
 Usage: hexmake [OPTIONS] [TARGETS]...
+       hexmake <COMMAND>
+
+Commands:
+  add-rule     Add a rule to the end of the Hexmake file
+  bench        Time repeated cold-cache, warm-cache, and no-op builds of a target
+  cache        Manage the build cache
+  clean        Remove the outputs of the given targets and everything they depend on
+  completions  Print a script that makes a shell complete Hexmake's subcommands, options, and targets
+  doctor       Check the Hexmake file, the cache, the shell, and the file system for anything that would break or slow down builds, and say how to fix it
+  env          Print what builds would see of this machine, as JSON: the variables passed through to commands, the build parameters, the toolchains, and the Hexmake version and OS
+  fmt          Rewrite the Hexmake file with sorted keys, rules, and inputs
+  install      Build a target and copy its outputs into a prefix directory
+  lock         Record the hashes of all source files and downloads in Hexmake.lock
+  outputs      Print the output files of the given targets
+  plan         Print the tasks that would run to build the given targets, in order
+  query        Answer questions about the build graph
+  remove-rule  Remove a rule from the Hexmake file, if no other rule or alias uses it
+  set-inputs   Replace the inputs of a rule in the Hexmake file
+  set-outputs  Replace the outputs of a rule in the Hexmake file
+  why-changed  List what differs for a target and the rules it depends on, compared to when an earlier build hashed them
 
 Arguments:
   [TARGETS]...
           The rules or output files to build
 
 Options:
+  -f, --file <PATH>
+          Read the Hexmake file from PATH, or from standard input if PATH is `-`, instead of looking for it. Paths in it are relative to the current directory
+
+  -C, --directory <DIR>
+          Change to DIR before doing anything else, including looking for the Hexmake file
+
+      --all
+          Build every rule in the Hexmake file
+
+      --affected-by <PATH>...
+          Build every rule that is affected by changes to the given files
+
       --list-targets
           List available targets and exit
 
+      --long
+          With --list-targets, show each target's description, outputs, and tags
+
+      --json
+          With --list-targets, print the targets as JSON
+
+      --labels
+          With --list-targets, print a `//package:rule` label for every rule and alias, including those in sub-workspaces
+
+      --filter <GLOB>
+          With --list-targets, only list targets whose names match a glob
+
+      --print-commands
+          Print the commands that building the targets would run, then exit
+
+      --dry-run
+          Print each rule that building the targets involves, in order, with its commands and whether it is expected to come from the cache, then exit without building
+
+      --trace-fs <RULE>
+          Run a rule's commands in the workspace under strace, and print the files they read as a suggested `inputs` list
+
+      --tui
+          Show the build on a full-screen display with a row per worker, instead of printing a line for each step
+
+      --web-ui[=<PORT>]
+          Serve a page that shows the progress of the build, at http://127.0.0.1:PORT/ (8080 if no port is given)
+
+      --summary-file <PATH>
+          Write a summary of the build to a file when it finishes, as Markdown if the file name ends in .md and as JSON otherwise
+
+      --manifest
+          After a successful build, write out/MANIFEST.json listing every output with its hash, size, and the rule that produced it
+
+      --notify
+          Show a desktop notification when the build finishes
+
+      --notify-command <COMMAND>
+          Run a shell command when the build finishes, instead of showing a desktop notification
+
+  -q, --quiet
+          Print only errors, warnings, and a summary at the end of the build
+
+  -v, --verbose...
+          Print more about each rule. With -v, print the worker, cache key, CPU time, peak memory, and disk I/O of each rule, and with -vv, also the output of commands that is hidden once another one fails
+
+  -k, --keep-going
+          When a rule fails, keep building the rules that do not depend on it, and report every failure at the end
+
+  -j, --jobs <N>
+          How many tasks to run at once (one per CPU by default)
+
+  -l, --load-average <N>
+          Wait to start more tasks while the system load average is above N
+
+      --schedule <SCHEDULE>
+          Which ready task to start first when several have the same priority
+
+          Possible values:
+          - depth:         The task that became ready most recently, which tends to finish one target before starting on the next
+          - breadth:       The task that became ready first, which works through the graph a level at a time
+          - critical-path: The task with the longest chain of tasks waiting on it, which keeps the workers busy near the end of the build
+          
+          [default: depth]
+
+      --timeout <DURATION>
+          Cancel the build if it is still running after this long, such as 90s, 15m, or 2h
+
+      --env <KEY=VALUE>
+          Give a variable listed in the Hexmake file's `env` a value for this build, overriding the environment and the env file
+
+      --define <NAME=VALUE>
+          Give a build parameter listed in the Hexmake file's `defines` a value for this build
+
+      --force
+          Rebuild the given targets even if their outputs are in the cache
+
+      --force-all
+          Rebuild every rule even if its outputs are in the cache
+
+      --cache <CACHE>
+          How to use the build cache
+
+          Possible values:
+          - read-write: Use cached outputs when possible, and insert newly built outputs
+          - write-only: Ignore existing entries and build every rule, but insert the results
+          
+          [default: read-write]
+
+      --audit-cache[=<N%>]
+          Rebuild N% of the rules whose outputs come from the cache (10% if no N is given), and fail if any output comes out different from the cached one
+
+      --hardlink-outputs
+          Hard link newly built outputs to their copies in the cache
+
+      --output-times <OUTPUT_TIMES>
+          What modification time outputs get when they are copied into out/
+
+          Possible values:
+          - now:      The time the output was copied into `out/`
+          - preserve: The time the output's contents were written, either by the rule or, for an output from the cache, when the cache first stored them
+          - fixed:    The same fixed time for every output, from SOURCE_DATE_EPOCH or else the start of 1980
+          
+          [default: now]
+
+      --locked
+          Fail if any source file or download differs from Hexmake.lock
+
   -h, --help
           Print help (see a summary with '-h')
 
   -V, --version
           Print version
 
-The tool expects a Hexmake file to exist in the current directory.
-A Hexmake file looks like this:
+The tool expects a Hexmake file to exist in the current directory or one of
+its parents. A Hexmake file looks like this:
 
 ```json
 {
@@ -114,18 +253,81 @@ A Hexmake file looks like this:
   ]
 }
 ```
+
+Exit codes:
+  0  The command succeeded
+  1  The build failed: a rule failed, the build timed out, or the cache audit
+     found a difference
+  2  The command line is wrong
+  3  The Hexmake file could not be read or parsed
+  4  The Hexmake file does not make sense
+  5  The targets could not be planned
+  6  Hexmake itself failed, such as because a file could not be written
 "#;
 
 /// The current short help string the tool outputs
 const SHORT_HELP_STRING: &str = r#"Run a multi-step build with caching
 
 Usage: hexmake [OPTIONS] [TARGETS]...
+       hexmake <COMMAND>
+
+Commands:
+  add-rule     Add a rule to the end of the Hexmake file
+  bench        Time repeated cold-cache, warm-cache, and no-op builds of a target
+  cache        Manage the build cache
+  clean        Remove the outputs of the given targets and everything they depend on
+  completions  Print a script that makes a shell complete Hexmake's subcommands, options, and targets
+  doctor       Check the Hexmake file, the cache, the shell, and the file system for anything that would break or slow down builds, and say how to fix it
+  env          Print what builds would see of this machine, as JSON: the variables passed through to commands, the build parameters, the toolchains, and the Hexmake version and OS
+  fmt          Rewrite the Hexmake file with sorted keys, rules, and inputs
+  install      Build a target and copy its outputs into a prefix directory
+  lock         Record the hashes of all source files and downloads in Hexmake.lock
+  outputs      Print the output files of the given targets
+  plan         Print the tasks that would run to build the given targets, in order
+  query        Answer questions about the build graph
+  remove-rule  Remove a rule from the Hexmake file, if no other rule or alias uses it
+  set-inputs   Replace the inputs of a rule in the Hexmake file
+  set-outputs  Replace the outputs of a rule in the Hexmake file
+  why-changed  List what differs for a target and the rules it depends on, compared to when an earlier build hashed them
 
 Arguments:
   [TARGETS]...  The rules or output files to build
 
 Options:
-      --list-targets  List available targets and exit
-  -h, --help          Print help (see more with '--help')
-  -V, --version       Print version
+  -f, --file <PATH>                  Read the Hexmake file from PATH, or from standard input if PATH is `-`, instead of looking for it. Paths in it are relative to the current directory
+  -C, --directory <DIR>              Change to DIR before doing anything else, including looking for the Hexmake file
+      --all                          Build every rule in the Hexmake file
+      --affected-by <PATH>...        Build every rule that is affected by changes to the given files
+      --list-targets                 List available targets and exit
+      --long                         With --list-targets, show each target's description, outputs, and tags
+      --json                         With --list-targets, print the targets as JSON
+      --labels                       With --list-targets, print a `//package:rule` label for every rule and alias, including those in sub-workspaces
+      --filter <GLOB>                With --list-targets, only list targets whose names match a glob
+      --print-commands               Print the commands that building the targets would run, then exit
+      --dry-run                      Print each rule that building the targets involves, in order, with its commands and whether it is expected to come from the cache, then exit without building
+      --trace-fs <RULE>              Run a rule's commands in the workspace under strace, and print the files they read as a suggested `inputs` list
+      --tui                          Show the build on a full-screen display with a row per worker, instead of printing a line for each step
+      --web-ui[=<PORT>]              Serve a page that shows the progress of the build, at http://127.0.0.1:PORT/ (8080 if no port is given)
+      --summary-file <PATH>          Write a summary of the build to a file when it finishes, as Markdown if the file name ends in .md and as JSON otherwise
+      --manifest                     After a successful build, write out/MANIFEST.json listing every output with its hash, size, and the rule that produced it
+      --notify                       Show a desktop notification when the build finishes
+      --notify-command <COMMAND>     Run a shell command when the build finishes, instead of showing a desktop notification
+  -q, --quiet                        Print only errors, warnings, and a summary at the end of the build
+  -v, --verbose...                   Print more about each rule. With -v, print the worker, cache key, CPU time, peak memory, and disk I/O of each rule, and with -vv, also the output of commands that is hidden once another one fails
+  -k, --keep-going                   When a rule fails, keep building the rules that do not depend on it, and report every failure at the end
+  -j, --jobs <N>                     How many tasks to run at once (one per CPU by default)
+  -l, --load-average <N>             Wait to start more tasks while the system load average is above N
+      --schedule <SCHEDULE>          Which ready task to start first when several have the same priority [default: depth] [possible values: depth, breadth, critical-path]
+      --timeout <DURATION>           Cancel the build if it is still running after this long, such as 90s, 15m, or 2h
+      --env <KEY=VALUE>              Give a variable listed in the Hexmake file's `env` a value for this build, overriding the environment and the env file
+      --define <NAME=VALUE>          Give a build parameter listed in the Hexmake file's `defines` a value for this build
+      --force                        Rebuild the given targets even if their outputs are in the cache
+      --force-all                    Rebuild every rule even if its outputs are in the cache
+      --cache <CACHE>                How to use the build cache [default: read-write] [possible values: read-write, write-only]
+      --audit-cache[=<N%>]           Rebuild N% of the rules whose outputs come from the cache (10% if no N is given), and fail if any output comes out different from the cached one
+      --hardlink-outputs             Hard link newly built outputs to their copies in the cache
+      --output-times <OUTPUT_TIMES>  What modification time outputs get when they are copied into out/ [default: now] [possible values: now, preserve, fixed]
+      --locked                       Fail if any source file or download differs from Hexmake.lock
+  -h, --help                         Print help (see more with '--help')
+  -V, --version                      Print version
 "#;
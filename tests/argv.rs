@@ -0,0 +1,42 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::contains;
+
+#[test]
+fn test_argv_command() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/argv/out");
+    let _ = fs_err::remove_dir_all("integration-tests/argv/.hex");
+
+    // The arguments are passed as they are, spaces and quotes included,
+    // and the command is shown quoted for the shell
+    hexmake_command()
+        .in_test_dir()
+        .arg("copy")
+        .assert()
+        .success()
+        .stdout(contains(
+            r#"[copy] Running: cp 'input file.txt' 'out/it'\''s a copy.txt'"#,
+        ));
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/argv/out/it's a copy.txt").unwrap(),
+        "hello\n"
+    );
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this test
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/argv")
+    }
+}
@@ -0,0 +1,83 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::prelude::*;
+use predicates::str::contains;
+
+#[test]
+fn test_audit_cache() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/audit-cache/out");
+    let _ = fs_err::remove_dir_all("integration-tests/audit-cache/.hex");
+
+    hexmake_command()
+        .in_test_dir()
+        .args(["stable", "stamp"])
+        .assert()
+        .success();
+
+    // A rule whose outputs are the same every time passes the audit
+    hexmake_command()
+        .in_test_dir()
+        .args(["--audit-cache=100%", "stable"])
+        .assert()
+        .success()
+        .stdout(
+            contains("[stable] Retrieved outputs from cache")
+                .and(contains("[stable] Audit passed")),
+        );
+
+    // A rule that writes the time does not, and the build fails
+    let cached = fs_err::read_to_string("integration-tests/audit-cache/out/stamp.txt").unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .args(["--audit-cache=100%", "stamp"])
+        .assert()
+        .failure()
+        .stdout(
+            contains("[stamp] Warning: audit failed")
+                .and(contains("out/stamp.txt"))
+                .and(contains("The cache audit rebuilt `stamp`")),
+        );
+    let rebuilt = fs_err::read_to_string("integration-tests/audit-cache/out/stamp.txt").unwrap();
+    assert_ne!(rebuilt, cached);
+
+    hexmake_command()
+        .in_test_dir()
+        .arg("program")
+        .assert()
+        .success();
+
+    // Auditing the program needs the object file that it is built from,
+    // so the object file is retrieved from the cache first
+    let _ = fs_err::remove_dir_all("integration-tests/audit-cache/out");
+    hexmake_command()
+        .in_test_dir()
+        .args(["--audit-cache=100%", "program"])
+        .assert()
+        .success()
+        .stdout(
+            contains("[object] Retrieved outputs from cache")
+                .and(contains("[program] Audit passed")),
+        );
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/audit-cache/out/program.txt").unwrap(),
+        "int main;\n"
+    );
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this test
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/audit-cache")
+    }
+}
@@ -41,6 +41,16 @@ fn test_basics() {
         .assert()
         .success()
         .stdout("Sum: 7\n");
+
+    // Force a rebuild of the target; its dependencies still use the cache
+    hexmake_command()
+        .in_test_dir()
+        .arg("--force")
+        .arg("main")
+        .assert()
+        .success()
+        .stdout(is_match(".main. Running: cc -o out/main out/lib.o out/main.o").unwrap())
+        .stdout(is_match(".main.o. Retrieved outputs from cache").unwrap());
 }
 
 /// A command for running `hexmake`
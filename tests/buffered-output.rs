@@ -14,6 +14,7 @@ fn test_buffered_output_no_interleaving() {
     // Run hexmake to build all three rules in parallel.
     let result = hexmake_command()
         .in_test_dir()
+        .args(["-j", "3"])
         .arg("rule1")
         .arg("rule2")
         .arg("rule3")
@@ -0,0 +1,69 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::read_to_string;
+use fs_err::remove_dir_all;
+
+#[test]
+fn test_build_id() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/build-id/out");
+    let _ = remove_dir_all("integration-tests/build-id/.hex");
+
+    // A build ID from the environment is passed to commands
+    hexmake_command()
+        .in_test_dir()
+        .env("HEXMAKE_BUILD_ID", "ci-build-42")
+        .arg("env-output")
+        .assert()
+        .success();
+    let env_output = read_to_string("integration-tests/build-id/out/env.txt").unwrap();
+    assert!(
+        env_output
+            .lines()
+            .any(|line| line == "HEXMAKE_BUILD_ID=ci-build-42"),
+        "expected HEXMAKE_BUILD_ID=ci-build-42 in env output, got:\n{env_output}"
+    );
+
+    // The build ID is also recorded in the status file
+    let status = read_to_string("integration-tests/build-id/.hex/status.json").unwrap();
+    assert!(
+        status.contains(r#""build_id": "ci-build-42""#),
+        "expected the build ID in the status file, got:\n{status}"
+    );
+
+    // Without one from the environment, each build gets a new random ID
+    let build_id = || {
+        hexmake_command()
+            .in_test_dir()
+            .env_remove("HEXMAKE_BUILD_ID")
+            .args(["--force", "env-output"])
+            .assert()
+            .success();
+        let env_output = read_to_string("integration-tests/build-id/out/env.txt").unwrap();
+        env_output
+            .lines()
+            .find_map(|line| line.strip_prefix("HEXMAKE_BUILD_ID="))
+            .unwrap()
+            .to_string()
+    };
+    let first = build_id();
+    assert_eq!(first.len(), 16);
+    assert!(first.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(first, build_id());
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/build-id")
+    }
+}
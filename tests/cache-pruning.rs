@@ -0,0 +1,62 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::prelude::*;
+use predicates::str::contains;
+
+#[test]
+fn test_cache_pruning() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/cache-pruning/out");
+    let _ = fs_err::remove_dir_all("integration-tests/cache-pruning/.hex");
+
+    hexmake_command()
+        .in_test_dir()
+        .arg("greeting")
+        .assert()
+        .success()
+        .stdout(contains("[upper] Running:").and(contains("[greeting] Running:")));
+
+    // Once the final output is cached, the rule it depends on is neither
+    // run nor retrieved
+    fs_err::remove_dir_all("integration-tests/cache-pruning/out").unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .arg("greeting")
+        .assert()
+        .success()
+        .stdout(contains("[greeting] Retrieved outputs from cache").and(contains("[upper]").not()));
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/cache-pruning/out/greeting.txt").unwrap(),
+        "HELLO\nHELLO\n"
+    );
+    assert!(!fs_err::exists("integration-tests/cache-pruning/out/upper.txt").unwrap());
+
+    // Asking for it by name still retrieves it
+    hexmake_command()
+        .in_test_dir()
+        .args(["upper", "greeting"])
+        .assert()
+        .success()
+        .stdout(contains("[upper] Retrieved outputs from cache"));
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/cache-pruning/out/upper.txt").unwrap(),
+        "HELLO\n"
+    );
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this test
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/cache-pruning")
+    }
+}
@@ -0,0 +1,145 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::prelude::*;
+use predicates::str::is_match;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::mpsc;
+use std::thread;
+
+#[test]
+fn test_cache_share() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/cache-share/out");
+    let _ = fs_err::remove_dir_all("integration-tests/cache-share/.hex");
+
+    // Build, and push the entries of that build to a file
+    hexmake_command()
+        .in_test_dir()
+        .arg("greeting")
+        .assert()
+        .success();
+    hexmake_command()
+        .in_test_dir()
+        .args(["cache", "push", "cache.tar.gz"])
+        .assert()
+        .success()
+        .stdout(is_match("Pushed 1 cache entries").unwrap());
+
+    // A fresh workspace can pull the archive and use its entries
+    fs_err::rename(
+        "integration-tests/cache-share/cache.tar.gz",
+        "integration-tests/cache-share/out/cache.tar.gz",
+    )
+    .unwrap();
+    fs_err::remove_dir_all("integration-tests/cache-share/.hex").unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .args(["cache", "pull", "out/cache.tar.gz"])
+        .assert()
+        .success()
+        .stdout(is_match("Added 2 files to the cache").unwrap());
+
+    // The pulled files have the permissions of the shared cache
+    let mode = |path: &str| {
+        let path = format!("integration-tests/cache-share/.hex/cache/{path}");
+        fs_err::metadata(path).unwrap().permissions().mode() & 0o777
+    };
+    assert_eq!(mode("outputs"), 0o770);
+    for entry in fs_err::read_dir("integration-tests/cache-share/.hex/cache/outputs").unwrap() {
+        let name = entry.unwrap().file_name();
+        assert_eq!(mode(&format!("outputs/{}", name.to_string_lossy())), 0o660);
+    }
+    fs_err::remove_file("integration-tests/cache-share/out/greeting.txt").unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .arg("greeting")
+        .assert()
+        .success()
+        .stdout(is_match(r"\[greeting\] Retrieved outputs from cache").unwrap());
+
+    // Pushing to a server sends the token named in the settings, without
+    // printing it
+    let (url, request) = receive_upload();
+    hexmake_command()
+        .in_test_dir()
+        .args(["cache", "push", &url])
+        .env("CACHE_SHARE_TOKEN", "secret-token")
+        .assert()
+        .success()
+        .stdout(
+            is_match("Pushed 1 cache entries")
+                .unwrap()
+                .and(predicate::str::contains("secret-token").not()),
+        )
+        .stderr(predicate::str::contains("secret-token").not());
+    let request = request.recv().unwrap().to_lowercase();
+    assert!(request.starts_with("put /cache.tar.gz "));
+    assert!(request.contains("\r\nauthorization: bearer secret-token\r\n"));
+
+    // A cache server that cannot be reached is retried, and then skipped
+    // without failing
+    hexmake_command()
+        .in_test_dir()
+        .args(["cache", "pull", "http://127.0.0.1:1/cache.tar.gz"])
+        .assert()
+        .success()
+        .stderr(
+            is_match("trying again in")
+                .unwrap()
+                .and(is_match("The build will not use the cache archive").unwrap()),
+        );
+}
+
+/// Start a server that accepts one upload, and return its URL and a
+/// channel that receives the request's line and headers
+fn receive_upload() -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/cache.tar.gz", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut head = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                length = value.trim().parse().unwrap();
+            }
+            head.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+        io::copy(&mut reader.take(length), &mut io::sink()).unwrap();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        sender.send(head).unwrap();
+    });
+    (url, receiver)
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/cache-share")
+    }
+}
@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+
+#[test]
+fn test_default_targets() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/default-targets/out");
+    let _ = fs_err::remove_dir_all("integration-tests/default-targets/.hex");
+
+    // With no arguments, only the default targets are built
+    hexmake_command().in_test_dir().assert().success();
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/default-targets/out/greeting.txt").unwrap(),
+        "hello\n"
+    );
+    assert!(!Path::new("integration-tests/default-targets/out/farewell.txt").exists());
+
+    // Naming a target builds that one instead
+    hexmake_command()
+        .in_test_dir()
+        .arg("farewell")
+        .assert()
+        .success();
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/default-targets/out/farewell.txt").unwrap(),
+        "goodbye\n"
+    );
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/default-targets")
+    }
+}
@@ -0,0 +1,59 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::read_to_string;
+use fs_err::remove_dir_all;
+use predicates::str::contains;
+
+#[test]
+fn test_define() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/define/out");
+    let _ = remove_dir_all("integration-tests/define/.hex");
+
+    // Without --define, the default value is used
+    hexmake_command()
+        .in_test_dir()
+        .arg("release")
+        .assert()
+        .success();
+    assert_eq!(
+        read_to_string("integration-tests/define/out/release-dev.txt").unwrap(),
+        "version dev\n"
+    );
+
+    // A value given on the command line reaches both the paths and the
+    // contents of the rules
+    hexmake_command()
+        .in_test_dir()
+        .args(["--define", "version=1.2", "release"])
+        .assert()
+        .success();
+    assert_eq!(
+        read_to_string("integration-tests/define/out/release-1.2.txt").unwrap(),
+        "version 1.2\n"
+    );
+
+    // Only the parameters listed in `defines` can be set
+    hexmake_command()
+        .in_test_dir()
+        .args(["--define", "edition=2024", "release"])
+        .assert()
+        .failure()
+        .stdout(contains("Cannot set `edition` with --define"));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/define")
+    }
+}
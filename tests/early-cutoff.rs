@@ -0,0 +1,57 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::prelude::*;
+use predicates::str::contains;
+
+const NOTES: &str = "integration-tests/early-cutoff/notes.txt";
+
+#[test]
+fn test_early_cutoff() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/early-cutoff/out");
+    let _ = fs_err::remove_dir_all("integration-tests/early-cutoff/.hex");
+    let original_notes = fs_err::read_to_string(NOTES).unwrap();
+
+    hexmake_command()
+        .in_test_dir()
+        .arg("shout")
+        .assert()
+        .success()
+        .stdout(contains("[shout] Running:"));
+
+    // Changing only a comment reruns the first rule, but its output is
+    // the same, so the second rule comes from the cache
+    fs_err::write(
+        NOTES,
+        original_notes.replace("A comment", "Another comment"),
+    )
+    .unwrap();
+    let result = hexmake_command().in_test_dir().arg("shout").assert();
+    fs_err::write(NOTES, &original_notes).unwrap();
+    result.success().stdout(
+        contains("[strip] Running:")
+            .and(contains("[strip] Outputs are unchanged"))
+            .and(contains("[shout] Retrieved outputs from cache")),
+    );
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/early-cutoff/out/shout.txt").unwrap(),
+        "HELLO\n"
+    );
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this test
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/early-cutoff")
+    }
+}
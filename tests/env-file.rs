@@ -0,0 +1,73 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::read_to_string;
+use fs_err::remove_dir_all;
+use predicates::str::contains;
+
+#[test]
+fn test_env_file() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/env-file/out");
+    let _ = remove_dir_all("integration-tests/env-file/.hex");
+
+    // The env file supplies the declared variables, but not others
+    hexmake_command()
+        .in_test_dir()
+        .env_remove("HEXMAKE_TEST_NAME")
+        .arg("greeting")
+        .assert()
+        .success();
+    assert_eq!(
+        read_to_string("integration-tests/env-file/out/greeting.txt").unwrap(),
+        "HEXMAKE_TEST_GREETING=hello\nHEXMAKE_TEST_NAME=the team\n"
+    );
+
+    // The environment takes precedence over the file, and the new value
+    // causes a rebuild
+    hexmake_command()
+        .in_test_dir()
+        .env("HEXMAKE_TEST_NAME", "everyone")
+        .arg("greeting")
+        .assert()
+        .success();
+    assert_eq!(
+        read_to_string("integration-tests/env-file/out/greeting.txt").unwrap(),
+        "HEXMAKE_TEST_GREETING=hello\nHEXMAKE_TEST_NAME=everyone\n"
+    );
+
+    // A value given with --env takes precedence over both
+    hexmake_command()
+        .in_test_dir()
+        .env("HEXMAKE_TEST_NAME", "everyone")
+        .args(["--env", "HEXMAKE_TEST_NAME=the world", "greeting"])
+        .assert()
+        .success();
+    assert_eq!(
+        read_to_string("integration-tests/env-file/out/greeting.txt").unwrap(),
+        "HEXMAKE_TEST_GREETING=hello\nHEXMAKE_TEST_NAME=the world\n"
+    );
+
+    // Only the variables listed in `env` can be set
+    hexmake_command()
+        .in_test_dir()
+        .args(["--env", "HEXMAKE_TEST_UNLISTED=yes", "greeting"])
+        .assert()
+        .failure()
+        .stdout(contains("Cannot set `HEXMAKE_TEST_UNLISTED` with --env"));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/env-file")
+    }
+}
@@ -0,0 +1,87 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+
+#[test]
+fn test_exit_codes() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/exit-codes/out");
+    let _ = fs_err::remove_dir_all("integration-tests/exit-codes/.hex");
+
+    // A build that succeeds
+    hexmake_command().in_test_dir().arg("fine").assert().code(0);
+
+    // A command that fails
+    hexmake_command()
+        .in_test_dir()
+        .arg("broken")
+        .assert()
+        .code(1);
+
+    // An unknown option
+    hexmake_command()
+        .in_test_dir()
+        .arg("--bogus")
+        .assert()
+        .code(2);
+
+    // A Hexmake file that is not JSON
+    hexmake_command()
+        .in_test_dir()
+        .args(["--file", "-", "broken"])
+        .write_stdin("{")
+        .assert()
+        .code(3);
+
+    // A Hexmake file whose rule depends on a rule that does not exist
+    hexmake_command()
+        .in_test_dir()
+        .args(["--file", "-", "main"])
+        .write_stdin(
+            r#"{"rules": [{"name": "main", "inputs": [], "outputs": ["out/main"],
+                "commands": [], "deps": ["missing"]}]}"#,
+        )
+        .assert()
+        .code(4);
+
+    // A command that refers to a build parameter that does not exist
+    hexmake_command()
+        .in_test_dir()
+        .args(["--file", "-", "main"])
+        .write_stdin(
+            r#"{"rules": [{"name": "main", "inputs": [], "outputs": ["out/main"],
+                "commands": ["echo $DEFINE{missing} > out/main"]}]}"#,
+        )
+        .assert()
+        .code(4);
+
+    // A target that is not in the Hexmake file
+    hexmake_command()
+        .in_test_dir()
+        .arg("bogus")
+        .assert()
+        .code(5);
+
+    // Checking the sources against a lock file that does not exist
+    hexmake_command()
+        .in_test_dir()
+        .args(["--locked", "fine"])
+        .assert()
+        .code(6);
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/exit-codes")
+    }
+}
@@ -0,0 +1,59 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::is_match;
+
+#[test]
+fn test_extract() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/extract/out");
+    let _ = fs_err::remove_dir_all("integration-tests/extract/.hex");
+
+    // The tarball is built by a command and unpacked by Hexmake itself
+    hexmake_command()
+        .in_test_dir()
+        .arg("extracted")
+        .assert()
+        .success();
+
+    let read = |path: &str| {
+        fs_err::read_to_string(format!("integration-tests/extract/out/extracted/{path}")).unwrap()
+    };
+    assert_eq!(read("hello.txt"), "hello\n");
+    assert_eq!(read("sub/nested.txt"), "nested\n");
+
+    // The directory output is retrieved from the cache once it is gone
+    fs_err::remove_dir_all("integration-tests/extract/out/extracted").unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .arg("extracted")
+        .assert()
+        .success()
+        .stdout(is_match(".extracted. Retrieved outputs from cache").unwrap());
+    assert_eq!(read("hello.txt"), "hello\n");
+    assert_eq!(read("sub/nested.txt"), "nested\n");
+
+    // The directory output can be cleaned
+    hexmake_command()
+        .in_test_dir()
+        .args(["clean", "extracted"])
+        .assert()
+        .success();
+    assert!(!fs_err::exists("integration-tests/extract/out/extracted").unwrap());
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/extract")
+    }
+}
@@ -0,0 +1,49 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::contains;
+
+#[test]
+fn test_file_option() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/file-option/out");
+    let _ = fs_err::remove_dir_all("integration-tests/file-option/.hex");
+
+    // -C changes to the test directory first, and the Hexmake file is
+    // read from a subdirectory of it, with paths relative to the test
+    // directory
+    hexmake_command()
+        .current_dir("integration-tests")
+        .args(["-C", "file-option", "--file", "build/Hexmake.json", "shout"])
+        .assert()
+        .success();
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/file-option/out/shout.txt").unwrap(),
+        "HELLO\n"
+    );
+
+    // The options work with subcommands too
+    hexmake_command()
+        .current_dir("integration-tests")
+        .args([
+            "outputs",
+            "shout",
+            "-C",
+            "file-option",
+            "-f",
+            "build/Hexmake.json",
+        ])
+        .assert()
+        .success()
+        .stdout("out/shout.txt\n");
+
+    hexmake_command()
+        .args(["-C", "integration-tests/missing", "shout"])
+        .assert()
+        .failure()
+        .stdout(contains("Could not change to `integration-tests/missing`"));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
@@ -0,0 +1,39 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::remove_dir_all;
+use predicates::str::contains;
+
+#[test]
+fn test_input_limits() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/input-limits/out");
+    let _ = remove_dir_all("integration-tests/input-limits/.hex");
+
+    // An input over a warning threshold still builds, with a warning that
+    // points at its largest parts
+    hexmake_command()
+        .in_test_dir()
+        .arg("assets")
+        .assert()
+        .success()
+        .stderr(contains(
+            "Warning: Input `assets` of `assets` has 5 files and 31 bytes, over the \
+             `warn_files` limit of 3. The largest parts are `assets/icons` (4 files and 24 bytes)",
+        ));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/input-limits")
+    }
+}
@@ -0,0 +1,59 @@
+use std::os::unix::fs::PermissionsExt;
+
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::is_match;
+
+#[test]
+fn test_install() {
+    // Clear the output directory, cache, and prefix
+    let _ = fs_err::remove_dir_all("integration-tests/install/out");
+    let _ = fs_err::remove_dir_all("integration-tests/install/.hex");
+    let prefix = std::env::temp_dir().join("hexmake-install-test");
+    let _ = fs_err::remove_dir_all(&prefix);
+
+    // The target is built, and its outputs are copied to where the
+    // Hexmake file's install settings say
+    hexmake_command()
+        .in_test_dir()
+        .arg("install")
+        .arg("app")
+        .arg("--prefix")
+        .arg(&prefix)
+        .assert()
+        .success()
+        .stdout(is_match(r"Installed 3 files into ").unwrap());
+
+    let read = |path: &str| fs_err::read_to_string(prefix.join(path)).unwrap();
+    let mode = |path: &str| {
+        fs_err::metadata(prefix.join(path))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777
+    };
+    assert_eq!(read("bin/app"), "echo hello\n");
+    assert_eq!(mode("bin/app"), 0o755);
+    assert_eq!(read("share/doc/app/README"), "readme\n");
+    assert_eq!(read("share/doc/app/guide/index.html"), "guide\n");
+    assert_eq!(mode("share/doc/app/guide/index.html"), 0o644);
+
+    let _ = fs_err::remove_dir_all(&prefix);
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/install")
+    }
+}
@@ -0,0 +1,61 @@
+use std::path::Path;
+
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::contains;
+
+#[test]
+fn test_keep_going() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/keep-going/out");
+    let _ = fs_err::remove_dir_all("integration-tests/keep-going/.hex");
+
+    // Both failures are reported, the rule that needs a failed one is
+    // skipped, and the independent rule is still built
+    hexmake_command()
+        .in_test_dir()
+        .args(["--keep-going", "-j", "1"])
+        .args(["uses-broken", "also-broken", "independent"])
+        .assert()
+        .failure()
+        .stdout(contains("[uses-broken] Skipped because `broken` failed"))
+        .stdout(contains("BUILD FAILED: 2 rules failed: "))
+        .stdout(contains("`broken`"))
+        .stdout(contains("`also-broken`"));
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/keep-going/out/independent.txt").unwrap(),
+        "fine\n"
+    );
+    assert!(!Path::new("integration-tests/keep-going/out/uses-broken.txt").exists());
+
+    // Without --keep-going, a rule that is still running when another one
+    // fails is allowed to finish, but nothing that needs it is started
+    let _ = fs_err::remove_dir_all("integration-tests/keep-going/out");
+    hexmake_command()
+        .in_test_dir()
+        .args(["-j", "2", "broken", "uses-uses-slow"])
+        .assert()
+        .failure()
+        .stdout(contains("[uses-slow] Cancelled"))
+        .stdout(contains("[uses-uses-slow] Cancelled"));
+    assert!(Path::new("integration-tests/keep-going/out/slow.txt").exists());
+    assert!(!Path::new("integration-tests/keep-going/out/uses-slow.txt").exists());
+    assert!(!Path::new("integration-tests/keep-going/out/uses-uses-slow.txt").exists());
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/keep-going")
+    }
+}
@@ -0,0 +1,62 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::is_match;
+
+#[test]
+fn test_locked() {
+    // Clear the output directory, cache, and lock file
+    let _ = fs_err::remove_dir_all("integration-tests/locked/out");
+    let _ = fs_err::remove_dir_all("integration-tests/locked/.hex");
+    let _ = fs_err::remove_file("integration-tests/locked/Hexmake.lock");
+
+    // Without a lock file, a locked build fails
+    hexmake_command()
+        .in_test_dir()
+        .args(["--locked", "copy"])
+        .assert()
+        .failure()
+        .stdout(is_match("Run `hexmake lock` to create one").unwrap());
+
+    // A locked build succeeds once the lock file is written
+    hexmake_command()
+        .in_test_dir()
+        .arg("lock")
+        .assert()
+        .success()
+        .stdout(is_match("Wrote Hexmake.lock with 1 sources and 0 downloads").unwrap());
+    hexmake_command()
+        .in_test_dir()
+        .args(["--locked", "copy"])
+        .assert()
+        .success();
+
+    // A source that differs from the lock file makes the build fail
+    let lock = fs_err::read_to_string("integration-tests/locked/Hexmake.lock").unwrap();
+    let lock = lock.replace(r#""hello.txt": ""#, r#""hello.txt": "0"#);
+    fs_err::write("integration-tests/locked/Hexmake.lock", lock).unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .args(["--locked", "copy"])
+        .assert()
+        .failure()
+        .stdout(is_match("Source `hello.txt` has changed").unwrap());
+
+    let _ = fs_err::remove_file("integration-tests/locked/Hexmake.lock");
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/locked")
+    }
+}
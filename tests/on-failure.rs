@@ -0,0 +1,76 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::{contains, is_match};
+use std::path::Path;
+
+#[test]
+fn test_on_failure() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/on-failure/out");
+    let _ = fs_err::remove_dir_all("integration-tests/on-failure/.hex");
+
+    // The build fails, and the hook is told about it. The rule that needs
+    // the failed one is reported as skipped.
+    hexmake_command()
+        .in_test_dir()
+        .env("HEXMAKE_BUILD_ID", "first")
+        .arg("after")
+        .assert()
+        .failure()
+        .stdout(is_match("Hook: rule=fail exit=3 log=.hex/log/first/fail.log").unwrap())
+        .stdout(contains("[after] Skipped because `fail` failed"));
+
+    // The log has the output of the failed command
+    let log =
+        fs_err::read_to_string("integration-tests/on-failure/.hex/log/first/fail.log").unwrap();
+    assert!(log.contains("oops"));
+    assert!(log.contains("Build ID: first"));
+
+    // The status file says which rule failed
+    let status = fs_err::read_to_string("integration-tests/on-failure/.hex/status.json").unwrap();
+    assert!(status.contains(r#""state": "failed""#), "{status}");
+    assert!(
+        status.contains(
+            r#""failed_rules": [
+    "fail"
+  ]"#
+        ),
+        "{status}"
+    );
+    assert!(
+        status.contains(
+            r#""skipped_rules": [
+    "after"
+  ]"#
+        ),
+        "{status}"
+    );
+
+    // Another failure of the same rule is logged for its own build, and
+    // the first log is kept
+    hexmake_command()
+        .in_test_dir()
+        .env("HEXMAKE_BUILD_ID", "second")
+        .arg("after")
+        .assert()
+        .failure()
+        .stdout(is_match("Hook: rule=fail exit=3 log=.hex/log/second/fail.log").unwrap());
+    assert!(Path::new("integration-tests/on-failure/.hex/log/first/fail.log").exists());
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/on-failure")
+    }
+}
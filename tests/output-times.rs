@@ -0,0 +1,58 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::{metadata, remove_dir_all};
+
+#[test]
+fn test_output_times() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/output-times/out");
+    let _ = remove_dir_all("integration-tests/output-times/.hex");
+
+    // Outputs built by a rule get the fixed time
+    hexmake_command()
+        .in_test_dir()
+        .env("SOURCE_DATE_EPOCH", "1600000000")
+        .args(["--output-times=fixed", "greeting"])
+        .assert()
+        .success();
+    let fixed_time = UNIX_EPOCH + Duration::from_secs(1600000000);
+    assert_eq!(modified("out/greeting.txt"), fixed_time);
+    assert_eq!(modified("out/tree/sub/nested.txt"), fixed_time);
+
+    // So do outputs restored from the cache
+    remove_dir_all("integration-tests/output-times/out").unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .env("SOURCE_DATE_EPOCH", "1600000000")
+        .args(["--output-times=fixed", "greeting"])
+        .assert()
+        .success();
+    assert_eq!(modified("out/greeting.txt"), fixed_time);
+    assert_eq!(modified("out/tree/sub/nested.txt"), fixed_time);
+}
+
+/// The modification time of a file in the test directory
+fn modified(path: &str) -> SystemTime {
+    metadata(format!("integration-tests/output-times/{path}"))
+        .unwrap()
+        .modified()
+        .unwrap()
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/output-times")
+    }
+}
@@ -0,0 +1,52 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::read_to_string;
+use fs_err::remove_dir_all;
+use predicates::prelude::*;
+use predicates::str::contains;
+
+#[test]
+fn test_secret_env_is_passed_but_redacted() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/secret-env/out");
+    let _ = remove_dir_all("integration-tests/secret-env/.hex");
+
+    // The secret is passed to the command, but its value is redacted
+    // from the command's output
+    hexmake_command()
+        .in_test_dir()
+        .env("HEXMAKE_TEST_SECRET", "hunter2")
+        .arg("secret-output")
+        .assert()
+        .success()
+        .stdout(contains("[secret-output] secret is [REDACTED]"))
+        .stdout(contains("hunter2").not());
+
+    let secret_output = read_to_string("integration-tests/secret-env/out/secret.txt").unwrap();
+    assert_eq!(secret_output, "hunter2\n");
+
+    // Changing the value of the secret does not change the cache key
+    hexmake_command()
+        .in_test_dir()
+        .env("HEXMAKE_TEST_SECRET", "swordfish")
+        .arg("secret-output")
+        .assert()
+        .success()
+        .stdout(contains("Retrieved outputs from cache"));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/secret-env")
+    }
+}
@@ -0,0 +1,62 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::contains;
+
+/// A Hexmake file that a generator might produce. The test directory has
+/// no Hexmake file of its own.
+const HEXMAKE_FILE: &str = r#"{"rules": [{"name": "main", "inputs": ["greeting.txt"],
+    "outputs": ["out/greeting.txt"], "commands": ["cp greeting.txt out/greeting.txt"]}]}"#;
+
+#[test]
+fn test_stdin_file() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/stdin-file/out");
+    let _ = fs_err::remove_dir_all("integration-tests/stdin-file/.hex");
+
+    // The build reads the Hexmake file from standard input
+    hexmake_command()
+        .in_test_dir()
+        .args(["--file", "-", "main"])
+        .write_stdin(HEXMAKE_FILE)
+        .assert()
+        .success();
+    assert_eq!(
+        fs_err::read_to_string("integration-tests/stdin-file/out/greeting.txt").unwrap(),
+        "hello\n"
+    );
+
+    // Formatting a file from standard input writes it to standard output
+    hexmake_command()
+        .in_test_dir()
+        .args(["fmt", "--file", "-"])
+        .write_stdin(HEXMAKE_FILE)
+        .assert()
+        .success()
+        .stdout(contains("      \"name\": \"main\",\n"));
+
+    // It cannot be edited
+    hexmake_command()
+        .in_test_dir()
+        .args(["remove-rule", "main", "--file", "-"])
+        .write_stdin(HEXMAKE_FILE)
+        .assert()
+        .failure()
+        .stdout(contains("cannot be edited"));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/stdin-file")
+    }
+}
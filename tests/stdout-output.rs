@@ -0,0 +1,40 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::prelude::*;
+use predicates::str::is_match;
+
+#[test]
+fn test_stdout_output() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/stdout-output/out");
+    let _ = fs_err::remove_dir_all("integration-tests/stdout-output/.hex");
+
+    // The output of the commands goes into the file instead of the terminal
+    hexmake_command()
+        .in_test_dir()
+        .arg("greeting")
+        .assert()
+        .success()
+        .stdout(is_match(r"\[greeting\] hello").unwrap().not());
+
+    let greeting =
+        fs_err::read_to_string("integration-tests/stdout-output/out/greeting.txt").unwrap();
+    assert_eq!(greeting, "hello\nworld\n");
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/stdout-output")
+    }
+}
@@ -0,0 +1,63 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::is_match;
+
+#[test]
+fn test_subworkspace() {
+    // Clear the output directories and caches
+    for dir in ["", "/lib"] {
+        let _ = fs_err::remove_dir_all(format!("integration-tests/subworkspace{dir}/out"));
+        let _ = fs_err::remove_dir_all(format!("integration-tests/subworkspace{dir}/.hex"));
+    }
+
+    // An output of the sub-workspace can be built directly
+    hexmake_command()
+        .in_test_dir()
+        .arg("lib/out/lib.txt")
+        .assert()
+        .success()
+        .stdout(is_match(r"\[lib\] Building sub-workspace").unwrap());
+    assert!(fs_err::exists("integration-tests/subworkspace/lib/out/lib.txt").unwrap());
+
+    // A rule in the main workspace can use it as an input
+    hexmake_command()
+        .in_test_dir()
+        .arg("app")
+        .assert()
+        .success();
+    let app = fs_err::read_to_string("integration-tests/subworkspace/out/app.txt").unwrap();
+    assert_eq!(app, "lib\napp\n");
+
+    // Labels name rules in either workspace
+    hexmake_command()
+        .in_test_dir()
+        .args(["--list-targets", "--labels"])
+        .assert()
+        .success()
+        .stdout("//:app\n//lib:lib\n");
+    fs_err::remove_dir_all("integration-tests/subworkspace/lib/out").unwrap();
+    hexmake_command()
+        .in_test_dir()
+        .arg("//lib:lib")
+        .assert()
+        .success()
+        .stdout(is_match(r"\[lib\] Building sub-workspace").unwrap());
+    assert!(fs_err::exists("integration-tests/subworkspace/lib/out/lib.txt").unwrap());
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/subworkspace")
+    }
+}
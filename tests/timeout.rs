@@ -0,0 +1,94 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::{contains, is_match};
+
+#[test]
+fn test_timeout() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/timeout/out");
+    let _ = fs_err::remove_dir_all("integration-tests/timeout/.hex");
+
+    // The build is cancelled long before the command would finish, and
+    // the error says what was still running
+    let start_time = Instant::now();
+    hexmake_command()
+        .in_test_dir()
+        .args(["--timeout", "1s", "slow"])
+        .assert()
+        .failure()
+        .stdout(
+            is_match("The build timed out after 1s. These tasks were still running: slow").unwrap(),
+        )
+        .stdout(contains("[slow] Cancelled"));
+    assert!(start_time.elapsed() < Duration::from_secs(20));
+    assert!(fs_err::metadata("integration-tests/timeout/out/slow.txt").is_err());
+
+    // A download is not stopped, but the rule that uses it never starts,
+    // even with --keep-going
+    let hexmake_file = format!(
+        r#"{{"rules": [
+            {{"name": "download", "kind": "http_file", "url": "{}",
+                "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                "outputs": ["out/download.txt"]}},
+            {{"name": "uses-download", "kind": "copy", "inputs": ["out/download.txt"],
+                "outputs": ["out/uses-download.txt"]}}]}}"#,
+        serve_slowly(b"hello", Duration::from_secs(3))
+    );
+    hexmake_command()
+        .in_test_dir()
+        .args(["--file", "-", "--keep-going", "--timeout", "1s"])
+        .arg("uses-download")
+        .write_stdin(hexmake_file)
+        .assert()
+        .failure()
+        .stdout(contains("These tasks were still running: download"))
+        .stdout(contains("[uses-download] Cancelled"));
+    assert!(fs_err::metadata("integration-tests/timeout/out/uses-download.txt").is_err());
+}
+
+/// Serve one HTTP response with the given body after a delay, and return
+/// the URL to fetch it from
+fn serve_slowly(body: &'static [u8], delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/file.txt", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let count = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..count]);
+        }
+        thread::sleep(delay);
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+    });
+    url
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this test
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/timeout")
+    }
+}
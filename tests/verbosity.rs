@@ -0,0 +1,83 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::prelude::*;
+use predicates::str::contains;
+
+#[test]
+fn test_verbosity() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/verbosity/out");
+    let _ = fs_err::remove_dir_all("integration-tests/verbosity/.hex");
+
+    // A quiet build prints only the summary
+    hexmake_command()
+        .in_test_dir()
+        .args(["--quiet", "greeting"])
+        .assert()
+        .success()
+        .stdout(contains("Running:").not())
+        .stdout(contains("Build succeeded in "))
+        .stdout(contains(": 1 rule built, 0 from the cache"));
+
+    // A verbose build prints the worker and the cache key
+    let _ = fs_err::remove_dir_all("integration-tests/verbosity/out");
+    let _ = fs_err::remove_dir_all("integration-tests/verbosity/.hex");
+    hexmake_command()
+        .in_test_dir()
+        .args(["-v", "greeting"])
+        .assert()
+        .success()
+        .stdout(contains("[greeting] Started on worker 0"))
+        .stdout(contains("[greeting] Cache key "));
+
+    // Otherwise, neither is printed, but the whole command still is
+    hexmake_command()
+        .in_test_dir()
+        .args(["--force", "greeting"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "[greeting] Running: echo hello\necho hello > out/greeting.txt\n",
+        ))
+        .stdout(contains("Cache key").not());
+
+    // Once a command fails, the output of the ones that succeed is only
+    // printed with -vv
+    hexmake_command()
+        .in_test_dir()
+        .args(["--keep-going", "-j", "1", "late", "broken"])
+        .assert()
+        .failure()
+        .stdout(contains("[late] late output").not());
+    hexmake_command()
+        .in_test_dir()
+        .args([
+            "-vv",
+            "--keep-going",
+            "-j",
+            "1",
+            "--force",
+            "late",
+            "broken",
+        ])
+        .assert()
+        .failure()
+        .stdout(contains("[late] late output"));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to the one for this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/verbosity")
+    }
+}
@@ -0,0 +1,51 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::read_to_string;
+use fs_err::remove_dir_all;
+use predicates::str::contains;
+
+#[test]
+fn test_volatile_status() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/volatile-status/out");
+    let _ = remove_dir_all("integration-tests/volatile-status/.hex");
+
+    // A stamped rule can read the status of the build
+    hexmake_command()
+        .in_test_dir()
+        .arg("stamped")
+        .assert()
+        .success();
+    let build_info =
+        read_to_string("integration-tests/volatile-status/out/build-info.txt").unwrap();
+    assert!(build_info.starts_with("BUILD_TIMESTAMP "));
+
+    // The status of the next build is different, but the rule is still
+    // retrieved from the cache
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    hexmake_command()
+        .in_test_dir()
+        .arg("stamped")
+        .assert()
+        .success()
+        .stdout(contains("[stamped] Retrieved outputs from cache"));
+    let status =
+        read_to_string("integration-tests/volatile-status/.hex/volatile-status.txt").unwrap();
+    assert!(!status.starts_with(&build_info));
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/volatile-status")
+    }
+}
@@ -0,0 +1,50 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use fs_err::read_to_string;
+use fs_err::remove_dir_all;
+
+#[test]
+fn test_worker_env() {
+    // Clear the output directory and cache
+    let _ = remove_dir_all("integration-tests/worker-env/out");
+    let _ = remove_dir_all("integration-tests/worker-env/.hex");
+
+    // Build with more than one worker
+    hexmake_command()
+        .in_test_dir()
+        .args(["-j", "3", "env-output"])
+        .assert()
+        .success();
+    let env_output = read_to_string("integration-tests/worker-env/out/env.txt").unwrap();
+
+    // Variables describing the worker are always present
+    let work_dir = std::path::absolute("integration-tests/worker-env/.hex/work/0").unwrap();
+    for expected in [
+        "HEXMAKE_RULE_NAME=env-output".to_string(),
+        format!("HEXMAKE_WORK_DIR={}", work_dir.display()),
+        format!("HEXMAKE_OUT_DIR={}", work_dir.join("out").display()),
+        "HEXMAKE_WORKER_ID=0".to_string(),
+        "HEXMAKE_JOBS=3".to_string(),
+    ] {
+        assert!(
+            env_output.lines().any(|line| line == expected),
+            "expected {expected} in env output, got:\n{env_output}"
+        );
+    }
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/worker-env")
+    }
+}
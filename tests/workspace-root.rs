@@ -0,0 +1,45 @@
+use assert_cmd::Command;
+use assert_cmd::cargo_bin;
+use predicates::str::is_match;
+
+#[test]
+fn test_workspace_root() {
+    // Clear the output directory and cache
+    let _ = fs_err::remove_dir_all("integration-tests/workspace-root/out");
+    let _ = fs_err::remove_dir_all("integration-tests/workspace-root/.hex");
+
+    // From a subdirectory, paths are relative to that directory
+    hexmake_command()
+        .in_test_dir()
+        .args(["--print-commands", "--affected-by", "hello.txt"])
+        .assert()
+        .success()
+        .stdout(is_match("# Rule copy").unwrap());
+
+    // The build runs at the workspace root
+    hexmake_command()
+        .in_test_dir()
+        .arg("../out/hello.txt")
+        .assert()
+        .success();
+    let hello = fs_err::read_to_string("integration-tests/workspace-root/out/hello.txt").unwrap();
+    assert_eq!(hello, "hello\n");
+    assert!(!fs_err::exists("integration-tests/workspace-root/src/out").unwrap());
+}
+
+/// A command for running `hexmake`
+fn hexmake_command() -> Command {
+    Command::new(cargo_bin!())
+}
+
+/// Extensions to Command for this test
+trait CommandExt {
+    /// Set the current directory to a subdirectory of this example
+    fn in_test_dir(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+    fn in_test_dir(&mut self) -> &mut Self {
+        self.current_dir("integration-tests/workspace-root/src")
+    }
+}